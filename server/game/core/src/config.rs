@@ -1,32 +1,260 @@
 use flux::session::server::SessionKey;
+use neutronium::net::endpoint;
 use serde_derive::{Deserialize, Serialize};
 use serdeconv;
-use std::path::Path;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub const DEFAULT_PORT: u16 = 28008;
+pub const MIN_FPS: u64 = 1;
+// The frame time is measured in whole milliseconds
+pub const MAX_FPS: u64 = 1000;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Server {
     pub address: String,
     pub token: SessionKey,
     pub max_clients: u16,
     pub threads: u16,
+    #[serde(default)]
+    pub timeouts: Timeouts,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Liveness settings of the client connections in milliseconds.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(default)]
+pub struct Timeouts {
+    pub handshake_ms: u64,
+    pub ingress_ms: u64,
+    pub keepalive_ms: u64,
+    pub housekeeping_ms: u64,
+}
+
+impl Timeouts {
+    /// Convert into the timeouts used by the network endpoint.
+    pub fn to_endpoint(&self) -> endpoint::Timeouts {
+        endpoint::Timeouts {
+            handshake: Duration::from_millis(self.handshake_ms),
+            ingress: Duration::from_millis(self.ingress_ms),
+            keepalive: Duration::from_millis(self.keepalive_ms),
+            housekeeping: Duration::from_millis(self.housekeeping_ms),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        let timeouts = endpoint::Timeouts::default();
+        let millis = |duration: Duration| duration.as_secs() * 1000 + u64::from(duration.subsec_millis());
+
+        Timeouts {
+            handshake_ms: millis(timeouts.handshake),
+            ingress_ms: millis(timeouts.ingress),
+            keepalive_ms: millis(timeouts.keepalive),
+            housekeeping_ms: millis(timeouts.housekeeping),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Game {
     pub fps: u64,
+    /// Watch the configuration file and apply changes to the running game.
+    #[serde(default)]
+    pub hot_reload: bool,
+}
+
+/// Settings of the Prometheus metrics exporter.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Metrics {
+    pub address: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct GameConfig {
     pub server: Server,
     pub game: Game,
+    /// The metrics exporter is disabled unless configured.
+    #[serde(default)]
+    pub metrics: Option<Metrics>,
 }
 
 impl GameConfig {
+    /// Load and validate the configuration file at the given path.
     pub fn load<P: AsRef<Path>>(path: P) -> GameConfig {
-        serdeconv::from_toml_file(path).expect("Error loading game configuration file")
+        let config: GameConfig =
+            serdeconv::from_toml_file(path).expect("Error loading game configuration file");
+
+        if let Err(errors) = config.validate() {
+            let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+            panic!("Invalid game configuration: {}", messages.join("; "));
+        }
+
+        config
+    }
+
+    /// Check the configuration for invalid values, reporting all problems at once.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+
+        if let Err(err) = self.server.address.parse::<SocketAddr>() {
+            errors.push(ConfigError::Address(self.server.address.clone(), err.to_string()));
+        }
+
+        if self.server.max_clients == 0 {
+            errors.push(ConfigError::MaxClients);
+        }
+
+        if self.server.threads == 0 {
+            errors.push(ConfigError::Threads);
+        }
+
+        let timeouts = &self.server.timeouts;
+        if timeouts.keepalive_ms >= timeouts.ingress_ms {
+            errors.push(ConfigError::Keepalive(timeouts.keepalive_ms, timeouts.ingress_ms));
+        }
+
+        if self.game.fps < MIN_FPS || self.game.fps > MAX_FPS {
+            errors.push(ConfigError::Fps(self.game.fps));
+        }
+
+        if let Some(metrics) = &self.metrics {
+            if let Err(err) = metrics.address.parse::<SocketAddr>() {
+                errors.push(ConfigError::MetricsAddress(metrics.address.clone(), err.to_string()));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            _ => Err(errors),
+        }
+    }
+
+    /// Checks that the supplied configuration only differs in fields that can be changed at runtime.
+    fn check_reload(&self, other: &GameConfig) -> Result<(), ReloadError> {
+        if self.server.address != other.server.address {
+            return Err(ReloadError::Immutable("server.address"));
+        }
+
+        if self.server.token[..] != other.server.token[..] {
+            return Err(ReloadError::Immutable("server.token"));
+        }
+
+        if self.server.max_clients != other.server.max_clients {
+            return Err(ReloadError::Immutable("server.max_clients"));
+        }
+
+        if self.server.threads != other.server.threads {
+            return Err(ReloadError::Immutable("server.threads"));
+        }
+
+        if self.metrics != other.metrics {
+            return Err(ReloadError::Immutable("metrics"));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ConfigError {
+    Address(String, String),
+    MaxClients,
+    Threads,
+    Keepalive(u64, u64),
+    Fps(u64),
+    MetricsAddress(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            ConfigError::Address(address, err) => {
+                write!(f, "server.address '{}' is not a valid socket address: {}", address, err)
+            }
+            ConfigError::MaxClients => write!(f, "server.max_clients must be greater than 0"),
+            ConfigError::Threads => write!(f, "server.threads must be at least 1"),
+            ConfigError::Keepalive(keepalive, ingress) => write!(
+                f,
+                "server.timeouts.keepalive_ms {} must be less than ingress_ms {}",
+                keepalive, ingress
+            ),
+            ConfigError::Fps(fps) => write!(f, "game.fps {} must be between {} and {}", fps, MIN_FPS, MAX_FPS),
+            ConfigError::MetricsAddress(address, err) => {
+                write!(f, "metrics.address '{}' is not a valid socket address: {}", address, err)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReloadError {
+    Io(String),
+    Parse(String),
+    Invalid(Vec<ConfigError>),
+    Immutable(&'static str),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            ReloadError::Io(err) => write!(f, "error reading configuration: {}", err),
+            ReloadError::Parse(err) => write!(f, "error parsing configuration: {}", err),
+            ReloadError::Invalid(errors) => {
+                let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "invalid configuration: {}", messages.join("; "))
+            }
+            ReloadError::Immutable(field) => write!(f, "field {} can't be changed at runtime", field),
+        }
+    }
+}
+
+/// Watches the game configuration file for changes. Reloaded configurations are only accepted if they
+/// leave the fields that can't be changed at runtime intact.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    contents: String,
+    config: GameConfig,
+}
+
+impl ConfigWatcher {
+    /// Start watching the configuration file at the given path. The supplied configuration is the one
+    /// currently in effect.
+    pub fn new<P: AsRef<Path>>(path: P, config: GameConfig) -> ConfigWatcher {
+        let path = path.as_ref().to_path_buf();
+        let contents = fs::read_to_string(&path).unwrap_or_default();
+
+        ConfigWatcher { path, contents, config }
+    }
+
+    /// Get the configuration currently in effect.
+    #[inline]
+    pub fn config(&self) -> &GameConfig {
+        &self.config
+    }
+
+    /// Check the configuration file for changes. Returns the new configuration if the file has changed
+    /// and the changes can be applied at runtime. Rejected changes are not reported again until the
+    /// file changes once more.
+    pub fn poll(&mut self) -> Result<Option<&GameConfig>, ReloadError> {
+        let contents = fs::read_to_string(&self.path).map_err(|err| ReloadError::Io(err.to_string()))?;
+
+        if contents == self.contents {
+            return Ok(None);
+        }
+
+        let config: GameConfig = serdeconv::from_toml_str(&contents);
+        self.contents = contents;
+
+        let config = config.map_err(|err| ReloadError::Parse(err.to_string()))?;
+        config.validate().map_err(ReloadError::Invalid)?;
+        self.config.check_reload(&config)?;
+        self.config = config;
+
+        Ok(Some(&self.config))
     }
 }
 
@@ -34,12 +262,175 @@ impl Default for GameConfig {
     fn default() -> GameConfig {
         GameConfig {
             server: Server {
-                address: format!("localhost:{}", DEFAULT_PORT),
+                address: format!("127.0.0.1:{}", DEFAULT_PORT),
                 token: SessionKey::new([0; SessionKey::SIZE]),
                 max_clients: 256,
                 threads: 8,
+                timeouts: Timeouts::default(),
+            },
+            game: Game {
+                fps: 20,
+                hot_reload: false,
             },
-            game: Game { fps: 20 },
+            metrics: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn temp_config_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("{}_{}.toml", name, process::id()))
+    }
+
+    fn write_config(path: &Path, config: &GameConfig) {
+        fs::write(path, serdeconv::to_toml_string(config).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_validate_default() {
+        assert_eq!(GameConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_address() {
+        let mut config = GameConfig::default();
+        config.server.address = "localhost".to_owned();
+
+        match config.validate().unwrap_err().as_slice() {
+            [ConfigError::Address(address, _)] => assert_eq!(address, "localhost"),
+            errors => panic!("Unexpected errors {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn test_validate_max_clients() {
+        let mut config = GameConfig::default();
+        config.server.max_clients = 0;
+
+        assert_eq!(config.validate(), Err(vec![ConfigError::MaxClients]));
+    }
+
+    #[test]
+    fn test_validate_threads() {
+        let mut config = GameConfig::default();
+        config.server.threads = 0;
+
+        assert_eq!(config.validate(), Err(vec![ConfigError::Threads]));
+    }
+
+    #[test]
+    fn test_validate_timeouts() {
+        let mut config = GameConfig::default();
+        config.server.timeouts.keepalive_ms = config.server.timeouts.ingress_ms;
+
+        assert_eq!(
+            config.validate(),
+            Err(vec![ConfigError::Keepalive(
+                config.server.timeouts.ingress_ms,
+                config.server.timeouts.ingress_ms
+            )])
+        );
+    }
+
+    #[test]
+    fn test_validate_fps() {
+        let mut config = GameConfig::default();
+
+        config.game.fps = 0;
+        assert_eq!(config.validate(), Err(vec![ConfigError::Fps(0)]));
+
+        config.game.fps = MAX_FPS + 1;
+        assert_eq!(config.validate(), Err(vec![ConfigError::Fps(MAX_FPS + 1)]));
+
+        config.game.fps = MAX_FPS;
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_metrics_address() {
+        let mut config = GameConfig::default();
+
+        config.metrics = Some(Metrics {
+            address: "127.0.0.1:28009".to_owned(),
+        });
+        assert_eq!(config.validate(), Ok(()));
+
+        config.metrics = Some(Metrics {
+            address: "metrics".to_owned(),
+        });
+        match config.validate().unwrap_err().as_slice() {
+            [ConfigError::MetricsAddress(address, _)] => assert_eq!(address, "metrics"),
+            errors => panic!("Unexpected errors {:?}", errors),
         }
     }
+
+    #[test]
+    fn test_validate_all_errors() {
+        let mut config = GameConfig::default();
+        config.server.max_clients = 0;
+        config.server.threads = 0;
+        config.game.fps = 0;
+
+        assert_eq!(
+            config.validate(),
+            Err(vec![ConfigError::MaxClients, ConfigError::Threads, ConfigError::Fps(0)])
+        );
+    }
+
+    #[test]
+    fn test_watcher_reload() {
+        let path = temp_config_path("test_watcher_reload");
+
+        let mut config = GameConfig::default();
+        config.game.hot_reload = true;
+        write_config(&path, &config);
+
+        let mut watcher = ConfigWatcher::new(&path, GameConfig::load(&path));
+
+        assert!(watcher.poll().unwrap().is_none());
+
+        config.game.fps = 60;
+        config.server.timeouts.keepalive_ms = 1000;
+        config.server.timeouts.ingress_ms = 10000;
+        write_config(&path, &config);
+
+        let reloaded = watcher.poll().unwrap().unwrap();
+        assert_eq!(reloaded.game.fps, 60);
+        assert_eq!(reloaded.server.timeouts, config.server.timeouts);
+
+        assert!(watcher.poll().unwrap().is_none());
+        assert_eq!(watcher.config().game.fps, 60);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_watcher_reject_immutable() {
+        let path = temp_config_path("test_watcher_reject_immutable");
+
+        let mut config = GameConfig::default();
+        write_config(&path, &config);
+
+        let mut watcher = ConfigWatcher::new(&path, GameConfig::load(&path));
+
+        config.game.fps = 60;
+        config.server.address = "127.0.0.1:1234".to_owned();
+        write_config(&path, &config);
+
+        match watcher.poll() {
+            Err(ReloadError::Immutable(field)) => assert_eq!(field, "server.address"),
+            _ => panic!("Expected the reload to be rejected"),
+        }
+
+        // The rejected change is neither applied nor reported again
+        assert!(watcher.poll().unwrap().is_none());
+        assert_eq!(watcher.config().game.fps, 20);
+
+        fs::remove_file(&path).unwrap();
+    }
 }
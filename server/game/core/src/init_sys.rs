@@ -13,5 +13,5 @@ fn init_replicator(world: &mut World, config: &GameConfig, log: &logging::Logger
 
     let replicator = Replicator::new(&config.server, log);
 
-    world.register_system(replicator);
+    world.register_persistent_system(replicator);
 }
@@ -1,3 +1,6 @@
+#![feature(integer_atomics, proc_macro_hygiene, decl_macro)]
+
 pub mod config;
+pub mod metrics;
 pub mod replicator;
 pub mod systems;
\ No newline at end of file
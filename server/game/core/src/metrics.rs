@@ -0,0 +1,186 @@
+use hashbrown::HashMap;
+use neutronium::world::FrameStats;
+use rocket::http::ContentType;
+use rocket::response::Content;
+use rocket::{get, routes, Rocket, State};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Counters published by the game loop and scraped by the metrics exporter. The game loop never waits
+/// on the exporter: scalars are atomics and the disconnect counts are skipped for the frame if a scrape
+/// is in progress. The counts are cumulative, so the next frame catches up.
+#[derive(Default)]
+pub struct Metrics {
+    frames: AtomicU64,
+    frame_overruns: AtomicU64,
+    frame_time_total_us: AtomicU64,
+    frame_time_max_us: AtomicU64,
+    live_channels: AtomicU64,
+    disconnects: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    #[inline]
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Publish the frame execution time statistics of the world.
+    #[inline]
+    pub fn publish_frames(&self, stats: &FrameStats) {
+        self.frames.store(stats.frames, Ordering::Relaxed);
+        self.frame_overruns.store(stats.overruns, Ordering::Relaxed);
+        self.frame_time_total_us.store(micros(stats.total), Ordering::Relaxed);
+        self.frame_time_max_us.store(stats.max.map_or(0, micros), Ordering::Relaxed);
+    }
+
+    /// Publish the number of live channels and the disconnect counts of the network endpoint.
+    #[inline]
+    pub fn publish_network(&self, live_channels: usize, disconnects: &HashMap<&'static str, u64>) {
+        self.live_channels.store(live_channels as u64, Ordering::Relaxed);
+
+        if let Ok(mut published) = self.disconnects.try_lock() {
+            published.extend(disconnects.iter().map(|(&reason, &count)| (reason, count)));
+        }
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counter = |out: &mut String, name: &str, help: &str, value: String| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        };
+
+        counter(
+            &mut out,
+            "game_frames_total",
+            "Number of frames executed.",
+            self.frames.load(Ordering::Relaxed).to_string(),
+        );
+        counter(
+            &mut out,
+            "game_frame_overruns_total",
+            "Number of frames exceeding the frame time budget.",
+            self.frame_overruns.load(Ordering::Relaxed).to_string(),
+        );
+        counter(
+            &mut out,
+            "game_frame_time_seconds_total",
+            "Total frame execution time.",
+            seconds(self.frame_time_total_us.load(Ordering::Relaxed)),
+        );
+
+        writeln!(out, "# HELP game_frame_time_max_seconds Longest frame execution time.").unwrap();
+        writeln!(out, "# TYPE game_frame_time_max_seconds gauge").unwrap();
+        writeln!(
+            out,
+            "game_frame_time_max_seconds {}",
+            seconds(self.frame_time_max_us.load(Ordering::Relaxed))
+        )
+        .unwrap();
+
+        writeln!(out, "# HELP game_live_channels Number of live client channels.").unwrap();
+        writeln!(out, "# TYPE game_live_channels gauge").unwrap();
+        writeln!(out, "game_live_channels {}", self.live_channels.load(Ordering::Relaxed)).unwrap();
+
+        let mut disconnects: Vec<_> = self
+            .disconnects
+            .lock()
+            .expect("Failed to acquire metrics lock")
+            .iter()
+            .map(|(&reason, &count)| (reason, count))
+            .collect();
+        disconnects.sort();
+
+        writeln!(out, "# HELP game_disconnects_total Number of closed channels by reason.").unwrap();
+        writeln!(out, "# TYPE game_disconnects_total counter").unwrap();
+        for (reason, count) in disconnects {
+            writeln!(out, "game_disconnects_total{{reason=\"{}\"}} {}", reason, count).unwrap();
+        }
+
+        out
+    }
+}
+
+#[inline]
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+#[inline]
+fn seconds(micros: u64) -> String {
+    format!("{}.{:06}", micros / 1_000_000, micros % 1_000_000)
+}
+
+#[get("/metrics")]
+fn scrape(metrics: State<Arc<Metrics>>) -> Content<String> {
+    Content(ContentType::Plain, metrics.render())
+}
+
+/// Mount the metrics route on the supplied rocket instance.
+pub fn build(rocket: Rocket, metrics: Arc<Metrics>) -> Rocket {
+    rocket.mount("/", routes![scrape]).manage(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Status;
+    use rocket::local::Client;
+
+    #[test]
+    fn test_render_empty() {
+        let text = Metrics::new().render();
+
+        assert!(text.contains("game_frames_total 0\n"));
+        assert!(text.contains("game_frame_time_seconds_total 0.000000\n"));
+        assert!(text.contains("# TYPE game_disconnects_total counter\n"));
+        assert!(!text.contains("game_disconnects_total{"));
+    }
+
+    #[test]
+    fn test_scrape() {
+        let metrics = Arc::new(Metrics::new());
+
+        metrics.publish_frames(&FrameStats {
+            frames: 120,
+            overruns: 2,
+            min: Some(Duration::from_millis(1)),
+            max: Some(Duration::from_micros(62_500)),
+            total: Duration::from_millis(1500),
+        });
+
+        let mut disconnects = HashMap::new();
+        disconnects.insert("crypto", 3);
+        disconnects.insert("ingress_timeout", 5);
+        metrics.publish_network(7, &disconnects);
+
+        let rocket = build(rocket::custom(rocket::Config::development()), metrics);
+        let client = Client::new(rocket).unwrap();
+
+        let mut response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::Plain));
+
+        let text = response.body_string().unwrap();
+
+        for expected in [
+            "game_frames_total 120\n",
+            "game_frame_overruns_total 2\n",
+            "game_frame_time_seconds_total 1.500000\n",
+            "game_frame_time_max_seconds 0.062500\n",
+            "game_live_channels 7\n",
+            "game_disconnects_total{reason=\"crypto\"} 3\n",
+            "game_disconnects_total{reason=\"ingress_timeout\"} 5\n",
+        ]
+        .iter()
+        {
+            assert!(text.contains(expected), "Missing {} in {}", expected, text);
+        }
+    }
+}
@@ -1,21 +1,224 @@
-use crate::config::Server;
+use crate::config::{Server, Timeouts};
+use crate::metrics::Metrics;
 use flux::logging;
-use neutronium::net::endpoint::Endpoint;
-use neutronium::prelude::{Context, Router, RunSystem, TransactionContext};
+use neutronium::net::channel::ChannelId;
+use neutronium::net::endpoint::{ConnectionChange, Endpoint, Health};
+use neutronium::net::support::{PayloadBatch, Serialize};
+use neutronium::prelude::{Context, EntityId, Router, RunSystem, TransactionContext};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Arc;
+
+// Scratch space for serializing payloads when comparing them against the last sent state
+const SCRATCH_SIZE: usize = 65536;
+
+/// World coordinates of a replicated entity.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Coordinates {
+    pub x: f32,
+    pub y: f32,
+}
+
+/// State update of a single replicated entity.
+#[derive(Debug, Clone)]
+pub struct Update<P> {
+    pub entity: EntityId,
+    pub position: Coordinates,
+    pub payload: P,
+}
+
+/// Identity of a replicated payload within its entity, e.g. the component class it carries. Payloads
+/// sharing both the entity and identity supersede each other.
+pub trait Identity {
+    fn identity(&self) -> u64;
+}
+
+/// Last state of a payload sent to a client.
+struct SentState {
+    bytes: Vec<u8>,
+    frame: u64,
+}
+
+/// Delta compression cache of a single client.
+#[derive(Default)]
+struct ClientCache {
+    frame: u64,
+    sent: HashMap<(EntityId, u64), SentState>,
+}
+
+/// Interest management for the replicator. Clients only receive updates of entities that fall into
+/// their area of interest.
+pub trait Interest {
+    fn is_relevant(&self, client: ChannelId, position: Coordinates) -> bool;
+}
+
+/// Default interest management, every client receives every update.
+pub struct ReplicateAll;
+
+impl Interest for ReplicateAll {
+    #[inline]
+    fn is_relevant(&self, _client: ChannelId, _position: Coordinates) -> bool {
+        true
+    }
+}
 
 pub struct Replicator {
     endpoint: Endpoint,
+    interest: Box<Interest>,
+    caches: HashMap<ChannelId, ClientCache>,
+    scratch: Vec<u8>,
+    metrics: Arc<Metrics>,
     log: logging::Logger,
 }
 
 impl Replicator {
+    /// Number of recorded frames after which unchanged payloads are sent again, allowing clients to
+    /// recover lost state.
+    pub const KEYFRAME_INTERVAL: u64 = 60;
+
     pub fn new(config: &Server, log: &logging::Logger) -> Replicator {
+        let mut endpoint = Endpoint::new(
+            &config.address,
+            config.token.clone(),
+            config.timeouts.to_endpoint(),
+            &log,
+        )
+        .expect("Failed creating endpoint");
+        endpoint.set_threads(config.threads as usize);
+
         Replicator {
-            endpoint: Endpoint::new(&config.address, config.token.clone(), &log)
-                .expect("Failed creating endpoint"),
+            endpoint,
+            interest: Box::new(ReplicateAll),
+            caches: HashMap::new(),
+            scratch: vec![0u8; SCRATCH_SIZE],
+            metrics: Arc::new(Metrics::new()),
             log: log.new(logging::o!())
         }
     }
+
+    /// Replace the interest management used to cull the updates sent to the clients.
+    pub fn set_interest<I: 'static + Interest>(&mut self, interest: I) {
+        self.interest = Box::new(interest);
+    }
+
+    /// Record the updates within the area of interest of the client into the payload buffer. Each call
+    /// counts as a frame for the client. Payloads identical to the last ones sent to the client are
+    /// skipped, unless they haven't been sent for a keyframe interval.
+    pub fn record<P: Serialize + Clone + Identity>(
+        &mut self,
+        client: ChannelId,
+        updates: &[Update<P>],
+        buffer: &mut PayloadBatch<P>,
+    ) {
+        let mut recorded = 0usize;
+
+        // At most every update is recorded, size the buffer upfront to avoid growing it one by one
+        buffer.reserve(updates.len());
+
+        let cache = self.caches.entry(client).or_insert_with(ClientCache::default);
+        let frame = cache.frame;
+        cache.frame += 1;
+
+        for update in updates {
+            if !self.interest.is_relevant(client, update.position) {
+                continue;
+            }
+
+            // Payloads that can't be serialized into the scratch space are always sent
+            let mut cursor = Cursor::new(&mut self.scratch[..]);
+            let size = match update.payload.serialize(&mut cursor) {
+                Ok(_) => cursor.position() as usize,
+                Err(_) => {
+                    buffer.push(update.payload.clone());
+                    recorded += 1;
+                    continue;
+                }
+            };
+            let bytes = &self.scratch[..size];
+
+            let key = (update.entity, update.payload.identity());
+
+            let changed = match cache.sent.get_mut(&key) {
+                Some(state) => {
+                    if state.bytes[..] != bytes[..] || frame - state.frame >= Self::KEYFRAME_INTERVAL {
+                        state.bytes.clear();
+                        state.bytes.extend_from_slice(bytes);
+                        state.frame = frame;
+                        true
+                    } else {
+                        false
+                    }
+                }
+                _ => {
+                    cache.sent.insert(
+                        key,
+                        SentState {
+                            bytes: bytes.to_vec(),
+                            frame,
+                        },
+                    );
+                    true
+                }
+            };
+
+            if changed {
+                buffer.push(update.payload.clone());
+                recorded += 1;
+            }
+        }
+
+        logging::trace!(self.log, "recorded updates for client";
+                        "context" => "record",
+                        "channel_id" => client,
+                        "update_count" => updates.len(),
+                        "recorded_count" => recorded);
+    }
+
+    /// Apply the supplied timeouts to the running network endpoint.
+    pub fn set_timeouts(&mut self, timeouts: &Timeouts) {
+        self.endpoint.set_timeouts(timeouts.to_endpoint());
+    }
+
+    /// Report the health of the network endpoint.
+    #[inline]
+    pub fn health(&self) -> Health {
+        self.endpoint.health()
+    }
+
+    /// Get the metrics the replicator publishes the network statistics into.
+    #[inline]
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Drop the delta compression state of the client, the next recording sends everything.
+    pub fn forget(&mut self, client: ChannelId) {
+        self.caches.remove(&client);
+    }
+
+    /// Drop the delta compression state of the clients that went away. Suspended clients missed the
+    /// updates sent in the meantime, they receive everything again once resumed.
+    fn process_changes(&mut self) {
+        let caches = &mut self.caches;
+
+        for change in self.endpoint.changes() {
+            if let Some(client) = Self::departed(&change) {
+                caches.remove(&client);
+
+                logging::debug!(self.log, "forgot client state";
+                                "context" => "process_changes",
+                                "channel_id" => client);
+            }
+        }
+    }
+
+    #[inline]
+    fn departed(change: &ConnectionChange) -> Option<ChannelId> {
+        match *change {
+            ConnectionChange::Suspended(client) | ConnectionChange::Disconnected(client) => Some(client),
+            _ => None,
+        }
+    }
 }
 
 impl RunSystem for Replicator {
@@ -32,10 +235,184 @@ impl RunSystem for Replicator {
         3. Sync
         */
         self.endpoint.sync(ctx.timestamp);
+        self.process_changes();
+
+        self.metrics.publish_network(self.endpoint.health().live_count, self.endpoint.disconnect_metrics());
     }
 
     fn init(&mut self) {
         logging::info!(self.log, "initializing Replicator system"; "context" => "init");
         self.endpoint.init();
     }
+
+    fn shutdown(&mut self) {
+        logging::info!(self.log, "shutting down Replicator system"; "context" => "shutdown");
+        self.endpoint.shutdown(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flux::session::server::SessionKey;
+    use neutronium::net::support::{NetworkResult, SizedWrite};
+    use std::io::Write;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct TestPayload(u64);
+
+    impl Serialize for TestPayload {
+        fn serialize<W: SizedWrite>(&self, stream: &mut W) -> NetworkResult<()> {
+            stream.write_all(&self.0.to_be_bytes())?;
+            Ok(())
+        }
+    }
+
+    impl Identity for TestPayload {
+        fn identity(&self) -> u64 {
+            0
+        }
+    }
+
+    /// Axis aligned rectangular region per client
+    struct Regions(HashMap<ChannelId, (Coordinates, Coordinates)>);
+
+    impl Interest for Regions {
+        fn is_relevant(&self, client: ChannelId, position: Coordinates) -> bool {
+            match self.0.get(&client) {
+                Some((min, max)) => {
+                    position.x >= min.x && position.x < max.x && position.y >= min.y && position.y < max.y
+                }
+                _ => false,
+            }
+        }
+    }
+
+    fn make_replicator() -> Replicator {
+        let config = Server {
+            address: "127.0.0.1:0".to_owned(),
+            token: SessionKey::new([0; SessionKey::SIZE]),
+            max_clients: 2,
+            threads: 1,
+            timeouts: Timeouts::default(),
+        };
+
+        Replicator::new(&config, &logging::Logger::root(logging::Discard, logging::o!()))
+    }
+
+    fn make_updates() -> Vec<Update<TestPayload>> {
+        vec![
+            Update {
+                entity: EntityId::from(0usize),
+                position: Coordinates { x: 1.0, y: 1.0 },
+                payload: TestPayload(0),
+            },
+            Update {
+                entity: EntityId::from(1usize),
+                position: Coordinates { x: 15.0, y: 12.0 },
+                payload: TestPayload(1),
+            },
+            Update {
+                entity: EntityId::from(2usize),
+                position: Coordinates { x: 5.0, y: 9.0 },
+                payload: TestPayload(2),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_record_replicate_all() {
+        let mut replicator = make_replicator();
+        let updates = make_updates();
+
+        let mut buffer = PayloadBatch::new();
+        replicator.record(0, &updates, &mut buffer);
+
+        // The buffer is sized for the updates upfront
+        assert!(buffer.capacity() >= updates.len());
+        assert_eq!(
+            buffer.drain().collect::<Vec<_>>(),
+            vec![TestPayload(0), TestPayload(1), TestPayload(2)]
+        );
+    }
+
+    #[test]
+    fn test_record_interest() {
+        let mut replicator = make_replicator();
+        let updates = make_updates();
+
+        let mut regions = HashMap::new();
+        regions.insert(0, (Coordinates { x: 0.0, y: 0.0 }, Coordinates { x: 10.0, y: 10.0 }));
+        regions.insert(1, (Coordinates { x: 10.0, y: 10.0 }, Coordinates { x: 20.0, y: 20.0 }));
+        replicator.set_interest(Regions(regions));
+
+        let mut buffer = PayloadBatch::new();
+
+        replicator.record(0, &updates, &mut buffer);
+        assert_eq!(buffer.drain().collect::<Vec<_>>(), vec![TestPayload(0), TestPayload(2)]);
+
+        replicator.record(1, &updates, &mut buffer);
+        assert_eq!(buffer.drain().collect::<Vec<_>>(), vec![TestPayload(1)]);
+
+        // Clients without a region receive nothing
+        replicator.record(2, &updates, &mut buffer);
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn test_record_delta() {
+        let mut replicator = make_replicator();
+        let mut updates = make_updates();
+
+        let mut buffer = PayloadBatch::new();
+
+        replicator.record(0, &updates, &mut buffer);
+        assert_eq!(buffer.len(), 3);
+        buffer.drain().count();
+
+        // Unchanged payloads are skipped, changed ones are sent
+        updates[1].payload = TestPayload(11);
+
+        replicator.record(0, &updates, &mut buffer);
+        assert_eq!(buffer.drain().collect::<Vec<_>>(), vec![TestPayload(11)]);
+
+        replicator.record(0, &updates, &mut buffer);
+        assert_eq!(buffer.len(), 0);
+
+        // Other clients keep their own state
+        replicator.record(1, &updates, &mut buffer);
+        assert_eq!(buffer.drain().count(), 3);
+
+        // Forgetting the client resets the state
+        replicator.forget(0);
+        replicator.record(0, &updates, &mut buffer);
+        assert_eq!(buffer.drain().count(), 3);
+    }
+
+    #[test]
+    fn test_record_delta_keyframe() {
+        let mut replicator = make_replicator();
+        let updates = make_updates();
+
+        let mut buffer = PayloadBatch::new();
+
+        replicator.record(0, &updates, &mut buffer);
+        assert_eq!(buffer.drain().count(), 3);
+
+        for _ in 1..Replicator::KEYFRAME_INTERVAL {
+            replicator.record(0, &updates, &mut buffer);
+            assert_eq!(buffer.len(), 0);
+        }
+
+        // Unchanged payloads are sent again once the keyframe interval elapses
+        replicator.record(0, &updates, &mut buffer);
+        assert_eq!(buffer.drain().count(), 3);
+    }
+
+    #[test]
+    fn test_departed() {
+        assert_eq!(Replicator::departed(&ConnectionChange::Suspended(3)), Some(3));
+        assert_eq!(Replicator::departed(&ConnectionChange::Disconnected(4)), Some(4));
+        assert_eq!(Replicator::departed(&ConnectionChange::Resumed(1, 5)), None);
+    }
 }
@@ -1,17 +1,42 @@
 use crate::config::GameConfig;
+use crate::metrics::Metrics;
 use crate::replicator::Replicator;
 use flux::logging;
-use neutronium::prelude::World;
+use neutronium::prelude::{SystemId, World};
+use std::sync::Arc;
 
-pub fn build_world(world: &mut World, config: &GameConfig, log: &logging::Logger) {
-    build_replicator(world, config, log);
+/// Handles of the game systems registered in the world.
+pub struct GameSystems {
+    /// Metrics published by the systems.
+    pub metrics: Arc<Metrics>,
+    pub replicator: SystemId,
+}
+
+/// Register the game systems and build the world.
+pub fn build_world(world: &mut World, config: &GameConfig, log: &logging::Logger) -> GameSystems {
+    let systems = build_replicator(world, config, log);
     world.build();
+    systems
+}
+
+/// Apply the fields of a reloaded configuration that can be changed at runtime to the running world.
+pub fn apply_config(world: &mut World, systems: &GameSystems, config: &GameConfig) {
+    world.set_fps(config.game.fps);
+
+    world
+        .get_system_mut::<Replicator>(systems.replicator)
+        .expect("Replicator system missing")
+        .set_timeouts(&config.server.timeouts);
 }
 
-fn build_replicator(world: &mut World, config: &GameConfig, log: &logging::Logger) {
+fn build_replicator(world: &mut World, config: &GameConfig, log: &logging::Logger) -> GameSystems {
     logging::info!(log, "building *** Replicator *** ");
 
     let replicator = Replicator::new(&config.server, log);
+    let metrics = replicator.metrics();
 
-    world.register_system(replicator);
+    GameSystems {
+        metrics,
+        replicator: world.register_persistent_system(replicator),
+    }
 }
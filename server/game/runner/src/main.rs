@@ -1,9 +1,13 @@
 use clap::{App, Arg};
 use flux::logging;
-use gamecore::config::GameConfig;
-use gamecore::systems::build_world;
+use gamecore::config::{ConfigWatcher, GameConfig};
+use gamecore::metrics;
+use gamecore::systems::{apply_config, build_world};
 use neutronium::prelude::World;
+use rocket::config::{Environment, LoggingLevel};
 use std::env::current_dir;
+use std::net::SocketAddr;
+use std::thread;
 
 fn main() {
     let matches = App::new("Game Server")
@@ -15,10 +19,18 @@ fn main() {
                 .help("Path to the config file")
                 .default_value("game_config.toml"),
         )
+        .arg(
+            Arg::with_name("LOG_FORMAT")
+                .long("log-format")
+                .help("Format of the log output")
+                .env("LOG_FORMAT")
+                .possible_values(&["terminal", "json"])
+                .default_value("terminal"),
+        )
         .get_matches();
 
     // Initialize logging
-    let log = logging::init();
+    let log = logging::init_format(matches.value_of("LOG_FORMAT").unwrap().parse().unwrap());
 
     logging::info!(log, ""; "working_directory" => ?current_dir().unwrap());
 
@@ -34,14 +46,82 @@ fn main() {
                    "server_address" => &config.server.address,
                    "server_max_clients" => config.server.max_clients,
                    "server_threads" => config.server.threads,
-                   "game_fps" => config.game.fps);
+                   "game_fps" => config.game.fps,
+                   "game_hot_reload" => config.game.hot_reload);
 
     let mut world = World::new(config.game.fps, &log);
 
     logging::info!(log, "initializing world instance"; "context" => "main",);
-    build_world(&mut world, &config, &log);
+    let systems = build_world(&mut world, &config, &log);
+    let game_metrics = systems.metrics.clone();
     logging::info!(log, "world instance initialized"; "context" => "main",);
 
+    if let Some(metrics_config) = &config.metrics {
+        // The address has been validated along with the rest of the configuration
+        let address: SocketAddr = metrics_config.address.parse().unwrap();
+
+        let rocket_config = rocket::Config::build(Environment::Production)
+            .address(address.ip().to_string())
+            .port(address.port())
+            .log_level(LoggingLevel::Critical)
+            .finalize()
+            .expect("Invalid metrics exporter configuration");
+
+        let exporter = metrics::build(rocket::custom(rocket_config), game_metrics.clone());
+
+        logging::info!(log, "starting metrics exporter";
+                       "context" => "main",
+                       "address" => %address);
+        thread::spawn(move || exporter.launch());
+    }
+
+    // Stop the game loop on SIGINT and SIGTERM, the clients are notified before exiting
+    let stop = world.stop_handle();
+    let signal_log = log.clone();
+    ctrlc::set_handler(move || {
+        logging::info!(signal_log, "stop signal received"; "context" => "main");
+        stop.stop();
+    })
+    .expect("Failed installing the signal handler");
+
     logging::info!(log, "starting game loop"; "context" => "main",);
-    world.run();
+
+    if !config.game.hot_reload {
+        world.run_until(|world| {
+            game_metrics.publish_frames(world.frame_stats());
+            false
+        });
+
+        logging::info!(log, "game loop stopped"; "context" => "main");
+        return;
+    }
+
+    // Run the game loop in one second slices, checking the configuration file in between
+    let mut watcher = ConfigWatcher::new(config_file_path, config);
+
+    loop {
+        world.run_for(watcher.config().game.fps);
+        game_metrics.publish_frames(world.frame_stats());
+
+        if world.is_stopped() {
+            logging::info!(log, "game loop stopped"; "context" => "main");
+            return;
+        }
+
+        match watcher.poll() {
+            Ok(Some(config)) => {
+                logging::info!(log, "configuration reloaded";
+                               "context" => "main",
+                               "game_fps" => config.game.fps,
+                               "server_timeouts" => ?config.server.timeouts);
+                apply_config(&mut world, &systems, config);
+            }
+            Ok(None) => (),
+            Err(err) => {
+                logging::warn!(log, "configuration reload rejected";
+                               "context" => "main",
+                               "error" => %err);
+            }
+        }
+    }
 }
@@ -1,10 +1,16 @@
 use byteorder::{LittleEndian, WriteBytesExt};
 use ctor::ctor;
 use libsodium_sys;
+use std::mem;
 
-pub const MAC_SIZE: usize = libsodium_sys::crypto_aead_chacha20poly1305_IETF_ABYTES as usize;
+const CHACHA20POLY1305_MAC_SIZE: usize = libsodium_sys::crypto_aead_chacha20poly1305_IETF_ABYTES as usize;
+const AES256GCM_MAC_SIZE: usize = libsodium_sys::crypto_aead_aes256gcm_ABYTES as usize;
+
+/// MAC size of the default cipher suite. None of the supported suites use a larger MAC.
+pub const MAC_SIZE: usize = CHACHA20POLY1305_MAC_SIZE;
 pub const KEY_SIZE: usize = libsodium_sys::crypto_aead_chacha20poly1305_IETF_KEYBYTES as usize;
 pub const NONCE_SIZE: usize = libsodium_sys::crypto_aead_chacha20poly1305_IETF_NPUBBYTES as usize;
+pub const HMAC_SIZE: usize = libsodium_sys::crypto_auth_hmacsha256_BYTES as usize;
 
 const NONCE_OFFSET: usize = NONCE_SIZE - 8;
 
@@ -19,6 +25,58 @@ fn init_sodium() {
     }
 }
 
+/// Supported AEAD constructions. All suites use the same key and nonce sizes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum CipherSuite {
+    ChaCha20Poly1305 = 0,
+    Aes256Gcm = 1,
+}
+
+impl CipherSuite {
+    /// Parse the cipher suite from its wire identifier.
+    #[inline]
+    pub fn from_id(id: u8) -> Option<CipherSuite> {
+        match id {
+            0 => Some(CipherSuite::ChaCha20Poly1305),
+            1 => Some(CipherSuite::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    /// Wire identifier of the cipher suite.
+    #[inline]
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    /// Size of the MAC appended to the encrypted messages.
+    #[inline]
+    pub fn mac_size(self) -> usize {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => CHACHA20POLY1305_MAC_SIZE,
+            CipherSuite::Aes256Gcm => AES256GCM_MAC_SIZE,
+        }
+    }
+
+    /// Returns true if the cipher suite is supported on the current hardware. AES-256-GCM requires
+    /// hardware acceleration.
+    #[inline]
+    pub fn is_available(self) -> bool {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => true,
+            CipherSuite::Aes256Gcm => unsafe { libsodium_sys::crypto_aead_aes256gcm_is_available() == 1 },
+        }
+    }
+}
+
+impl Default for CipherSuite {
+    #[inline]
+    fn default() -> Self {
+        CipherSuite::ChaCha20Poly1305
+    }
+}
+
 #[inline]
 fn nonce_to_bytes(nonce: u64) -> [u8; NONCE_SIZE] {
     let mut nonce_bytes = [0u8; NONCE_SIZE];
@@ -28,92 +86,452 @@ fn nonce_to_bytes(nonce: u64) -> [u8; NONCE_SIZE] {
     nonce_bytes
 }
 
-/// Encrypts the provided plain text into the cipher buffer. The encrypted message size will be the plain
-/// text size plus the MAC size (24 bytes). The function will fail if the cipher slice is not large enough.
+/// Dispatches the encryption to the AEAD construction of the cipher suite.
+#[inline]
+unsafe fn aead_encrypt(
+    suite: CipherSuite,
+    cipher: *mut u8,
+    plain: &[u8],
+    additional_data: &[u8],
+    nonce: u64,
+    key: &[u8; KEY_SIZE],
+) -> bool {
+    let nonce_bytes = nonce_to_bytes(nonce);
+
+    let encrypt_fn = match suite {
+        CipherSuite::ChaCha20Poly1305 => libsodium_sys::crypto_aead_chacha20poly1305_ietf_encrypt,
+        CipherSuite::Aes256Gcm => libsodium_sys::crypto_aead_aes256gcm_encrypt,
+    };
+
+    let result = encrypt_fn(
+        cipher,
+        ::std::ptr::null_mut(),
+        plain.as_ptr(),
+        plain.len() as u64,
+        additional_data.as_ptr(),
+        additional_data.len() as u64,
+        ::std::ptr::null(),
+        nonce_bytes.as_ptr(),
+        key.as_ptr(),
+    );
+
+    result >= 0
+}
+
+/// Dispatches the decryption to the AEAD construction of the cipher suite. The plain and cipher
+/// buffers may overlap.
+#[inline]
+unsafe fn aead_decrypt(
+    suite: CipherSuite,
+    plain: *mut u8,
+    cipher: *const u8,
+    cipher_len: usize,
+    additional_data: &[u8],
+    nonce: u64,
+    key: &[u8; KEY_SIZE],
+) -> bool {
+    let nonce_bytes = nonce_to_bytes(nonce);
+
+    let decrypt_fn = match suite {
+        CipherSuite::ChaCha20Poly1305 => libsodium_sys::crypto_aead_chacha20poly1305_ietf_decrypt,
+        CipherSuite::Aes256Gcm => libsodium_sys::crypto_aead_aes256gcm_decrypt,
+    };
+
+    let result = decrypt_fn(
+        plain,
+        ::std::ptr::null_mut(),
+        ::std::ptr::null_mut(),
+        cipher,
+        cipher_len as u64,
+        additional_data.as_ptr(),
+        additional_data.len() as u64,
+        nonce_bytes.as_ptr(),
+        key.as_ptr(),
+    );
+
+    result >= 0
+}
+
+/// Encrypts the provided plain text into the cipher buffer using the given cipher suite. The encrypted
+/// message size will be the plain text size plus the MAC size of the suite. The function will fail if
+/// the cipher slice is not large enough.
 ///
 /// The additional data, nonce and key must match those used during encryption, the decryption will fail
 /// otherwise.
 #[inline]
 pub fn encrypt(
+    suite: CipherSuite,
     cipher: &mut [u8],
     plain: &[u8],
     additional_data: &[u8],
     nonce: u64,
     key: &[u8; KEY_SIZE],
 ) -> bool {
-    let nonce_bytes = nonce_to_bytes(nonce);
-
-    if cipher.len() != plain.len() + MAC_SIZE {
+    if cipher.len() != plain.len() + suite.mac_size() {
         panic!(
             "Encryption: cipher data length ({}) must be plain data length ({}) + MAC size ({})",
             cipher.len(),
             plain.len(),
-            MAC_SIZE
+            suite.mac_size()
         )
     }
 
-    unsafe {
-        let result = libsodium_sys::crypto_aead_chacha20poly1305_ietf_encrypt(
-            cipher.as_mut_ptr(),
-            ::std::ptr::null_mut(),
-            plain.as_ptr(),
-            plain.len() as u64,
-            additional_data.as_ptr(),
-            additional_data.len() as u64,
-            ::std::ptr::null(),
-            nonce_bytes.as_ptr(),
-            key.as_ptr(),
-        );
-
-        result >= 0
-    }
+    unsafe { aead_encrypt(suite, cipher.as_mut_ptr(), plain, additional_data, nonce, key) }
 }
 
-/// Decrypts the provided ciphertext into the plain buffer. The decoded message size is equal to the cipher
-/// text length minus the MAC (24 bytes). The function will fail if the sizes do not match.
+/// Decrypts the provided ciphertext into the plain buffer using the given cipher suite. The decoded
+/// message size is equal to the cipher text length minus the MAC size of the suite. The function will
+/// fail if the sizes do not match.
 ///
 /// The additional data, nonce and key must match those used during encryption, the decryption will fail
 /// otherwise.
 #[inline]
 pub fn decrypt(
+    suite: CipherSuite,
     plain: &mut [u8],
     cipher: &[u8],
     additional_data: &[u8],
     nonce: u64,
     key: &[u8; KEY_SIZE],
 ) -> bool {
-    let nonce_bytes = nonce_to_bytes(nonce);
-
-    if cipher.len() != plain.len() + MAC_SIZE {
+    if cipher.len() != plain.len() + suite.mac_size() {
         panic!(
             "Decryption: cipher data length ({}) must be plain data length ({}) + MAC size ({})",
             cipher.len(),
             plain.len(),
-            MAC_SIZE
+            suite.mac_size()
         )
     }
 
     unsafe {
-        let result = libsodium_sys::crypto_aead_chacha20poly1305_ietf_decrypt(
+        aead_decrypt(
+            suite,
             plain.as_mut_ptr(),
-            ::std::ptr::null_mut(),
-            ::std::ptr::null_mut(),
             cipher.as_ptr(),
-            cipher.len() as u64,
-            additional_data.as_ptr(),
-            additional_data.len() as u64,
-            nonce_bytes.as_ptr(),
-            key.as_ptr(),
-        );
+            cipher.len(),
+            additional_data,
+            nonce,
+            key,
+        )
+    }
+}
+
+/// Decrypts the provided ciphertext in place using the given cipher suite. On success, the decoded
+/// message occupies the beginning of the buffer and its size is equal to the buffer length minus the MAC
+/// size of the suite. The buffer is left untouched in case the decryption fails.
+///
+/// The additional data, nonce and key must match those used during encryption, the decryption will fail
+/// otherwise.
+#[inline]
+pub fn decrypt_in_place(
+    suite: CipherSuite,
+    data: &mut [u8],
+    additional_data: &[u8],
+    nonce: u64,
+    key: &[u8; KEY_SIZE],
+) -> bool {
+    if data.len() < suite.mac_size() {
+        panic!(
+            "Decryption: cipher data length ({}) must be at least the MAC size ({})",
+            data.len(),
+            suite.mac_size()
+        )
+    }
 
-        result >= 0
+    // Libsodium supports overlapping plain and cipher text buffers
+    unsafe {
+        aead_decrypt(
+            suite,
+            data.as_mut_ptr(),
+            data.as_ptr(),
+            data.len(),
+            additional_data,
+            nonce,
+            key,
+        )
+    }
+}
+
+/// Computes the HMAC-SHA256 of the concatenation of the supplied message parts.
+#[inline]
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; HMAC_SIZE] {
+    let mut mac = [0u8; HMAC_SIZE];
+
+    unsafe {
+        let mut state: libsodium_sys::crypto_auth_hmacsha256_state = mem::zeroed();
+
+        libsodium_sys::crypto_auth_hmacsha256_init(&mut state, key.as_ptr(), key.len());
+
+        for part in parts {
+            libsodium_sys::crypto_auth_hmacsha256_update(&mut state, part.as_ptr(), part.len() as u64);
+        }
+
+        libsodium_sys::crypto_auth_hmacsha256_final(&mut state, mac.as_mut_ptr());
+    }
+
+    mac
+}
+
+/// HKDF-SHA256 (RFC 5869) extract step. Produces a pseudorandom key from the input key material and salt.
+#[inline]
+pub fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> [u8; HMAC_SIZE] {
+    hmac_sha256(salt, &[ikm])
+}
+
+/// HKDF-SHA256 (RFC 5869) expand step. Fills the output buffer with key material derived from the
+/// pseudorandom key and the context specific info. The output may be at most 255 * `HMAC_SIZE` bytes long.
+#[inline]
+pub fn hkdf_expand(out: &mut [u8], prk: &[u8; HMAC_SIZE], info: &[u8]) {
+    if out.len() > 255 * HMAC_SIZE {
+        panic!(
+            "HKDF: output length ({}) must not exceed {}",
+            out.len(),
+            255 * HMAC_SIZE
+        )
+    }
+
+    let mut block = [0u8; HMAC_SIZE];
+    let mut block_len = 0;
+
+    for (index, chunk) in out.chunks_mut(HMAC_SIZE).enumerate() {
+        block = hmac_sha256(prk, &[&block[..block_len], info, &[(index + 1) as u8]]);
+        block_len = HMAC_SIZE;
+
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+}
+
+/// Derives a new key from the supplied key using HKDF-SHA256. Distinct salt and info values produce
+/// independent keys.
+#[inline]
+pub fn derive_key(key: &[u8; KEY_SIZE], salt: &[u8], info: &[u8]) -> [u8; KEY_SIZE] {
+    let mut derived = [0u8; KEY_SIZE];
+    hkdf_expand(&mut derived, &hkdf_extract(salt, key), info);
+    derived
+}
+
+/// Compares the two slices in constant time. The comparison time only depends on the length of the
+/// slices, not their contents. Slices of differing length are never equal.
+#[inline]
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    unsafe {
+        libsodium_sys::sodium_memcmp(
+            a.as_ptr() as *const ::std::ffi::c_void,
+            b.as_ptr() as *const ::std::ffi::c_void,
+            a.len(),
+        ) == 0
     }
 }
 
 /// Fills the provided buffer with cryptographically secure random bytes
+#[cfg(not(any(test, feature = "deterministic-rng")))]
 #[inline]
 pub fn random_bytes(out: &mut [u8]) {
+    secure_random_bytes(out);
+}
+
+/// Fills the provided buffer with random bytes. If the current thread has been seeded via
+/// `seed_random`, the bytes come from the deterministic generator instead of the OS CSPRNG.
+#[cfg(any(test, feature = "deterministic-rng"))]
+pub fn random_bytes(out: &mut [u8]) {
+    let seeded = deterministic::RNG.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(rng) => {
+            rng.fill(out);
+            true
+        }
+        _ => false,
+    });
+
+    if !seeded {
+        secure_random_bytes(out);
+    }
+}
+
+#[inline]
+fn secure_random_bytes(out: &mut [u8]) {
     unsafe {
         libsodium_sys::randombytes_buf(out.as_mut_ptr() as *mut ::std::ffi::c_void, out.len());
     }
 }
+
+/// Seeds a deterministic generator for `random_bytes` on the current thread. Only available
+/// in tests or with the `deterministic-rng` feature, never use it in production builds.
+#[cfg(any(test, feature = "deterministic-rng"))]
+pub fn seed_random(seed: u64) {
+    deterministic::RNG.with(|rng| *rng.borrow_mut() = Some(deterministic::SplitMix64 { state: seed }));
+}
+
+/// Restores the OS CSPRNG as the source for `random_bytes` on the current thread.
+#[cfg(any(test, feature = "deterministic-rng"))]
+pub fn clear_random_seed() {
+    deterministic::RNG.with(|rng| *rng.borrow_mut() = None);
+}
+
+#[cfg(any(test, feature = "deterministic-rng"))]
+mod deterministic {
+    use std::cell::RefCell;
+
+    thread_local! {
+        pub static RNG: RefCell<Option<SplitMix64>> = RefCell::new(None);
+    }
+
+    /// Small, fast and entirely predictable generator. Not suitable for anything but tests.
+    pub struct SplitMix64 {
+        pub state: u64,
+    }
+
+    impl SplitMix64 {
+        #[inline]
+        fn next(&mut self) -> u64 {
+            self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        }
+
+        pub fn fill(&mut self, out: &mut [u8]) {
+            for chunk in out.chunks_mut(8) {
+                let bytes = self.next().to_le_bytes();
+                chunk.copy_from_slice(&bytes[..chunk.len()]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_hkdf_rfc5869_vector() {
+        let ikm = [0x0bu8; 22];
+        let salt = from_hex("000102030405060708090a0b0c");
+        let info = from_hex("f0f1f2f3f4f5f6f7f8f9");
+
+        let prk = hkdf_extract(&salt, &ikm);
+
+        assert_eq!(
+            &prk[..],
+            &from_hex("077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5")[..]
+        );
+
+        let mut okm = [0u8; 42];
+        hkdf_expand(&mut okm, &prk, &info);
+
+        assert_eq!(
+            &okm[..],
+            &from_hex("3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865")[..]
+        );
+    }
+
+    fn roundtrip(suite: CipherSuite) {
+        let key = [3u8; KEY_SIZE];
+        let plain = [1u8, 2, 3, 4, 5];
+        let mut cipher = [0u8; 5 + MAC_SIZE];
+
+        assert!(encrypt(suite, &mut cipher, &plain, b"ad", 10, &key));
+        assert_ne!(&cipher[..5], &plain[..]);
+
+        let mut decrypted = [0u8; 5];
+        assert!(decrypt(suite, &mut decrypted, &cipher, b"ad", 10, &key));
+        assert_eq!(decrypted, plain);
+
+        // Tampering with the additional data or the nonce is detected
+        assert!(!decrypt(suite, &mut decrypted, &cipher, b"xx", 10, &key));
+        assert!(!decrypt(suite, &mut decrypted, &cipher, b"ad", 11, &key));
+
+        assert!(decrypt_in_place(suite, &mut cipher, b"ad", 10, &key));
+        assert_eq!(&cipher[..5], &plain[..]);
+    }
+
+    #[test]
+    fn test_roundtrip_chacha20poly1305() {
+        roundtrip(CipherSuite::ChaCha20Poly1305);
+    }
+
+    #[test]
+    fn test_roundtrip_aes256gcm() {
+        // AES-256-GCM is only available with hardware support
+        if CipherSuite::Aes256Gcm.is_available() {
+            roundtrip(CipherSuite::Aes256Gcm);
+        }
+    }
+
+    #[test]
+    fn test_cipher_suite_mismatch() {
+        if !CipherSuite::Aes256Gcm.is_available() {
+            return;
+        }
+
+        let key = [3u8; KEY_SIZE];
+        let plain = [1u8, 2, 3, 4, 5];
+        let mut cipher = [0u8; 5 + MAC_SIZE];
+        let mut decrypted = [0u8; 5];
+
+        assert!(encrypt(CipherSuite::ChaCha20Poly1305, &mut cipher, &plain, b"ad", 10, &key));
+        assert!(!decrypt(CipherSuite::Aes256Gcm, &mut decrypted, &cipher, b"ad", 10, &key));
+    }
+
+    #[test]
+    fn test_cipher_suite_id() {
+        for &suite in &[CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm] {
+            assert_eq!(CipherSuite::from_id(suite.id()), Some(suite));
+        }
+
+        assert_eq!(CipherSuite::from_id(2), None);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(&[], &[]));
+        assert!(constant_time_eq(&[1, 2, 3], &[1, 2, 3]));
+
+        assert!(!constant_time_eq(&[1, 2, 3], &[1, 2, 4]));
+        assert!(!constant_time_eq(&[0, 2, 3], &[1, 2, 3]));
+        assert!(!constant_time_eq(&[1, 2, 3], &[1, 2]));
+        assert!(!constant_time_eq(&[1, 2], &[1, 2, 3]));
+        assert!(!constant_time_eq(&[], &[1]));
+    }
+
+    #[test]
+    fn test_derive_key() {
+        let key = [7u8; KEY_SIZE];
+
+        let send1 = derive_key(&key, b"salt", b"send");
+        let send2 = derive_key(&key, b"salt", b"send");
+        let recv = derive_key(&key, b"salt", b"recv");
+        let other_salt = derive_key(&key, b"other", b"send");
+
+        assert_eq!(send1, send2);
+        assert_ne!(send1, recv);
+        assert_ne!(send1, other_salt);
+        assert_ne!(send1, key);
+    }
+
+    #[test]
+    fn test_seed_random() {
+        let mut first = [0u8; 37];
+        let mut second = [0u8; 37];
+
+        seed_random(42);
+        random_bytes(&mut first);
+        seed_random(42);
+        random_bytes(&mut second);
+        clear_random_seed();
+
+        assert_eq!(&first[..], &second[..]);
+        assert_ne!(&first[..], &[0u8; 37][..]);
+    }
+}
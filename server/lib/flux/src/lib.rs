@@ -7,6 +7,8 @@ pub const VERSION_ID: [u8; 16] = *b"NOB_VON_PEN_ISLE";
 pub const CONNECTION_TOKEN_EXPIRY_SECS: u64 = 10;
 
 pub type UserId = u64;
+/// Bitmask of the roles granted to a user, see `session::user::roles`.
+pub type Roles = u32;
 
 pub mod crypto;
 pub mod logging;
@@ -36,4 +38,193 @@ pub mod encoding {
             decode(s).map_err(de::Error::custom)
         }
     }
+
+    /// Lowercase hex encoding. Both cases are accepted when decoding.
+    pub mod hex {
+        use serde::{de, Deserialize, Deserializer, Serializer};
+        use std::fmt;
+
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+        #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+        pub enum DecodeError {
+            OddLength,
+            InvalidChar(char, usize),
+        }
+
+        impl fmt::Display for DecodeError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self {
+                    DecodeError::OddLength => write!(f, "Odd number of hex digits"),
+                    DecodeError::InvalidChar(chr, pos) => write!(f, "Invalid hex digit {:?} at {}", chr, pos),
+                }
+            }
+        }
+
+        #[inline]
+        pub fn encode<T: ?Sized + AsRef<[u8]>>(input: &T) -> String {
+            let bytes = input.as_ref();
+            let mut encoded = String::with_capacity(bytes.len() * 2);
+
+            for &byte in bytes {
+                encoded.push(DIGITS[(byte >> 4) as usize] as char);
+                encoded.push(DIGITS[(byte & 0xf) as usize] as char);
+            }
+
+            encoded
+        }
+
+        #[inline]
+        pub fn decode<T: ?Sized + AsRef<[u8]>>(input: &T) -> Result<Vec<u8>, DecodeError> {
+            let digits = input.as_ref();
+
+            if digits.len() % 2 != 0 {
+                return Err(DecodeError::OddLength);
+            }
+
+            let value = |pos: usize| match digits[pos] {
+                digit @ b'0'..=b'9' => Ok(digit - b'0'),
+                digit @ b'a'..=b'f' => Ok(digit - b'a' + 10),
+                digit @ b'A'..=b'F' => Ok(digit - b'A' + 10),
+                digit => Err(DecodeError::InvalidChar(digit as char, pos)),
+            };
+
+            (0..digits.len())
+                .step_by(2)
+                .map(|pos| Ok(value(pos)? << 4 | value(pos + 1)?))
+                .collect()
+        }
+
+        #[inline]
+        pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.serialize_str(&encode(bytes))
+        }
+
+        #[inline]
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let s = <&str>::deserialize(deserializer)?;
+            decode(s).map_err(de::Error::custom)
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            #[test]
+            fn test_roundtrip() {
+                let key: Vec<u8> = (0..=255).collect();
+
+                assert_eq!(encode(&[0x00, 0x0f, 0xab, 0xff]), "000fabff");
+                assert_eq!(decode(&encode(&key)).unwrap(), key);
+                assert_eq!(decode(&encode(&crate::VERSION_ID)).unwrap(), crate::VERSION_ID);
+            }
+
+            #[test]
+            fn test_decode_mixed_case() {
+                assert_eq!(decode("ABcdEf").unwrap(), vec![0xab, 0xcd, 0xef]);
+            }
+
+            #[test]
+            fn test_decode_invalid() {
+                assert_eq!(decode("abc"), Err(DecodeError::OddLength));
+                assert_eq!(decode("0g"), Err(DecodeError::InvalidChar('g', 1)));
+                assert_eq!(decode("+/"), Err(DecodeError::InvalidChar('+', 0)));
+            }
+        }
+    }
+
+    /// URL safe base64 without padding, for data delivered over URLs such as tokens and keys.
+    pub mod base64url {
+        pub use ::base64::DecodeError;
+        use serde::{de, Deserialize, Deserializer, Serializer};
+
+        #[inline]
+        pub fn encode<T: ?Sized + AsRef<[u8]>>(input: &T) -> String {
+            ::base64::encode_config(input, ::base64::URL_SAFE_NO_PAD)
+        }
+
+        #[inline]
+        pub fn decode<T: ?Sized + AsRef<[u8]>>(input: &T) -> Result<Vec<u8>, DecodeError> {
+            ::base64::decode_config(input, ::base64::URL_SAFE_NO_PAD)
+        }
+
+        #[inline]
+        pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.serialize_str(&encode(bytes))
+        }
+
+        #[inline]
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let s = <&str>::deserialize(deserializer)?;
+            decode(s).map_err(de::Error::custom)
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use serde_derive::{Deserialize, Serialize};
+            use serde_json;
+
+            #[derive(Serialize, Deserialize, Debug, PartialEq)]
+            struct Key {
+                #[serde(with = "crate::encoding::base64url")]
+                data: Vec<u8>,
+            }
+
+            #[test]
+            fn test_url_safe_alphabet() {
+                let bytes = [0xfb, 0xff, 0xbf];
+
+                assert_eq!(crate::encoding::base64::encode(&bytes), "+/+/");
+                assert_eq!(encode(&bytes), "-_-_");
+                assert_eq!(decode("-_-_").unwrap(), bytes);
+
+                // No padding is emitted
+                assert_eq!(encode(&[0xfb, 0xff]), "-_8");
+                assert_eq!(decode("-_8").unwrap(), vec![0xfb, 0xff]);
+            }
+
+            #[test]
+            fn test_roundtrip() {
+                let key: Vec<u8> = (0..=255).collect();
+
+                assert_eq!(decode(&encode(&key)).unwrap(), key);
+            }
+
+            #[test]
+            fn test_decode_invalid() {
+                // Standard alphabet
+                assert!(decode("+/+/").is_err());
+                // Garbage
+                assert!(decode("ab$d").is_err());
+                // Invalid length
+                assert!(decode("a").is_err());
+            }
+
+            #[test]
+            fn test_serde() {
+                let key = Key {
+                    data: vec![0xfb, 0xff, 0xbf, 1, 2],
+                };
+
+                let json = serde_json::to_string(&key).unwrap();
+                assert_eq!(json, r#"{"data":"-_-_AQI"}"#);
+                assert_eq!(serde_json::from_str::<Key>(&json).unwrap(), key);
+
+                assert!(serde_json::from_str::<Key>(r#"{"data":"+/+/AQI"}"#).is_err());
+            }
+        }
+    }
 }
@@ -1,16 +1,134 @@
 use serdeconv;
 use slog;
+use slog::Drain;
+use slog_json;
 use sloggers;
 use sloggers::{Config, LoggerConfig};
 use std::env::current_exe;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
-pub use slog::{crit, debug, error, info, o, trace, warn, Discard, Logger, Record, Result, Serializer};
+pub use slog::{
+    crit, debug, error, info, o, trace, warn, Discard, Drain, Key, Level, Logger, Never, OwnedKVList, Record,
+    Result, Serializer, KV,
+};
 
 const LOG_CONFIG: &str = r#"
 type = "terminal"
 level = "trace"
 destination = "stderr""#;
 
+/// Numeric value of `Level::Trace`, the default minimum level of the emitted records.
+pub const DEFAULT_LEVEL: usize = 6;
+
+// Minimum level of the emitted records, adjustable at runtime
+static LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_LEVEL);
+
+/// Set the minimum level of the records emitted by loggers created via `init`, `init_json` or `json`.
+/// Records below the `release_max_level_debug` threshold are compiled out of release builds regardless.
+pub fn set_level(level: Level) {
+    LEVEL.store(level.as_usize(), Ordering::Relaxed);
+}
+
+/// Get the minimum level of the emitted records.
+pub fn level() -> Level {
+    Level::from_usize(LEVEL.load(Ordering::Relaxed)).expect("Logging: invalid level")
+}
+
+/// Drain dropping the records below a level that can be adjusted at runtime.
+pub struct RuntimeLevel<D> {
+    drain: D,
+    level: &'static AtomicUsize,
+}
+
+impl<D> RuntimeLevel<D> {
+    /// Filter the drain using the global level set via `set_level`.
+    pub fn new(drain: D) -> RuntimeLevel<D> {
+        RuntimeLevel::with_level(drain, &LEVEL)
+    }
+
+    /// Filter the drain using the supplied level.
+    pub fn with_level(drain: D, level: &'static AtomicUsize) -> RuntimeLevel<D> {
+        RuntimeLevel { drain, level }
+    }
+
+    #[inline]
+    fn accepts(&self, level: Level) -> bool {
+        level.as_usize() <= self.level.load(Ordering::Relaxed)
+    }
+}
+
+impl<D: Drain<Ok = ()>> Drain for RuntimeLevel<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    #[inline]
+    fn log(&self, record: &Record, values: &OwnedKVList) -> std::result::Result<(), D::Err> {
+        match self.accepts(record.level()) {
+            true => self.drain.log(record, values),
+            _ => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn is_enabled(&self, level: Level) -> bool {
+        self.accepts(level) && self.drain.is_enabled(level)
+    }
+}
+
+/// Output format of the log.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    /// Human readable output, configured by the `log.toml` file next to the executable.
+    Terminal,
+    /// Newline delimited JSON on stderr, for ingestion by log shippers.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Format, String> {
+        match s {
+            "terminal" => Ok(Format::Terminal),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown log format {}, expected terminal or json", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Terminal => write!(f, "terminal"),
+            Format::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Initialize the log using the supplied output format.
+pub fn init_format(format: Format) -> slog::Logger {
+    match format {
+        Format::Terminal => init(),
+        Format::Json => init_json(),
+    }
+}
+
+/// Initialize the log emitting newline delimited JSON records on stderr.
+pub fn init_json() -> slog::Logger {
+    json(io::stderr())
+}
+
+/// Create a log emitting newline delimited JSON records into the supplied writer. Each record carries
+/// the timestamp, level and message along with all the key-value pairs.
+pub fn json<W: io::Write + Send + 'static>(writer: W) -> slog::Logger {
+    let drain = slog_json::Json::new(writer).add_default_keys().build();
+    slog::Logger::root(RuntimeLevel::new(Mutex::new(drain).fuse()), o!())
+}
+
 pub fn init() -> slog::Logger {
     let mut path = current_exe().expect("Logging: failed to retrieve executable");
 
@@ -20,7 +138,7 @@ pub fn init() -> slog::Logger {
 
     path.set_extension("log.toml");
 
-    if path.exists() {
+    let logger = if path.exists() {
         let config: LoggerConfig = serdeconv::from_toml_file(&path).expect("");
         let logger = config.build_logger().expect("Logging: invalid configuration");
         slog::info!(logger, ""; "log_config_file" => path.to_str().unwrap());
@@ -33,5 +151,107 @@ pub fn init() -> slog::Logger {
                     "level" => "trace",
                     "destination" => "stderr");
         logger
+    };
+
+    slog::Logger::root(RuntimeLevel::new(logger), o!())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    /// Writer appending into a shared buffer
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!("terminal".parse::<Format>(), Ok(Format::Terminal));
+        assert_eq!("json".parse::<Format>(), Ok(Format::Json));
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_json_record() {
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let log = json(buffer.clone()).new(o!("channel_id" => 7));
+
+        info!(log, "channel opened"; "context" => "open", "user_id" => 8008);
+        info!(log, "channel closed"; "context" => "close");
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let records: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(records.len(), 2);
+
+        let record = &records[0];
+        assert_eq!(record["msg"], "channel opened");
+        assert_eq!(record["level"], "INFO");
+        assert!(record["ts"].is_string());
+        assert_eq!(record["context"], "open");
+        assert_eq!(record["user_id"], 8008);
+        assert_eq!(record["channel_id"], 7);
+
+        assert_eq!(records[1]["context"], "close");
+    }
+
+    /// Drain counting the records it receives
+    struct CountDrain(Arc<AtomicUsize>);
+
+    impl Drain for CountDrain {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, _record: &Record, _values: &OwnedKVList) -> std::result::Result<(), Never> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_runtime_level() {
+        // Use a dedicated level to avoid interfering with the global one
+        static TEST_LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_LEVEL);
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let log = Logger::root(RuntimeLevel::with_level(CountDrain(count.clone()), &TEST_LEVEL), o!());
+
+        debug!(log, "emitted");
+        info!(log, "emitted");
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+
+        TEST_LEVEL.store(Level::Warning.as_usize(), Ordering::Relaxed);
+
+        debug!(log, "suppressed");
+        info!(log, "suppressed");
+        warn!(log, "emitted");
+        error!(log, "emitted");
+        assert_eq!(count.load(Ordering::Relaxed), 4);
+
+        TEST_LEVEL.store(Level::Debug.as_usize(), Ordering::Relaxed);
+
+        debug!(log, "emitted");
+        assert_eq!(count.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn test_default_level() {
+        assert_eq!(Level::from_usize(DEFAULT_LEVEL), Some(Level::Trace));
     }
 }
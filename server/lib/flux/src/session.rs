@@ -1,9 +1,10 @@
 /// Shared infrastructure pertaining to the Server Session, that is an authenticated game server connected
 /// to the master server.
 pub mod server {
-    use crate::encoding::base64;
+    use crate::encoding::{base64, hex};
     use serde::{de, Deserialize, Deserializer};
     use serde_derive::{Deserialize, Serialize};
+    use std::fmt;
     use std::ops::{Deref, DerefMut};
 
     const SESSION_KEY_SIZE: usize = 32;
@@ -23,14 +24,31 @@ pub mod server {
         D: Deserializer<'de>,
     {
         let s = <&str>::deserialize(deserializer)?;
-        let decoded_raw = base64::decode(s).map_err(de::Error::custom)?;
-        let mut decoded = [0u8; SESSION_KEY_SIZE];
+        SessionKey::from_base64(s)
+            .map(|key| key.0)
+            .map_err(de::Error::custom)
+    }
 
-        for (i, &byte) in decoded_raw.iter().enumerate() {
-            decoded[i] = byte;
-        }
+    /// Error constructing a session key from its encoded form.
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    pub enum KeyError {
+        Base64(base64::DecodeError),
+        Hex(hex::DecodeError),
+        Length(usize),
+    }
 
-        Ok(decoded)
+    impl fmt::Display for KeyError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                KeyError::Base64(err) => write!(f, "Invalid base64 session key: {}", err),
+                KeyError::Hex(err) => write!(f, "Invalid hex session key: {}", err),
+                KeyError::Length(len) => write!(
+                    f,
+                    "Invalid session key length: expected {} bytes, got {}",
+                    SESSION_KEY_SIZE, len
+                ),
+            }
+        }
     }
 
     impl SessionKey {
@@ -40,6 +58,31 @@ pub mod server {
         pub fn new(key: [u8; Self::SIZE]) -> SessionKey {
             SessionKey(key)
         }
+
+        /// Construct a session key from its (standard, padded) base64 encoding.
+        pub fn from_base64(encoded: &str) -> Result<SessionKey, KeyError> {
+            base64::decode(encoded)
+                .map_err(KeyError::Base64)
+                .and_then(|decoded| Self::from_slice(&decoded))
+        }
+
+        /// Construct a session key from its hex encoding, either case is accepted.
+        pub fn from_hex(encoded: &str) -> Result<SessionKey, KeyError> {
+            hex::decode(encoded)
+                .map_err(KeyError::Hex)
+                .and_then(|decoded| Self::from_slice(&decoded))
+        }
+
+        #[inline]
+        fn from_slice(decoded: &[u8]) -> Result<SessionKey, KeyError> {
+            if decoded.len() != Self::SIZE {
+                return Err(KeyError::Length(decoded.len()));
+            }
+
+            let mut key = [0u8; Self::SIZE];
+            key.copy_from_slice(decoded);
+            Ok(SessionKey(key))
+        }
     }
 
     impl Deref for SessionKey {
@@ -57,56 +100,390 @@ pub mod server {
             &mut self.0
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_from_base64() {
+            let key = SessionKey::from_base64(&base64::encode(&[7u8; SessionKey::SIZE])).unwrap();
+            assert_eq!(*key, [7u8; SessionKey::SIZE]);
+
+            assert_eq!(
+                SessionKey::from_base64(&base64::encode(&[7u8; 16])).err(),
+                Some(KeyError::Length(16))
+            );
+            assert_eq!(
+                SessionKey::from_base64(&base64::encode(&[7u8; 48])).err(),
+                Some(KeyError::Length(48))
+            );
+
+            match SessionKey::from_base64("not base64!") {
+                Err(KeyError::Base64(_)) => (),
+                _ => panic!("Malformed base64 accepted"),
+            }
+        }
+
+        #[test]
+        fn test_from_hex() {
+            let key = SessionKey::from_hex(&"aB".repeat(SessionKey::SIZE)).unwrap();
+            assert_eq!(*key, [0xab; SessionKey::SIZE]);
+
+            assert_eq!(SessionKey::from_hex("abab").err(), Some(KeyError::Length(2)));
+            assert_eq!(
+                SessionKey::from_hex("abc").err(),
+                Some(KeyError::Hex(hex::DecodeError::OddLength))
+            );
+            assert_eq!(
+                SessionKey::from_hex("zz").err(),
+                Some(KeyError::Hex(hex::DecodeError::InvalidChar('z', 0)))
+            );
+        }
+
+        #[test]
+        fn test_key_error_display() {
+            assert_eq!(
+                KeyError::Length(16).to_string(),
+                "Invalid session key length: expected 32 bytes, got 16"
+            );
+        }
+    }
 }
 
 /// Shared infrastructure pertaining to the User Session, that is an authenticated user connected to a
 /// game server.
 pub mod user {
+    use crate::crypto;
+    use crate::crypto::CipherSuite;
+    use crate::time::timestamp_secs;
+    use crate::Roles;
     use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
-    use std::io::{Error, Read, Write};
-    use std::mem;
+    use std::io::{Error, ErrorKind, Read, Write};
+
+    /// Role bits of the user roles mask.
+    pub mod roles {
+        use crate::Roles;
+
+        pub const ADMIN: Roles = 1;
+    }
 
-    /// Private data part (visible only to the server) of the connection token.
+    /// Private data part (visible only to the server) of the connection token. The leading version
+    /// identifies the layout. The size of the connection token follows from the layout of the current
+    /// version, so tokens of any other version can't be read and are rejected.
     pub struct PrivateData {
+        pub version: u8,
         pub user_id: u64,
+        pub roles: Roles,
         pub server_key: [u8; 32],
         pub client_key: [u8; 32],
     }
 
     impl PrivateData {
-        pub const SIZE: usize = 72;
+        pub const SIZE: usize = 77;
+        pub const VERSION: u8 = 2;
 
-        /// Parse the supplied stream as a private data structure.
+        /// Parse the supplied stream as a private data structure. Versions other than the current one
+        /// are rejected with an `InvalidData` error.
         #[inline]
         pub fn read<R: Read>(mut stream: R) -> Result<PrivateData, Error> {
-            let mut instance = unsafe { mem::uninitialized::<PrivateData>() };
+            let version = stream.read_u8()?;
 
-            instance.user_id = stream.read_u64::<BigEndian>()?;
-            stream.read_exact(&mut instance.server_key)?;
-            stream.read_exact(&mut instance.client_key)?;
+            if version != PrivateData::VERSION {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unsupported private data version {}", version),
+                ));
+            }
 
-            Ok(instance)
+            let user_id = stream.read_u64::<BigEndian>()?;
+            let mut client_key = [0u8; 32];
+            stream.read_exact(&mut client_key)?;
+            let mut server_key = [0u8; 32];
+            stream.read_exact(&mut server_key)?;
+            let roles = stream.read_u32::<BigEndian>()?;
+
+            Ok(PrivateData {
+                version,
+                user_id,
+                roles,
+                server_key,
+                client_key,
+            })
         }
 
-        /// Write the private data to the supplied stream.
+        /// Write the private data to the supplied stream. The client key precedes the server key.
         #[inline]
         pub fn write<W: Write>(&self, mut stream: W) -> Result<(), Error> {
+            stream.write_u8(self.version)?;
             stream.write_u64::<BigEndian>(self.user_id)?;
             stream.write_all(&self.client_key)?;
-            stream.write_all(&self.server_key).map_err(Into::into)
+            stream.write_all(&self.server_key)?;
+            stream.write_u32::<BigEndian>(self.roles).map_err(Into::into)
         }
 
-        /// Construct the additional encryption data.
+        /// Construct the additional encryption data. The cipher suite negotiated for the session is
+        /// included to prevent tampering.
         #[inline]
-        pub fn additional_data(version: &[u8], protocol: u16, expires: u64) -> Result<[u8; 26], Error> {
-            let mut additional_data = [0u8; 26];
+        pub fn additional_data(
+            version: &[u8],
+            protocol: u16,
+            suite: CipherSuite,
+            expires: u64,
+        ) -> Result<[u8; 27], Error> {
+            let mut additional_data = [0u8; 27];
             let mut additional_data_slice = &mut additional_data[..];
 
             additional_data_slice.write_all(version)?;
             additional_data_slice.write_u16::<LittleEndian>(protocol)?;
+            additional_data_slice.write_u8(suite.id())?;
             additional_data_slice.write_u64::<LittleEndian>(expires)?;
 
             Ok(additional_data)
         }
     }
+
+    /// Builds connection tokens in their on-wire format, that is the public part (version, protocol,
+    /// cipher suite, expiry and sequence) followed by the private data encrypted with the secret key
+    /// shared by the authenticator and the game server. Serves as the reference encoder for clients.
+    pub struct TokenBuilder {
+        version: [u8; 16],
+        protocol: u16,
+        suite: CipherSuite,
+        expires: u64,
+        sequence: u64,
+        data: PrivateData,
+    }
+
+    impl TokenBuilder {
+        pub const SIZE: usize = 35 + PrivateData::SIZE + crypto::MAC_SIZE;
+
+        /// Create a builder for the supplied private data. The token defaults to the current version and
+        /// protocol, the default cipher suite and the standard expiry.
+        pub fn new(data: PrivateData) -> TokenBuilder {
+            TokenBuilder {
+                version: crate::VERSION_ID,
+                protocol: crate::PROTOCOL_ID,
+                suite: CipherSuite::default(),
+                expires: timestamp_secs() + crate::CONNECTION_TOKEN_EXPIRY_SECS,
+                sequence: 0,
+                data,
+            }
+        }
+
+        pub fn version(mut self, version: [u8; 16]) -> TokenBuilder {
+            self.version = version;
+            self
+        }
+
+        pub fn protocol(mut self, protocol: u16) -> TokenBuilder {
+            self.protocol = protocol;
+            self
+        }
+
+        pub fn suite(mut self, suite: CipherSuite) -> TokenBuilder {
+            self.suite = suite;
+            self
+        }
+
+        /// Unix timestamp after which the token is rejected.
+        pub fn expires(mut self, expires: u64) -> TokenBuilder {
+            self.expires = expires;
+            self
+        }
+
+        /// Sequence used as the nonce when encrypting the private data, it must not be reused with the
+        /// same secret key.
+        pub fn sequence(mut self, sequence: u64) -> TokenBuilder {
+            self.sequence = sequence;
+            self
+        }
+
+        /// Encrypt and write the token to the supplied stream. The private data is always encrypted with
+        /// the default suite, the suite of the token only applies to the channel traffic.
+        pub fn write<W: Write>(
+            &self,
+            mut stream: W,
+            secret_key: &[u8; crypto::KEY_SIZE],
+        ) -> Result<(), Error> {
+            let mut plain = [0u8; PrivateData::SIZE];
+            self.data.write(&mut plain[..])?;
+
+            let additional_data =
+                PrivateData::additional_data(&self.version, self.protocol, self.suite, self.expires)?;
+
+            let mut cipher = [0u8; PrivateData::SIZE + crypto::MAC_SIZE];
+            crypto::encrypt(
+                CipherSuite::default(),
+                &mut cipher,
+                &plain,
+                &additional_data,
+                self.sequence,
+                secret_key,
+            );
+
+            stream.write_all(&self.version)?;
+            stream.write_u16::<BigEndian>(self.protocol)?;
+            stream.write_u8(self.suite.id())?;
+            stream.write_u64::<BigEndian>(self.expires)?;
+            stream.write_u64::<BigEndian>(self.sequence)?;
+            stream.write_all(&cipher)
+        }
+
+        /// Encrypt the token into a new buffer.
+        pub fn build(&self, secret_key: &[u8; crypto::KEY_SIZE]) -> Vec<u8> {
+            let mut token = Vec::with_capacity(Self::SIZE);
+            self.write(&mut token, secret_key)
+                .expect("Writing into a vector can't fail");
+            token
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::ErrorKind;
+
+        #[test]
+        fn test_private_data_roundtrip() {
+            let data = PrivateData {
+                version: PrivateData::VERSION,
+                user_id: 8008,
+                roles: roles::ADMIN,
+                server_key: [15; 32],
+                client_key: [101; 32],
+            };
+
+            let mut buffer = [0u8; PrivateData::SIZE];
+            data.write(&mut buffer[..]).unwrap();
+
+            let result = PrivateData::read(&buffer[..]).unwrap();
+
+            assert_eq!(result.user_id, 8008);
+            assert_eq!(result.roles, roles::ADMIN);
+            assert_eq!(result.server_key, [15; 32]);
+            assert_eq!(result.client_key, [101; 32]);
+        }
+
+        #[test]
+        fn test_private_data_layout() {
+            let data = PrivateData {
+                version: PrivateData::VERSION,
+                user_id: 8008,
+                roles: roles::ADMIN,
+                server_key: [15; 32],
+                client_key: [101; 32],
+            };
+
+            let mut buffer = [0u8; PrivateData::SIZE];
+            data.write(&mut buffer[..]).unwrap();
+
+            assert_eq!(buffer[0], PrivateData::VERSION);
+            assert_eq!((&buffer[1..9]).read_u64::<BigEndian>().unwrap(), 8008);
+            assert_eq!(&buffer[9..41], &[101; 32][..]);
+            assert_eq!(&buffer[41..73], &[15; 32][..]);
+            assert_eq!((&buffer[73..]).read_u32::<BigEndian>().unwrap(), roles::ADMIN);
+        }
+
+        #[test]
+        fn test_private_data_read_truncated() {
+            let data = PrivateData {
+                version: PrivateData::VERSION,
+                user_id: 8008,
+                roles: 0,
+                server_key: [15; 32],
+                client_key: [101; 32],
+            };
+
+            let mut buffer = [0u8; PrivateData::SIZE];
+            data.write(&mut buffer[..]).unwrap();
+
+            // Cut off in the middle of the server key, the read must fail without yielding an instance.
+            let result = PrivateData::read(&buffer[..PrivateData::SIZE - 10]);
+            assert_eq!(result.err().unwrap().kind(), ErrorKind::UnexpectedEof);
+
+            // Cut off in the middle of the user id.
+            let result = PrivateData::read(&buffer[..4]);
+            assert_eq!(result.err().unwrap().kind(), ErrorKind::UnexpectedEof);
+        }
+
+        #[test]
+        fn test_token_builder() {
+            let key = [33u8; crypto::KEY_SIZE];
+
+            let token = TokenBuilder::new(PrivateData {
+                version: PrivateData::VERSION,
+                user_id: 8008,
+                roles: roles::ADMIN,
+                server_key: [15; 32],
+                client_key: [101; 32],
+            })
+            .version([5; 16])
+            .protocol(123)
+            .expires(1000)
+            .sequence(20)
+            .build(&key);
+
+            assert_eq!(token.len(), TokenBuilder::SIZE);
+
+            let mut stream = &token[..];
+            let mut version = [0u8; 16];
+            stream.read_exact(&mut version).unwrap();
+
+            assert_eq!(version, [5; 16]);
+            assert_eq!(stream.read_u16::<BigEndian>().unwrap(), 123);
+            assert_eq!(stream.read_u8().unwrap(), CipherSuite::default().id());
+            assert_eq!(stream.read_u64::<BigEndian>().unwrap(), 1000);
+            assert_eq!(stream.read_u64::<BigEndian>().unwrap(), 20);
+
+            // The private data decrypts with the same parameters
+            let additional_data =
+                PrivateData::additional_data(&[5; 16], 123, CipherSuite::default(), 1000).unwrap();
+            let mut plain = [0u8; PrivateData::SIZE];
+
+            assert!(crypto::decrypt(
+                CipherSuite::default(),
+                &mut plain,
+                stream,
+                &additional_data,
+                20,
+                &key
+            ));
+
+            let data = PrivateData::read(&plain[..]).unwrap();
+            assert_eq!(data.user_id, 8008);
+            assert_eq!(data.roles, roles::ADMIN);
+            assert_eq!(data.server_key, [15; 32]);
+            assert_eq!(data.client_key, [101; 32]);
+        }
+
+        #[test]
+        fn test_private_data_read_v1() {
+            // Version 1 carried no roles
+            let mut buffer = vec![1u8];
+            buffer.write_u64::<BigEndian>(8008).unwrap();
+            buffer.extend_from_slice(&[101; 32]);
+            buffer.extend_from_slice(&[15; 32]);
+
+            let result = PrivateData::read(&buffer[..]);
+            assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+        }
+
+        #[test]
+        fn test_private_data_read_unknown_version() {
+            let data = PrivateData {
+                version: PrivateData::VERSION + 1,
+                user_id: 8008,
+                roles: 0,
+                server_key: [15; 32],
+                client_key: [101; 32],
+            };
+
+            let mut buffer = [0u8; PrivateData::SIZE];
+            data.write(&mut buffer[..]).unwrap();
+
+            let result = PrivateData::read(&buffer[..]);
+            assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+        }
+    }
 }
@@ -1,10 +1,160 @@
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-/// Returns the current unix timestamp (seconds elapsed since 1970-01-01)
+/// Returns the time elapsed since the unix epoch (1970-01-01)
 #[inline]
-pub fn timestamp_secs() -> u64 {
+fn since_epoch() -> Duration {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("Closed timelike curve, reality compromised")
-        .as_secs()
+}
+
+/// Returns the current unix timestamp (seconds elapsed since 1970-01-01)
+#[inline]
+pub fn timestamp_secs() -> u64 {
+    since_epoch().as_secs()
+}
+
+/// Returns the current unix timestamp in milliseconds (milliseconds elapsed since 1970-01-01)
+#[inline]
+pub fn timestamp_millis() -> u64 {
+    let elapsed = since_epoch();
+    elapsed.as_secs() * 1_000 + u64::from(elapsed.subsec_millis())
+}
+
+/// Returns the current unix timestamp in microseconds (microseconds elapsed since 1970-01-01)
+#[inline]
+pub fn timestamp_micros() -> u64 {
+    let elapsed = since_epoch();
+    elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros())
+}
+
+/// Source of time. Allows time dependent code to be driven by a manually advanced clock in tests.
+pub trait Clock: Send + Sync {
+    /// Monotonic high resolution time.
+    fn now(&self) -> Instant;
+
+    /// Current unix timestamp (seconds elapsed since 1970-01-01).
+    fn timestamp_secs(&self) -> u64;
+
+    /// Block for the supplied duration.
+    fn sleep(&self, duration: Duration);
+}
+
+/// Clock backed by the system time.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    #[inline]
+    fn timestamp_secs(&self) -> u64 {
+        timestamp_secs()
+    }
+
+    #[inline]
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// Clock that only advances when told to. Clones share the same time, so a test can hold on to a clone
+/// while the code under test owns another. Sleeping advances the clock immediately.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    start_secs: u64,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    /// Create a new clock starting at the current system time.
+    pub fn new() -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            start_secs: timestamp_secs(),
+            elapsed: Arc::new(Mutex::new(Duration::from_secs(0))),
+        }
+    }
+
+    /// Advance the clock by the supplied duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().expect("Failed to acquire clock lock") += duration;
+    }
+
+    #[inline]
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().expect("Failed to acquire clock lock")
+    }
+}
+
+impl Clock for ManualClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    #[inline]
+    fn timestamp_secs(&self) -> u64 {
+        self.start_secs + self.elapsed().as_secs()
+    }
+
+    #[inline]
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamps() {
+        let secs = timestamp_secs();
+        let millis = timestamp_millis();
+        let micros = timestamp_micros();
+
+        // Taken in order, the finer timestamps can only be ahead of the coarser ones
+        assert!(millis / 1_000 >= secs && millis / 1_000 - secs <= 1);
+        assert!(micros / 1_000 >= millis && micros / 1_000 - millis <= 1_000);
+
+        let mut prev_millis = millis;
+        let mut prev_micros = micros;
+
+        for _ in 0..1000 {
+            let millis = timestamp_millis();
+            let micros = timestamp_micros();
+
+            assert!(millis >= prev_millis);
+            assert!(micros >= prev_micros);
+
+            prev_millis = millis;
+            prev_micros = micros;
+        }
+    }
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let shared = clock.clone();
+
+        let start = clock.now();
+        let start_secs = clock.timestamp_secs();
+
+        assert_eq!(clock.now(), start);
+
+        shared.advance(Duration::from_millis(1500));
+        assert_eq!(clock.now() - start, Duration::from_millis(1500));
+        assert_eq!(clock.timestamp_secs(), start_secs + 1);
+
+        clock.sleep(Duration::from_millis(500));
+        assert_eq!(shared.now() - start, Duration::from_secs(2));
+        assert_eq!(shared.timestamp_secs(), start_secs + 2);
+    }
 }
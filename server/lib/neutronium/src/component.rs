@@ -2,20 +2,39 @@ use crate::alloc::DynPtr;
 use crate::entity::{EntityId, ShardDef};
 use crate::alloc::{DynVec, DynVecOps};
 use crate::identity::{ComponentClass, ShardKey};
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
+use lazy_static::lazy_static;
 use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::TypeId;
+use std::cell::RefCell;
 use std::fmt::Debug;
+use std::intrinsics::type_name;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 
 #[macro_export]
 macro_rules! component_init {
     ($name: ident) => {
-        $crate::custom_type_id_init!($name, ComponentClass, Component, get_class);
+        $crate::component_init!($name, stringify!($name));
+    };
+    ($name: ident; codec) => {
+        $crate::component_init!($name, stringify!($name); codec);
+    };
+    ($name: ident, $display_name: expr; codec) => {
+        $crate::component_init!($name, $display_name);
+        $crate::serde_codec!($name);
+    };
+    ($name: ident, $display_name: expr) => {
+        $crate::custom_type_id_init!($name, ComponentClass, Component, get_class, $display_name);
 
         $crate::identity::paste::item! {
             #[allow(non_upper_case_globals)]
             #[allow(non_snake_case)]
             #[$crate::identity::ctor::ctor]
             fn [<_ $name _component_init>]() {
+                $crate::component::ensure_unregistered::<$name>();
+
                 // Get lock
                 let _lock = ComponentClass::id_gen_lock();
 
@@ -24,7 +43,9 @@ macro_rules! component_init {
 
                 // Set up component builders
                 unsafe {
-                    $crate::component::COMP_VEC_BUILDERS.push(Box::new(|| Box::new(Vec::<$name>::new())))
+                    $crate::component::COMP_VEC_BUILDERS.push(Box::new(|| Box::new(Vec::<$name>::new())));
+                    $crate::component::COMP_DEF_BUILDERS
+                        .push(Box::new(|| $crate::component::CompDefVec::new(Vec::<$name>::new())));
                 }
             }
         }
@@ -34,6 +55,83 @@ macro_rules! component_init {
 pub static mut COMP_VEC_BUILDERS: Vec<Box<Fn() -> Box<ComponentVec>>> = Vec::new();
 pub static mut COMP_DEF_BUILDERS: Vec<Box<Fn() -> CompDefVec>> = Vec::new();
 
+lazy_static! {
+    static ref GENERIC_CLASSES: Mutex<HashMap<TypeId, ComponentClass>> = { Mutex::new(HashMap::new()) };
+    static ref REGISTERED: Mutex<HashSet<TypeId>> = { Mutex::new(HashSet::new()) };
+}
+
+thread_local! {
+    // Classes never change once registered, each thread caches them to avoid locking on every lookup
+    static GENERIC_CLASS_CACHE: RefCell<HashMap<TypeId, ComponentClass>> = RefCell::new(HashMap::new());
+}
+
+/// Panics if a class was already registered for the component. Registering a component twice would
+/// push duplicate entries into the class vectors and corrupt the shard keys.
+#[doc(hidden)]
+pub fn ensure_unregistered<T: 'static>() {
+    // Release the lock before panicking to avoid poisoning it
+    let is_new = REGISTERED
+        .lock()
+        .expect("Failed to acquire component registration lock")
+        .insert(TypeId::of::<T>());
+
+    if !is_new {
+        panic!("Component {} is registered more than once", unsafe { type_name::<T>() })
+    }
+}
+
+/// Registers a new component class under the given name and sets up its builders. Used by the
+/// `Component` derive.
+#[doc(hidden)]
+pub fn register_component<T: 'static + Component>(name: &'static str) -> ComponentClass {
+    ensure_unregistered::<T>();
+
+    let _lock = ComponentClass::id_gen_lock();
+
+    unsafe {
+        let class = ComponentClass::new::<T>(ComponentClass::get_name_vec().len());
+
+        ComponentClass::get_name_vec().push(name);
+        ComponentClass::get_id_vec().push(class);
+        COMP_VEC_BUILDERS.push(Box::new(|| Box::new(Vec::<T>::new())));
+        COMP_DEF_BUILDERS.push(Box::new(|| CompDefVec::new(Vec::<T>::new())));
+
+        class
+    }
+}
+
+/// Gets the component class of a generic component. Statics can't be generic, so each instantiation
+/// is registered on first use and looked up by its type id afterwards.
+#[doc(hidden)]
+pub fn generic_class<T: 'static + Component>() -> ComponentClass {
+    let type_id = TypeId::of::<T>();
+
+    if let Some(class) = GENERIC_CLASS_CACHE.with(|cache| cache.borrow().get(&type_id).cloned()) {
+        return class;
+    }
+
+    let class = *GENERIC_CLASSES
+        .lock()
+        .expect("Failed to acquire generic component lock")
+        .entry(type_id)
+        .or_insert_with(|| register_component::<T>(unsafe { type_name::<T>() }));
+
+    GENERIC_CLASS_CACHE.with(|cache| cache.borrow_mut().insert(type_id, class));
+    class
+}
+
+/// Find the registered component class with the supplied name.
+pub fn find_class(name: &str) -> Option<ComponentClass> {
+    let _lock = ComponentClass::id_gen_lock();
+
+    unsafe {
+        ComponentClass::get_name_vec()
+            .iter()
+            .position(|&class_name| class_name == name)
+            .map(ComponentClass::from_indexer)
+    }
+}
+
 pub trait ComponentClassAux {
     fn comp_vec_builder(&self) -> &'static Box<Fn() -> Box<ComponentVec>>;
     fn comp_def_builder(&self) -> &'static Box<Fn() -> CompDefVec>;
@@ -42,20 +140,24 @@ pub trait ComponentClassAux {
 impl ComponentClassAux for ComponentClass {
     fn comp_vec_builder(&self) -> &'static Box<Fn() -> Box<ComponentVec>> {
         unsafe {
-            COMP_VEC_BUILDERS.get_unchecked(self.indexer())
+            COMP_VEC_BUILDERS
+                .get(self.indexer())
+                .unwrap_or_else(|| panic!("Component class {} was never registered", self))
         }
     }
 
     fn comp_def_builder(&self) -> &'static Box<Fn() -> CompDefVec> {
         unsafe {
-            COMP_DEF_BUILDERS.get_unchecked(self.indexer())
+            COMP_DEF_BUILDERS
+                .get(self.indexer())
+                .unwrap_or_else(|| panic!("Component class {} was never registered", self))
         }
     }
 }
 
 pub(crate) type ComponentCoords = (ShardKey, usize);
 
-pub trait Component: DeserializeOwned + Debug {
+pub trait Component: Serialize + DeserializeOwned + Debug {
     fn get_class() -> ComponentClass;
 
     #[inline]
@@ -72,7 +174,12 @@ pub trait Component: DeserializeOwned + Debug {
 pub trait ComponentVec {
     fn append(&mut self, data: &mut CompDefVec);
     fn remove(&mut self, loc: usize);
+    fn transfer(&mut self, loc: usize, data: &mut CompDefVec);
     fn len(&self) -> usize;
+    fn capacity(&self) -> usize;
+    fn shrink_to_fit(&mut self);
+    fn to_json(&self) -> serde_json::Result<Vec<u8>>;
+    fn row_to_json(&self, loc: usize) -> serde_json::Result<String>;
     unsafe fn get_ptr(&self) -> DynPtr;
 }
 
@@ -91,11 +198,36 @@ where
         self.swap_remove(loc);
     }
 
+    #[inline]
+    fn transfer(&mut self, loc: usize, data: &mut CompDefVec) {
+        data.push(self.swap_remove(loc));
+    }
+
     #[inline]
     fn len(&self) -> usize {
         self.len()
     }
 
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.capacity()
+    }
+
+    #[inline]
+    fn shrink_to_fit(&mut self) {
+        self.shrink_to_fit();
+    }
+
+    #[inline]
+    fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+
+    #[inline]
+    fn row_to_json(&self, loc: usize) -> serde_json::Result<String> {
+        serde_json::to_string(&self[loc])
+    }
+
     #[inline]
     unsafe fn get_ptr(&self) -> DynPtr {
         DynPtr::new_unchecked(self as *const Vec<T>)
@@ -104,6 +236,7 @@ where
 
 pub trait CompDef: DynVecOps + Debug {
     fn push_json(&mut self, json: &str);
+    fn extend_json(&mut self, json: &[u8]) -> serde_json::Result<usize>;
     fn clone_box(&self) -> Box<CompDef>;
 }
 
@@ -116,6 +249,14 @@ impl<T> CompDef for Vec<T>
         self.push(serde_json::from_str(json).expect("Error deserializing component"));
     }
 
+    #[inline]
+    fn extend_json(&mut self, json: &[u8]) -> serde_json::Result<usize> {
+        let mut data: Vec<T> = serde_json::from_slice(json)?;
+        let count = data.len();
+        self.append(&mut data);
+        Ok(count)
+    }
+
     #[inline]
     fn clone_box(&self) -> Box<CompDef> {
         Box::new(Vec::<T>::new())
@@ -150,6 +291,8 @@ pub struct Shard {
     // The pointer to the vec itself needs to be stable, hence the box.
     entities: Box<Vec<EntityId>>,
     store: HashMap<ComponentClass, Box<ComponentVec>>,
+    // Set whenever the contents change, boxed for the same reason as the entities.
+    dirty: Box<AtomicBool>,
 }
 
 impl Shard {
@@ -158,6 +301,7 @@ impl Shard {
             key,
             entities: Box::new(Vec::new()),
             store,
+            dirty: Box::new(AtomicBool::new(true)),
         }
     }
 
@@ -170,6 +314,7 @@ impl Shard {
             key,
             entities: Box::new(entities),
             store,
+            dirty: Box::new(AtomicBool::new(true)),
         }
     }
 
@@ -185,6 +330,7 @@ impl Shard {
         let loc_start = self.entities.len();
 
         self.entities.extend(&shard_def.entity_ids);
+        self.mark_dirty();
 
         loc_start
     }
@@ -192,6 +338,7 @@ impl Shard {
     #[inline]
     pub fn remove(&mut self, loc: usize) -> Option<EntityId> {
         self.entities.swap_remove(loc);
+        self.mark_dirty();
 
         for data in self.store.values_mut() {
             data.remove(loc);
@@ -200,11 +347,79 @@ impl Shard {
         self.entities.get(loc).and_then(|eid| Some(*eid))
     }
 
+    /// Move the entity at the supplied location into the shard definition. Components missing from the
+    /// definition are dropped. Returns the id of the entity swapped into the vacated location, if any.
+    #[inline]
+    pub fn transfer(&mut self, loc: usize, shard_def: &mut ShardDef) -> Option<EntityId> {
+        self.entities.swap_remove(loc);
+        self.mark_dirty();
+
+        for (id, data) in self.store.iter_mut() {
+            match shard_def.components.get_mut(id) {
+                Some(target) => data.transfer(loc, target),
+                None => data.remove(loc),
+            }
+        }
+
+        self.entities.get(loc).and_then(|eid| Some(*eid))
+    }
+
     #[inline]
     pub fn len(&self) -> usize {
         self.entities.len()
     }
 
+    /// Number of entities the shard can hold without reallocating.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.entities.capacity()
+    }
+
+    /// Release the memory held beyond the current contents. The vectors themselves stay in place, so
+    /// the data pointers held by the systems remain valid.
+    pub fn shrink(&mut self) {
+        self.entities.shrink_to_fit();
+
+        for data in self.store.values_mut() {
+            data.shrink_to_fit();
+        }
+    }
+
+    /// Check whether the shard changed since the dirty flag was last cleared. Entities being added,
+    /// removed or moved and systems writing the components all mark the shard dirty.
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub(crate) fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn clear_dirty(&self) {
+        self.dirty.store(false, Ordering::Relaxed);
+    }
+
+    /// Pointer to the dirty flag for write queries to mark the shard when accessing the data.
+    #[inline]
+    pub(crate) fn dirty_ptr(&self) -> *const AtomicBool {
+        self.dirty.as_ref() as *const AtomicBool
+    }
+
+    /// Iterate over the component data of the shard, excluding the entity ids.
+    #[inline]
+    pub(crate) fn iter_components(&self) -> impl Iterator<Item = (ComponentClass, &ComponentVec)> {
+        self.store.iter().map(|(&cls, data)| (cls, data.as_ref()))
+    }
+
+    /// Iterate over the entities in the shard along with their location.
+    #[inline]
+    pub fn iter_entities(&self) -> impl Iterator<Item = (EntityId, usize)> + '_ {
+        self.entities.iter().enumerate().map(|(loc, &id)| (id, loc))
+    }
+
     #[inline]
     pub fn data_ptr<T>(&self) -> *const Vec<T>
     where
@@ -213,16 +428,30 @@ impl Shard {
         if T::get_class() == EntityId::get_class() {
             unsafe { self.entities.get_ptr().cast_checked_raw() }
         } else {
-            unsafe {
-                self.store
-                    .get(&T::get_class())
-                    .unwrap()
-                    .get_ptr()
-                    .cast_checked_raw()
-            }
+            unsafe { self.component_vec::<T>().get_ptr().cast_checked_raw() }
         }
     }
 
+    /// The data of the given component as a contiguous slice, ordered by entity location. Useful for bulk
+    /// numeric work the compiler can vectorize, as opposed to indexing the entities one by one.
+    #[inline]
+    pub fn column_slice<T>(&self) -> &[T]
+    where
+        T: 'static + Component,
+    {
+        unsafe { &*self.data_ptr::<T>() }
+    }
+
+    /// Mutable counterpart of `column_slice`, marks the shard dirty. The entity ids are not writeable.
+    #[inline]
+    pub fn column_slice_mut<T>(&mut self) -> &mut [T]
+    where
+        T: 'static + Component,
+    {
+        self.mark_dirty();
+        unsafe { &mut *self.data_mut_ptr::<T>() }
+    }
+
     #[inline]
     pub fn data_mut_ptr<T>(&self) -> *mut Vec<T>
     where
@@ -232,12 +461,19 @@ impl Shard {
             panic!("Entity ID vector is not writeable")
         }
 
-        unsafe {
-            self.store
-                .get(&T::get_class())
-                .unwrap()
-                .get_ptr()
-                .cast_checked_raw()
+        unsafe { self.component_vec::<T>().get_ptr().cast_checked_raw() }
+    }
+
+    /// Panics if the component was never instantiated in the shard, e.g. when a system queries a
+    /// component the shard doesn't carry.
+    #[inline]
+    fn component_vec<T>(&self) -> &ComponentVec
+    where
+        T: 'static + Component,
+    {
+        match self.store.get(&T::get_class()) {
+            Some(data) => data.as_ref(),
+            _ => panic!("Component {} is not instantiated in the shard", unsafe { type_name::<T>() }),
         }
     }
 }
@@ -355,4 +591,155 @@ mod tests {
         let shard = Shard::new(ShardKey::empty(), HashMap::new());
         shard.data_mut_ptr::<EntityId>();
     }
+
+    #[test]
+    #[should_panic(expected = "Component component::tests::SomeComponent is not instantiated in the shard")]
+    fn test_data_ptr_not_instantiated() {
+        let shard = Shard::new(ShardKey::empty(), HashMap::new());
+        shard.data_ptr::<SomeComponent>();
+    }
+
+    #[test]
+    #[should_panic(expected = "Component class ComponentClass(4611686018427387904) was never registered")]
+    fn test_builder_not_registered() {
+        ComponentClass::from_indexer(62).comp_vec_builder();
+    }
+
+    #[test]
+    #[should_panic(expected = "Component component::tests::SomeComponent is registered more than once")]
+    fn test_register_twice() {
+        // The class was already registered at startup by `component_init!`
+        register_component::<SomeComponent>("SomeComponent");
+    }
+
+    #[test]
+    fn test_iter_entities() {
+        let mut map: HashMap<_, Box<ComponentVec>> = HashMap::new();
+        map.insert(
+            SomeComponent::get_class(),
+            Box::new(Vec::<SomeComponent>::new()),
+        );
+
+        let mut shard = Shard::new(ShardKey::empty(), map);
+
+        let mut shard_def = ShardDef {
+            entity_ids: vec![5.into(), 3.into(), 9.into()],
+            components: HashMap::new(),
+        };
+
+        let data = vec![
+            SomeComponent { x: 5, y: 5 },
+            SomeComponent { x: 3, y: 3 },
+            SomeComponent { x: 9, y: 9 },
+        ];
+
+        shard_def
+            .components
+            .insert(SomeComponent::get_class(), CompDefVec::new(data));
+
+        assert_eq!(shard.ingest(&mut shard_def), 0);
+        assert_eq!(
+            shard.iter_entities().collect::<Vec<_>>(),
+            vec![(EntityId::from(5), 0), (EntityId::from(3), 1), (EntityId::from(9), 2)]
+        );
+
+        // Removal swaps the last entity into the vacated location
+        shard.remove(0);
+        assert_eq!(
+            shard.iter_entities().collect::<Vec<_>>(),
+            vec![(EntityId::from(9), 0), (EntityId::from(3), 1)]
+        );
+    }
+
+    #[test]
+    fn test_column_slice() {
+        use crate::system::store::{Data, Query};
+        use crate::system::{Read, Write};
+
+        let mut map: HashMap<_, Box<ComponentVec>> = HashMap::new();
+        map.insert(
+            SomeComponent::get_class(),
+            Box::new(Vec::<SomeComponent>::new()),
+        );
+
+        let mut shard = Shard::new(ShardKey::empty(), map);
+
+        let mut shard_def = ShardDef {
+            entity_ids: (0..16).map(EntityId::from).collect(),
+            components: HashMap::new(),
+        };
+
+        let data: Vec<_> = (0..16).map(|i| SomeComponent { x: i, y: -i }).collect();
+
+        shard_def
+            .components
+            .insert(SomeComponent::get_class(), CompDefVec::new(data));
+
+        shard.ingest(&mut shard_def);
+        shard.clear_dirty();
+
+        // Integrate the components in bulk
+        for comp in shard.column_slice_mut::<SomeComponent>() {
+            comp.x += comp.y * 2;
+        }
+
+        assert!(shard.is_dirty());
+
+        let ids = shard.column_slice::<EntityId>();
+        let column = shard.column_slice::<SomeComponent>();
+        let mut reader = <Read<SomeComponent> as Query>::execute(&shard);
+
+        assert_eq!(column.len(), shard.len());
+        assert_eq!(ids.len(), shard.len());
+
+        for (id, loc) in shard.iter_entities() {
+            let comp = reader.get(loc);
+
+            assert_eq!(ids[loc], id);
+            assert_eq!((column[loc].x, column[loc].y), (comp.x, comp.y));
+            assert_eq!(column[loc].x, -(loc as i32));
+        }
+
+        // Writes through the slice are visible to the per entity interface and vice versa
+        let mut writer = <Write<SomeComponent> as Query>::execute(&shard);
+        writer.get(3).y = 100;
+
+        assert_eq!(shard.column_slice::<SomeComponent>()[3].y, 100);
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct ReplicatedComponent {
+        name: String,
+        pos: (f32, f32),
+        tags: Vec<u16>,
+    }
+
+    component_init!(ReplicatedComponent; codec);
+
+    #[test]
+    fn test_codec_roundtrip() {
+        use crate::net::support::PayloadBatch;
+        use std::io;
+
+        let components: Vec<_> = (0..3)
+            .map(|i| ReplicatedComponent {
+                name: format!("comp{}", i),
+                pos: (i as f32, -1.5),
+                tags: vec![i; i as usize],
+            })
+            .collect();
+
+        let mut buffer = vec![0u8; 256];
+
+        let mut outgoing = PayloadBatch::new();
+        components.iter().cloned().for_each(|comp| outgoing.push(comp));
+        outgoing.write(&mut io::Cursor::new(&mut buffer[..])).unwrap();
+        assert_eq!(outgoing.len(), 0);
+
+        let mut incoming = PayloadBatch::<ReplicatedComponent>::new();
+        incoming.read(&mut io::Cursor::new(&buffer[..])).unwrap();
+
+        assert_eq!(incoming.drain().collect::<Vec<_>>(), components);
+        assert_eq!(ReplicatedComponent::get_type_name(), "ReplicatedComponent");
+    }
 }
@@ -4,15 +4,45 @@ use crate::component_init;
 use crate::identity::{ComponentClass, ShardKey};
 use hashbrown::HashMap;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+// Low bits of the entity id hold the index, the high bits the generation
+const INDEX_BITS: u32 = 40;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+const MAX_GENERATION: u32 = (1 << (64 - INDEX_BITS)) - 1;
+
+/// Identifies an entity by its index and the generation of the index. Indices of removed entities are
+/// reused with the next generation, so the ids of removed entities never resolve to a later entity.
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct EntityId(usize);
 
 component_init!(EntityId);
 
+impl EntityId {
+    #[inline]
+    pub fn new(index: usize, generation: u32) -> EntityId {
+        if index > INDEX_MASK || generation > MAX_GENERATION {
+            panic!("Entity index {} or generation {} out of range", index, generation);
+        }
+
+        EntityId(index | ((generation as usize) << INDEX_BITS))
+    }
+
+    #[inline]
+    pub fn index(self) -> usize {
+        self.0 & INDEX_MASK
+    }
+
+    #[inline]
+    pub fn generation(self) -> u32 {
+        (self.0 >> INDEX_BITS) as u32
+    }
+}
+
 impl From<usize> for EntityId {
     #[inline]
     fn from(id: usize) -> Self {
@@ -58,7 +88,7 @@ pub struct ShardDef {
 
 impl ShardDef {
     #[inline]
-    fn new(comp_cls: &[ComponentClass]) -> ShardDef {
+    pub(crate) fn new(comp_cls: &[ComponentClass]) -> ShardDef {
         let map: HashMap<_, _> = comp_cls
             .iter()
             .map(|cls| (*cls, cls.comp_def_builder()()))
@@ -76,21 +106,108 @@ impl ShardDef {
     }
 }
 
+/// Staged change to the component set of an existing entity.
+#[derive(Debug)]
+pub(crate) enum ComponentEdit {
+    Add(EntityId, ComponentClass, CompDefVec),
+    Remove(EntityId, ComponentClass),
+}
+
+/// Staged deletion of all entities matching the predicate.
+pub(crate) struct DeletePredicate(pub(crate) Box<Fn(EntityId) -> bool + Send>);
+
+impl fmt::Debug for DeletePredicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DeletePredicate")
+    }
+}
+
+/// Source of entity ids shared by the transaction contexts. Fresh indices are handed out in increasing
+/// order, the indices of removed entities are recycled first with their generation bumped.
+#[derive(Debug, Default)]
+pub struct IdPool {
+    counter: AtomicUsize,
+    free: Mutex<VecDeque<EntityId>>,
+}
+
+impl IdPool {
+    #[inline]
+    pub fn new() -> IdPool {
+        IdPool::default()
+    }
+
+    /// Hand out the given number of ids. Recycled ids are handed out in the order they were released.
+    pub(crate) fn allocate<F: FnMut(EntityId)>(&self, count: usize, mut f: F) {
+        let recycled = {
+            let mut free = self.free.lock().expect("Failed to acquire id pool lock");
+            let recycled = count.min(free.len());
+            free.drain(..recycled).for_each(&mut f);
+            recycled
+        };
+
+        let fresh = count - recycled;
+
+        if fresh > 0 {
+            let start = self.counter.fetch_add(fresh, Ordering::AcqRel);
+            (start..start + fresh).map(EntityId).for_each(f);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn allocate_one(&self) -> EntityId {
+        let mut id = None;
+        self.allocate(1, |allocated| id = Some(allocated));
+        id.unwrap()
+    }
+
+    /// Return the index of a removed entity to the pool, it is handed out again with the next generation.
+    /// Indices that weren't handed out by the pool are ignored, as are indices with their generations
+    /// exhausted.
+    pub(crate) fn release(&self, id: EntityId) {
+        if id.index() < self.counter() && id.generation() < MAX_GENERATION {
+            self.free
+                .lock()
+                .expect("Failed to acquire id pool lock")
+                .push_back(EntityId::new(id.index(), id.generation() + 1));
+        }
+    }
+
+    /// Number of indices handed out so far.
+    #[inline]
+    pub(crate) fn counter(&self) -> usize {
+        self.counter.load(Ordering::SeqCst)
+    }
+
+    /// Make sure the indices up to the counter are never handed out fresh and forget the recycled ones,
+    /// they might be in use by restored entities.
+    pub(crate) fn restore(&self, counter: usize) {
+        if counter > self.counter() {
+            self.counter.store(counter, Ordering::SeqCst);
+        }
+
+        self.free.lock().expect("Failed to acquire id pool lock").clear();
+    }
+}
+
 /// Context for recording entity transactions. Prepared by the `World` after all components have been
 /// registered and the world is finalized.
 #[derive(Debug)]
 pub struct TransactionContext {
     pub(crate) added: HashMap<ShardKey, ShardDef>,
     pub(crate) deleted: Vec<EntityId>,
-    pub(crate) id_counter: Arc<AtomicUsize>,
+    pub(crate) deleted_where: Vec<DeletePredicate>,
+    pub(crate) edited: Vec<ComponentEdit>,
+    pub(crate) id_pool: Arc<IdPool>,
 }
 
 impl TransactionContext {
-    pub fn new(counter: Arc<AtomicUsize>) -> TransactionContext {
+    pub fn new(id_pool: Arc<IdPool>) -> TransactionContext {
         TransactionContext {
             added: HashMap::new(),
             deleted: Vec::new(),
-            id_counter: counter,
+            deleted_where: Vec::new(),
+            edited: Vec::new(),
+            id_pool,
         }
     }
 
@@ -103,6 +220,16 @@ impl TransactionContext {
         T::new_batch_builder(self)
     }
 
+    /// Create a batch entity builder for adding entities with differing components in one go.
+    #[inline]
+    pub fn multi_batch(&mut self) -> MultiBatchBuilder<'_> {
+        MultiBatchBuilder {
+            ctx: self,
+            rows: Vec::new(),
+            committed: Vec::new(),
+        }
+    }
+
     /// Create a batch entity builder for ingesting JSON data
     pub fn batch_json<'i>(&'i mut self, comp_classes: &'i [ComponentClass]) -> JsonBatchBuilder<'i> {
         let shard_key: ShardKey = comp_classes.iter().collect();
@@ -115,7 +242,7 @@ impl TransactionContext {
         JsonBatchBuilder {
             comp_classes,
             shard,
-            id_counter: self.id_counter.clone(),
+            id_pool: self.id_pool.clone(),
             batch_counter: 0,
         }
     }
@@ -134,12 +261,72 @@ impl TransactionContext {
     pub fn remove(&mut self, id: EntityId) {
         self.deleted.push(id);
     }
+
+    /// Delete all entities with the given ids.
+    #[inline]
+    pub fn remove_all(&mut self, ids: &[EntityId]) {
+        self.deleted.extend_from_slice(ids);
+    }
+
+    /// Delete all entities matching the predicate. The predicate is evaluated against the live entities
+    /// when the transaction is processed.
+    #[inline]
+    pub fn remove_where<F>(&mut self, predicate: F)
+    where
+        F: 'static + Fn(EntityId) -> bool + Send,
+    {
+        self.deleted_where.push(DeletePredicate(Box::new(predicate)));
+    }
+
+    /// Add a component to an existing entity. The entity will be moved to the shard matching its new
+    /// set of components when the transaction is processed. An existing component of the same class
+    /// is overwritten.
+    #[inline]
+    pub fn add_component<T>(&mut self, id: EntityId, component: T)
+    where
+        T: 'static + Component,
+    {
+        self.edited
+            .push(ComponentEdit::Add(id, T::get_class(), CompDefVec::new(vec![component])));
+    }
+
+    /// Remove a component from an existing entity. The entity will be moved to the shard matching its
+    /// new set of components when the transaction is processed.
+    #[inline]
+    pub fn remove_component(&mut self, id: EntityId, comp_cls: ComponentClass) {
+        if comp_cls == EntityId::get_class() {
+            panic!("Entity ID component can't be removed")
+        }
+
+        self.edited.push(ComponentEdit::Remove(id, comp_cls));
+    }
+
+    /// Discard all staged changes. Entity ids handed out for discarded additions are not reused.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.added.clear();
+        self.deleted.clear();
+        self.deleted_where.clear();
+        self.edited.clear();
+    }
+
+    /// Check whether the context has any staged changes.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.deleted.is_empty()
+            && self.deleted_where.is_empty()
+            && self.edited.is_empty()
+            && self.added.values().all(|shard| shard.entity_ids.is_empty())
+    }
 }
 
+/// Batch builder for JSON data. Entities added but not committed yet are committed when the builder is
+/// dropped.
+#[must_use = "entities are only added through the builder"]
 pub struct JsonBatchBuilder<'a> {
     comp_classes: &'a [ComponentClass],
     shard: &'a mut ShardDef,
-    id_counter: Arc<AtomicUsize>,
+    id_pool: Arc<IdPool>,
     batch_counter: usize,
 }
 
@@ -157,15 +344,11 @@ impl<'a> JsonBatchBuilder<'a> {
         self.batch_counter += 1;
     }
     pub fn commit(&mut self) -> &[EntityId] {
-        // Bump the id counter by the number of recorded entries in the batch
-        let start_id = self.id_counter.fetch_add(self.batch_counter, Ordering::AcqRel);
-
         let new_slice_start = self.shard.entity_ids.len();
 
-        // Generate entity Ids
-        for id in start_id..(start_id + self.batch_counter) {
-            self.shard.entity_ids.push(EntityId(id));
-        }
+        // Generate entity Ids for the recorded entries in the batch
+        let entity_ids = &mut self.shard.entity_ids;
+        self.id_pool.allocate(self.batch_counter, |id| entity_ids.push(id));
 
         // Reset the batch counter
         self.batch_counter = 0;
@@ -180,6 +363,49 @@ impl<'a> Drop for JsonBatchBuilder<'a> {
     }
 }
 
+/// Batch builder for entities with differing components. Each entity is staged in the shard definition
+/// matching its components, the ids are assigned in the order of addition on commit. Entities added
+/// but not committed yet are committed when the builder is dropped.
+#[must_use = "entities are only added through the builder"]
+pub struct MultiBatchBuilder<'a> {
+    ctx: &'a mut TransactionContext,
+    rows: Vec<ShardKey>,
+    committed: Vec<EntityId>,
+}
+
+impl<'a> MultiBatchBuilder<'a> {
+    #[inline]
+    pub fn add<T>(&mut self, tuple: T)
+    where
+        T: ComponentIngress<'a>,
+    {
+        let shard_key = tuple.stage(self.ctx);
+        self.rows.push(shard_key);
+    }
+
+    pub fn commit(&mut self) -> &[EntityId] {
+        self.committed.clear();
+
+        // Generate entity Ids for the recorded entries in the batch
+        let committed = &mut self.committed;
+        self.ctx.id_pool.allocate(self.rows.len(), |id| committed.push(id));
+
+        for (&id, shard_key) in self.committed.iter().zip(self.rows.drain(..)) {
+            self.ctx.added.get_mut(&shard_key).unwrap().entity_ids.push(id);
+        }
+
+        &self.committed
+    }
+}
+
+impl<'a> Drop for MultiBatchBuilder<'a> {
+    fn drop(&mut self) {
+        if !self.rows.is_empty() {
+            self.commit();
+        }
+    }
+}
+
 /// Tuple defining a batch builder that can efficiently add uniform entities.
 pub trait BatchDef<'a>: ComponentTuple<'a> {
     type Builder;
@@ -198,7 +424,7 @@ macro_rules! batch_def_tup {
             fn new_batch_builder(ctx: &'a mut TransactionContext) -> Self::Builder {
                 let ids = Self::get_ids();
 
-                let id_counter = ctx.id_counter.clone();
+                let id_pool = ctx.id_pool.clone();
                 let shard = Self::get_shard(&ids, ctx);
 
                 // The below is safe because of previous checks
@@ -207,7 +433,7 @@ macro_rules! batch_def_tup {
                         $(shard.components[&ids.$field_seq].cast_mut_unchecked::<$field_type>()),*,
                     );
 
-                    BatchBuilder::new(tup, &mut shard.entity_ids, id_counter)
+                    BatchBuilder::new(tup, &mut shard.entity_ids, id_pool)
                 }
             }
         }
@@ -223,10 +449,13 @@ batch_def_tup!(A:0, B:1, C:2, D:3, E:4, F:5);
 batch_def_tup!(A:0, B:1, C:2, D:3, E:4, F:5, G:6);
 batch_def_tup!(A:0, B:1, C:2, D:3, E:4, F:5, G:6, H:7);
 
+/// Batch builder for uniform entities. Entities added but not committed yet are committed when the
+/// builder is dropped.
+#[must_use = "entities are only added through the builder"]
 pub struct BatchBuilder<'a, T> {
     tup: T,
     entity_vec: &'a mut Vec<EntityId>,
-    id_counter: Arc<AtomicUsize>,
+    id_pool: Arc<IdPool>,
     batch_counter: usize,
 }
 
@@ -235,26 +464,22 @@ impl<'a, T> BatchBuilder<'a, T> {
     pub fn new(
         tup: T,
         entity_vec: &'a mut Vec<EntityId>,
-        id_counter: Arc<AtomicUsize>,
+        id_pool: Arc<IdPool>,
     ) -> BatchBuilder<'a, T> {
         BatchBuilder {
             tup,
             entity_vec,
-            id_counter,
+            id_pool,
             batch_counter: 0,
         }
     }
 
     pub fn commit(&mut self) -> &[EntityId] {
-        // Bump the id counter by the number of recorded entries in the batch
-        let start_id = self.id_counter.fetch_add(self.batch_counter, Ordering::AcqRel);
-
         let new_slice_start = self.entity_vec.len();
 
-        // Generate entity Ids
-        for id in start_id..(start_id + self.batch_counter) {
-            self.entity_vec.push(EntityId(id));
-        }
+        // Generate entity Ids for the recorded entries in the batch
+        let entity_vec = &mut self.entity_vec;
+        self.id_pool.allocate(self.batch_counter, |id| entity_vec.push(id));
 
         // Reset the batch counter
         self.batch_counter = 0;
@@ -357,6 +582,10 @@ comp_tup!(8, A:0, B:1, C:2, D:3, E:4, F:5, G:6, H:7);
 /// Trait for handling the ingress of a single data-tuple
 pub trait ComponentIngress<'a>: ComponentTuple<'a> {
     fn ingest(self, ctx: &mut TransactionContext) -> EntityId;
+
+    /// Push the components into the matching shard definition without adding an entity id. Returns the
+    /// key of the shard definition.
+    fn stage(self, ctx: &mut TransactionContext) -> ShardKey;
 }
 
 macro_rules! comp_ingress {
@@ -369,7 +598,7 @@ macro_rules! comp_ingress {
             fn ingest(self, ctx: &mut TransactionContext) -> EntityId {
                 let ids = Self::get_ids();
 
-                let entity_id = EntityId(ctx.id_counter.fetch_add(1, Ordering::AcqRel));
+                let entity_id = ctx.id_pool.allocate_one();
 
                 let shard = Self::get_shard(&ids, ctx);
 
@@ -379,6 +608,18 @@ macro_rules! comp_ingress {
 
                 entity_id
             }
+
+            #[inline]
+            fn stage(self, ctx: &mut TransactionContext) -> ShardKey {
+                let ids = Self::get_ids();
+                let shard_key: ShardKey = ($(ids.$field_seq)|*).into();
+
+                let shard = Self::get_shard(&ids, ctx);
+
+                $(shard.get_mut_vec(&ids.$field_seq).push(self.$field_seq));*;
+
+                shard_key
+            }
         }
     };
 }
@@ -68,6 +68,14 @@ macro_rules! bitflag_type_id {
                 }
             }
 
+            /// Reconstructs the id from its indexer.
+            #[inline]
+            pub fn from_indexer(indexer: usize) -> $name {
+                $name {
+                    id: (1 as $type) << indexer,
+                }
+            }
+
             #[inline]
             pub fn indexer(&self) -> usize {
                 self.id.trailing_zeros() as usize
@@ -132,6 +140,12 @@ macro_rules! bitflag_type_id {
                 })
             }
 
+            /// Names of the ids in the key, in the order of their indexers.
+            #[inline]
+            pub fn names(&self) -> Vec<&'static str> {
+                self.decompose().map(|id| id.name()).collect()
+            }
+
             #[inline]
             pub fn contains_key(&self, other: $composite_key) -> bool {
                 (self.0 & other.0) == other.0
@@ -236,6 +250,9 @@ macro_rules! bitflag_type_id {
 #[macro_export]
 macro_rules! custom_type_id_init {
     ($name: ident, $id_type: ty, $trait_type: ty, $accessor: ident) => {
+        $crate::custom_type_id_init!($name, $id_type, $trait_type, $accessor, stringify!($name));
+    };
+    ($name: ident, $id_type: ty, $trait_type: ty, $accessor: ident, $display_name: expr) => {
         $crate::identity::paste::item! {
             #[allow(non_upper_case_globals)]
             pub static mut [<_ $name _id>]: $id_type = $id_type{id: 0};
@@ -251,7 +268,7 @@ macro_rules! custom_type_id_init {
 
                         [<_ $name _id>] = $id_type::new::<$name>(counter);
 
-                        name_vec.push(stringify!($name));
+                        name_vec.push($display_name);
                         id_vec.push([<_ $name _id>]);
                     }
                 }
@@ -298,3 +315,4 @@ bitflag_type_id!(
 // Re-export dependencies to avoid the need for consumers to handle them
 pub use ctor;
 pub use paste;
+pub use serde;
@@ -7,6 +7,7 @@
 #![feature(box_into_raw_non_null)]
 #![feature(crate_visibility_modifier)]
 #![feature(duration_float)]
+#![feature(try_from)]
 
 #![allow(clippy::len_without_is_empty)]
 #![allow(clippy::new_without_default)]
@@ -24,6 +25,7 @@ pub mod component;
 pub mod entity;
 pub mod registry;
 pub mod sentinel;
+pub mod snapshot;
 pub mod sync;
 
 pub mod net;
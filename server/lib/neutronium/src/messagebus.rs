@@ -1,10 +1,24 @@
 use crate::alloc::{DynVec, DynVecOps};
-use crate::identity::{TopicBundle, Topic};
+use crate::identity::{SystemId, TopicBundle, Topic};
+use hashbrown::HashMap;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::intrinsics::type_name;
+use std::mem;
 
 #[macro_export]
 macro_rules! topic_init {
     ($name: ident) => {
+        $crate::topic_init!($name, 0);
+    };
+    ($name: ident, $priority: expr) => {
+        $crate::topic_init!(@register $name, $priority, None);
+    };
+    ($name: ident, $priority: expr, group = $group: expr) => {
+        $crate::topic_init!(@register $name, $priority, Some($group));
+    };
+    (@register $name: ident, $priority: expr, $group: expr) => {
         $crate::custom_type_id_init!($name, Topic, Message, get_topic);
 
         $crate::identity::paste::item! {
@@ -20,7 +34,9 @@ macro_rules! topic_init {
 
                 // Set up component builders
                 unsafe {
-                    $crate::messagebus::MSG_QUEUE_TPL.push($crate::alloc::DynVec::empty::<$name>())
+                    $crate::messagebus::MSG_QUEUE_TPL.push($crate::alloc::DynVec::empty::<$name>());
+                    $crate::messagebus::MSG_PRIORITY.push($priority);
+                    $crate::messagebus::assign_group($group);
                 }
             }
         }
@@ -28,6 +44,63 @@ macro_rules! topic_init {
 }
 
 pub static mut MSG_QUEUE_TPL: Vec<DynVec<MessageQueue>> = Vec::new();
+pub static mut MSG_PRIORITY: Vec<i32> = Vec::new();
+pub static mut MSG_GROUP: Vec<Option<MessageGroup>> = Vec::new();
+static mut MSG_GROUP_NAMES: Vec<&'static str> = Vec::new();
+
+/// Group of related topics sharing a partition of the bus, allowing them to be drained together.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct MessageGroup(usize);
+
+impl MessageGroup {
+    /// Look up a group by its name.
+    pub fn find(name: &str) -> Option<MessageGroup> {
+        unsafe { MSG_GROUP_NAMES.iter().position(|&group| group == name).map(MessageGroup) }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        unsafe { MSG_GROUP_NAMES[self.0] }
+    }
+}
+
+/// Assigns the topic being registered to the named group, creating the group if needed. Must be called
+/// with the ID generator lock held.
+#[doc(hidden)]
+pub unsafe fn assign_group(group: Option<&'static str>) {
+    let group = group.map(|name| match MessageGroup::find(name) {
+        Some(group) => group,
+        None => {
+            MSG_GROUP_NAMES.push(name);
+            MessageGroup(MSG_GROUP_NAMES.len() - 1)
+        }
+    });
+
+    MSG_GROUP.push(group);
+}
+
+/// Registers a new topic under the given name, priority and group and sets up its queue template. Used
+/// by the `Message` derive.
+#[doc(hidden)]
+pub fn register_topic<T: 'static + Message>(
+    name: &'static str,
+    priority: i32,
+    group: Option<&'static str>,
+) -> Topic {
+    let _lock = Topic::id_gen_lock();
+
+    unsafe {
+        let topic = Topic::new::<T>(Topic::get_name_vec().len());
+
+        Topic::get_name_vec().push(name);
+        Topic::get_id_vec().push(topic);
+        MSG_QUEUE_TPL.push(DynVec::empty::<T>());
+        MSG_PRIORITY.push(priority);
+        assign_group(group);
+
+        topic
+    }
+}
 
 /// Designates a struct as a topic for the message bus
 pub trait Message: Clone + Debug {
@@ -42,6 +115,18 @@ pub trait Message: Clone + Debug {
     fn get_topic_name() -> &'static str {
         unsafe { Topic::get_name_vec()[Self::get_indexer()] }
     }
+
+    /// Delivery priority of the topic. Topics with higher priority are delivered first.
+    #[inline]
+    fn get_priority() -> i32 {
+        unsafe { MSG_PRIORITY[Self::get_indexer()] }
+    }
+
+    /// Group the topic belongs to, if any.
+    #[inline]
+    fn get_group() -> Option<MessageGroup> {
+        unsafe { MSG_GROUP[Self::get_indexer()] }
+    }
 }
 
 /// Appendable and cloneable message queue
@@ -83,6 +168,11 @@ impl Clone for DynVec<MessageQueue> {
 pub struct Bus {
     topics: Vec<DynVec<MessageQueue>>,
     activity: TopicBundle,
+    delivery_order: Vec<Topic>,
+    groups: Vec<TopicBundle>,
+    history: HashMap<Topic, TopicHistory>,
+    sources: HashMap<Topic, Vec<MessageSource>>,
+    dead_letters: Vec<DeadLetter>,
 }
 
 impl Bus {
@@ -91,20 +181,120 @@ impl Bus {
         Bus {
             topics: unsafe { MSG_QUEUE_TPL.clone() },
             activity: TopicBundle::empty(),
+            delivery_order: Self::delivery_order(),
+            groups: Self::groups(),
+            history: HashMap::new(),
+            sources: HashMap::new(),
+            dead_letters: Vec::new(),
+        }
+    }
+
+    /// Sort all registered topics by descending priority. Topics with equal priority retain their
+    /// registration order.
+    fn delivery_order() -> Vec<Topic> {
+        let mut order = unsafe { Topic::get_id_vec().clone() };
+        order.sort_by_key(|topic| Reverse(unsafe { MSG_PRIORITY[topic.indexer()] }));
+        order
+    }
+
+    /// Collect the topics of each group into a bundle, indexed by the group.
+    fn groups() -> Vec<TopicBundle> {
+        unsafe {
+            (0..MSG_GROUP_NAMES.len())
+                .map(|group| {
+                    Topic::get_id_vec()
+                        .iter()
+                        .filter(|topic| MSG_GROUP[topic.indexer()] == Some(MessageGroup(group)))
+                        .collect()
+                })
+                .collect()
         }
     }
 
     /// Transfer the messages in the `other` `Bus` into the current `Bus`.
     #[inline]
     pub fn transfer(&mut self, other: &mut Bus) {
+        self.transfer_tagged(other, None);
+    }
+
+    /// Transfer the messages in the `other` `Bus` into the current `Bus`, tagging them with the
+    /// system that published them.
+    #[inline]
+    pub fn transfer_from(&mut self, other: &mut Bus, source: SystemId) {
+        self.transfer_tagged(other, Some(source));
+    }
+
+    #[inline]
+    fn transfer_tagged(&mut self, other: &mut Bus, source: Option<SystemId>) {
         // Iter all the active topics in the other bus and move over the messages to the current.
         for topic_id in other.activity.decompose() {
-            self.topics[topic_id.indexer()].append(&mut other.topics[topic_id.indexer()]);
+            let queue = &mut self.topics[topic_id.indexer()];
+
+            let start = queue.len();
+            queue.append(&mut other.topics[topic_id.indexer()]);
+            let end = queue.len();
+
+            if let Some(system) = source {
+                self.sources
+                    .entry(topic_id)
+                    .or_insert_with(Vec::new)
+                    .push(MessageSource { system, start, end });
+            }
+
+            self.activity += topic_id;
         }
-        self.activity = other.activity;
 
         // Clear out the activity in the other bus
         other.activity = TopicBundle::empty();
+
+        self.dead_letters.append(&mut other.dead_letters);
+    }
+
+    /// Read the messages for a particular topic that were published by the given system.
+    #[inline]
+    pub fn read_from<'a, T>(&'a self, source: SystemId) -> impl Iterator<Item = &'a T> + 'a
+    where
+        T: 'static + Message,
+    {
+        let messages = self.read::<T>();
+
+        self.sources
+            .get(&T::get_topic())
+            .into_iter()
+            .flat_map(|sources| sources.iter())
+            .filter(move |src| src.system == source)
+            .flat_map(move |src| messages[src.start..src.end].iter())
+    }
+
+    /// Iterate over the topics with pending messages in delivery order, highest priority first.
+    #[inline]
+    pub fn topics<'a>(&'a self) -> impl Iterator<Item = Topic> + 'a {
+        self.delivery_order
+            .iter()
+            .cloned()
+            .filter(move |&topic| self.activity.contains_id(topic))
+    }
+
+    /// Iterate over the topics of the group with pending messages in delivery order.
+    #[inline]
+    pub fn group_topics<'a>(&'a self, group: MessageGroup) -> impl Iterator<Item = Topic> + 'a {
+        let bundle = self.groups[group.0];
+        self.topics().filter(move |&topic| bundle.contains_id(topic))
+    }
+
+    /// Clear out the messages of all topics in the group. The history of retained topics only
+    /// advances when the whole bus is cleared, their messages are discarded.
+    pub fn clear_group(&mut self, group: MessageGroup) {
+        let bundle = self.groups[group.0];
+
+        for topic in bundle.decompose() {
+            if let Some(sources) = self.sources.get_mut(&topic) {
+                sources.clear();
+            }
+
+            self.topics[topic.indexer()].clear();
+            self.activity -= topic;
+        }
     }
 
     /// Read the messages for a particular topic.
@@ -116,14 +306,85 @@ impl Bus {
         self.topics[T::get_indexer()].cast_vector::<T>()
     }
 
-    /// Publish the supplied message on the bus.
+    /// Take ownership of the messages of a particular topic. The topic is empty afterwards, systems
+    /// reading it later in the same frame won't see the drained messages and they don't enter the
+    /// history of retained topics.
+    #[inline]
+    pub fn drain<T>(&mut self) -> Vec<T>
+    where
+        T: 'static + Message,
+    {
+        let mut messages = Vec::new();
+        self.drain_into(&mut messages);
+        messages
+    }
+
+    /// Move the messages of a particular topic to the end of the supplied vector. The same ordering
+    /// caveats apply as for `drain`. Draining a topic unknown to the bus records a dead letter.
+    #[inline]
+    pub fn drain_into<T>(&mut self, target: &mut Vec<T>)
+    where
+        T: 'static + Message,
+    {
+        let topic = T::get_topic();
+
+        if let Some(sources) = self.sources.get_mut(&topic) {
+            sources.clear();
+        }
+
+        let queue = match self.topics.get_mut(topic.indexer()) {
+            Some(queue) => queue.cast_mut_vector::<T>(),
+            _ => {
+                self.dead_letters.push(DeadLetter {
+                    message: unsafe { type_name::<T>() },
+                });
+                return;
+            }
+        };
+
+        match target.is_empty() {
+            true => mem::swap(target, queue),
+            _ => target.append(queue),
+        }
+
+        self.activity -= topic;
+    }
+
+    /// Publish the supplied message on the bus. Messages of topics unknown to the bus are recorded as
+    /// dead letters.
     #[inline]
     pub fn publish<T>(&mut self, message: T)
     where
         T: 'static + Message,
     {
-        self.activity += T::get_topic();
-        self.topics[T::get_indexer()].cast_mut_vector::<T>().push(message);
+        if let Err(letter) = self.try_publish(message) {
+            self.dead_letters.push(letter);
+        }
+    }
+
+    /// Publish the supplied message on the bus. Fails if the topic of the message is unknown to the
+    /// bus, e.g. because it was never registered.
+    #[inline]
+    pub fn try_publish<T>(&mut self, message: T) -> Result<(), DeadLetter>
+    where
+        T: 'static + Message,
+    {
+        match self.topics.get_mut(T::get_indexer()) {
+            Some(queue) => {
+                queue.cast_mut_vector::<T>().push(message);
+                self.activity += T::get_topic();
+                Ok(())
+            }
+            _ => Err(DeadLetter {
+                message: unsafe { type_name::<T>() },
+            }),
+        }
+    }
+
+    /// Messages published since the last clear that could not be delivered.
+    #[inline]
+    pub fn dead_letters(&self) -> &[DeadLetter] {
+        &self.dead_letters
     }
 
     /// Batch publish messages of a given type.
@@ -136,14 +397,98 @@ impl Bus {
         Batcher::new(self.topics[T::get_indexer()].cast_mut_vector::<T>())
     }
 
+    /// Retain the messages of the given topic for the last `frames` frames. Retained messages are
+    /// moved into the history when the bus is cleared and can be accessed via `read_history`.
+    pub fn retain<T>(&mut self, frames: usize)
+    where
+        T: 'static + Message,
+    {
+        if frames == 0 {
+            panic!("Topic {} must be retained for at least one frame", T::get_topic_name())
+        }
+
+        self.history.insert(T::get_topic(), TopicHistory::new(frames));
+    }
+
+    /// Read the retained messages of a particular topic, oldest first. The messages of the current
+    /// frame are not included. Returns an empty iterator for topics that are not retained.
+    #[inline]
+    pub fn read_history<'a, T>(&'a self) -> impl Iterator<Item = &'a T> + 'a
+    where
+        T: 'static + Message,
+    {
+        self.history
+            .get(&T::get_topic())
+            .into_iter()
+            .flat_map(|history| history.frames.iter())
+            .flat_map(|frame| frame.cast_vector::<T>().iter())
+    }
+
     /// Clear out all the messages from this bus.
     #[inline]
     pub fn clear(&mut self) {
+        // Move the messages of retained topics into their history
+        for (topic, history) in self.history.iter_mut() {
+            history.record(&mut self.topics[topic.indexer()]);
+        }
+
+        for sources in self.sources.values_mut() {
+            sources.clear();
+        }
+
         for topic in self.activity.decompose() {
             self.topics[topic.indexer()].clear();
         }
 
         self.activity = TopicBundle::empty();
+        self.dead_letters.clear();
+    }
+}
+
+/// Record of a message published on a topic unknown to the bus.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DeadLetter {
+    pub message: &'static str,
+}
+
+/// Range of messages in a topic queue that were published by a particular system.
+#[derive(Copy, Clone, Debug)]
+struct MessageSource {
+    system: SystemId,
+    start: usize,
+    end: usize,
+}
+
+/// Ring buffer holding the messages of a topic for a bounded number of frames.
+#[derive(Clone)]
+struct TopicHistory {
+    frames: VecDeque<DynVec<MessageQueue>>,
+    capacity: usize,
+}
+
+impl TopicHistory {
+    #[inline]
+    fn new(capacity: usize) -> TopicHistory {
+        TopicHistory {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Move the contents of the queue into a new frame, evicting the oldest frame if the history
+    /// is at capacity. Evicted frames are reused to avoid reallocation.
+    #[inline]
+    fn record(&mut self, queue: &mut DynVec<MessageQueue>) {
+        let mut frame = if self.frames.len() == self.capacity {
+            let mut frame = self.frames.pop_front().unwrap();
+            frame.clear();
+            frame
+        } else {
+            queue.clone()
+        };
+
+        frame.append(queue);
+        self.frames.push_back(frame);
     }
 }
 
@@ -185,6 +530,26 @@ mod tests {
 
     topic_init!(T2);
 
+    #[derive(Debug, Clone)]
+    pub struct THigh(i32);
+
+    topic_init!(THigh, 10);
+
+    #[derive(Debug, Clone)]
+    pub struct TLow(i32);
+
+    topic_init!(TLow, -10);
+
+    #[derive(Debug, Clone)]
+    pub struct TGroup1(i32);
+
+    topic_init!(TGroup1, 0, group = "bus_test_group");
+
+    #[derive(Debug, Clone)]
+    pub struct TGroup2(i32);
+
+    topic_init!(TGroup2, 0, group = "bus_test_group");
+
     #[test]
     fn test_auto_register_topics() {
         let bus = Bus::new();
@@ -192,6 +557,76 @@ mod tests {
         assert!(bus.topics.len() >= 2);
     }
 
+    #[test]
+    fn test_drain() {
+        let mut bus = Bus::new();
+
+        bus.publish(T1(0));
+        bus.publish(T1(1));
+        bus.publish(T2(2));
+
+        let drained: Vec<T1> = bus.drain();
+        assert_eq!(drained.iter().map(|msg| msg.0).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(bus.read::<T1>().len(), 0);
+        assert!(!bus.activity.contains_id(T1::get_topic()));
+
+        // Draining into a non-empty vector appends
+        let mut target = vec![T2(1)];
+        bus.drain_into(&mut target);
+        assert_eq!(target.iter().map(|msg| msg.0).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(bus.activity, TopicBundle::empty());
+    }
+
+    /// Topic that is never registered with the bus
+    #[derive(Debug, Clone)]
+    pub struct TUnregistered(i32);
+
+    impl Message for TUnregistered {
+        fn get_topic() -> Topic {
+            Topic::from_indexer(63)
+        }
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        let mut bus1 = Bus::new();
+        let mut bus2 = Bus::new();
+
+        bus1.publish(T1(0));
+        bus1.publish(TUnregistered(0));
+
+        let letter = DeadLetter {
+            message: "messagebus::tests::TUnregistered",
+        };
+
+        assert_eq!(bus1.try_publish(TUnregistered(1)), Err(letter));
+        assert_eq!(bus1.dead_letters(), &[letter]);
+        assert_eq!(bus1.activity.count(), 1);
+
+        // Dead letters are carried over by transfers
+        bus2.transfer(&mut bus1);
+        assert!(bus1.dead_letters().is_empty());
+        assert_eq!(bus2.dead_letters(), &[letter]);
+        assert_eq!(bus2.read::<T1>().len(), 1);
+
+        bus2.clear();
+        assert!(bus2.dead_letters().is_empty());
+    }
+
+    #[test]
+    fn test_drain_unregistered() {
+        let mut bus = Bus::new();
+
+        let drained: Vec<TUnregistered> = bus.drain();
+        assert!(drained.is_empty());
+
+        let letter = DeadLetter {
+            message: "messagebus::tests::TUnregistered",
+        };
+
+        assert_eq!(bus.dead_letters(), &[letter]);
+    }
+
     #[test]
     fn test_transfer() {
         let mut bus1 = Bus::new();
@@ -275,4 +710,118 @@ mod tests {
         assert_eq!(bus.activity, TopicBundle::empty());
         assert_eq!(messages.len(), 0);
     }
+
+    #[test]
+    fn test_priority_delivery_order() {
+        let mut bus1 = Bus::new();
+        let mut bus2 = Bus::new();
+
+        bus1.publish(TLow(0));
+        bus1.publish(T1(0));
+        bus1.publish(THigh(0));
+
+        assert_eq!(THigh::get_priority(), 10);
+        assert_eq!(T1::get_priority(), 0);
+        assert_eq!(TLow::get_priority(), -10);
+
+        let topics: Vec<_> = bus1.topics().collect();
+        assert_eq!(topics, vec![THigh::get_topic(), T1::get_topic(), TLow::get_topic()]);
+
+        // Delivery order is retained after transferring to another bus
+        bus2.transfer(&mut bus1);
+
+        let topics: Vec<_> = bus2.topics().collect();
+        assert_eq!(topics, vec![THigh::get_topic(), T1::get_topic(), TLow::get_topic()]);
+        assert_eq!(bus1.topics().count(), 0);
+    }
+
+    #[test]
+    fn test_read_history() {
+        let mut bus = Bus::new();
+        bus.retain::<T1>(2);
+
+        assert_eq!(bus.read_history::<T1>().count(), 0);
+
+        for frame in 0..4 {
+            bus.publish(T1(frame * 10));
+            bus.publish(T1(frame * 10 + 1));
+            bus.publish(T2(frame));
+            bus.clear();
+        }
+
+        // Only the last two frames are retained
+        let history: Vec<_> = bus.read_history::<T1>().map(|msg| msg.0).collect();
+        assert_eq!(history, vec![20, 21, 30, 31]);
+
+        // Regular topics are unaffected
+        assert_eq!(bus.read::<T1>().len(), 0);
+        assert_eq!(bus.read::<T2>().len(), 0);
+        assert_eq!(bus.read_history::<T2>().count(), 0);
+
+        // Frames without messages still advance the history
+        bus.clear();
+
+        let history: Vec<_> = bus.read_history::<T1>().map(|msg| msg.0).collect();
+        assert_eq!(history, vec![30, 31]);
+    }
+
+    #[test]
+    fn test_read_from() {
+        let source1 = SystemId::new::<u8>(0);
+        let source2 = SystemId::new::<u16>(1);
+
+        let mut central = Bus::new();
+        let mut bus1 = Bus::new();
+        let mut bus2 = Bus::new();
+
+        bus1.publish(T1(0));
+        bus1.publish(T1(1));
+        bus2.publish(T1(2));
+        bus2.publish(T2(3));
+
+        central.transfer_from(&mut bus1, source1);
+        central.transfer_from(&mut bus2, source2);
+
+        let msgs: Vec<_> = central.read_from::<T1>(source1).map(|msg| msg.0).collect();
+        assert_eq!(msgs, vec![0, 1]);
+
+        let msgs: Vec<_> = central.read_from::<T1>(source2).map(|msg| msg.0).collect();
+        assert_eq!(msgs, vec![2]);
+
+        assert_eq!(central.read_from::<T2>(source1).count(), 0);
+        assert_eq!(central.read_from::<T2>(source2).count(), 1);
+        assert_eq!(central.read::<T1>().len(), 3);
+
+        central.clear();
+
+        assert_eq!(central.read_from::<T1>(source1).count(), 0);
+    }
+
+    #[test]
+    fn test_message_group() {
+        let group = MessageGroup::find("bus_test_group").unwrap();
+
+        assert_eq!(TGroup1::get_group(), Some(group));
+        assert_eq!(TGroup2::get_group(), Some(group));
+        assert_eq!(T1::get_group(), None);
+        assert_eq!(group.name(), "bus_test_group");
+
+        let mut bus = Bus::new();
+
+        bus.publish(TGroup1(0));
+        bus.publish(T1(1));
+        bus.publish(TGroup2(2));
+
+        let topics: Vec<_> = bus.group_topics(group).collect();
+        assert_eq!(topics, vec![TGroup1::get_topic(), TGroup2::get_topic()]);
+
+        // Clearing the group leaves other topics intact
+        bus.clear_group(group);
+
+        assert_eq!(bus.group_topics(group).count(), 0);
+        assert_eq!(bus.read::<TGroup1>().len(), 0);
+        assert_eq!(bus.read::<TGroup2>().len(), 0);
+        assert_eq!(bus.read::<T1>().len(), 1);
+        assert_eq!(bus.activity, T1::get_topic().into());
+    }
 }
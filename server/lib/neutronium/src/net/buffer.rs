@@ -1,5 +1,6 @@
 use slice_deque::SliceDeque;
 use std::io;
+use std::mem;
 
 type ByteDeque = SliceDeque<u8>;
 
@@ -8,24 +9,100 @@ const BUF_SIZE_INCREMENT: usize = 65536;
 
 /// A dynamically sized and double ended and buffered FIFO byte queue. Data is appended at the
 /// head, and read from the tail.
+///
+/// Growable buffers allocate additional chunks of `BUF_SIZE_INCREMENT` bytes on demand, up to the
+/// maximum size. The data is always kept contiguous.
 pub struct Buffer {
     data: ByteDeque,
     size: usize,
+    max_size: usize,
 }
 
 impl Buffer {
     #[inline]
     pub fn new(size: usize) -> Buffer {
+        Self::growable(size, size)
+    }
+
+    /// Creates a buffer with an initial size of `size` that can grow up to `max_size`.
+    #[inline]
+    pub fn growable(size: usize, max_size: usize) -> Buffer {
+        Self::check_size(size);
+        Self::check_size(max_size);
+
+        if max_size < size {
+            panic!("Buffer max size {} is smaller than the initial size {}", max_size, size);
+        }
+
+        let mut data = ByteDeque::new();
+        data.reserve(size);
+        Buffer { data, size, max_size }
+    }
+
+    #[inline]
+    fn check_size(size: usize) {
         if size % BUF_SIZE_INCREMENT != 0 {
             panic!(
                 "Buffer size must be divisible by {}, got {}",
                 BUF_SIZE_INCREMENT, size
             );
         }
+    }
 
-        let mut data = ByteDeque::new();
-        data.reserve(size);
-        Buffer { data, size }
+    /// The current size of the buffer.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The size up to which the buffer is allowed to grow.
+    #[inline]
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Set the size up to which the buffer is allowed to grow. Buffers never shrink, so the
+    /// maximum size is clamped to the current size.
+    #[inline]
+    pub fn set_max_size(&mut self, max_size: usize) {
+        Self::check_size(max_size);
+        self.max_size = max_size.max(self.size);
+    }
+
+    /// Ensure that the buffer has at least `count` bytes of free capacity, growing it if
+    /// necessary. Returns false if the buffer can't grow enough to satisfy the request.
+    #[inline]
+    pub fn reserve(&mut self, count: usize) -> bool {
+        let required = self.data.len() + count;
+
+        if required <= self.size {
+            return true;
+        }
+
+        // Round up the required size to the next increment
+        let new_size = ((required + BUF_SIZE_INCREMENT - 1) / BUF_SIZE_INCREMENT) * BUF_SIZE_INCREMENT;
+
+        if new_size > self.max_size {
+            return false;
+        }
+
+        self.data.reserve(new_size - self.data.len());
+        self.size = new_size;
+        true
+    }
+
+    /// Shrink an empty buffer down to a single increment, it grows again on demand by `reserve`. Returns
+    /// the number of bytes released, zero if the buffer holds any data or is already at its minimum.
+    #[inline]
+    pub fn release(&mut self) -> usize {
+        if !self.data.is_empty() || self.size <= BUF_SIZE_INCREMENT {
+            return 0;
+        }
+
+        // Keep a single increment around, small writes such as keepalives don't reallocate
+        self.data = ByteDeque::new();
+        self.data.reserve(BUF_SIZE_INCREMENT);
+        mem::replace(&mut self.size, BUF_SIZE_INCREMENT) - BUF_SIZE_INCREMENT
     }
 
     /// The number of bytes in the buffer.
@@ -64,6 +141,19 @@ impl Buffer {
         self.data.as_slice()
     }
 
+    /// Mutable slice containing exactly the first `count` bytes of data, allowing frames to be
+    /// processed in place. The underlying deque is mirrored in virtual memory, so the slice is
+    /// contiguous even if the data wraps around the end of the allocation. Returns `None` if there
+    /// is not enough data in the buffer.
+    #[inline]
+    pub fn read_frame_slice(&mut self, count: usize) -> Option<&mut [u8]> {
+        if self.data.len() < count {
+            return None;
+        }
+
+        Some(&mut self.data.as_mut_slice()[..count])
+    }
+
     #[inline]
     pub fn clear(&mut self) {
         unsafe { self.data.move_head(self.len() as isize) };
@@ -98,7 +188,7 @@ impl Buffer {
     pub fn ingress<R: io::Read>(&mut self, mut reader: R) -> io::Result<usize> {
         let orig_capacity = self.free_capacity();
 
-        while self.data.len() < self.size {
+        while self.data.len() < self.size || self.reserve(BUF_SIZE_INCREMENT) {
             unsafe {
                 let read_count = reader.read(self.data.tail_head_slice()).or_else(|err| {
                     // Return zero read in case the operation would block but some data has already been read.
@@ -131,7 +221,9 @@ impl Buffer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use std::cmp::min;
+    use std::collections::VecDeque;
     use std::io::Cursor;
 
     struct MockChannel {
@@ -240,6 +332,85 @@ mod tests {
         assert_eq!(err.to_string(), "Buffer overrun")
     }
 
+    #[test]
+    fn test_ingress_growable() {
+        let mock_data: Vec<_> = (0..BUF_SIZE_INCREMENT * 2 + 100).map(|item| item as u8).collect();
+        let mut channel = MockChannel::new(mock_data.clone(), 5000, mock_data.len());
+
+        let mut buffer = Buffer::growable(BUF_SIZE_INCREMENT, BUF_SIZE_INCREMENT * 4);
+
+        let result = buffer.ingress(&mut channel);
+
+        assert_eq!(result.unwrap(), mock_data.len());
+        assert_eq!(buffer.size(), BUF_SIZE_INCREMENT * 3);
+        assert_eq!(buffer.read_slice(), &mock_data[..]);
+
+        channel.clear();
+        let count = buffer.egress(&mut channel).unwrap();
+
+        assert_eq!(count, mock_data.len());
+        assert!(buffer.is_empty());
+        assert_eq!(channel.data[..], mock_data[..]);
+    }
+
+    #[test]
+    fn test_ingress_growable_overrun() {
+        let mock_data: Vec<_> = (0..BUF_SIZE_INCREMENT * 3).map(|item| item as u8).collect();
+
+        let mut buffer = Buffer::growable(BUF_SIZE_INCREMENT, BUF_SIZE_INCREMENT * 2);
+
+        let result = buffer.ingress(&mock_data[..]);
+
+        assert_eq!(result.err().unwrap().to_string(), "Buffer overrun");
+        assert_eq!(buffer.size(), BUF_SIZE_INCREMENT * 2);
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut buffer = Buffer::growable(BUF_SIZE_INCREMENT, BUF_SIZE_INCREMENT * 2);
+
+        // Write past the initial capacity
+        let data: Vec<_> = (0..BUF_SIZE_INCREMENT + 10).map(|item| item as u8).collect();
+
+        assert!(buffer.reserve(data.len()));
+        buffer.write_slice()[..data.len()].copy_from_slice(&data);
+        buffer.move_tail(data.len());
+
+        assert_eq!(buffer.size(), BUF_SIZE_INCREMENT * 2);
+        assert_eq!(buffer.len(), data.len());
+        assert!(buffer.free_capacity() >= BUF_SIZE_INCREMENT * 2 - data.len());
+
+        // Consume part of the data and ensure the rest is intact
+        buffer.move_head(10);
+        assert_eq!(buffer.read_slice(), &data[10..]);
+
+        // The maximum size can't be exceeded
+        assert!(!buffer.reserve(BUF_SIZE_INCREMENT * 2));
+        assert_eq!(buffer.size(), BUF_SIZE_INCREMENT * 2);
+
+        // Non-growable buffers report back-pressure
+        let mut fixed = Buffer::new(BUF_SIZE_INCREMENT);
+        assert!(!fixed.reserve(BUF_SIZE_INCREMENT + 1));
+    }
+
+    #[test]
+    fn test_read_frame_slice() {
+        let mut buffer = Buffer::new(BUF_SIZE_INCREMENT);
+
+        buffer.write_slice()[..4].copy_from_slice(&[1, 2, 3, 4]);
+        buffer.move_tail(4);
+
+        assert!(buffer.read_frame_slice(5).is_none());
+
+        {
+            let frame = buffer.read_frame_slice(3).unwrap();
+            assert_eq!(frame, &[1, 2, 3]);
+            frame[0] = 10;
+        }
+
+        assert_eq!(buffer.read_slice(), &[10, 2, 3, 4]);
+    }
+
     #[test]
     fn test_no_err() {
         let mut cursor = Cursor::new(vec![1, 2, 3]);
@@ -263,4 +434,199 @@ mod tests {
     fn test_fail_on_incorrect_increment() {
         let _ = Buffer::new(100000);
     }
+
+    /// Operation applied to the buffer in the property tests. Sizes are clamped to what the buffer can
+    /// accommodate at the time the operation is applied.
+    #[derive(Debug, Clone)]
+    enum Op {
+        Ingress { len: usize, chunk: usize },
+        Egress { chunk: usize, max: usize },
+        Write(usize),
+        Consume(usize),
+        Reserve(usize),
+        Clear,
+    }
+
+    fn op_strategy() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0..3 * BUF_SIZE_INCREMENT, 1..BUF_SIZE_INCREMENT)
+                .prop_map(|(len, chunk)| Op::Ingress { len, chunk }),
+            (1..BUF_SIZE_INCREMENT, 0..2 * BUF_SIZE_INCREMENT)
+                .prop_map(|(chunk, max)| Op::Egress { chunk, max }),
+            (0..2 * BUF_SIZE_INCREMENT).prop_map(Op::Write),
+            (0..2 * BUF_SIZE_INCREMENT).prop_map(Op::Consume),
+            (0..3 * BUF_SIZE_INCREMENT).prop_map(Op::Reserve),
+            Just(Op::Clear),
+        ]
+    }
+
+    /// Generate the next `count` bytes of a sequence with a prime period, so that data read back at the
+    /// wrong offset is detected.
+    fn next_bytes(counter: &mut u64, count: usize) -> Vec<u8> {
+        (0..count)
+            .map(|_| {
+                *counter += 1;
+                (*counter % 251) as u8
+            })
+            .collect()
+    }
+
+    /// Apply the operations to a buffer growable up to the given number of increments, checking the
+    /// accounting and the contents against a reference model after each step. Sequences shrunk by
+    /// proptest can be replayed as regular tests.
+    fn run_ops(max_increments: usize, ops: &[Op]) -> Result<(), TestCaseError> {
+        let mut buffer = Buffer::growable(BUF_SIZE_INCREMENT, BUF_SIZE_INCREMENT * max_increments);
+        let mut model = VecDeque::new();
+        let mut counter = 0u64;
+
+        for op in ops {
+            match *op {
+                Op::Ingress { len, chunk } => {
+                    let data = next_bytes(&mut counter, len);
+                    let mut channel = MockChannel::new(data.clone(), chunk, len);
+
+                    let before = buffer.len();
+                    let result = buffer.ingress(&mut channel);
+                    let read = buffer.len() - before;
+
+                    prop_assert_eq!(read, channel.cursor);
+
+                    match result {
+                        Ok(count) => prop_assert_eq!(count, read),
+                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => prop_assert_eq!(read, 0),
+                        Err(err) => {
+                            prop_assert_eq!(err.to_string(), "Buffer overrun");
+                            prop_assert!(buffer.len() >= buffer.size());
+                            prop_assert!(buffer.len() + BUF_SIZE_INCREMENT > buffer.max_size());
+                        }
+                    }
+
+                    model.extend(&data[..read]);
+                }
+                Op::Egress { chunk, max } => {
+                    let mut channel = MockChannel::new(Vec::new(), chunk, max);
+
+                    let before = buffer.len();
+                    let result = buffer.egress(&mut channel);
+                    let written = before - buffer.len();
+
+                    match result {
+                        Ok(count) => {
+                            prop_assert_eq!(count, written);
+                            prop_assert!(buffer.is_empty());
+                        }
+                        Err(err) => prop_assert_eq!(err.kind(), io::ErrorKind::WouldBlock),
+                    }
+
+                    let expected: Vec<u8> = model.drain(..written).collect();
+                    prop_assert_eq!(channel.data, expected);
+                }
+                Op::Write(count) => {
+                    let count = count.min(buffer.free_capacity());
+                    let data = next_bytes(&mut counter, count);
+
+                    buffer.write_slice()[..count].copy_from_slice(&data);
+                    buffer.move_tail(count);
+
+                    model.extend(&data);
+                }
+                Op::Consume(count) => {
+                    let count = count.min(buffer.len());
+
+                    buffer.move_head(count);
+                    model.drain(..count);
+                }
+                Op::Reserve(count) => {
+                    let size = buffer.size();
+
+                    match buffer.reserve(count) {
+                        true => prop_assert!(buffer.free_capacity() >= count),
+                        _ => {
+                            prop_assert_eq!(buffer.size(), size);
+                            prop_assert!(buffer.len() + count > buffer.max_size());
+                        }
+                    }
+                }
+                Op::Clear => {
+                    buffer.clear();
+                    model.clear();
+                }
+            }
+
+            // Accounting
+            prop_assert_eq!(buffer.size() % BUF_SIZE_INCREMENT, 0);
+            prop_assert!(buffer.size() <= buffer.max_size());
+            prop_assert!(buffer.len() + buffer.free_capacity() >= buffer.size());
+            prop_assert_eq!(buffer.read_slice().len(), buffer.len());
+            prop_assert_eq!(buffer.write_slice().len(), buffer.free_capacity());
+            prop_assert_eq!(buffer.is_empty(), buffer.len() == 0);
+
+            // The head never passes the tail and the data reads back intact
+            let len = buffer.len();
+            prop_assert!(buffer.read_frame_slice(len).is_some());
+            prop_assert!(buffer.read_frame_slice(len + 1).is_none());
+            prop_assert!(buffer.read_slice().iter().eq(model.iter()));
+        }
+
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_invariants(max_increments in 1usize..5, ops in prop::collection::vec(op_strategy(), 0..64)) {
+            run_ops(max_increments, &ops)?;
+        }
+    }
+
+    #[test]
+    fn test_ops_replay() {
+        // Fill the buffer, then refill it across the wrap-around point of the underlying allocation
+        let ops = [
+            Op::Write(BUF_SIZE_INCREMENT),
+            Op::Consume(100),
+            Op::Ingress { len: 200, chunk: 7 },
+            Op::Egress {
+                chunk: 1000,
+                max: BUF_SIZE_INCREMENT,
+            },
+            Op::Write(BUF_SIZE_INCREMENT),
+            Op::Reserve(BUF_SIZE_INCREMENT),
+            Op::Ingress {
+                len: 2 * BUF_SIZE_INCREMENT,
+                chunk: 5000,
+            },
+            Op::Clear,
+        ];
+
+        run_ops(2, &ops).unwrap();
+    }
+
+    #[test]
+    fn test_release() {
+        let mut buffer = Buffer::growable(BUF_SIZE_INCREMENT * 2, BUF_SIZE_INCREMENT * 3);
+
+        buffer.write_slice()[..10].copy_from_slice(&[1; 10]);
+        buffer.move_tail(10);
+
+        // Buffers holding data are retained
+        assert_eq!(buffer.release(), 0);
+        assert_eq!(buffer.size(), BUF_SIZE_INCREMENT * 2);
+
+        buffer.move_head(10);
+        assert_eq!(buffer.release(), BUF_SIZE_INCREMENT);
+        assert_eq!(buffer.size(), BUF_SIZE_INCREMENT);
+        assert!(buffer.free_capacity() >= BUF_SIZE_INCREMENT);
+
+        // Buffers at a single increment are left alone
+        assert_eq!(buffer.release(), 0);
+        assert_eq!(buffer.size(), BUF_SIZE_INCREMENT);
+
+        // The capacity grows again on demand
+        assert!(buffer.reserve(BUF_SIZE_INCREMENT + 10));
+        assert_eq!(buffer.size(), BUF_SIZE_INCREMENT * 2);
+        assert!(buffer.free_capacity() >= BUF_SIZE_INCREMENT + 10);
+        assert_eq!(buffer.max_size(), BUF_SIZE_INCREMENT * 3);
+    }
 }
@@ -1,17 +1,23 @@
 use crate::net::buffer::Buffer;
-use crate::net::frame::{Category, ControlFrame, Frame, PayloadInfo};
-use crate::net::support::{Deserialize, ErrorType, NetworkError, NetworkResult, PayloadBatch, Serialize};
+use crate::net::frame::{Category, ControlFrame, Frame, Header, PayloadInfo, ResumeToken, RESUME_TOKEN_SIZE};
+use crate::net::support::{
+    Deserialize, ErrorType, NetworkError, NetworkResult, PayloadBatch, PayloadReader, Serialize,
+};
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use flux::crypto;
+use flux::crypto::CipherSuite;
 use flux::logging;
 use flux::session::server::SessionKey;
 use flux::session::user::PrivateData;
-use flux::time::timestamp_secs;
-use flux::UserId;
+use flux::time::{timestamp_millis, Clock, SystemClock};
+use flux::{Roles, UserId};
 use mio::net::TcpStream;
+use std::collections::VecDeque;
 use std::io;
 use std::io::{Cursor, Read, Write};
+use std::mem;
 use std::net::Shutdown;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 // Write buffer should be 512k
@@ -19,20 +25,74 @@ const WRITE_BUF_SIZE: usize = 8 * 65536;
 const READ_BUF_SIZE: usize = 65536;
 // Use the write buffer as it is bigger
 const PAYLOAD_BUF_SIZE: usize = WRITE_BUF_SIZE;
+// Free capacity a growable write buffer attempts to maintain when writing payloads
+const WRITE_BUF_RESERVE: usize = 65536;
+// Write buffer capacity held back from payloads so that control frames can go out under back-pressure
+const CONTROL_RESERVE: usize = 1024;
 
-const HEADER_SIZE: usize = 11;
+// Category + Sequence + Payload Size + Checksum
+const HEADER_SIZE: usize = Header::SIZE;
 const OVERHEAD_SIZE: usize = HEADER_SIZE + crypto::MAC_SIZE;
 
-const fn max_plain_payload_size(capacity: usize) -> usize {
-    capacity - OVERHEAD_SIZE
+// Session key derivation
+const SESSION_SALT_SIZE: usize = 2 + 16 + 8;
+const CLIENT2SERVER_INFO: &[u8] = b"bushhammer client2server";
+const SERVER2CLIENT_INFO: &[u8] = b"bushhammer server2client";
+
+// The handshake is the connection token followed by a resume token, all zeros for a fresh session
+const HANDSHAKE_SIZE: usize = ConnectionToken::SIZE + RESUME_TOKEN_SIZE;
+
+/// Largest plaintext payload fitting in the given capacity after the header and mac. Capacities that
+/// can't accommodate the overhead have no room for a payload.
+#[inline]
+fn max_plain_payload_size(capacity: usize) -> usize {
+    match capacity > OVERHEAD_SIZE {
+        true => capacity - OVERHEAD_SIZE,
+        _ => 0,
+    }
 }
 
 pub type ChannelId = usize;
 
+/// Priority of the payload data written to a channel. Control frames take precedence over payloads of
+/// either priority. Under back-pressure bulk payloads are shed first, leaving part of the write buffer
+/// free for high priority payloads and control frames.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Priority {
+    High,
+    Bulk,
+}
+
+impl Priority {
+    /// Write buffer capacity payloads of this priority must leave free, given the maximum size of the
+    /// write buffer.
+    #[inline]
+    fn headroom(self, max_size: usize) -> usize {
+        match self {
+            Priority::High => CONTROL_RESERVE,
+            Priority::Bulk => max_size / 4,
+        }
+    }
+}
+
+/// Progress of reading the next frame from the read buffer, telling what a `Wait` returned by a read
+/// is waiting on.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReadState {
+    /// Not waiting on any data.
+    Idle,
+    /// The header is incomplete, the given number of bytes are missing.
+    Header(usize),
+    /// The header is complete, the given number of body bytes are missing.
+    Body(usize),
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ChannelState {
     Handshake(Instant),
     Connected(UserId),
+    /// The client dropped, the unsent frames are retained until the session is resumed or closed.
+    Suspended(UserId),
     Disconnected,
 }
 
@@ -48,6 +108,7 @@ pub struct Channel {
     // Validation
     version: [u8; 16],
     protocol: u16,
+    suite: CipherSuite,
 
     // Sequence of packets received from the client
     client_sequence: u64,
@@ -57,34 +118,71 @@ pub struct Channel {
     // Communication Timestamps
     last_egress: Instant,
     last_ingress: Instant,
+    // Bytes received since the last progress check
+    ingress_progress: usize,
 
     // Client2Server Key
     server_key: [u8; crypto::KEY_SIZE],
     // Server2Client Key
     client_key: [u8; crypto::KEY_SIZE],
+    // Client2Server Key awaiting the acknowledgement of a key rotation
+    pending_server_key: Option<[u8; crypto::KEY_SIZE]>,
+    // Server2Client Key taking effect once the acknowledgement of a key rotation received from the other
+    // side has been written
+    pending_client_key: Option<[u8; crypto::KEY_SIZE]>,
+
+    // Last server sequence the client has acknowledged as processed
+    acked_sequence: Option<u64>,
+
+    // Token the client can use to resume the session after a drop
+    resume_token: ResumeToken,
 
     // Channel Buffers
     read_buffer: Buffer,
     write_buffer: Buffer,
+    // Sizes of the frames in the write buffer, the first one may have been sent in part
+    egress_frames: VecDeque<usize>,
+    // Total size of the frames in the write buffer, including the parts already sent
+    egress_queued: usize,
+    // Size of the last frame read, it is consumed from the read buffer on the next read
+    read_pending: usize,
+    // Data the last read was waiting on
+    read_state: ReadState,
 
     // Payload buffer
     payload: Box<[u8; PAYLOAD_BUF_SIZE]>,
 
-    // Log
+    // Source of time
+    clock: Arc<Clock>,
+
+    // Log, scoped with the connection context
     log: logging::Logger,
+    // Log the scoped log is derived from
+    base_log: logging::Logger,
 }
 
 impl Channel {
-    /// Initializes a new channel with the supplied TcpStream, version and protocol.
+    /// Initializes a new channel with the supplied version and protocol.
     #[inline]
     pub fn new<'a, L: Into<Option<&'a logging::Logger>>>(
         version: [u8; 16],
         protocol: u16,
         log: L,
     ) -> Channel {
-        let now = Instant::now();
+        Self::with_clock(version, protocol, Arc::new(SystemClock), log)
+    }
+
+    /// Initializes a new channel with the supplied version, protocol and source of time.
+    #[inline]
+    pub fn with_clock<'a, L: Into<Option<&'a logging::Logger>>>(
+        version: [u8; 16],
+        protocol: u16,
+        clock: Arc<Clock>,
+        log: L,
+    ) -> Channel {
+        let now = clock.now();
 
-        let channel_log = match log.into() {
+        let base_log = match log.into() {
             Some(log) => log.new(logging::o!()),
             _ => logging::Logger::root(logging::Discard, logging::o!()),
         };
@@ -95,16 +193,28 @@ impl Channel {
             state: ChannelState::Disconnected,
             version,
             protocol,
+            suite: CipherSuite::default(),
             client_sequence: 0,
             server_sequence: 0,
             last_egress: now,
             last_ingress: now,
+            ingress_progress: 0,
             server_key: Self::random_key(),
             client_key: Self::random_key(),
+            pending_server_key: None,
+            pending_client_key: None,
+            acked_sequence: None,
+            resume_token: [0u8; RESUME_TOKEN_SIZE],
             read_buffer: Buffer::new(READ_BUF_SIZE),
             write_buffer: Buffer::new(WRITE_BUF_SIZE),
+            egress_frames: VecDeque::new(),
+            egress_queued: 0,
+            read_pending: 0,
+            read_state: ReadState::Idle,
             payload: Box::new([0; PAYLOAD_BUF_SIZE]),
-            log: channel_log,
+            clock,
+            log: base_log.new(logging::o!()),
+            base_log,
         }
     }
 
@@ -119,8 +229,11 @@ impl Channel {
         self.id = Some(id);
         self.state = ChannelState::Handshake(now);
         self.stream = Some(stream);
+        self.scope_log();
 
-        logging::debug!(self.log, "channel opened"; "context" => "open", "channel_id" => self.id);
+        logging::debug!(self.log, "channel opened";
+                        "context" => "open",
+                        "timestamp_ms" => timestamp_millis());
     }
 
     /// Closes the channel, the underlying stream and clears out all private data.
@@ -128,7 +241,6 @@ impl Channel {
     pub fn close(&mut self, notify: bool) {
         logging::debug!(self.log, "closing channel";
                         "context" => "close",
-                        "channel_id" => self.id,
                         "client_sequence" => self.client_sequence,
                         "server_sequence" => self.server_sequence,
                         "last_egress" => ?self.last_egress,
@@ -139,7 +251,7 @@ impl Channel {
         if notify {
             // Attempt to send a disconnection notice, but ignore any failures
             if let ChannelState::Connected(user_id) = self.state {
-                logging::debug!(self.log, "notifying client"; "context" => "close", "channel_id" => self.id);
+                logging::debug!(self.log, "notifying client"; "context" => "close");
                 drop(self.write_control(ControlFrame::ConnectionClosed(user_id)));
                 drop(self.send_raw());
             }
@@ -149,6 +261,11 @@ impl Channel {
         // corrupted otherwise.
         self.read_buffer.clear();
         self.write_buffer.clear();
+        self.egress_frames.clear();
+        self.egress_queued = 0;
+        self.read_pending = 0;
+        self.read_state = ReadState::Idle;
+        self.ingress_progress = 0;
         self.id = None;
 
         self.state = ChannelState::Disconnected;
@@ -158,14 +275,160 @@ impl Channel {
 
         self.server_key = Self::random_key();
         self.client_key = Self::random_key();
+        self.pending_server_key = None;
+        self.pending_client_key = None;
+        self.acked_sequence = None;
+        self.resume_token = [0u8; RESUME_TOKEN_SIZE];
+
+        // The stream of suspended channels has already been shut down
+        if let Some(stream) = self.stream.take() {
+            stream.shutdown(Shutdown::Both).unwrap_or_else(|err| panic!(err));
+        }
+
+        logging::debug!(self.log, "channel closed";
+                        "context" => "close",
+                        "timestamp_ms" => timestamp_millis());
+
+        self.scope_log();
+    }
+
+    /// Suspends the channel of a dropped client. The stream is shut down and the read side cleared, but the
+    /// frames still waiting in the write buffer are retained so that they can be carried over to the
+    /// connection resuming the session, see `take_over`. The unsent remainder of a partially sent frame is
+    /// dropped. With a key rotation in flight all the frames are dropped, as some of them are encrypted with
+    /// a key that has since been replaced.
+    pub fn suspend(&mut self) {
+        let user_id = match self.state {
+            ChannelState::Connected(user_id) => user_id,
+            state => panic!("Attempted to suspend a channel in state {:?}", state),
+        };
+
+        self.settle_egress();
+
+        match self.pending_server_key {
+            Some(_) => {
+                self.write_buffer.clear();
+                self.egress_frames.clear();
+                self.egress_queued = 0;
+            }
+            _ => {
+                let sent = self.egress_queued.saturating_sub(self.write_buffer.len());
+
+                if sent > 0 {
+                    let size = self.egress_frames.pop_front().expect("Partially sent frame must be queued");
+                    self.write_buffer.move_head(size - sent);
+                    self.egress_queued -= size;
+                }
+            }
+        }
+
+        logging::debug!(self.log, "suspending channel";
+                        "context" => "suspend",
+                        "server_sequence" => self.server_sequence,
+                        "frame_count" => self.egress_frames.len(),
+                        "write_size" => self.write_buffer.len());
+
+        self.read_buffer.clear();
+        self.read_pending = 0;
+        self.read_state = ReadState::Idle;
+        self.ingress_progress = 0;
+        self.state = ChannelState::Suspended(user_id);
 
         self.stream
             .take()
             .expect("Channel must have valid stream")
             .shutdown(Shutdown::Both)
             .unwrap_or_else(|err| panic!(err));
+    }
+
+    /// Takes over the session of a suspended channel. The payload frames retained by the suspended channel
+    /// are decrypted with its keys and written anew on this channel, control frames only concern the old
+    /// connection and are dropped. Frames that don't fit the write buffer are dropped as well. The suspended
+    /// channel is closed. Returns the number of frames carried over.
+    pub fn take_over(&mut self, suspended: &mut Channel) -> usize {
+        let mut carried = 0;
+
+        while let Some(size) = suspended.egress_frames.pop_front() {
+            let plain_size = {
+                let frame = &suspended.write_buffer.read_slice()[..size];
+                let header = match Header::read(frame) {
+                    Ok(header) => header,
+                    _ => break,
+                };
+
+                if header.category != Category::Payload as u8 {
+                    suspended.write_buffer.move_head(size);
+                    continue;
+                }
+
+                let plain_size = header.payload_size as usize - suspended.suite.mac_size();
+
+                if !crypto::decrypt(
+                    suspended.suite,
+                    &mut self.payload[..plain_size],
+                    &frame[HEADER_SIZE..],
+                    &suspended.additional_data(header.category),
+                    header.sequence,
+                    &suspended.client_key,
+                ) {
+                    break;
+                }
+
+                plain_size
+            };
+
+            suspended.write_buffer.move_head(size);
+
+            if self.write(plain_size, Category::Payload).is_err() {
+                break;
+            }
+
+            carried += 1;
+        }
+
+        logging::debug!(self.log, "took over suspended session";
+                        "context" => "take_over",
+                        "frame_count" => carried,
+                        "dropped_count" => suspended.egress_frames.len());
+
+        suspended.close(false);
+        carried
+    }
+
+    /// Assigns a new id to an open channel. Used when a resumed session is moved to the channel id
+    /// of the original session.
+    #[inline]
+    pub fn rebind(&mut self, id: ChannelId) {
+        if self.state == ChannelState::Disconnected {
+            panic!("Attempted to rebind a disconnected channel");
+        }
+
+        logging::debug!(self.log, "channel rebound";
+                        "context" => "rebind",
+                        "new_channel_id" => id);
+
+        self.id = Some(id);
+        self.scope_log();
+    }
+
+    /// Generates a fresh resume token for the channel. The previous token is invalidated.
+    #[inline]
+    pub fn issue_resume_token(&mut self) -> ResumeToken {
+        crypto::random_bytes(&mut self.resume_token);
+        self.resume_token
+    }
+
+    /// Get the last resume token issued on the channel.
+    #[inline]
+    pub fn resume_token(&self) -> ResumeToken {
+        self.resume_token
+    }
 
-        logging::debug!(self.log, "channel closed"; "context" => "close", "channel_id" => self.id);
+    /// Get the last server sequence the client has acknowledged as processed. Sequences restart after
+    /// a key rotation, as does the acknowledgement.
+    #[inline]
+    pub fn acked_sequence(&self) -> Option<u64> {
+        self.acked_sequence
     }
 
     /// Returns the time elapsed since the last egress.
@@ -180,12 +443,72 @@ impl Channel {
         now.duration_since(self.last_ingress)
     }
 
+    /// Returns the number of bytes received since the last progress check.
+    #[inline]
+    pub fn ingress_progress(&self) -> usize {
+        self.ingress_progress
+    }
+
+    /// Returns the number of bytes received since the last progress check and starts a new check.
+    #[inline]
+    pub fn take_ingress_progress(&mut self) -> usize {
+        mem::replace(&mut self.ingress_progress, 0)
+    }
+
+    /// Set the cipher suite used for encrypting the channel traffic. Connection tokens negotiating a
+    /// different suite are rejected. Panics if the suite is not available on the current hardware.
+    #[inline]
+    pub fn set_cipher_suite(&mut self, suite: CipherSuite) {
+        if !suite.is_available() {
+            panic!("Cipher suite {:?} is not available on this hardware", suite)
+        }
+
+        self.suite = suite;
+    }
+
+    /// Allow the write buffer to grow up to the supplied size before reporting back-pressure.
+    #[inline]
+    pub fn set_write_buffer_limit(&mut self, max_size: usize) {
+        self.write_buffer.set_max_size(max_size);
+    }
+
+    /// Get the data the last read was waiting on, `ReadState::Idle` unless it returned `Wait`.
+    #[inline]
+    pub fn read_state(&self) -> ReadState {
+        self.read_state
+    }
+
+    /// Shrink the empty read and write buffers down to a single increment, they grow again on demand once
+    /// there is traffic on the channel. Returns the number of bytes released.
+    #[inline]
+    pub fn compact(&mut self) -> usize {
+        self.read_buffer.release() + self.write_buffer.release()
+    }
+
+    /// Number of bytes allocated for the read and write buffers.
+    #[inline]
+    pub fn buffer_capacity(&self) -> usize {
+        self.read_buffer.size() + self.write_buffer.size()
+    }
+
     /// Returns true if there is outgoing data on the channel.
     #[inline]
     pub fn has_egress(&self) -> bool {
         !self.write_buffer.is_empty()
     }
 
+    /// Derive the channel log from the base log with the current connection context. Every subsequent
+    /// line logged on the channel is tagged with the channel id and, once connected, the user id.
+    fn scope_log(&mut self) {
+        self.log = match (self.id, self.state) {
+            (Some(channel_id), ChannelState::Connected(user_id)) => self
+                .base_log
+                .new(logging::o!("channel_id" => channel_id, "user_id" => user_id)),
+            (Some(channel_id), _) => self.base_log.new(logging::o!("channel_id" => channel_id)),
+            _ => self.base_log.new(logging::o!()),
+        };
+    }
+
     /// Get the channel state.
     #[inline]
     pub fn get_state(&self) -> ChannelState {
@@ -217,8 +540,7 @@ impl Channel {
     #[inline]
     pub fn deregister(&self, poll: &mio::Poll) -> NetworkResult<()> {
         logging::trace!(self.log, "deregistering channel on poll";
-                        "context" => "deregister",
-                        "channel_id" => self.id);
+                        "context" => "deregister");
 
         let result = poll.deregister(
             self.stream
@@ -229,7 +551,6 @@ impl Channel {
 
         logging::debug!(self.log, "channel deregistered";
                         "context" => "deregister",
-                        "channel_id" => self.id,
                         "result" => ?result);
 
         result
@@ -239,7 +560,7 @@ impl Channel {
     /// transmitted.
     #[inline]
     pub fn receive(&mut self, now: Instant) -> NetworkResult<usize> {
-        logging::trace!(self.log, "receiving data from network"; "context" => "receive", "channel_id" => self.id);
+        logging::trace!(self.log, "receiving data from network"; "context" => "receive");
 
         let stream = &mut self.stream.as_ref().expect("Channel must have valid stream");
 
@@ -247,11 +568,11 @@ impl Channel {
 
         if received > 0 {
             self.last_ingress = now;
+            self.ingress_progress += received;
         }
 
         logging::debug!(self.log, "received data from network";
                         "context" => "receive",
-                        "channel_id" => self.id,
                         "bytes" => received);
 
         Ok(received)
@@ -261,13 +582,16 @@ impl Channel {
     /// transmitted.
     #[inline]
     pub fn send(&mut self, now: Instant) -> NetworkResult<usize> {
-        logging::trace!(self.log, "sending data on the network"; "context" => "send", "channel_id" => self.id);
+        logging::trace!(self.log, "sending data on the network"; "context" => "send");
 
         if self.write_buffer.is_empty() {
             return Ok(0);
         }
 
-        let sent = Self::fold_result(self.send_raw())?;
+        let result = self.send_raw();
+        self.settle_egress();
+
+        let sent = Self::fold_result(result)?;
 
         if sent > 0 {
             self.last_egress = now;
@@ -275,7 +599,6 @@ impl Channel {
 
         logging::debug!(self.log, "sent data on the network";
                         "context" => "send",
-                        "channel_id" => self.id,
                         "bytes" => sent);
 
         Ok(sent)
@@ -288,21 +611,56 @@ impl Channel {
         self.write_buffer.egress(stream)
     }
 
-    /// Constructs the array holding additional data
+    /// Forget the frames that have been sent in full.
+    #[inline]
+    fn settle_egress(&mut self) {
+        let mut sent = self.egress_queued.saturating_sub(self.write_buffer.len());
+
+        while let Some(&size) = self.egress_frames.front() {
+            if size > sent {
+                break;
+            }
+
+            sent -= size;
+            self.egress_queued -= size;
+            self.egress_frames.pop_front();
+        }
+    }
+
+    /// Constructs the array holding additional data: version, protocol (little endian), cipher suite
+    /// and category.
     #[inline]
-    fn additional_data(&self, category: u8) -> [u8; 19] {
-        let mut additional_data = [0u8; 19];
+    fn additional_data(&self, category: u8) -> [u8; 20] {
+        let mut additional_data = [0u8; 20];
         {
             let mut buf = &mut additional_data[..];
             buf.write_all(&self.version[..]).expect("Error writing version");
             buf.write_u16::<LittleEndian>(self.protocol)
                 .expect("Error writing protocol");
+            buf.write_u8(self.suite.id()).expect("Error writing cipher suite");
             buf.write_u8(category).expect("Error writing payload category");
         }
 
         additional_data
     }
 
+    /// Derives the session keys from the keys in the connection token. The keys are salted with the
+    /// protocol, version and token sequence so that the token key material is never used verbatim.
+    #[inline]
+    fn derive_session_keys(&mut self, token: &ConnectionToken) {
+        let mut salt = [0u8; SESSION_SALT_SIZE];
+
+        {
+            let mut stream = &mut salt[..];
+            stream.write_u16::<BigEndian>(token.protocol).expect("Error writing session salt");
+            stream.write_all(&token.version).expect("Error writing session salt");
+            stream.write_u64::<BigEndian>(token.sequence).expect("Error writing session salt");
+        }
+
+        self.server_key = crypto::derive_key(&token.data.server_key, &salt, CLIENT2SERVER_INFO);
+        self.client_key = crypto::derive_key(&token.data.client_key, &salt, SERVER2CLIENT_INFO);
+    }
+
     /// Generates a random key. Used for the initial setup.
     #[inline]
     fn random_key() -> [u8; crypto::KEY_SIZE] {
@@ -325,16 +683,119 @@ impl Channel {
 }
 
 impl Channel {
+    /// Initiate a key rotation. The new keys are sent to the client encrypted under the current keys,
+    /// after which all outgoing frames use the new Server2Client key. Incoming frames are decrypted
+    /// with the current Client2Server key until the client acknowledges the rotation, as frames sent
+    /// by the client before it received the new keys are still encrypted with the old key.
+    pub fn rotate_keys(&mut self) -> NetworkResult<()> {
+        // Only a single rotation can be in flight at a time
+        if self.pending_server_key.is_some() {
+            return Err(NetworkError::Wait);
+        }
+
+        let new_server_key = Self::random_key();
+        let new_client_key = Self::random_key();
+
+        self.write_control(ControlFrame::KeyRotate {
+            new_server_key,
+            new_client_key,
+        })?;
+
+        logging::debug!(self.log, "initiated key rotation";
+                        "context" => "rotate_keys");
+
+        self.client_key = new_client_key;
+        self.server_sequence = 0;
+        self.acked_sequence = None;
+        self.pending_server_key = Some(new_server_key);
+
+        Ok(())
+    }
+
+    /// Apply control frames affecting the channel state received from the other side.
+    fn process_control(&mut self, frame: &ControlFrame) -> NetworkResult<()> {
+        match frame {
+            ControlFrame::KeyRotate {
+                new_server_key,
+                new_client_key,
+            } => {
+                // The sides are mirrored. The other side sends everything after the rotation with the new
+                // Client2Server key, the incoming direction switches right away.
+                self.server_key = *new_client_key;
+                self.client_sequence = 0;
+
+                // The acknowledgement is the last frame sent with the current outgoing key. If the write
+                // buffer is full, it goes out ahead of the next frame written instead.
+                self.pending_client_key = Some(*new_server_key);
+                match self.write_rotation_ack() {
+                    Err(NetworkError::Wait) => {
+                        logging::debug!(self.log, "deferred key rotation acknowledgement";
+                                        "context" => "process_control");
+                    }
+                    result => result?,
+                }
+
+                logging::debug!(self.log, "applied key rotation";
+                                "context" => "process_control");
+            }
+            ControlFrame::KeyRotateAck => match self.pending_server_key.take() {
+                Some(key) => {
+                    self.server_key = key;
+                    self.client_sequence = 0;
+
+                    logging::debug!(self.log, "key rotation acknowledged";
+                                    "context" => "process_control");
+                }
+                _ => return Err(NetworkError::Fatal(ErrorType::KeyRotation)),
+            },
+            // Acknowledgements of frames that have not been sent or predate the last acknowledgement
+            // are stale, e.g. sent before a key rotation, and are ignored.
+            &ControlFrame::Ack(sequence) => {
+                if sequence < self.server_sequence && self.acked_sequence.map_or(true, |acked| sequence > acked) {
+                    self.acked_sequence = Some(sequence);
+                }
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Write the pending acknowledgement of a key rotation and switch the outgoing direction over to the
+    /// new key. Every frame written afterwards is encrypted with the new key.
+    fn write_rotation_ack(&mut self) -> NetworkResult<()> {
+        if let Some(key) = self.pending_client_key {
+            self.write_control_frame(ControlFrame::KeyRotateAck)?;
+
+            self.pending_client_key = None;
+            self.client_key = key;
+            self.server_sequence = 0;
+            self.acked_sequence = None;
+        }
+
+        Ok(())
+    }
+
     /// Write control data to the channel.
     pub fn write_control(&mut self, frame: ControlFrame) -> NetworkResult<()> {
+        self.write_rotation_ack()?;
+        self.write_control_frame(frame)
+    }
+
+    #[inline]
+    fn write_control_frame(&mut self, frame: ControlFrame) -> NetworkResult<()> {
         // Bail out if there isn't enough capacity to write the data
-        if self.write_buffer.free_capacity() <= OVERHEAD_SIZE {
+        if !self.write_buffer.reserve(OVERHEAD_SIZE + 1) {
             return Err(NetworkError::Wait);
         }
 
         // Restrict payload size to account for header and mac
         let plain_payload_size = max_plain_payload_size(self.payload.len());
 
+        if plain_payload_size == 0 {
+            return Err(NetworkError::Wait);
+        }
+
         let payload_slice = &mut self.payload[..plain_payload_size];
 
         let mut cursor = Cursor::new(payload_slice);
@@ -346,61 +807,74 @@ impl Channel {
         self.write(payload_size, category)
     }
 
-    /// Write payload data to the channel from a batch buffer.
-    pub fn write_payload<P: Serialize>(&mut self, batch: &mut PayloadBatch<P>) -> NetworkResult<()> {
-        // Bail out if there isn't enough capacity to write the data
-        if self.write_buffer.free_capacity() <= OVERHEAD_SIZE {
+    /// Write payload data to the channel from a batch buffer. Returns `NetworkError::Wait` if the write
+    /// buffer doesn't have enough capacity left for the given priority, the unwritten data is retained
+    /// in the batch. On success, returns the number of messages written and the number left over.
+    pub fn write_payload<P: Serialize>(
+        &mut self,
+        batch: &mut PayloadBatch<P>,
+        priority: Priority,
+    ) -> NetworkResult<(usize, usize)> {
+        self.write_rotation_ack()?;
+
+        let headroom = priority.headroom(self.write_buffer.max_size());
+
+        // Attempt to grow the buffer if it is running low on capacity and bail out if there isn't
+        // enough capacity to write the data without eating into the headroom
+        if !self.write_buffer.reserve(WRITE_BUF_RESERVE.max(headroom + OVERHEAD_SIZE + 1))
+            && self.write_buffer.free_capacity() <= headroom + OVERHEAD_SIZE
+        {
             return Err(NetworkError::Wait);
         }
 
-        // Restrict payload size to account for header and mac
-        let plain_payload_size = max_plain_payload_size(self.write_buffer.free_capacity());
+        // Restrict payload size to account for header, mac and headroom
+        let available = self.write_buffer.free_capacity() - headroom;
+        let plain_payload_size = max_plain_payload_size(available).min(self.payload.len());
+
+        if plain_payload_size == 0 {
+            return Err(NetworkError::Wait);
+        }
 
         let payload_slice = &mut self.payload[..plain_payload_size];
 
         let mut cursor = Cursor::new(payload_slice);
-        batch.write(&mut cursor)?;
+        let counts = batch.drain_to_capacity(&mut cursor)?;
         let payload_size = cursor.position() as usize;
 
-        self.write(payload_size, Category::Payload)
+        self.write(payload_size, Category::Payload)?;
+        Ok(counts)
     }
 
     /// Write the current payload into the buffer
     fn write(&mut self, payload_size: usize, category: Category) -> NetworkResult<()> {
-        let encrypted_size = payload_size + crypto::MAC_SIZE;
+        let encrypted_size = payload_size + self.suite.mac_size();
         let total_size = encrypted_size + HEADER_SIZE;
 
         logging::trace!(self.log, "writing message to output buffer";
                         "context" => "write",
-                        "channel_id" => self.id,
                         "server_sequence" => self.server_sequence,
                         "write_buffer_capacity" => ?self.write_buffer.free_capacity(),
                         "plaintext_size" => ?payload_size,
                         "encrypted_size" => ?encrypted_size,
                         "total_size" => ?total_size);
 
-        if total_size > self.write_buffer.free_capacity() {
+        if !self.write_buffer.reserve(total_size) {
             return Err(NetworkError::Wait);
         }
 
         let category_num = category as u8;
 
         let additional_data = self.additional_data(category_num);
-        let mut stream = self.write_buffer.write_slice();
-
-        // Write header
-        stream.write_u8(category_num)?;
-        stream.write_u64::<BigEndian>(self.server_sequence)?;
-        stream.write_u16::<BigEndian>(encrypted_size as u16)?;
+        let frame = &mut self.write_buffer.write_slice()[..total_size];
 
         logging::trace!(self.log, "encrypting message";
                         "context" => "write",
-                        "channel_id" => self.id,
                         "server_sequence" => self.server_sequence);
 
         // Write payload
         if !crypto::encrypt(
-            &mut stream[..encrypted_size],
+            self.suite,
+            &mut frame[HEADER_SIZE..],
             &self.payload[..payload_size],
             &additional_data,
             self.server_sequence,
@@ -409,11 +883,22 @@ impl Channel {
             return Err(NetworkError::Fatal(ErrorType::Crypto));
         }
 
+        // Write header
+        let mut header = Header {
+            category: category_num,
+            sequence: self.server_sequence,
+            payload_size: encrypted_size as u16,
+            checksum: 0,
+        };
+        header.checksum = header.compute_checksum(&frame[HEADER_SIZE..]);
+        header.write(&mut &mut frame[..HEADER_SIZE])?;
+
         self.write_buffer.move_tail(total_size);
+        self.egress_frames.push_back(total_size);
+        self.egress_queued += total_size;
 
         logging::trace!(self.log, "message written to output buffer";
                         "context" => "write",
-                        "channel_id" => self.id,
                         "server_sequence" => self.server_sequence);
 
         self.server_sequence += 1;
@@ -433,13 +918,16 @@ impl Channel {
     #[inline]
     pub fn read(&mut self) -> NetworkResult<Frame> {
         let (size, category) = self.read_unpack()?;
-        let result = Frame::read(&self.payload[..size], category);
+        let result = Frame::read(&self.frame_payload()[..size], category);
 
         logging::trace!(self.log, "read in control frame";
                         "context" => "read",
-                        "channel_id" => self.id,
                         "result" => ?result);
 
+        if let Ok(Frame::Control(ref frame)) = result {
+            self.process_control(frame)?;
+        }
+
         result
     }
 
@@ -452,49 +940,70 @@ impl Channel {
         batch: &mut PayloadBatch<P>,
         pinfo: PayloadInfo,
     ) -> NetworkResult<()> {
-        let mut cursor = Cursor::new(pinfo.select(&*self.payload));
+        let mut cursor = Cursor::new(pinfo.select(self.frame_payload())?);
 
         logging::trace!(self.log, "reading payload frame";
-                        "context" => "read_payload",
-                        "channel_id" => self.id);
+                        "context" => "read_payload");
 
         let result = batch.read(&mut cursor);
 
         logging::trace!(self.log, "read in payload frame";
                         "context" => "read",
-                        "channel_id" => self.id,
                         "result" => ?result);
 
         result
     }
 
-    /// Read and unpack the data from the read buffer into the payload buffer.
+    /// Reader yielding the messages of the payload one by one, without buffering them up front.
+    #[inline]
+    pub fn payload_reader<P: Deserialize>(&self, pinfo: PayloadInfo) -> NetworkResult<PayloadReader<P>> {
+        PayloadReader::new(pinfo.select(self.frame_payload())?)
+    }
+
+    /// Decrypted payload of the last frame read.
+    #[inline]
+    fn frame_payload(&self) -> &[u8] {
+        &self.read_buffer.read_slice()[HEADER_SIZE..]
+    }
+
+    /// Read and decrypt the next frame in place in the read buffer. The frame is consumed from the
+    /// buffer on the subsequent read.
     fn read_unpack(&mut self) -> Result<(usize, u8), NetworkError> {
-        let mut stream = self.read_buffer.read_slice();
+        // Consume the previous frame
+        self.read_buffer.move_head(self.read_pending);
+        self.read_pending = 0;
+        self.read_state = ReadState::Idle;
+
+        let stream = self.read_buffer.read_slice();
 
         logging::trace!(self.log, "reading message into the input buffer";
                         "context" => "read_unpack",
-                        "channel_id" => self.id,
                         "client_sequence" => self.client_sequence);
 
         // Wait until there is enough data for the header
         if stream.len() < HEADER_SIZE {
+            self.read_state = ReadState::Header(HEADER_SIZE - stream.len());
+
             logging::trace!(self.log, "not enough data to parse the header";
                             "context" => "read_unpack",
-                            "channel_id" => self.id,
-                            "client_sequence" => self.client_sequence);
+                            "client_sequence" => self.client_sequence,
+                            "read_state" => ?self.read_state);
 
             return Err(NetworkError::Wait);
         }
 
         // Read header
-        let category = stream.read_u8()?;
-        let sequence = stream.read_u64::<BigEndian>()?;
-        let payload_size = stream.read_u16::<BigEndian>()? as usize;
+        let header = Header::read(stream)?;
+        let Header {
+            category,
+            sequence,
+            payload_size,
+            checksum,
+        } = header;
+        let payload_size = payload_size as usize;
 
         logging::trace!(self.log, "read control message header";
                         "context" => "read_unpack",
-                        "channel_id" => self.id,
                         "received_sequence" => sequence,
                         "client_sequence" => self.client_sequence,
                         "payload_size" => payload_size);
@@ -514,18 +1023,46 @@ impl Channel {
             return Err(NetworkError::Fatal(ErrorType::SequenceMismatch));
         }
 
-        if stream.len() < payload_size {
+        if stream.len() - HEADER_SIZE < payload_size {
+            self.read_state = ReadState::Body(payload_size - (stream.len() - HEADER_SIZE));
+
+            logging::trace!(self.log, "not enough data to read the body";
+                            "context" => "read_unpack",
+                            "client_sequence" => self.client_sequence,
+                            "read_state" => ?self.read_state);
+
             return Err(NetworkError::Wait);
         }
 
+        // Bail out if the payload can't even hold the MAC
+        if payload_size < self.suite.mac_size() {
+            return Err(NetworkError::Fatal(ErrorType::Crypto));
+        }
+
         // Adjust for the MAC
-        let decrypted_size = payload_size - crypto::MAC_SIZE;
+        let decrypted_size = payload_size - self.suite.mac_size();
         let additional_data = self.additional_data(category);
 
-        // Read payload
-        if !crypto::decrypt(
-            &mut self.payload[..decrypted_size],
-            &stream[..payload_size],
+        let frame_size = HEADER_SIZE + payload_size;
+
+        // The read buffer is mirrored in virtual memory, frames wrapping around the end of the allocation
+        // are still contiguous. There is no chunk boundary to split a frame, hence no need to fall back to
+        // decrypting a copy of the payload.
+        let frame = match self.read_buffer.read_frame_slice(frame_size) {
+            Some(frame) => frame,
+            _ => return Err(NetworkError::Wait),
+        };
+
+        // Cheaply drop corrupt frames before attempting decryption. The checksum itself carries no
+        // authority, a frame passing the check still has to authenticate.
+        if header.compute_checksum(&frame[HEADER_SIZE..]) != checksum {
+            return Err(NetworkError::Fatal(ErrorType::Checksum));
+        }
+
+        // Decrypt payload
+        if !crypto::decrypt_in_place(
+            self.suite,
+            &mut frame[HEADER_SIZE..],
             &additional_data,
             sequence,
             &self.server_key,
@@ -533,11 +1070,10 @@ impl Channel {
             return Err(NetworkError::Fatal(ErrorType::Crypto));
         }
 
-        self.read_buffer.move_head(HEADER_SIZE + payload_size);
+        self.read_pending = frame_size;
 
         logging::trace!(self.log, "decrypted control message";
                         "context" => "read_unpack",
-                        "channel_id" => self.id,
                         "received_sequence" => sequence,
                         "client_sequence" => self.client_sequence,
                         "decrypted_size" => decrypted_size);
@@ -549,56 +1085,81 @@ impl Channel {
 }
 
 impl Channel {
-    /// Reads the connection token off the channel, parses the contents and returns the client id.
-    pub fn read_connection_token(&mut self, session_key: &SessionKey) -> Result<UserId, NetworkError> {
+    /// Reads the handshake off the channel, parses the contents and returns the client id along with
+    /// the resume token, if the client requested to resume a previous session.
+    pub fn read_connection_token(&mut self, session_key: &SessionKey) -> Result<Handshake, NetworkError> {
+        // Wait for the entire handshake, the token is only consumed once it has been fully received
+        if self.read_buffer.len() < HANDSHAKE_SIZE {
+            return Err(NetworkError::Wait);
+        }
+
         let token = ConnectionToken::read(self.read_buffer.read_slice(), session_key)?;
 
         logging::debug!(self.log, "read in connection token";
                         "context" => "read_connection_token",
-                        "channel_id" => self.id,
                         "user_id" => token.data.user_id,
                         "expiry" => token.expires,
                         "protocol" => ?token.protocol,
                         "verson" => ?token.version);
 
-        if token.expires < timestamp_secs() {
+        if token.expires < self.clock.timestamp_secs() {
             return Err(NetworkError::Fatal(ErrorType::Expired));
         }
 
-        if token.protocol != self.protocol {
+        if !crypto::constant_time_eq(&token.protocol.to_be_bytes(), &self.protocol.to_be_bytes()) {
             return Err(NetworkError::Fatal(ErrorType::ProtocolMismatch));
         }
 
-        if token.version != self.version {
+        if !crypto::constant_time_eq(&token.version, &self.version) {
             return Err(NetworkError::Fatal(ErrorType::VersionMismatch));
         }
 
-        self.server_key = token.data.server_key;
-        self.client_key = token.data.client_key;
+        if token.suite != self.suite {
+            return Err(NetworkError::Fatal(ErrorType::CipherSuiteMismatch));
+        }
+
+        let mut resume_token = [0u8; RESUME_TOKEN_SIZE];
+        resume_token.copy_from_slice(&self.read_buffer.read_slice()[ConnectionToken::SIZE..HANDSHAKE_SIZE]);
+
+        self.derive_session_keys(&token);
 
-        self.read_buffer.move_head(ConnectionToken::SIZE);
+        self.read_buffer.move_head(HANDSHAKE_SIZE);
         self.state = ChannelState::Connected(token.data.user_id);
+        self.scope_log();
 
-        logging::trace!(self.log, "validated connection token";
-                        "context" => "read_connection_token",
-                        "channel_id" => self.id,
-                        "user_id" => token.data.user_id);
+        logging::trace!(self.log, "validated connection token"; "context" => "read_connection_token");
 
-        Ok(token.data.user_id)
+        Ok(Handshake {
+            user_id: token.data.user_id,
+            roles: token.data.roles,
+            resume: match resume_token.iter().all(|&byte| byte == 0) {
+                true => None,
+                _ => Some(resume_token),
+            },
+        })
     }
 }
 
+/// Result of a successful handshake.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Handshake {
+    pub user_id: UserId,
+    pub roles: Roles,
+    pub resume: Option<ResumeToken>,
+}
+
 /// Connection token sent by the client as part of the handshake process.
 pub struct ConnectionToken {
     pub version: [u8; 16],
     pub protocol: u16,
+    pub suite: CipherSuite,
     pub expires: u64,
     pub sequence: u64,
     pub data: PrivateData,
 }
 
 impl ConnectionToken {
-    pub const SIZE: usize = 34 + PrivateData::SIZE + crypto::MAC_SIZE;
+    pub const SIZE: usize = 35 + PrivateData::SIZE + crypto::MAC_SIZE;
 
     /// Read in the connection token form the supplied stream and decrypt the private
     /// data using the secret key.
@@ -612,6 +1173,8 @@ impl ConnectionToken {
         let mut version: [u8; 16] = [0u8; 16];
         stream.read_exact(&mut version)?;
         let protocol = stream.read_u16::<BigEndian>()?;
+        let suite = CipherSuite::from_id(stream.read_u8()?)
+            .ok_or(NetworkError::Fatal(ErrorType::CipherSuiteMismatch))?;
         let expires = stream.read_u64::<BigEndian>()?;
         let sequence = stream.read_u64::<BigEndian>()?;
 
@@ -619,10 +1182,12 @@ impl ConnectionToken {
         let mut plain = [0u8; PrivateData::SIZE];
 
         // Construct the additional data used for the encryption.
-        let additional_data = PrivateData::additional_data(&version, protocol, expires)?;
+        let additional_data = PrivateData::additional_data(&version, protocol, suite, expires)?;
 
-        // Decrypt the cipher into the plain data.
+        // Decrypt the cipher into the plain data. The private data is always encrypted with the default
+        // suite, the negotiated suite only applies to the channel traffic.
         if !crypto::decrypt(
+            CipherSuite::default(),
             &mut plain,
             &stream[..PrivateData::SIZE + crypto::MAC_SIZE],
             &additional_data,
@@ -632,26 +1197,89 @@ impl ConnectionToken {
             return Err(NetworkError::Fatal(ErrorType::Crypto));
         }
 
+        // The private data is only ever rejected as invalid if its version is unknown.
+        let data = PrivateData::read(&plain[..]).map_err(|err| match err.kind() {
+            io::ErrorKind::InvalidData => NetworkError::Fatal(ErrorType::VersionMismatch),
+            _ => err.into(),
+        })?;
+
         let instance = ConnectionToken {
             version,
             protocol,
+            suite,
             expires,
             sequence,
-            data: PrivateData::read(&plain[..])?,
+            data,
         };
 
         Ok(instance)
     }
 }
 
+/// Entry points running the parsers on raw, attacker controlled bytes, for use as fuzz targets. Malformed
+/// input is only ever reported as a `NetworkError`, never as a panic.
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz {
+    use super::*;
+
+    /// Create a channel with the supplied data in its read buffer. Data beyond the buffer capacity is
+    /// dropped, it could never be received in one go.
+    fn make_channel(data: &[u8]) -> Channel {
+        let mut channel = Channel::new(flux::VERSION_ID, flux::PROTOCOL_ID, None);
+
+        let size = data.len().min(channel.read_buffer.free_capacity());
+        channel.read_buffer.write_slice()[..size].copy_from_slice(&data[..size]);
+        channel.read_buffer.move_tail(size);
+
+        channel
+    }
+
+    /// Run the data through the full read path of a connected channel, decrypting with the supplied
+    /// key. Frames, including the payloads, are read until the first error, which is returned.
+    pub fn read_frames<P: Deserialize>(data: &[u8], key: &[u8; crypto::KEY_SIZE]) -> NetworkError {
+        let mut channel = make_channel(data);
+        channel.server_key = *key;
+
+        loop {
+            let result = match channel.read() {
+                Ok(Frame::Payload(pinfo)) => channel.read_payload(&mut PayloadBatch::<P>::new(), pinfo),
+                Ok(Frame::Control(_)) => Ok(()),
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = result {
+                return err;
+            }
+        }
+    }
+
+    /// Parse the decrypted frame payload following the category in the first byte.
+    pub fn read_frame(data: &[u8]) -> NetworkResult<Frame> {
+        match data.split_first() {
+            Some((&category, payload)) => Frame::read(payload, category),
+            _ => Err(NetworkError::Wait),
+        }
+    }
+
+    /// Parse the data as a handshake, decrypting the connection token with the session key.
+    pub fn read_handshake(data: &[u8], session_key: &SessionKey) -> NetworkResult<Handshake> {
+        make_channel(data).read_connection_token(session_key)
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::net::support::{Deserialize, SizedRead, SizedWrite};
+    use flux::session::user::{roles, TokenBuilder};
+    use flux::time::ManualClock;
+    use std::fmt;
     use std::mem;
+    use std::panic;
+    use std::sync::Mutex;
 
-    const VERSION: [u8; 16] = [5; 16];
-    const PROTOCOL: u16 = 123;
+    pub(crate) const VERSION: [u8; 16] = [5; 16];
+    pub(crate) const PROTOCOL: u16 = 123;
 
     struct TestPayload(u64);
 
@@ -673,53 +1301,86 @@ mod tests {
         }
     }
 
-    fn make_connection_token() -> ConnectionToken {
+    pub(crate) fn make_connection_token() -> ConnectionToken {
         ConnectionToken {
             version: VERSION,
             protocol: PROTOCOL,
-            expires: timestamp_secs() + 3600,
+            suite: CipherSuite::default(),
+            expires: flux::time::timestamp_secs() + 3600,
             sequence: 20,
             data: PrivateData {
+                version: PrivateData::VERSION,
                 user_id: 8008,
+                roles: 0,
                 server_key: [15; crypto::KEY_SIZE],
                 client_key: [101; crypto::KEY_SIZE],
             },
         }
     }
 
-    fn serialize_connection_token(
+    pub(crate) fn serialize_connection_token(
         buffer: &mut Buffer,
         token: &ConnectionToken,
         key: &[u8; crypto::KEY_SIZE],
     ) {
-        let mut stream = buffer.write_slice();
+        serialize_handshake(buffer, token, key, &[0u8; RESUME_TOKEN_SIZE]);
+    }
 
-        stream.write_all(&token.version).unwrap();
-        stream.write_u16::<BigEndian>(token.protocol).unwrap();
-        stream.write_u64::<BigEndian>(token.expires).unwrap();
-        stream.write_u64::<BigEndian>(token.sequence).unwrap();
+    /// Open a client side channel on the supplied stream, with the handshake for the token queued for
+    /// sending. The channel uses the session keys derived from the token, mirrored.
+    pub(crate) fn open_client_channel(
+        stream: TcpStream,
+        token: &ConnectionToken,
+        key: &[u8; crypto::KEY_SIZE],
+    ) -> Channel {
+        open_resuming_client_channel(stream, token, key, &[0u8; RESUME_TOKEN_SIZE])
+    }
 
-        let mut plain = [0u8; PrivateData::SIZE];
-        let mut private_data_stream = &mut plain[..];
+    /// Open a client side channel like `open_client_channel`, requesting to resume the session of the
+    /// resume token.
+    pub(crate) fn open_resuming_client_channel(
+        stream: TcpStream,
+        token: &ConnectionToken,
+        key: &[u8; crypto::KEY_SIZE],
+        resume_token: &ResumeToken,
+    ) -> Channel {
+        let mut channel = Channel::new(token.version, token.protocol, None);
+        channel.open(0, stream, Instant::now());
 
-        private_data_stream
-            .write_u64::<BigEndian>(token.data.user_id)
-            .unwrap();
-        private_data_stream.write_all(&token.data.server_key).unwrap();
-        private_data_stream.write_all(&token.data.client_key).unwrap();
+        serialize_handshake(&mut channel.write_buffer, token, key, resume_token);
 
-        let additional_data =
-            PrivateData::additional_data(&token.version, token.protocol, token.expires).unwrap();
+        channel.derive_session_keys(token);
+        mem::swap(&mut channel.server_key, &mut channel.client_key);
+        channel.state = ChannelState::Connected(token.data.user_id);
 
-        crypto::encrypt(
-            &mut stream[..PrivateData::SIZE + crypto::MAC_SIZE],
-            &plain,
-            &additional_data,
-            token.sequence,
-            key,
-        );
+        channel
+    }
+
+    pub(crate) fn serialize_handshake(
+        buffer: &mut Buffer,
+        token: &ConnectionToken,
+        key: &[u8; crypto::KEY_SIZE],
+        resume_token: &ResumeToken,
+    ) {
+        let builder = TokenBuilder::new(PrivateData {
+            version: token.data.version,
+            user_id: token.data.user_id,
+            roles: token.data.roles,
+            server_key: token.data.server_key,
+            client_key: token.data.client_key,
+        })
+        .version(token.version)
+        .protocol(token.protocol)
+        .suite(token.suite)
+        .expires(token.expires)
+        .sequence(token.sequence);
+
+        let mut stream = buffer.write_slice();
 
-        buffer.move_tail(ConnectionToken::SIZE);
+        builder.write(&mut stream[..TokenBuilder::SIZE], key).unwrap();
+        stream[TokenBuilder::SIZE..][..RESUME_TOKEN_SIZE].copy_from_slice(resume_token);
+
+        buffer.move_tail(HANDSHAKE_SIZE);
     }
 
     #[test]
@@ -730,9 +1391,13 @@ mod tests {
 
         assert_eq!(&ad[..16], &[5u8; 16]);
 
+        // The protocol is little endian, unlike the frame header
+        assert_eq!(&ad[16..18], &[123, 0]);
+
         let mut reader = Cursor::new(&ad[16..]);
 
         assert_eq!(reader.read_u16::<LittleEndian>().unwrap(), 123);
+        assert_eq!(reader.read_u8().unwrap(), CipherSuite::default().id());
         assert_eq!(reader.read_u8().unwrap(), 255);
     }
 
@@ -746,14 +1411,78 @@ mod tests {
 
         serialize_connection_token(&mut channel.read_buffer, &token, &secret_key);
 
-        let user_id = channel.read_connection_token(&secret_key).unwrap();
+        let handshake = channel.read_connection_token(&secret_key).unwrap();
+
+        assert_eq!(handshake.user_id, token.data.user_id);
+        assert_eq!(handshake.roles, token.data.roles);
+        assert_eq!(handshake.resume, None);
+        assert_eq!(channel.read_buffer.len(), 0);
+
+        // The session keys are derived from, but never equal to the token keys
+        assert_ne!(channel.server_key, token.data.server_key);
+        assert_ne!(channel.client_key, token.data.client_key);
+        assert_ne!(channel.server_key, channel.client_key);
+    }
+
+    #[test]
+    fn test_read_built_connection_token() {
+        let secret_key = SessionKey::new([33; crypto::KEY_SIZE]);
+
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
 
-        assert_eq!(user_id, token.data.user_id);
-        assert_eq!(channel.server_key, token.data.server_key);
-        assert_eq!(channel.client_key, token.data.client_key);
+        let token = TokenBuilder::new(PrivateData {
+            version: PrivateData::VERSION,
+            user_id: 42,
+            roles: roles::ADMIN,
+            server_key: [1; crypto::KEY_SIZE],
+            client_key: [2; crypto::KEY_SIZE],
+        })
+        .version(VERSION)
+        .protocol(PROTOCOL)
+        .build(&secret_key);
+
+        // No resume token follows the connection token
+        let stream = channel.read_buffer.write_slice();
+        stream[..token.len()].copy_from_slice(&token);
+        stream[token.len()..][..RESUME_TOKEN_SIZE].copy_from_slice(&[0u8; RESUME_TOKEN_SIZE]);
+        channel.read_buffer.move_tail(HANDSHAKE_SIZE);
+
+        let handshake = channel.read_connection_token(&secret_key).unwrap();
+
+        assert_eq!(handshake.user_id, 42);
+        assert_eq!(handshake.roles, roles::ADMIN);
+        assert_eq!(handshake.resume, None);
         assert_eq!(channel.read_buffer.len(), 0);
     }
 
+    #[test]
+    fn test_derive_session_keys() {
+        let mut token = make_connection_token();
+
+        // Use identical token keys to ensure the directions are still separated
+        token.data.client_key = token.data.server_key;
+
+        let mut channel1 = Channel::new(VERSION, PROTOCOL, None);
+        let mut channel2 = Channel::new(VERSION, PROTOCOL, None);
+
+        channel1.derive_session_keys(&token);
+        channel2.derive_session_keys(&token);
+
+        // Derivation is deterministic
+        assert_eq!(channel1.server_key, channel2.server_key);
+        assert_eq!(channel1.client_key, channel2.client_key);
+
+        // The two directions use different keys
+        assert_ne!(channel1.server_key, channel1.client_key);
+
+        // A different session produces different keys
+        token.sequence += 1;
+        channel2.derive_session_keys(&token);
+
+        assert_ne!(channel1.server_key, channel2.server_key);
+        assert_ne!(channel1.client_key, channel2.client_key);
+    }
+
     #[test]
     fn test_read_connection_token_err_wait() {
         let secret_key = SessionKey::new([33; crypto::KEY_SIZE]);
@@ -775,17 +1504,19 @@ mod tests {
     fn test_read_connection_token_err_expired() {
         let secret_key = SessionKey::new([33; crypto::KEY_SIZE]);
 
-        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+        let clock = ManualClock::new();
+        let mut channel = Channel::with_clock(VERSION, PROTOCOL, Arc::new(clock.clone()), None);
 
-        let mut token = make_connection_token();
-        token.expires -= 7200;
+        // The token expires an hour from now
+        let token = make_connection_token();
+        clock.advance(Duration::from_secs(3601));
 
         serialize_connection_token(&mut channel.read_buffer, &token, &secret_key);
 
         let result = channel.read_connection_token(&secret_key);
 
         assert_eq!(result.err().unwrap(), NetworkError::Fatal(ErrorType::Expired));
-        assert_eq!(channel.read_buffer.len(), ConnectionToken::SIZE);
+        assert_eq!(channel.read_buffer.len(), HANDSHAKE_SIZE);
     }
 
     #[test]
@@ -805,31 +1536,72 @@ mod tests {
             result.err().unwrap(),
             NetworkError::Fatal(ErrorType::VersionMismatch)
         );
-        assert_eq!(channel.read_buffer.len(), ConnectionToken::SIZE);
+        assert_eq!(channel.read_buffer.len(), HANDSHAKE_SIZE);
     }
 
     #[test]
-    fn test_read_connection_token_err_protocol() {
+    fn test_read_connection_token_err_data_version() {
         let secret_key = SessionKey::new([33; crypto::KEY_SIZE]);
 
         let mut channel = Channel::new(VERSION, PROTOCOL, None);
 
         let mut token = make_connection_token();
-        token.protocol -= 1;
+        token.data.version = PrivateData::VERSION + 1;
 
         serialize_connection_token(&mut channel.read_buffer, &token, &secret_key);
 
         let result = channel.read_connection_token(&secret_key);
 
         assert_eq!(
-            result.unwrap_err(),
-            NetworkError::Fatal(ErrorType::ProtocolMismatch)
+            result.err().unwrap(),
+            NetworkError::Fatal(ErrorType::VersionMismatch)
         );
-        assert_eq!(channel.read_buffer.len(), ConnectionToken::SIZE);
+        assert_eq!(channel.read_buffer.len(), HANDSHAKE_SIZE);
     }
 
     #[test]
-    fn test_write_read_frame_roundtrip() {
+    fn test_read_connection_token_err_data_v1() {
+        let secret_key = SessionKey::new([33; crypto::KEY_SIZE]);
+
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        // Tokens carrying private data of an earlier version are rejected as a whole
+        let mut token = make_connection_token();
+        token.data.version = 1;
+
+        serialize_connection_token(&mut channel.read_buffer, &token, &secret_key);
+
+        let result = channel.read_connection_token(&secret_key);
+
+        assert_eq!(
+            result.err().unwrap(),
+            NetworkError::Fatal(ErrorType::VersionMismatch)
+        );
+        assert_eq!(channel.read_buffer.len(), HANDSHAKE_SIZE);
+    }
+
+    #[test]
+    fn test_read_connection_token_err_protocol() {
+        let secret_key = SessionKey::new([33; crypto::KEY_SIZE]);
+
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        let mut token = make_connection_token();
+        token.protocol -= 1;
+
+        serialize_connection_token(&mut channel.read_buffer, &token, &secret_key);
+
+        let result = channel.read_connection_token(&secret_key);
+
+        assert_eq!(
+            result.unwrap_err(),
+            NetworkError::Fatal(ErrorType::ProtocolMismatch)
+        );
+        assert_eq!(channel.read_buffer.len(), HANDSHAKE_SIZE);
+    }
+
+    #[test]
+    fn test_write_read_frame_roundtrip() {
         let mut channel = Channel::new(VERSION, PROTOCOL, None);
 
         channel.write_control(ControlFrame::Keepalive(123)).unwrap();
@@ -861,7 +1633,7 @@ mod tests {
         }
 
         // Write out the batch
-        channel.write_payload(&mut outgoing).unwrap();
+        channel.write_payload(&mut outgoing, Priority::High).unwrap();
 
         assert_eq!(outgoing.len(), 0);
         assert_eq!(channel.server_sequence, 1);
@@ -887,7 +1659,8 @@ mod tests {
         let mut channel = Channel::new(VERSION, PROTOCOL, None);
 
         // The maximal number of messages that can fit in the write buffer
-        let expected_consumed_messages = (WRITE_BUF_SIZE - OVERHEAD_SIZE) / 8;
+        let expected_consumed_messages =
+            (WRITE_BUF_SIZE - CONTROL_RESERVE - OVERHEAD_SIZE - PayloadBatch::<TestPayload>::COUNT_SIZE) / 8;
 
         // Fill up the outgoing batch buffer with more messages than what can fit in the write buffer
         let mut outgoing = PayloadBatch::new();
@@ -895,11 +1668,17 @@ mod tests {
             outgoing.push(TestPayload(i as u64));
         }
 
-        // Write out the batch
-        channel.write_payload(&mut outgoing).unwrap();
+        // Write out the batch, the counts of written and retained messages are reported
+        assert_eq!(
+            channel.write_payload(&mut outgoing, Priority::High).unwrap(),
+            (expected_consumed_messages, expected_consumed_messages)
+        );
 
         assert_eq!(outgoing.len(), expected_consumed_messages);
         assert_eq!(channel.server_sequence, 1);
+
+        // Ensure that the messages that were not written are retained
+        assert_eq!(outgoing.drain().next().unwrap().0, expected_consumed_messages as u64);
     }
 
     #[test]
@@ -911,13 +1690,37 @@ mod tests {
         outgoing.push(TestPayload(1));
 
         // Write out the batch
-        let result = channel.write_payload(&mut outgoing);
+        let result = channel.write_payload(&mut outgoing, Priority::High);
+
+        assert_eq!(result.unwrap_err(), NetworkError::Wait);
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(channel.server_sequence, 0);
+    }
+
+    #[test]
+    fn test_write_batch_overhead_only() {
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+        channel.write_buffer.move_tail(WRITE_BUF_SIZE - OVERHEAD_SIZE);
+
+        let mut outgoing = PayloadBatch::new();
+        outgoing.push(TestPayload(1));
+
+        let result = channel.write_payload(&mut outgoing, Priority::High);
 
         assert_eq!(result.unwrap_err(), NetworkError::Wait);
         assert_eq!(outgoing.len(), 1);
         assert_eq!(channel.server_sequence, 0);
     }
 
+    #[test]
+    fn test_max_plain_payload_size() {
+        assert_eq!(max_plain_payload_size(0), 0);
+        assert_eq!(max_plain_payload_size(OVERHEAD_SIZE - 1), 0);
+        assert_eq!(max_plain_payload_size(OVERHEAD_SIZE), 0);
+        assert_eq!(max_plain_payload_size(OVERHEAD_SIZE + 1), 1);
+        assert_eq!(max_plain_payload_size(WRITE_BUF_SIZE), WRITE_BUF_SIZE - OVERHEAD_SIZE);
+    }
+
     #[test]
     fn test_read_frame_zero_size() {
         let mut channel = Channel::new(VERSION, PROTOCOL, None);
@@ -1116,4 +1919,768 @@ mod tests {
 
         assert_eq!(result.unwrap_err(), NetworkError::Wait);
     }
+
+    #[test]
+    fn test_batch_count_framing() {
+        let mut buffer = [0u8; 128];
+
+        let mut outgoing = PayloadBatch::new();
+        for i in 0..5 {
+            outgoing.push(TestPayload(i));
+        }
+
+        let written = {
+            let mut cursor = Cursor::new(&mut buffer[..]);
+            outgoing.write(&mut cursor).unwrap();
+
+            // Append trailing data after the batch
+            cursor.write_u32::<BigEndian>(0xdead_beef).unwrap();
+            cursor.position() as usize
+        };
+
+        assert_eq!(outgoing.len(), 0);
+        assert_eq!(written, PayloadBatch::<TestPayload>::COUNT_SIZE + 5 * 8 + 4);
+
+        let mut cursor = Cursor::new(&buffer[..written]);
+        let mut received = PayloadBatch::<TestPayload>::new();
+        received.read(&mut cursor).unwrap();
+
+        let values: Vec<u64> = received.drain().map(|payload| payload.0).collect();
+        assert_eq!(values, vec![0, 1, 2, 3, 4]);
+
+        // The trailing data is left intact
+        assert_eq!(cursor.read_u32::<BigEndian>().unwrap(), 0xdead_beef);
+    }
+
+    #[test]
+    fn test_write_batch_growable() {
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+        channel.set_write_buffer_limit(WRITE_BUF_SIZE * 2);
+
+        // Fill up the write buffer so that only a fraction of the payload could fit
+        let offset = WRITE_BUF_SIZE - OVERHEAD_SIZE - 1;
+        channel.write_buffer.move_tail(offset);
+
+        let mut outgoing = PayloadBatch::new();
+        outgoing.push(TestPayload(1));
+        outgoing.push(TestPayload(2));
+
+        channel.write_payload(&mut outgoing, Priority::High).unwrap();
+
+        let frame_size = OVERHEAD_SIZE + PayloadBatch::<TestPayload>::COUNT_SIZE + 2 * 8;
+
+        assert_eq!(outgoing.len(), 0);
+        assert_eq!(channel.server_sequence, 1);
+        assert_eq!(channel.write_buffer.size(), WRITE_BUF_SIZE + WRITE_BUF_RESERVE);
+        assert_eq!(channel.write_buffer.len(), offset + frame_size);
+
+        // Move the written frame into the read buffer and read it back
+        channel.read_buffer.write_slice()[..frame_size]
+            .copy_from_slice(&channel.write_buffer.read_slice()[offset..]);
+        channel.read_buffer.move_tail(frame_size);
+        mem::swap(&mut channel.server_key, &mut channel.client_key);
+
+        let pinfo = match channel.read().unwrap() {
+            Frame::Payload(pinfo) => pinfo,
+            resp => panic!("Unexpected response {:?}", resp),
+        };
+
+        let mut received = PayloadBatch::<TestPayload>::new();
+        channel.read_payload(&mut received, pinfo).unwrap();
+
+        let values: Vec<u64> = received.drain().map(|payload| payload.0).collect();
+        assert_eq!(values, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_read_frame_wraparound() {
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        let mut outgoing = PayloadBatch::new();
+        for i in 0..10 {
+            outgoing.push(TestPayload(i));
+        }
+
+        channel.write_payload(&mut outgoing, Priority::High).unwrap();
+
+        let frame_size = channel.write_buffer.len();
+
+        // Position the read buffer so that the frame spans the end of the underlying allocation
+        let offset = READ_BUF_SIZE - frame_size / 2;
+        channel.read_buffer.move_tail(offset);
+        channel.read_buffer.move_head(offset);
+
+        channel.read_buffer.write_slice()[..frame_size].copy_from_slice(channel.write_buffer.read_slice());
+        channel.read_buffer.move_tail(frame_size);
+        mem::swap(&mut channel.server_key, &mut channel.client_key);
+
+        let pinfo = match channel.read().unwrap() {
+            Frame::Payload(pinfo) => pinfo,
+            resp => panic!("Unexpected response {:?}", resp),
+        };
+
+        let mut received = PayloadBatch::<TestPayload>::new();
+        channel.read_payload(&mut received, pinfo).unwrap();
+
+        let values: Vec<u64> = received.drain().map(|payload| payload.0).collect();
+        assert_eq!(values, (0..10).collect::<Vec<u64>>());
+
+        // The frame is consumed on the next read
+        assert_eq!(channel.read_buffer.len(), frame_size);
+        assert_eq!(channel.read().unwrap_err(), NetworkError::Wait);
+        assert_eq!(channel.read_buffer.len(), 0);
+    }
+
+    #[test]
+    fn test_key_rotation_roundtrip() {
+        fn transmit(from: &mut Channel, to: &mut Channel) {
+            let size = from.write_buffer.len();
+            to.read_buffer.write_slice()[..size].copy_from_slice(from.write_buffer.read_slice());
+            to.read_buffer.move_tail(size);
+            from.write_buffer.clear();
+        }
+
+        fn send(channel: &mut Channel, value: u64) {
+            let mut outgoing = PayloadBatch::new();
+            outgoing.push(TestPayload(value));
+            channel.write_payload(&mut outgoing, Priority::High).unwrap();
+        }
+
+        fn receive(channel: &mut Channel) -> u64 {
+            let pinfo = match channel.read().unwrap() {
+                Frame::Payload(pinfo) => pinfo,
+                resp => panic!("Unexpected response {:?}", resp),
+            };
+
+            let mut received = PayloadBatch::<TestPayload>::new();
+            channel.read_payload(&mut received, pinfo).unwrap();
+            received.drain().next().unwrap().0
+        }
+
+        let mut server = Channel::new(VERSION, PROTOCOL, None);
+        let mut client = Channel::new(VERSION, PROTOCOL, None);
+
+        // The client side uses the mirrored keys
+        client.server_key = server.client_key;
+        client.client_key = server.server_key;
+
+        let old_server_key = server.server_key;
+        let old_client_key = server.client_key;
+
+        // Frame sent by the client under the old keys that is still in flight during the rotation
+        send(&mut client, 1);
+
+        server.rotate_keys().unwrap();
+        assert_eq!(server.rotate_keys().unwrap_err(), NetworkError::Wait);
+        send(&mut server, 2);
+
+        transmit(&mut server, &mut client);
+
+        match client.read().unwrap() {
+            Frame::Control(ControlFrame::KeyRotate { .. }) => (),
+            resp => panic!("Unexpected response {:?}", resp),
+        };
+        assert_eq!(receive(&mut client), 2);
+
+        transmit(&mut client, &mut server);
+
+        // The in-flight frame is still readable with the old key, followed by the acknowledgement
+        assert_eq!(receive(&mut server), 1);
+        assert_eq!(server.read().unwrap(), Frame::Control(ControlFrame::KeyRotateAck));
+
+        assert_ne!(server.server_key, old_server_key);
+        assert_ne!(server.client_key, old_client_key);
+        assert_eq!(server.pending_server_key, None);
+
+        // Continue exchanging frames under the new keys
+        send(&mut client, 3);
+        transmit(&mut client, &mut server);
+        assert_eq!(receive(&mut server), 3);
+
+        send(&mut server, 4);
+        transmit(&mut server, &mut client);
+        assert_eq!(receive(&mut client), 4);
+    }
+
+    #[test]
+    fn test_key_rotation_deferred_ack() {
+        fn transmit(from: &mut Channel, to: &mut Channel) {
+            let size = from.write_buffer.len();
+            to.read_buffer.write_slice()[..size].copy_from_slice(from.write_buffer.read_slice());
+            to.read_buffer.move_tail(size);
+            from.write_buffer.clear();
+        }
+
+        fn send(channel: &mut Channel, value: u64) -> NetworkResult<(usize, usize)> {
+            let mut outgoing = PayloadBatch::new();
+            outgoing.push(TestPayload(value));
+            channel.write_payload(&mut outgoing, Priority::High)
+        }
+
+        fn receive(channel: &mut Channel) -> u64 {
+            let pinfo = match channel.read().unwrap() {
+                Frame::Payload(pinfo) => pinfo,
+                resp => panic!("Unexpected response {:?}", resp),
+            };
+
+            let mut received = PayloadBatch::<TestPayload>::new();
+            channel.read_payload(&mut received, pinfo).unwrap();
+            received.drain().next().unwrap().0
+        }
+
+        let mut server = Channel::new(VERSION, PROTOCOL, None);
+        let mut client = Channel::new(VERSION, PROTOCOL, None);
+
+        client.server_key = server.client_key;
+        client.client_key = server.server_key;
+
+        server.rotate_keys().unwrap();
+        send(&mut server, 1).unwrap();
+        transmit(&mut server, &mut client);
+
+        // The rotation is applied even though the acknowledgement doesn't fit the full write buffer
+        client.write_buffer.move_tail(WRITE_BUF_SIZE);
+
+        match client.read().unwrap() {
+            Frame::Control(ControlFrame::KeyRotate { .. }) => (),
+            resp => panic!("Unexpected response {:?}", resp),
+        };
+        assert!(client.pending_client_key.is_some());
+        assert_eq!(receive(&mut client), 1);
+        assert_eq!(send(&mut client, 2).unwrap_err(), NetworkError::Wait);
+
+        // The acknowledgement goes out ahead of the next frame, which is already encrypted with the new key
+        client.write_buffer.clear();
+        send(&mut client, 2).unwrap();
+        assert_eq!(client.pending_client_key, None);
+
+        transmit(&mut client, &mut server);
+        assert_eq!(server.read().unwrap(), Frame::Control(ControlFrame::KeyRotateAck));
+        assert_eq!(receive(&mut server), 2);
+
+        send(&mut server, 3).unwrap();
+        transmit(&mut server, &mut client);
+        assert_eq!(receive(&mut client), 3);
+    }
+
+    #[test]
+    fn test_key_rotation_unexpected_ack() {
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        channel.write_control(ControlFrame::KeyRotateAck).unwrap();
+
+        mem::swap(&mut channel.read_buffer, &mut channel.write_buffer);
+        mem::swap(&mut channel.server_key, &mut channel.client_key);
+
+        assert_eq!(
+            channel.read().unwrap_err(),
+            NetworkError::Fatal(ErrorType::KeyRotation)
+        );
+    }
+
+    #[test]
+    fn test_read_connection_token_err_cipher_suite() {
+        let secret_key = SessionKey::new([33; crypto::KEY_SIZE]);
+
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        let mut token = make_connection_token();
+        token.suite = CipherSuite::Aes256Gcm;
+
+        serialize_connection_token(&mut channel.read_buffer, &token, &secret_key);
+
+        let result = channel.read_connection_token(&secret_key);
+
+        assert_eq!(
+            result.unwrap_err(),
+            NetworkError::Fatal(ErrorType::CipherSuiteMismatch)
+        );
+        assert_eq!(channel.read_buffer.len(), HANDSHAKE_SIZE);
+    }
+
+    #[test]
+    fn test_set_cipher_suite() {
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        channel.set_cipher_suite(CipherSuite::ChaCha20Poly1305);
+        assert_eq!(channel.suite, CipherSuite::ChaCha20Poly1305);
+
+        // AES-256-GCM is only accepted where the hardware supports it
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            channel.set_cipher_suite(CipherSuite::Aes256Gcm);
+        }));
+        assert_eq!(result.is_ok(), CipherSuite::Aes256Gcm.is_available());
+    }
+
+    #[test]
+    fn test_seeded_random_keys() {
+        crypto::seed_random(1234);
+        let channel1 = Channel::new(VERSION, PROTOCOL, None);
+        crypto::seed_random(1234);
+        let channel2 = Channel::new(VERSION, PROTOCOL, None);
+        crypto::clear_random_seed();
+
+        assert_eq!(channel1.server_key, channel2.server_key);
+        assert_eq!(channel1.client_key, channel2.client_key);
+        assert_ne!(channel1.server_key, channel1.client_key);
+    }
+
+    #[test]
+    fn test_read_frame_err_checksum() {
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        channel.write_control(ControlFrame::Keepalive(123)).unwrap();
+
+        let data = channel.write_buffer.data_slice();
+
+        // Flip a byte in the encrypted payload
+        data[HEADER_SIZE + 2] ^= 0xff;
+
+        // Swap both read/write buffers and client/server key so decryption would otherwise succeed
+        mem::swap(&mut channel.read_buffer, &mut channel.write_buffer);
+        mem::swap(&mut channel.server_key, &mut channel.client_key);
+
+        let response = channel.read_unpack();
+
+        assert_eq!(response.unwrap_err(), NetworkError::Fatal(ErrorType::Checksum));
+        assert_eq!(channel.client_sequence, 0);
+    }
+
+    #[test]
+    fn test_read_frame_err_checksum_header() {
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        channel.write_control(ControlFrame::Keepalive(123)).unwrap();
+
+        let data = channel.write_buffer.data_slice();
+
+        // Relabel the keepalive as a payload frame, the checksum covers the header fields too
+        data[0] = Category::Payload.into();
+
+        mem::swap(&mut channel.read_buffer, &mut channel.write_buffer);
+        mem::swap(&mut channel.server_key, &mut channel.client_key);
+
+        let response = channel.read_unpack();
+
+        assert_eq!(response.unwrap_err(), NetworkError::Fatal(ErrorType::Checksum));
+        assert_eq!(channel.client_sequence, 0);
+    }
+
+    #[test]
+    fn test_read_connection_token_resume() {
+        let secret_key = SessionKey::new([33; crypto::KEY_SIZE]);
+
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        let token = make_connection_token();
+
+        serialize_handshake(&mut channel.read_buffer, &token, &secret_key, &[7u8; RESUME_TOKEN_SIZE]);
+
+        let handshake = channel.read_connection_token(&secret_key).unwrap();
+
+        assert_eq!(handshake.user_id, token.data.user_id);
+        assert_eq!(handshake.resume, Some([7u8; RESUME_TOKEN_SIZE]));
+        assert_eq!(channel.read_buffer.len(), 0);
+    }
+
+    #[test]
+    fn test_connection_accepted_resume_token_roundtrip() {
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        let resume_token = channel.issue_resume_token();
+
+        assert_eq!(channel.resume_token(), resume_token);
+        assert_ne!(channel.issue_resume_token(), resume_token);
+
+        let resume_token = channel.resume_token();
+
+        channel
+            .write_control(ControlFrame::ConnectionAccepted(8008, resume_token))
+            .unwrap();
+
+        mem::swap(&mut channel.read_buffer, &mut channel.write_buffer);
+        mem::swap(&mut channel.server_key, &mut channel.client_key);
+
+        match channel.read().unwrap() {
+            Frame::Control(ControlFrame::ConnectionAccepted(user_id, token)) => {
+                assert_eq!(user_id, 8008);
+                assert_eq!(token, resume_token);
+            }
+            resp => panic!("Unexpected response {:?}", resp),
+        };
+    }
+
+    #[test]
+    fn test_ack_roundtrip() {
+        fn transmit(from: &mut Channel, to: &mut Channel) {
+            let size = from.write_buffer.len();
+            to.read_buffer.write_slice()[..size].copy_from_slice(from.write_buffer.read_slice());
+            to.read_buffer.move_tail(size);
+            from.write_buffer.clear();
+        }
+
+        let mut server = Channel::new(VERSION, PROTOCOL, None);
+        let mut client = Channel::new(VERSION, PROTOCOL, None);
+
+        // The client side uses the mirrored keys
+        client.server_key = server.client_key;
+        client.client_key = server.server_key;
+
+        assert_eq!(server.acked_sequence(), None);
+
+        for value in 0..3 {
+            let mut outgoing = PayloadBatch::new();
+            outgoing.push(TestPayload(value));
+            server.write_payload(&mut outgoing, Priority::High).unwrap();
+        }
+
+        transmit(&mut server, &mut client);
+
+        for value in 0..3 {
+            let pinfo = match client.read().unwrap() {
+                Frame::Payload(pinfo) => pinfo,
+                resp => panic!("Unexpected response {:?}", resp),
+            };
+
+            let mut received = PayloadBatch::<TestPayload>::new();
+            client.read_payload(&mut received, pinfo).unwrap();
+            assert_eq!(received.drain().next().unwrap().0, value);
+        }
+
+        // Acknowledge the processed frames, followed by a stale and a premature acknowledgement
+        client.write_control(ControlFrame::Ack(2)).unwrap();
+        client.write_control(ControlFrame::Ack(1)).unwrap();
+        client.write_control(ControlFrame::Ack(10)).unwrap();
+
+        transmit(&mut client, &mut server);
+
+        assert_eq!(server.read().unwrap(), Frame::Control(ControlFrame::Ack(2)));
+        assert_eq!(server.acked_sequence(), Some(2));
+
+        assert_eq!(server.read().unwrap(), Frame::Control(ControlFrame::Ack(1)));
+        assert_eq!(server.acked_sequence(), Some(2));
+
+        assert_eq!(server.read().unwrap(), Frame::Control(ControlFrame::Ack(10)));
+        assert_eq!(server.acked_sequence(), Some(2));
+    }
+
+    /// Drain capturing the persistent key-value pairs of the logger
+    struct CaptureDrain(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl logging::Serializer for CaptureDrain {
+        fn emit_arguments(&mut self, key: logging::Key, val: &fmt::Arguments) -> logging::Result {
+            self.0.lock().unwrap().push((key.to_string(), format!("{}", val)));
+            Ok(())
+        }
+    }
+
+    impl logging::Drain for CaptureDrain {
+        type Ok = ();
+        type Err = logging::Never;
+
+        fn log(&self, record: &logging::Record, values: &logging::OwnedKVList) -> Result<(), logging::Never> {
+            use logging::KV;
+
+            values.serialize(record, &mut CaptureDrain(self.0.clone())).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_scoped_log() {
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let log = logging::Logger::root(CaptureDrain(captured.clone()), logging::o!());
+
+        let secret_key = SessionKey::new([33; crypto::KEY_SIZE]);
+        let mut channel = Channel::new(VERSION, PROTOCOL, &log);
+
+        channel.id = Some(7);
+        channel.scope_log();
+
+        let token = make_connection_token();
+        serialize_connection_token(&mut channel.read_buffer, &token, &secret_key);
+        channel.read_connection_token(&secret_key).unwrap();
+
+        // Lines logged after the handshake carry both the channel and user id
+        captured.lock().unwrap().clear();
+        channel.rotate_keys().unwrap();
+
+        let captured = captured.lock().unwrap();
+        assert!(captured.contains(&("channel_id".to_owned(), "7".to_owned())));
+        assert!(captured.contains(&("user_id".to_owned(), "8008".to_owned())));
+    }
+
+    /// Frame header with the supplied fields followed by the payload, checksummed.
+    fn make_fuzz_frame(category: u8, sequence: u64, payload_size: u16, payload: &[u8]) -> Vec<u8> {
+        let mut header = Header {
+            category,
+            sequence,
+            payload_size,
+            checksum: 0,
+        };
+        header.checksum = header.compute_checksum(payload);
+
+        let mut frame = Vec::new();
+        header.write(&mut frame).unwrap();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn test_fuzz_seed_truncated_header() {
+        let key = [33; crypto::KEY_SIZE];
+        let frame = make_fuzz_frame(Category::Payload.into(), 0, 64, &[0u8; 64]);
+
+        for size in 0..HEADER_SIZE {
+            assert_eq!(fuzz::read_frames::<TestPayload>(&frame[..size], &key), NetworkError::Wait);
+        }
+
+        // Control frames cut short
+        assert_eq!(
+            fuzz::read_frame(&[Category::Keepalive.into(), 1, 2]),
+            Err(NetworkError::Fatal(ErrorType::Io(io::ErrorKind::UnexpectedEof)))
+        );
+        assert_eq!(fuzz::read_frame(&[]), Err(NetworkError::Wait));
+    }
+
+    #[test]
+    fn test_fuzz_seed_zero_size() {
+        let key = [33; crypto::KEY_SIZE];
+        let frame = make_fuzz_frame(Category::Payload.into(), 0, 0, &[]);
+
+        assert_eq!(
+            fuzz::read_frames::<TestPayload>(&frame, &key),
+            NetworkError::Fatal(ErrorType::EmptyPayload)
+        );
+
+        // Frames too small to hold the MAC
+        let frame = make_fuzz_frame(Category::Payload.into(), 0, 1, &[0u8]);
+
+        assert_eq!(
+            fuzz::read_frames::<TestPayload>(&frame, &key),
+            NetworkError::Fatal(ErrorType::Crypto)
+        );
+
+        // Empty frame payloads of every category
+        match fuzz::read_frame(&[Category::Payload.into()]) {
+            Ok(Frame::Payload(pinfo)) => assert_eq!(pinfo.select(&[]), Ok(&[][..])),
+            resp => panic!("Unexpected response {:?}", resp),
+        }
+        assert_eq!(
+            fuzz::read_frame(&[Category::KeyRotateAck.into()]),
+            Ok(Frame::Control(ControlFrame::KeyRotateAck))
+        );
+
+        for category in 0..=u8::max_value() {
+            let _ = fuzz::read_frame(&[category]);
+        }
+    }
+
+    #[test]
+    fn test_fuzz_seed_oversized_length() {
+        let key = [33; crypto::KEY_SIZE];
+        let frame = make_fuzz_frame(Category::Payload.into(), 0, u16::max_value(), &[0u8; 64]);
+
+        assert_eq!(
+            fuzz::read_frames::<TestPayload>(&frame, &key),
+            NetworkError::Fatal(ErrorType::PayloadTooLarge)
+        );
+
+        // Lengths beyond the data received so far wait for the rest of the frame
+        let frame = make_fuzz_frame(Category::Payload.into(), 0, 1024, &[0u8; 64]);
+        assert_eq!(fuzz::read_frames::<TestPayload>(&frame, &key), NetworkError::Wait);
+
+        // Data beyond the capacity of the read buffer is dropped
+        let data = vec![0u8; 2 * READ_BUF_SIZE];
+        assert_eq!(
+            fuzz::read_frames::<TestPayload>(&data, &key),
+            NetworkError::Fatal(ErrorType::EmptyPayload)
+        );
+    }
+
+    #[test]
+    fn test_fuzz_seed_corrupted_frames() {
+        let key = [33; crypto::KEY_SIZE];
+
+        let mut sender = Channel::new(flux::VERSION_ID, flux::PROTOCOL_ID, None);
+        sender.client_key = key;
+
+        let mut batch = PayloadBatch::new();
+        batch.push(TestPayload(8008));
+        sender.write_payload(&mut batch, Priority::High).unwrap();
+        sender.write_control(ControlFrame::Ack(3)).unwrap();
+
+        let data = sender.write_buffer.read_slice().to_vec();
+
+        // The intact frames are read in full
+        assert_eq!(fuzz::read_frames::<TestPayload>(&data, &key), NetworkError::Wait);
+
+        // Truncated frames wait for the rest of the data
+        for size in 0..data.len() {
+            assert_eq!(fuzz::read_frames::<TestPayload>(&data[..size], &key), NetworkError::Wait);
+        }
+
+        // Corrupted sizes may leave the channel waiting for data that never arrives, any other corruption
+        // is rejected
+        let first_size = HEADER_SIZE + (&data[9..11]).read_u16::<BigEndian>().unwrap() as usize;
+        let size_fields = [9, 10, first_size + 9, first_size + 10];
+
+        for pos in 0..data.len() {
+            let mut corrupted = data.clone();
+            corrupted[pos] ^= 0x80;
+
+            let result = fuzz::read_frames::<TestPayload>(&corrupted, &key);
+
+            if !size_fields.contains(&pos) {
+                assert_ne!(result, NetworkError::Wait);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_seed_handshake() {
+        let secret_key = SessionKey::new([33; crypto::KEY_SIZE]);
+
+        let mut token = make_connection_token();
+        token.version = flux::VERSION_ID;
+        token.protocol = flux::PROTOCOL_ID;
+
+        let mut buffer = Buffer::new(READ_BUF_SIZE);
+        serialize_connection_token(&mut buffer, &token, &secret_key);
+        let data = buffer.read_slice().to_vec();
+
+        let handshake = fuzz::read_handshake(&data, &secret_key).unwrap();
+        assert_eq!(handshake.user_id, token.data.user_id);
+
+        assert_eq!(
+            fuzz::read_handshake(&data[..HANDSHAKE_SIZE - 1], &secret_key).unwrap_err(),
+            NetworkError::Wait
+        );
+        assert!(fuzz::read_handshake(&[0u8; HANDSHAKE_SIZE], &secret_key).is_err());
+        assert!(fuzz::read_handshake(&[0xff; HANDSHAKE_SIZE], &secret_key).is_err());
+    }
+
+    #[test]
+    fn test_write_payload_priority() {
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        // Write bulk payloads until they are shed, leaving the headroom free
+        let mut bulk = PayloadBatch::new();
+        for i in 0..WRITE_BUF_SIZE as u64 / 8 {
+            bulk.push(TestPayload(i));
+        }
+
+        while channel.write_payload(&mut bulk, Priority::Bulk).is_ok() {}
+
+        let bulk_written = WRITE_BUF_SIZE as u64 / 8 - bulk.len() as u64;
+        assert!(bulk.len() > 0);
+        assert!(channel.write_buffer.free_capacity() > WRITE_BUF_SIZE / 4);
+
+        // High priority payloads still go out
+        let mut high = PayloadBatch::new();
+        for i in 0..10 {
+            high.push(TestPayload(1_000_000 + i));
+        }
+
+        channel.write_payload(&mut high, Priority::High).unwrap();
+        assert_eq!(high.len(), 0);
+        assert_eq!(
+            channel.write_payload(&mut bulk, Priority::Bulk).unwrap_err(),
+            NetworkError::Wait
+        );
+
+        // Fill up the rest of the buffer with high priority payloads, control frames still go out
+        for i in 10..WRITE_BUF_SIZE as u64 / 8 {
+            high.push(TestPayload(1_000_000 + i));
+        }
+
+        while channel.write_payload(&mut high, Priority::High).is_ok() {}
+
+        assert!(high.len() > 0);
+        channel.write_control(ControlFrame::Keepalive(123)).unwrap();
+
+        // Read back the frames, they are delivered in the order they were written
+        mem::swap(&mut channel.read_buffer, &mut channel.write_buffer);
+        mem::swap(&mut channel.server_key, &mut channel.client_key);
+
+        let mut received = PayloadBatch::<TestPayload>::new();
+        let keepalive = loop {
+            match channel.read().unwrap() {
+                Frame::Payload(pinfo) => channel.read_payload(&mut received, pinfo).unwrap(),
+                Frame::Control(frame) => break frame,
+            }
+        };
+
+        let values: Vec<u64> = received.drain().map(|payload| payload.0).collect();
+        let high_written = values.len() as u64 - bulk_written;
+
+        assert_eq!(keepalive, ControlFrame::Keepalive(123));
+        assert!(values[..bulk_written as usize].iter().cloned().eq(0..bulk_written));
+        assert!(values[bulk_written as usize..]
+            .iter()
+            .cloned()
+            .eq(1_000_000..1_000_000 + high_written));
+        assert_eq!(channel.read().unwrap_err(), NetworkError::Wait);
+    }
+
+    #[test]
+    fn test_payload_reader_stop_early() {
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+
+        let mut outgoing = PayloadBatch::new();
+        for i in 0..1000 {
+            outgoing.push(TestPayload(i));
+        }
+
+        channel.write_payload(&mut outgoing, Priority::High).unwrap();
+
+        mem::swap(&mut channel.read_buffer, &mut channel.write_buffer);
+        mem::swap(&mut channel.server_key, &mut channel.client_key);
+
+        let pinfo = match channel.read().unwrap() {
+            Frame::Payload(pinfo) => pinfo,
+            resp => panic!("Unexpected response {:?}", resp),
+        };
+
+        // Only consume the first few messages of the batch
+        let mut reader = channel.payload_reader::<TestPayload>(pinfo).unwrap();
+        let first: Vec<_> = reader.by_ref().take(10).map(|payload| payload.unwrap().0).collect();
+
+        assert_eq!(first, (0..10).collect::<Vec<_>>());
+        assert_eq!(reader.remaining(), 990);
+        assert_eq!(reader.next().map(|payload| payload.unwrap().0), Some(10));
+    }
+
+    #[test]
+    fn test_read_state() {
+        let mut channel = Channel::new(VERSION, PROTOCOL, None);
+        let frame = make_fuzz_frame(Category::Payload.into(), 0, 100, &[0u8; 100]);
+
+        let feed = |channel: &mut Channel, data: &[u8]| {
+            channel.read_buffer.write_slice()[..data.len()].copy_from_slice(data);
+            channel.read_buffer.move_tail(data.len());
+            channel.read().unwrap_err()
+        };
+
+        assert_eq!(channel.read_state(), ReadState::Idle);
+
+        // Partial header
+        assert_eq!(feed(&mut channel, &frame[..5]), NetworkError::Wait);
+        assert_eq!(channel.read_state(), ReadState::Header(HEADER_SIZE - 5));
+
+        // Header only
+        assert_eq!(feed(&mut channel, &frame[5..HEADER_SIZE]), NetworkError::Wait);
+        assert_eq!(channel.read_state(), ReadState::Body(100));
+
+        // Partial body
+        assert_eq!(feed(&mut channel, &frame[HEADER_SIZE..HEADER_SIZE + 60]), NetworkError::Wait);
+        assert_eq!(channel.read_state(), ReadState::Body(40));
+
+        // Complete frame, failing authentication
+        assert_eq!(
+            feed(&mut channel, &frame[HEADER_SIZE + 60..]),
+            NetworkError::Fatal(ErrorType::Crypto)
+        );
+        assert_eq!(channel.read_state(), ReadState::Idle);
+    }
 }
@@ -1,29 +1,82 @@
-use crate::net::channel::{Channel, ChannelId, ChannelState};
-use crate::net::frame::{ControlFrame, Frame};
+use crate::net::channel::{Channel, ChannelId, ChannelState, Priority};
+use crate::net::frame::{ControlFrame, Frame, ResumeToken};
 use crate::net::support::{
     Deserialize, ErrorType, ErrorUtils, NetworkError, NetworkResult, PayloadBatch, Serialize,
 };
 use flux;
+use flux::crypto;
 use flux::logging;
 use flux::session::server::SessionKey;
-use indexmap::IndexSet;
+use flux::time::{timestamp_millis, Clock, SystemClock};
+use hashbrown::HashMap;
+use indexmap::{IndexMap, IndexSet};
 use mio;
 use mio::net::TcpListener;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time;
 
 /// Describes a change in the connectivity status of a channel. A newly connected channel
-/// is described by the user id and channel id.
+/// is described by the user id, the roles granted to the user and the channel id.
+///
+/// Channels of dropped clients are first suspended. If the client resumes the session within the
+/// grace period, it is rebound to the original channel id, otherwise the channel is disconnected.
 #[derive(Debug, Copy, Clone)]
 pub enum ConnectionChange {
-    Connected(flux::UserId, ChannelId),
+    Connected(flux::UserId, flux::Roles, ChannelId),
+    Suspended(ChannelId),
+    Resumed(flux::UserId, ChannelId),
     Disconnected(ChannelId),
 }
 
+/// Liveness settings of the channels.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Timeouts {
+    /// Time allowed for completing the handshake after connecting.
+    pub handshake: time::Duration,
+    /// Channels that haven't received anything for this long are dropped.
+    pub ingress: time::Duration,
+    /// Idle channels are sent a keepalive after this long. Must be shorter than the ingress timeout.
+    pub keepalive: time::Duration,
+    /// Interval of checking the channels for timeouts.
+    pub housekeeping: time::Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            handshake: time::Duration::from_secs(5),
+            ingress: time::Duration::from_secs(30),
+            keepalive: time::Duration::from_secs(3),
+            housekeeping: time::Duration::from_secs(3),
+        }
+    }
+}
+
+/// Health status of the endpoint for liveness probes.
+#[derive(Debug, Copy, Clone)]
+pub struct Health {
+    /// Whether the listener is accepting connections, i.e. the endpoint was initialized and not shut down.
+    pub listening: bool,
+    /// Timestamp of the last `sync`, which is driven by the game loop.
+    pub last_sync: time::Instant,
+    /// Number of live channels.
+    pub live_count: usize,
+}
+
+impl Health {
+    /// The endpoint is alive if it is listening and has been synced within `max_stall` of `now`.
+    #[inline]
+    pub fn is_alive(&self, now: time::Instant, max_stall: time::Duration) -> bool {
+        self.listening && (now <= self.last_sync || now.duration_since(self.last_sync) <= max_stall)
+    }
+}
+
 /// Handles all connection management and network transmission.
 pub struct Endpoint {
     server: TcpListener,
+    listening: bool,
 
     server_poll: mio::Poll,
     data_poll: mio::Poll,
@@ -34,20 +87,25 @@ pub struct Endpoint {
     channels: Vec<Channel>,
     free: Vec<ChannelId>,
     live: IndexSet<ChannelId>,
+    resumable: Resumable,
 
     changes: Vec<ConnectionChange>,
+    disconnects: HashMap<&'static str, u64>,
 
+    timeouts: Timeouts,
     current_time: time::Instant,
     housekeeping_time: time::Instant,
+    clock: Arc<Clock>,
+
+    pool: Option<rayon::ThreadPool>,
 
     log: logging::Logger,
 }
 
 impl Endpoint {
-    const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
-    const INGRESS_TIMEOUT: time::Duration = time::Duration::from_secs(30);
-    const KEEPALIVE_INTERVAL: time::Duration = time::Duration::from_secs(3);
-    const HOUSEKEEPING_INTERVAL: time::Duration = time::Duration::from_secs(3);
+    const RESUME_GRACE: time::Duration = time::Duration::from_secs(30);
+    /// Buffers of channels that haven't received anything for this long are released.
+    const IDLE_COMPACTION: time::Duration = time::Duration::from_secs(10);
     const ZERO_TIME: time::Duration = time::Duration::from_secs(0);
     const SERVER_POLL_TOKEN: mio::Token = mio::Token(0);
 
@@ -55,13 +113,35 @@ impl Endpoint {
     /// format `<ip_or_domain>:<port>`.
     /// The `secret_key` is shared with an external authenticator service, so the initial client handshake
     /// can be decrypted.
-    /// Finally, the `version` should denote unique and incompatible transmission protocol versions.
+    /// Finally, the `timeouts` govern how quickly unresponsive channels are dropped. Panics if the
+    /// keepalive interval is not shorter than the ingress timeout.
+    #[inline]
+    pub fn new(
+        address: &str,
+        secret_key: SessionKey,
+        timeouts: Timeouts,
+        log: &logging::Logger,
+    ) -> NetworkResult<Endpoint> {
+        Self::with_clock(address, secret_key, timeouts, Arc::new(SystemClock), log)
+    }
+
+    /// Construct a new `Endpoint` using the supplied source of time. The clock is shared with the
+    /// channels, the timestamps driving the timeouts are supplied to `sync`.
     #[inline]
-    pub fn new(address: &str, secret_key: SessionKey, log: &logging::Logger) -> NetworkResult<Endpoint> {
-        let now = time::Instant::now();
+    pub fn with_clock(
+        address: &str,
+        secret_key: SessionKey,
+        timeouts: Timeouts,
+        clock: Arc<Clock>,
+        log: &logging::Logger,
+    ) -> NetworkResult<Endpoint> {
+        Self::check_timeouts(&timeouts);
+
+        let now = clock.now();
 
         let endpoint = Endpoint {
             server: TcpListener::bind(&address.parse::<SocketAddr>()?)?,
+            listening: false,
             server_poll: mio::Poll::new()?,
             data_poll: mio::Poll::new()?,
             events: mio::Events::with_capacity(8192),
@@ -69,17 +149,80 @@ impl Endpoint {
             channels: Vec::new(),
             free: Vec::new(),
             live: IndexSet::new(),
+            resumable: Resumable::new(),
             changes: Vec::new(),
+            disconnects: HashMap::new(),
+            timeouts,
             current_time: now,
             housekeeping_time: now,
+            clock,
+            pool: None,
             log: log.new(logging::o!()),
         };
 
         Ok(endpoint)
     }
 
+    /// Get the address the listener is bound to.
+    #[inline]
+    pub fn local_addr(&self) -> NetworkResult<SocketAddr> {
+        self.server.local_addr().map_err(Into::into)
+    }
+
+    /// Report the health of the endpoint.
+    #[inline]
+    pub fn health(&self) -> Health {
+        Health {
+            listening: self.listening,
+            last_sync: self.current_time,
+            live_count: self.live.len(),
+        }
+    }
+
+    /// Replace the timeouts of the running endpoint, taking effect from the next `sync`. Panics if the
+    /// keepalive interval is not shorter than the ingress timeout.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        Self::check_timeouts(&timeouts);
+
+        logging::info!(self.log, "timeouts changed";
+                       "context" => "set_timeouts",
+                       "timeouts" => ?timeouts);
+
+        self.timeouts = timeouts;
+    }
+
+    #[inline]
+    fn check_timeouts(timeouts: &Timeouts) {
+        if timeouts.keepalive >= timeouts.ingress {
+            panic!("Keepalive interval must be shorter than the ingress timeout")
+        }
+    }
+
+    /// Distribute the per channel send and receive work of `sync` across the given number of worker
+    /// threads. The channels are synced on the calling thread if set to 1, the default. Accepting
+    /// connections and reading handshakes always happens on the calling thread.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.pool = match threads > 1 {
+            true => Some(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .expect("Failed creating the network thread pool"),
+            ),
+            _ => None,
+        };
+    }
+
+    /// Number of channels closed so far, keyed by the reason. Fatal errors are keyed by the error
+    /// descriptor, other reasons are `handshake_timeout`, `handshake_stalled`, `ingress_timeout`,
+    /// `peer_closed`, `unexpected_control` and `shutdown`.
     #[inline]
-    pub fn init(&self) {
+    pub fn disconnect_metrics(&self) -> &HashMap<&'static str, u64> {
+        &self.disconnects
+    }
+
+    #[inline]
+    pub fn init(&mut self) {
         self.server_poll
             .register(
                 &self.server,
@@ -88,20 +231,123 @@ impl Endpoint {
                 mio::PollOpt::edge(),
             )
             .unwrap();
+        self.listening = true;
     }
 
+    /// Push the payload batch into the channel. Returns `NetworkError::Wait` when the channel can't take
+    /// any more data of the given priority for the time being, the caller may retry on the next frame or
+    /// drop the data. Bulk data is turned away well before high priority data. The channel is closed on
+    /// fatal errors. On success, returns the number of messages written and the number left in the batch.
     #[inline]
-    pub fn push<P: Serialize>(&mut self, channel_id: ChannelId, data: &mut PayloadBatch<P>) {
+    pub fn push<P: Serialize>(
+        &mut self,
+        channel_id: ChannelId,
+        data: &mut PayloadBatch<P>,
+        priority: Priority,
+    ) -> NetworkResult<(usize, usize)> {
         logging::trace!(self.log, "pushing payload to channel";
                         "context" => "push",
                         "channel_id" => channel_id,
+                        "priority" => ?priority,
                         "size" => data.len());
 
-        let channel = &mut self.channels[channel_id];
+        let mut ctx = self.get_comm_ctx(channel_id);
+
+        let result = ctx.channel.write_payload(data, priority);
+
+        if let Err(NetworkError::Fatal(ref err)) = result {
+            logging::error!(ctx.log, "fatal write error";
+                            "context" => "push",
+                            "channel_id" => channel_id,
+                            "result" => "error",
+                            "reason" => err.descriptor(),
+                            "error" => ?err);
+            ctx.disconnect(true, err.descriptor())
+        }
+
+        result
+    }
+
+    /// Push a copy of the payload batch into every connected channel, draining the batch. Channels still
+    /// in the handshake are skipped. Each channel is written independently, back-pressure on one channel
+    /// doesn't hold back the others. Returns the channels that couldn't accept the entire batch, they may
+    /// have received a part of it. Channels failing with a fatal error are closed, as with `push`.
+    pub fn broadcast<P: Serialize + Clone>(
+        &mut self,
+        data: &mut PayloadBatch<P>,
+        priority: Priority,
+    ) -> Vec<ChannelId> {
+        logging::trace!(self.log, "broadcasting payload to live channels";
+                        "context" => "broadcast",
+                        "priority" => ?priority,
+                        "size" => data.len(),
+                        "live_count" => self.live.len());
+
+        if data.len() == 0 {
+            return Vec::new();
+        }
+
+        let channels = &self.channels;
+        let channel_ids: Vec<_> = self
+            .live
+            .iter()
+            .cloned()
+            .filter(|&channel_id| match channels[channel_id].get_state() {
+                ChannelState::Connected(_) => true,
+                _ => false,
+            })
+            .collect();
+        let mut rejected = Vec::new();
+
+        for channel_id in channel_ids {
+            let mut batch = data.clone();
+
+            // Batches exceeding a single frame are written across several
+            loop {
+                match self.push(channel_id, &mut batch, priority) {
+                    Ok((_, 0)) => break,
+                    Ok(_) => continue,
+                    Err(_) => {
+                        rejected.push(channel_id);
+                        break;
+                    }
+                }
+            }
+        }
+
+        if !rejected.is_empty() {
+            logging::debug!(self.log, "channels rejected the broadcast";
+                            "context" => "broadcast",
+                            "rejected" => ?rejected);
+        }
+
+        data.drain().for_each(drop);
+        rejected
+    }
+
+    /// Initiate an encryption key rotation on the given channel. Returns `NetworkError::Wait` if a rotation
+    /// is already in flight or the channel is out of capacity. The channel is closed on fatal errors.
+    #[inline]
+    pub fn rotate_keys(&mut self, channel_id: ChannelId) -> NetworkResult<()> {
+        logging::debug!(self.log, "rotating channel keys";
+                        "context" => "rotate_keys",
+                        "channel_id" => channel_id);
+
+        let mut ctx = self.get_comm_ctx(channel_id);
+
+        let result = ctx.channel.rotate_keys();
 
-        if channel.write_payload(data).has_failed() {
-            panic!("Fatal write error");
+        if let Err(NetworkError::Fatal(ref err)) = result {
+            logging::error!(ctx.log, "fatal write error";
+                            "context" => "rotate_keys",
+                            "channel_id" => channel_id,
+                            "result" => "error",
+                            "reason" => err.descriptor(),
+                            "error" => ?err);
+            ctx.disconnect(true, err.descriptor())
         }
+
+        result
     }
 
     pub fn pull<P: Deserialize>(&mut self, channel_id: ChannelId, data: &mut PayloadBatch<P>) {
@@ -124,17 +370,46 @@ impl Endpoint {
                                                 "result" => "ok",
                                                 "type" => "control",
                                                 "message" => "ConnectionClosed");
-                                ctx.disconnect(false)
+                                ctx.disconnect(false, "peer_closed")
                             }
                             // Connection accepted sent by client in error, close channel and notify.
-                            ControlFrame::ConnectionAccepted(_) => {
+                            ControlFrame::ConnectionAccepted(..) => {
                                 logging::debug!(ctx.log, "erroneous connection acceptance message received";
                                                 "context" => "pull",
                                                 "channel_id" => channel_id,
                                                 "result" => "error",
                                                 "type" => "control",
                                                 "message" => "ConnectionAccepted");
-                                ctx.disconnect(true)
+                                ctx.disconnect(true, "unexpected_control")
+                            }
+                            // Key rotations are initiated by the server only, close channel and notify.
+                            ControlFrame::KeyRotate { .. } => {
+                                logging::debug!(ctx.log, "erroneous key rotation message received";
+                                                "context" => "pull",
+                                                "channel_id" => channel_id,
+                                                "result" => "error",
+                                                "type" => "control",
+                                                "message" => "KeyRotate");
+                                ctx.disconnect(true, "unexpected_control")
+                            }
+                            // Key rotation acknowledgements are handled by the channel.
+                            ControlFrame::KeyRotateAck => {
+                                logging::debug!(ctx.log, "key rotation acknowledged";
+                                                "context" => "pull",
+                                                "channel_id" => channel_id,
+                                                "result" => "ok",
+                                                "type" => "control",
+                                                "message" => "KeyRotateAck");
+                            }
+                            // Acknowledgements are tracked by the channel.
+                            ControlFrame::Ack(sequence) => {
+                                logging::trace!(ctx.log, "acknowledgement received";
+                                                "context" => "pull",
+                                                "channel_id" => channel_id,
+                                                "result" => "ok",
+                                                "type" => "control",
+                                                "message" => "Ack",
+                                                "sequence" => sequence);
                             }
                             // Keepalive requests are ignored at this stage.
                             ControlFrame::Keepalive(_) => {
@@ -154,8 +429,8 @@ impl Endpoint {
                                         "result" => "ok",
                                         "type" => "payload",
                                         "payload_info" => ?pinfo);
-                        if ctx.channel.read_payload(data, pinfo).has_failed() {
-                            ctx.disconnect(true)
+                        if let Err(NetworkError::Fatal(err)) = ctx.channel.read_payload(data, pinfo) {
+                            ctx.disconnect(true, err.descriptor())
                         }
                     }
                 }
@@ -165,8 +440,9 @@ impl Endpoint {
                                 "context" => "pull",
                                 "channel_id" => channel_id,
                                 "result" => "error",
+                                "reason" => err.descriptor(),
                                 "error" => ?err);
-                ctx.disconnect(true)
+                ctx.disconnect(true, err.descriptor())
             }
             Err(NetworkError::Wait) => {
                 logging::debug!(ctx.log, "pull";
@@ -183,7 +459,7 @@ impl Endpoint {
                         "context" => "sync",
                         "current_time" => ?self.current_time);
 
-        if now.duration_since(self.housekeeping_time) >= Self::HOUSEKEEPING_INTERVAL {
+        if now.duration_since(self.housekeeping_time) >= self.timeouts.housekeeping {
             self.housekeeping();
             self.housekeeping_time = now;
         }
@@ -191,47 +467,51 @@ impl Endpoint {
         let log = &self.log;
         let live_set = &mut self.live;
         let free_set = &mut self.free;
+        let resumable = &mut self.resumable;
         let channels = &mut self.channels;
         let changes = &mut self.changes;
+        let disconnects = &mut self.disconnects;
+        let pool = self.pool.as_ref();
 
         logging::trace!(log, "current status";
                         "context" => "sync",
                         "live_count" => live_set.len(),
                         "free_count" => free_set.len(),
+                        "resumable_count" => resumable.len(),
                         "channel_count" => channels.len());
 
         // Force send data on all live channels
-        live_set.retain(|&channel_id| {
-            logging::debug!(log, "sending data";
-                            "context" => "sync",
-                            "channel_id" => channel_id);
-
-            let channel = &mut channels[channel_id];
+        let failures = Self::partitioned(pool, channels, |offset, chunk, failures| {
+            for (idx, channel) in chunk.iter_mut().enumerate() {
+                let channel_id = offset + idx;
 
-            let result = if channel.has_egress() {
-                channel.send(now)
-            } else {
-                Ok(0)
-            };
-
-            // Close the channel in case of a send error. No point in trying to send a notice.
-            if result.has_failed() {
-                let err = result.unwrap_err();
+                if !live_set.contains(&channel_id) || !channel.has_egress() {
+                    continue;
+                }
 
-                logging::error!(log, "disconnecting channel due to write error";
+                logging::debug!(log, "sending data";
                                 "context" => "sync",
-                                "channel_id" => channel_id,
-                                "error" => ?err);
+                                "channel_id" => channel_id);
 
-                channel.close(false);
-                free_set.push(channel_id);
-                changes.push(ConnectionChange::Disconnected(channel_id));
-                return false;
+                if let Err(NetworkError::Fatal(err)) = channel.send(now) {
+                    failures.push((channel_id, err));
+                }
             }
-
-            true
         });
 
+        // Close the channels with send errors. No point in trying to send a notice.
+        for (channel_id, err) in failures {
+            logging::error!(log, "dropping channel due to write error";
+                            "context" => "sync",
+                            "channel_id" => channel_id,
+                            "reason" => err.descriptor(),
+                            "error" => ?err);
+
+            Self::record_disconnect(disconnects, err.descriptor());
+            live_set.remove(&channel_id);
+            Self::drop_channel(&mut channels[channel_id], channel_id, now, resumable, free_set, changes);
+        }
+
         logging::trace!(log, "running listen poll"; "context" => "sync");
 
         // Run listen poll
@@ -243,41 +523,50 @@ impl Endpoint {
             logging::trace!(log, "listen server event"; "context" => "sync", "event" => ?event);
             // Readiness indicates *possible* incoming connection
             if event.readiness().is_readable() {
-                // See if there is a connection to be accepted
-                match self.server.accept() {
-                    Ok((stream, addr)) => {
-                        // Retrieve an existing channel instance or create a new one
-                        let id = match free_set.pop() {
-                            Some(id) => id,
-                            None => {
-                                let id = channels.len();
-                                channels.push(Channel::new(
-                                    flux::VERSION_ID,
-                                    flux::PROTOCOL_ID,
-                                    Some(&self.log),
-                                ));
-                                id
-                            }
-                        };
-
-                        logging::info!(log, "incoming connection";
-                                       "context" => "sync",
-                                       "channel_id" => id,
-                                       "address" => ?addr);
+                // The listener is polled edge triggered, accept all the pending connections
+                loop {
+                    match self.server.accept() {
+                        Ok((stream, addr)) => {
+                            // Retrieve an existing channel instance or create a new one
+                            let id = match free_set.pop() {
+                                Some(id) => id,
+                                None => {
+                                    let id = channels.len();
+                                    channels.push(Channel::with_clock(
+                                        flux::VERSION_ID,
+                                        flux::PROTOCOL_ID,
+                                        self.clock.clone(),
+                                        Some(&self.log),
+                                    ));
+                                    id
+                                }
+                            };
 
-                        // Open the channel
-                        let channel = &mut channels[id];
-                        channel.open(id, stream, self.current_time);
+                            logging::info!(log, "incoming connection";
+                                           "context" => "sync",
+                                           "timestamp_ms" => timestamp_millis(),
+                                           "channel_id" => id,
+                                           "address" => ?addr);
+
+                            // Open the channel
+                            let channel = &mut channels[id];
+                            channel.open(id, stream, self.current_time);
+
+                            // Register the channel on the handshake poll. Clients must deliver a valid
+                            // handshake message before the connection is fully accepted.
+                            channel
+                                .register(id, &self.data_poll)
+                                .expect("Stream registration failed");
+
+                            // Track the channel so that the handshake timeout applies
+                            live_set.insert(id);
+                        }
+                        Err(err) => {
+                            if err.kind() != io::ErrorKind::WouldBlock {
+                                panic!("Failure accepting connection {:?}", err);
+                            }
 
-                        // Register the channel on the handshake poll. Clients must deliver a valid
-                        // handshake message before the connection is fully accepted.
-                        channel
-                            .register(id, &self.data_poll)
-                            .expect("Stream registration failed");
-                    }
-                    Err(err) => {
-                        if err.kind() != io::ErrorKind::WouldBlock {
-                            panic!("Failure accepting connection {:?}", err);
+                            break;
                         }
                     }
                 }
@@ -294,6 +583,8 @@ impl Endpoint {
 
         let session_key = &self.session_key;
         let data_poll = &self.data_poll;
+        let mut resumes = Vec::new();
+        let mut ready = Vec::new();
 
         for event in &self.events {
             if event.readiness().is_readable() {
@@ -311,24 +602,32 @@ impl Endpoint {
                         channel
                             .receive(now)
                             .and_then(|_| channel.read_connection_token(session_key))
-                            .and_then(|user_id| {
+                            .and_then(|handshake| {
+                                let user_id = handshake.user_id;
+                                let roles = handshake.roles;
+
                                 logging::info!(log, "handshake accepted";
                                        "context" => "sync",
+                                       "timestamp_ms" => timestamp_millis(),
                                        "channel_id" => channel_id,
-                                       "user_id" => user_id);
-
-                                if channel
-                                    .write_control(ControlFrame::ConnectionAccepted(user_id))
-                                    .has_failed()
-                                {
-                                    panic!("Failure writing connection accepted frame")
+                                       "user_id" => user_id,
+                                       "roles" => roles,
+                                       "resume" => handshake.resume.is_some());
+
+                                // Resumed sessions are rebound to their original channel once all
+                                // events have been processed.
+                                if let Some(resume_token) = handshake.resume {
+                                    resumes.push((channel_id, user_id, roles, resume_token));
+                                    return Ok(());
                                 }
 
+                                Self::accept(channel, user_id);
+
                                 logging::debug!(log, "moving channel to live set";
                                         "context" => "sync",
                                         "channel_id" => channel_id);
                                 live_set.insert(channel_id);
-                                changes.push(ConnectionChange::Connected(user_id, channel_id));
+                                changes.push(ConnectionChange::Connected(user_id, roles, channel_id));
                                 Ok(())
                             })
                             .unwrap_or_else(|err| {
@@ -337,7 +636,9 @@ impl Endpoint {
                                     logging::error!(log, "disconnecting channel due to handshake read error";
                                             "context" => "sync",
                                             "channel_id" => channel_id,
+                                            "reason" => err.descriptor(),
                                             "error" => ?err);
+                                    Self::record_disconnect(disconnects, err.descriptor());
                                     channel.close(false);
                                     live_set.remove(&channel_id);
                                     free_set.push(channel_id);
@@ -348,62 +649,284 @@ impl Endpoint {
                                 }
                             });
                     }
-                    ChannelState::Connected(_) => {
-                        // Perform both receive and send operations, disconnecting the channel if there is a fatal error.
-                        Self::ready_op(readiness.is_readable(), || {
-                            let result = channel.receive(now);
+                    // Channel IO is performed once all the events have been collected
+                    ChannelState::Connected(_) => ready.push((channel_id, readiness)),
+                    _ => {
+                        panic!("Disconnected channel on data poll");
+                    }
+                }
+            }
+        }
+        self.events.clear();
 
-                            logging::debug!(log, "received data";
-                                "context" => "sync",
-                                "channel_id" => channel_id,
-                                "result" => ?result);
+        // Perform both receive and send operations on the ready channels, disconnecting the channel if
+        // there is a fatal error.
+        let failures = Self::partitioned(pool, channels, |offset, chunk, failures| {
+            let in_chunk = |&&(channel_id, _): &&(ChannelId, mio::Ready)| {
+                channel_id >= offset && channel_id < offset + chunk.len()
+            };
 
-                            result.map(|_| ())
-                        })
-                        .and_then(|_| {
-                            Self::ready_op(readiness.is_writable(), || {
-                                let result = channel.send(now);
+            for &(channel_id, readiness) in ready.iter().filter(in_chunk) {
+                let channel = &mut chunk[channel_id - offset];
 
-                                logging::debug!(log, "sent data";
-                                    "context" => "sync",
-                                    "channel_id" => channel_id,
-                                    "result" => ?result);
+                let result = Self::ready_op(readiness.is_readable(), || {
+                    let result = channel.receive(now);
 
-                                result.map(|_| ())
-                            })
-                        })
-                        .unwrap_or_else(|err| {
-                            logging::error!(log, "disconnecting live channel due to error";
+                    logging::debug!(log, "received data";
+                        "context" => "sync",
+                        "channel_id" => channel_id,
+                        "result" => ?result);
+
+                    // Nothing is received once the client has closed the connection. The data received
+                    // up to that point is left for pulling, the channel is dropped once the ingress
+                    // timeout elapses.
+                    match result {
+                        Ok(0) => Err(NetworkError::Wait),
+                        result => result.map(|_| ()),
+                    }
+                })
+                .and_then(|_| {
+                    Self::ready_op(readiness.is_writable(), || {
+                        let result = channel.send(now);
+
+                        logging::debug!(log, "sent data";
+                            "context" => "sync",
+                            "channel_id" => channel_id,
+                            "result" => ?result);
+
+                        // Done once the write buffer has been flushed
+                        match result {
+                            Ok(0) => Err(NetworkError::Wait),
+                            result => result.map(|_| ()),
+                        }
+                    })
+                });
+
+                if let Err(err) = result {
+                    failures.push((channel_id, err));
+                }
+            }
+        });
+
+        for (channel_id, err) in failures {
+            logging::error!(log, "dropping live channel due to error";
                             "context" => "sync",
                             "channel_id" => channel_id,
+                            "reason" => err.descriptor(),
                             "error" => ?err);
 
-                            channel.deregister(data_poll).expect("Deregistration failed");
-                            channel.close(true);
-                            live_set.remove(&channel_id);
-                            free_set.push(channel_id);
-                            changes.push(ConnectionChange::Disconnected(channel_id));
-                        });
-                    }
-                    _ => {
-                        panic!("Disconnected channel on data poll");
-                    }
+            Self::record_disconnect(disconnects, err.descriptor());
+
+            let channel = &mut channels[channel_id];
+            channel.deregister(data_poll).expect("Deregistration failed");
+            live_set.remove(&channel_id);
+            Self::drop_channel(channel, channel_id, now, resumable, free_set, changes);
+        }
+
+        for (channel_id, user_id, roles, resume_token) in resumes {
+            let resumed = resumable.resume(user_id, &resume_token, now, Self::RESUME_GRACE);
+
+            let (channel_id, suspended_id) = match resumed {
+                Some(prior_id) => {
+                    logging::info!(log, "resuming session";
+                                   "context" => "sync",
+                                   "timestamp_ms" => timestamp_millis(),
+                                   "channel_id" => channel_id,
+                                   "prior_channel_id" => prior_id,
+                                   "user_id" => user_id);
+
+                    // Move the new connection into the slot of the suspended channel, so that the
+                    // session continues under the original channel id.
+                    channels[channel_id]
+                        .deregister(data_poll)
+                        .expect("Deregistration failed");
+                    channels.swap(channel_id, prior_id);
+                    live_set.remove(&channel_id);
+
+                    let channel = &mut channels[prior_id];
+                    channel.rebind(prior_id);
+                    channel
+                        .register(prior_id, data_poll)
+                        .expect("Stream registration failed");
+
+                    changes.push(ConnectionChange::Resumed(user_id, prior_id));
+                    (prior_id, Some(channel_id))
                 }
+                _ => {
+                    logging::info!(log, "session not resumable, connecting afresh";
+                                   "context" => "sync",
+                                   "channel_id" => channel_id,
+                                   "user_id" => user_id);
+
+                    changes.push(ConnectionChange::Connected(user_id, roles, channel_id));
+                    (channel_id, None)
+                }
+            };
+
+            Self::accept(&mut channels[channel_id], user_id);
+            live_set.insert(channel_id);
+
+            // The frames the suspended session had yet to send follow the acceptance, the slot it
+            // vacated is free afterwards
+            if let Some(suspended_id) = suspended_id {
+                let (channel, suspended) = Self::channel_pair(channels, channel_id, suspended_id);
+                channel.take_over(suspended);
+                free_set.push(suspended_id);
             }
         }
-        self.events.clear();
 
         logging::trace!(log, "network sync finished";
                         "context" => "sync",
                         "change_count" => changes.len());
     }
 
+    /// Close all channels, notifying the connected clients if requested. Suspended sessions are
+    /// released, every connected or suspended channel is reported as disconnected.
+    pub fn shutdown(&mut self, notify: bool) {
+        logging::info!(self.log, "shutting down endpoint";
+                       "context" => "shutdown",
+                       "timestamp_ms" => timestamp_millis(),
+                       "notify" => notify,
+                       "live_count" => self.live.len(),
+                       "resumable_count" => self.resumable.len());
+
+        // Stop accepting new connections
+        if self.listening {
+            self.server_poll.deregister(&self.server).unwrap();
+            self.listening = false;
+        }
+
+        for &channel_id in self.live.iter() {
+            let channel = &mut self.channels[channel_id];
+
+            if let ChannelState::Connected(_) = channel.get_state() {
+                self.changes.push(ConnectionChange::Disconnected(channel_id));
+            }
+
+            Self::record_disconnect(&mut self.disconnects, "shutdown");
+            channel.close(notify);
+            self.free.push(channel_id);
+        }
+        self.live.clear();
+
+        let channels = &mut self.channels;
+        let free_set = &mut self.free;
+        let changes = &mut self.changes;
+        self.resumable.expire(self.current_time, Self::ZERO_TIME, |channel_id| {
+            channels[channel_id].close(false);
+            free_set.push(channel_id);
+            changes.push(ConnectionChange::Disconnected(channel_id));
+        });
+    }
+
     /// Drains all the changes accumulated since the last `sync`
     #[inline]
     pub fn changes(&mut self) -> impl Iterator<Item = ConnectionChange> + '_ {
         self.changes.drain(..)
     }
 
+    /// Issue a fresh resume token and notify the client of the accepted connection.
+    #[inline]
+    fn accept(channel: &mut Channel, user_id: flux::UserId) {
+        let resume_token = channel.issue_resume_token();
+
+        if channel
+            .write_control(ControlFrame::ConnectionAccepted(user_id, resume_token))
+            .has_failed()
+        {
+            panic!("Failure writing connection accepted frame")
+        }
+    }
+
+    /// Close a channel that dropped without an explicit disconnect. Connected channels are suspended so
+    /// the client may resume the session, others are released immediately.
+    #[inline]
+    fn drop_channel(
+        channel: &mut Channel,
+        channel_id: ChannelId,
+        now: time::Instant,
+        resumable: &mut Resumable,
+        free_set: &mut Vec<ChannelId>,
+        changes: &mut Vec<ConnectionChange>,
+    ) {
+        match channel.get_state() {
+            ChannelState::Connected(user_id) => {
+                resumable.suspend(channel_id, user_id, channel.resume_token(), now);
+                channel.suspend();
+                changes.push(ConnectionChange::Suspended(channel_id));
+            }
+            // Channels that never completed the handshake were not reported as connected
+            _ => {
+                channel.close(false);
+                free_set.push(channel_id);
+            }
+        }
+    }
+
+    /// Mutable references to two distinct channels.
+    #[inline]
+    fn channel_pair(
+        channels: &mut [Channel],
+        first: ChannelId,
+        second: ChannelId,
+    ) -> (&mut Channel, &mut Channel) {
+        match first < second {
+            true => {
+                let (head, tail) = channels.split_at_mut(second);
+                (&mut head[first], &mut tail[0])
+            }
+            _ => {
+                let (head, tail) = channels.split_at_mut(first);
+                (&mut tail[0], &mut head[second])
+            }
+        }
+    }
+
+    /// Run the operation on the channels partitioned into contiguous chunks, one per worker thread of the
+    /// pool, or on a single chunk on the calling thread without a pool. Each chunk is borrowed exclusively
+    /// by a single worker, the operation is passed the id of the first channel in the chunk along with a
+    /// list collecting the failed channels.
+    fn partitioned<F>(
+        pool: Option<&rayon::ThreadPool>,
+        channels: &mut [Channel],
+        op: F,
+    ) -> Vec<(ChannelId, ErrorType)>
+    where
+        F: Fn(ChannelId, &mut [Channel], &mut Vec<(ChannelId, ErrorType)>) + Sync,
+    {
+        let mut failures = Vec::new();
+
+        match pool {
+            Some(pool) if channels.len() > 1 => {
+                let threads = pool.current_num_threads();
+                let chunk_size = (channels.len() + threads - 1) / threads;
+                let mut chunk_failures: Vec<Vec<_>> = (0..threads).map(|_| Vec::new()).collect();
+                let op = &op;
+
+                pool.scope(|scope| {
+                    for ((idx, chunk), failures) in channels
+                        .chunks_mut(chunk_size)
+                        .enumerate()
+                        .zip(chunk_failures.iter_mut())
+                    {
+                        scope.spawn(move |_| op(idx * chunk_size, chunk, failures));
+                    }
+                });
+
+                chunk_failures.into_iter().for_each(|chunk| failures.extend(chunk));
+            }
+            _ => op(0, channels, &mut failures),
+        }
+
+        failures
+    }
+
+    /// Count a closed channel under the given reason.
+    #[inline]
+    fn record_disconnect(disconnects: &mut HashMap<&'static str, u64>, reason: &'static str) {
+        *disconnects.entry(reason).or_insert(0) += 1;
+    }
+
     #[inline]
     fn ready_op<F: FnMut() -> NetworkResult<()>>(trigger: bool, mut op: F) -> Result<(), ErrorType> {
         if trigger {
@@ -425,16 +948,33 @@ impl Endpoint {
         let now = self.current_time;
         let live_set = &mut self.live;
         let free_set = &mut self.free;
+        let resumable = &mut self.resumable;
         let channels = &mut self.channels;
         let changes = &mut self.changes;
+        let disconnects = &mut self.disconnects;
+        let timeouts = &self.timeouts;
 
         logging::info!(log, "running housekeeping";
                        "context" => "housekeeping",
+                       "timestamp_ms" => timestamp_millis(),
                        "current_time" => ?now,
                        "live_count" => live_set.len(),
                        "free_count" => free_set.len(),
+                       "resumable_count" => resumable.len(),
                        "channel_count" => channels.len());
 
+        // Release the suspended channels whose grace period has elapsed
+        resumable.expire(now, Self::RESUME_GRACE, |channel_id| {
+            logging::info!(log, "suspended session expired";
+                           "context" => "housekeeping",
+                           "timestamp_ms" => timestamp_millis(),
+                           "channel_id" => channel_id);
+
+            channels[channel_id].close(false);
+            free_set.push(channel_id);
+            changes.push(ConnectionChange::Disconnected(channel_id));
+        });
+
         live_set.retain(|&channel_id| {
             let channel = &mut channels[channel_id];
 
@@ -442,36 +982,76 @@ impl Endpoint {
                             "context" => "housekeeping",
                             "channel_id" => channel_id);
 
+            let ingress_timed_out = channel.last_ingress_elapsed(now) >= timeouts.ingress;
+            let ingress_progress = channel.take_ingress_progress();
+
+            // Handshakes are sent in one go. A channel that has been around for an entire housekeeping
+            // interval and is still trickling in data without completing the handshake is dropped early.
+            let handshake_stalled = |timestamp| {
+                ingress_progress > 0 && now.duration_since(timestamp) >= timeouts.housekeeping
+            };
+
             let retain = match channel.get_state() {
-                ChannelState::Handshake(timestamp) => now.duration_since(timestamp) < Self::HANDSHAKE_TIMEOUT,
+                ChannelState::Handshake(timestamp) => {
+                    now.duration_since(timestamp) < timeouts.handshake && !handshake_stalled(timestamp)
+                }
+                ChannelState::Connected(_) if ingress_timed_out => false,
                 ChannelState::Connected(user_id) => {
-                    if channel.last_ingress_elapsed(now) >= Self::INGRESS_TIMEOUT {
-                        return false;
+                    // Shrink the buffers of quiet channels, the keepalive fits into what is left. Channels
+                    // that were already compacted release nothing on subsequent passes.
+                    if channel.last_ingress_elapsed(now) >= Self::IDLE_COMPACTION {
+                        let released = channel.compact();
+
+                        if released > 0 {
+                            logging::debug!(log, "released idle channel buffers";
+                                            "context" => "housekeeping",
+                                            "channel_id" => channel_id,
+                                            "bytes" => released);
+                        }
                     }
 
-                    if channel.last_egress_elapsed(now) >= Self::KEEPALIVE_INTERVAL
-                        && channel
-                            .write_control(ControlFrame::Keepalive(user_id))
-                            .has_failed()
-                    {
-                        panic!("Fatal write error")
+                    if channel.last_egress_elapsed(now) >= timeouts.keepalive {
+                        let result = channel.write_control(ControlFrame::Keepalive(user_id));
+
+                        if let Err(NetworkError::Fatal(ref err)) = result {
+                            logging::error!(log, "fatal write error";
+                                            "context" => "housekeeping",
+                                            "channel_id" => channel_id,
+                                            "result" => "error",
+                                            "reason" => err.descriptor(),
+                                            "error" => ?err);
+
+                            Self::record_disconnect(disconnects, err.descriptor());
+                            channel.close(true);
+                            changes.push(ConnectionChange::Disconnected(channel_id));
+                            free_set.push(channel_id);
+                            return false;
+                        }
                     }
 
                     true
                 }
+                ChannelState::Suspended(_) => panic!("Suspended channel in live set"),
                 ChannelState::Disconnected => panic!("Disconnected channel in live set"),
             };
 
             // Close the channel in case of a timeout. Don't send a notification since the connection is
             // most likely dead.
             if !retain {
-                logging::warn!(log, "disconnecting channel due to timeout";
+                let reason = match channel.get_state() {
+                    ChannelState::Handshake(timestamp) if handshake_stalled(timestamp) => "handshake_stalled",
+                    ChannelState::Handshake(_) => "handshake_timeout",
+                    _ => "ingress_timeout",
+                };
+
+                logging::warn!(log, "dropping channel due to timeout";
                               "context" => "housekeeping",
-                              "channel_id" => channel_id);
+                              "timestamp_ms" => timestamp_millis(),
+                              "channel_id" => channel_id,
+                              "reason" => reason);
 
-                channel.close(false);
-                free_set.push(channel_id);
-                changes.push(ConnectionChange::Disconnected(channel_id));
+                Self::record_disconnect(disconnects, reason);
+                Self::drop_channel(channel, channel_id, now, resumable, free_set, changes);
             }
 
             retain
@@ -484,6 +1064,7 @@ impl Endpoint {
             id: channel_id,
             channel: &mut self.channels[channel_id],
             changes: &mut self.changes,
+            disconnects: &mut self.disconnects,
             live: &mut self.live,
             free: &mut self.free,
             log: &self.log,
@@ -495,6 +1076,7 @@ struct CommCtx<'a> {
     id: ChannelId,
     channel: &'a mut Channel,
     changes: &'a mut Vec<ConnectionChange>,
+    disconnects: &'a mut HashMap<&'static str, u64>,
     live: &'a mut IndexSet<ChannelId>,
     free: &'a mut Vec<ChannelId>,
     log: &'a logging::Logger,
@@ -502,10 +1084,1139 @@ struct CommCtx<'a> {
 
 impl<'a> CommCtx<'a> {
     #[inline]
-    fn disconnect(&mut self, notify: bool) {
+    fn disconnect(&mut self, notify: bool, reason: &'static str) {
+        Endpoint::record_disconnect(self.disconnects, reason);
         self.channel.close(notify);
         self.changes.push(ConnectionChange::Disconnected(self.id));
         self.live.remove(&self.id);
         self.free.push(self.id);
     }
 }
+
+/// Book-keeping of suspended sessions, that is channels of dropped clients that may still be resumed.
+struct Resumable {
+    sessions: IndexMap<ChannelId, SuspendedSession>,
+}
+
+struct SuspendedSession {
+    user_id: flux::UserId,
+    resume_token: ResumeToken,
+    since: time::Instant,
+}
+
+impl Resumable {
+    #[inline]
+    fn new() -> Resumable {
+        Resumable {
+            sessions: IndexMap::new(),
+        }
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Suspend the session on the given channel.
+    #[inline]
+    fn suspend(
+        &mut self,
+        channel_id: ChannelId,
+        user_id: flux::UserId,
+        resume_token: ResumeToken,
+        now: time::Instant,
+    ) {
+        self.sessions.insert(
+            channel_id,
+            SuspendedSession {
+                user_id,
+                resume_token,
+                since: now,
+            },
+        );
+    }
+
+    /// Find the suspended session matching the user and resume token. The session is removed and the
+    /// id of its channel returned if it is still within the grace period.
+    fn resume(
+        &mut self,
+        user_id: flux::UserId,
+        resume_token: &ResumeToken,
+        now: time::Instant,
+        grace: time::Duration,
+    ) -> Option<ChannelId> {
+        let channel_id = self
+            .sessions
+            .iter()
+            .find(|(_, session)| {
+                session.user_id == user_id
+                    && now.duration_since(session.since) < grace
+                    && crypto::constant_time_eq(&session.resume_token, resume_token)
+            })
+            .map(|(&channel_id, _)| channel_id)?;
+
+        self.sessions.swap_remove(&channel_id);
+
+        Some(channel_id)
+    }
+
+    /// Remove all sessions that have exceeded the grace period, passing their channel ids to the
+    /// supplied closure.
+    fn expire<F: FnMut(ChannelId)>(&mut self, now: time::Instant, grace: time::Duration, mut expired: F) {
+        self.sessions.retain(|&channel_id, session| {
+            if now.duration_since(session.since) < grace {
+                return true;
+            }
+
+            expired(channel_id);
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::buffer::Buffer;
+    use crate::net::frame::{Category, Header, RESUME_TOKEN_SIZE};
+    use crate::net::channel::tests::{
+        make_connection_token, open_client_channel, open_resuming_client_channel, serialize_handshake,
+    };
+    use crate::net::channel::ConnectionToken;
+    use crate::net::support::{SizedRead, SizedWrite};
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use flux::time::ManualClock;
+    use hashbrown::{HashMap, HashSet};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const KEY: [u8; crypto::KEY_SIZE] = [33; crypto::KEY_SIZE];
+
+    const GRACE: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn test_resume_within_grace() {
+        let now = Instant::now();
+        let mut resumable = Resumable::new();
+
+        resumable.suspend(3, 8008, [7u8; 16], now);
+        resumable.suspend(5, 9009, [9u8; 16], now);
+
+        assert_eq!(resumable.resume(8008, &[7u8; 16], now + Duration::from_secs(10), GRACE), Some(3));
+        assert_eq!(resumable.len(), 1);
+
+        // A session can only be resumed once
+        assert_eq!(resumable.resume(8008, &[7u8; 16], now + Duration::from_secs(10), GRACE), None);
+    }
+
+    #[test]
+    fn test_resume_mismatch() {
+        let now = Instant::now();
+        let mut resumable = Resumable::new();
+
+        resumable.suspend(3, 8008, [7u8; 16], now);
+
+        // Wrong token
+        assert_eq!(resumable.resume(8008, &[8u8; 16], now, GRACE), None);
+        // Wrong user
+        assert_eq!(resumable.resume(9009, &[7u8; 16], now, GRACE), None);
+
+        assert_eq!(resumable.len(), 1);
+    }
+
+    #[test]
+    fn test_resume_expired() {
+        let now = Instant::now();
+        let mut resumable = Resumable::new();
+
+        resumable.suspend(3, 8008, [7u8; 16], now);
+        resumable.suspend(5, 9009, [9u8; 16], now + Duration::from_secs(20));
+
+        let later = now + GRACE;
+
+        assert_eq!(resumable.resume(8008, &[7u8; 16], later, GRACE), None);
+
+        let mut expired = Vec::new();
+        resumable.expire(later, GRACE, |channel_id| expired.push(channel_id));
+
+        assert_eq!(expired, vec![3]);
+        assert_eq!(resumable.len(), 1);
+        assert_eq!(resumable.resume(9009, &[9u8; 16], later, GRACE), Some(5));
+    }
+
+    fn make_endpoint(clock: &ManualClock) -> Endpoint {
+        make_endpoint_with_timeouts(clock, Timeouts::default())
+    }
+
+    fn make_endpoint_with_timeouts(clock: &ManualClock, timeouts: Timeouts) -> Endpoint {
+        let log = logging::Logger::root(logging::Discard, logging::o!());
+        let clock = Arc::new(clock.clone());
+        let mut endpoint =
+            Endpoint::with_clock("127.0.0.1:0", SessionKey::new(KEY), timeouts, clock, &log).unwrap();
+        endpoint.init();
+        endpoint
+    }
+
+    /// Sync the endpoint until the predicate holds. The time on the manual clock stands still, the loop
+    /// only waits for the loopback network operations to complete.
+    fn sync_until<F: FnMut(&mut Endpoint) -> bool>(endpoint: &mut Endpoint, clock: &ManualClock, mut pred: F) {
+        for _ in 0..10000 {
+            endpoint.sync(clock.now());
+
+            if pred(endpoint) {
+                return;
+            }
+
+            thread::yield_now();
+        }
+
+        panic!("Endpoint failed to reach the expected state")
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let _client = TcpStream::connect(endpoint.local_addr().unwrap()).unwrap();
+
+        sync_until(&mut endpoint, &clock, |endpoint| endpoint.live.len() == 1);
+
+        // The channel survives until the handshake timeout elapses
+        clock.advance(Timeouts::default().housekeeping);
+        endpoint.sync(clock.now());
+        assert_eq!(endpoint.live.len(), 1);
+
+        clock.advance(Timeouts::default().handshake);
+        endpoint.sync(clock.now());
+        assert_eq!(endpoint.live.len(), 0);
+        assert_eq!(endpoint.free, vec![0]);
+        assert_eq!(endpoint.disconnect_metrics().get("handshake_timeout"), Some(&1));
+
+        // The channel was never reported as connected
+        assert_eq!(endpoint.changes().count(), 0);
+    }
+
+    #[test]
+    fn test_handshake_stalled() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let mut client = TcpStream::connect(endpoint.local_addr().unwrap()).unwrap();
+
+        sync_until(&mut endpoint, &clock, |endpoint| endpoint.live.len() == 1);
+
+        // Trickle in a few bytes at a time, well within the handshake timeout
+        for _ in 0..3 {
+            client.write_all(&[0u8; 4]).unwrap();
+        }
+
+        sync_until(&mut endpoint, &clock, |endpoint| endpoint.channels[0].ingress_progress() == 12);
+
+        clock.advance(Timeouts::default().housekeeping);
+        endpoint.sync(clock.now());
+
+        assert!(Timeouts::default().housekeeping < Timeouts::default().handshake);
+        assert_eq!(endpoint.live.len(), 0);
+        assert_eq!(endpoint.disconnect_metrics().get("handshake_stalled"), Some(&1));
+        assert_eq!(endpoint.disconnect_metrics().get("handshake_timeout"), None);
+    }
+
+    /// Connect a new client and send the handshake, resuming the session of the resume token.
+    fn send_handshake(endpoint: &Endpoint, resume_token: &ResumeToken) -> (TcpStream, ConnectionToken) {
+        let mut client = TcpStream::connect(endpoint.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut token = make_connection_token();
+        token.version = flux::VERSION_ID;
+        token.protocol = flux::PROTOCOL_ID;
+
+        let mut handshake = Buffer::new(65536);
+        serialize_handshake(&mut handshake, &token, &KEY, resume_token);
+        client.write_all(handshake.read_slice()).unwrap();
+
+        (client, token)
+    }
+
+    /// Connect a new client and complete the handshake.
+    fn connect_client(endpoint: &mut Endpoint, clock: &ManualClock) -> (TcpStream, ChannelId) {
+        let (client, token) = send_handshake(endpoint, &[0u8; RESUME_TOKEN_SIZE]);
+
+        let mut changes = Vec::new();
+        sync_until(endpoint, clock, |endpoint| {
+            changes.extend(endpoint.changes());
+            !changes.is_empty()
+        });
+
+        match changes[0] {
+            ConnectionChange::Connected(user_id, roles, channel_id) => {
+                assert_eq!(user_id, token.data.user_id);
+                assert_eq!(roles, token.data.roles);
+                (client, channel_id)
+            }
+            change => panic!("Unexpected change {:?}", change),
+        }
+    }
+
+    /// Payload of a fixed size that fails to serialize when poisoned.
+    struct TestPayload {
+        poisoned: bool,
+    }
+
+    impl Serialize for TestPayload {
+        fn serialize<W: SizedWrite>(&self, stream: &mut W) -> NetworkResult<()> {
+            if self.poisoned {
+                return Err(NetworkError::Fatal(ErrorType::Serialization));
+            }
+
+            match stream.free_capacity() >= 1024 {
+                true => stream.write_all(&[0u8; 1024]).map_err(Into::into),
+                _ => Err(NetworkError::Wait),
+            }
+        }
+    }
+
+    #[test]
+    fn test_push_backpressure() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let (_client1, channel1) = connect_client(&mut endpoint, &clock);
+        let (_client2, channel2) = connect_client(&mut endpoint, &clock);
+
+        // Without syncing, the write buffer of the channel eventually fills up
+        let mut batch = PayloadBatch::new();
+        for _ in 0..4096 {
+            batch.push(TestPayload { poisoned: false });
+        }
+
+        let mut result = Ok((0, 0));
+        for _ in 0..100 {
+            result = endpoint.push(channel1, &mut batch, Priority::High);
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        assert_eq!(result, Err(NetworkError::Wait));
+        assert!(endpoint.live.contains(&channel1));
+
+        // Fatal errors close the channel
+        let mut batch = PayloadBatch::new();
+        batch.push(TestPayload { poisoned: true });
+
+        assert_eq!(
+            endpoint.push(channel2, &mut batch, Priority::High),
+            Err(NetworkError::Fatal(ErrorType::Serialization))
+        );
+        assert!(!endpoint.live.contains(&channel2));
+        assert!(endpoint.live.contains(&channel1));
+        assert_eq!(endpoint.disconnect_metrics().get("serialization"), Some(&1));
+
+        match endpoint.changes().next() {
+            Some(ConnectionChange::Disconnected(id)) => assert_eq!(id, channel2),
+            change => panic!("Unexpected change {:?}", change),
+        }
+    }
+
+    #[test]
+    fn test_disconnect_metrics_crypto() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let (mut client, channel_id) = connect_client(&mut endpoint, &clock);
+
+        // Intact frame carrying a payload that fails authentication
+        let payload = [7u8; 64];
+        let mut header = Header {
+            category: Category::Payload.into(),
+            sequence: 0,
+            payload_size: payload.len() as u16,
+            checksum: 0,
+        };
+        header.checksum = header.compute_checksum(&payload);
+
+        let mut frame = Vec::new();
+        header.write(&mut frame).unwrap();
+        frame.extend_from_slice(&payload);
+        client.write_all(&frame).unwrap();
+
+        let mut batch = PayloadBatch::<Counter>::new();
+        sync_until(&mut endpoint, &clock, |endpoint| {
+            endpoint.pull(channel_id, &mut batch);
+            !endpoint.live.contains(&channel_id)
+        });
+
+        assert_eq!(endpoint.disconnect_metrics().get("crypto"), Some(&1));
+        assert_eq!(endpoint.disconnect_metrics().len(), 1);
+    }
+
+    #[test]
+    fn test_shutdown_notify() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+        let mut tracker = ChangeTracker::default();
+
+        let mut client = MockClient::connect(&endpoint, 8008);
+
+        drive_until(|| {
+            client.sync();
+            serve(&mut endpoint, &clock, &mut tracker);
+            tracker.connected.len() == 1
+        });
+
+        endpoint.shutdown(true);
+
+        assert!(endpoint.live.is_empty());
+        assert_eq!(endpoint.disconnect_metrics().get("shutdown"), Some(&1));
+        tracker.apply(&mut endpoint);
+        assert!(tracker.connected.is_empty());
+
+        // The client is notified before the connection is closed
+        let mut closed = false;
+        drive_until(|| {
+            drop(client.channel.receive(Instant::now()));
+
+            while let Ok(frame) = client.channel.read() {
+                if let Frame::Control(ControlFrame::ConnectionClosed(user_id)) = frame {
+                    assert_eq!(user_id, 8008);
+                    closed = true;
+                }
+            }
+
+            closed
+        });
+    }
+
+    #[test]
+    fn test_health() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+        let max_stall = Duration::from_secs(1);
+
+        let health = endpoint.health();
+        assert!(health.listening);
+        assert_eq!(health.live_count, 0);
+        assert!(health.is_alive(clock.now(), max_stall));
+
+        // The endpoint is considered stalled unless the game loop keeps syncing it
+        clock.advance(Duration::from_secs(2));
+        assert!(!endpoint.health().is_alive(clock.now(), max_stall));
+
+        endpoint.sync(clock.now());
+        assert!(endpoint.health().is_alive(clock.now(), max_stall));
+
+        // Endpoints that were shut down no longer listen
+        endpoint.shutdown(false);
+        let health = endpoint.health();
+        assert!(!health.listening);
+        assert!(!health.is_alive(clock.now(), max_stall));
+    }
+
+    #[test]
+    fn test_configured_timeouts() {
+        let clock = ManualClock::new();
+        let timeouts = Timeouts {
+            handshake: Duration::from_millis(100),
+            ingress: Duration::from_millis(400),
+            keepalive: Duration::from_millis(100),
+            housekeeping: Duration::from_millis(50),
+        };
+        let mut endpoint = make_endpoint_with_timeouts(&clock, timeouts);
+
+        let (_client, channel_id) = connect_client(&mut endpoint, &clock);
+
+        // The idle channel survives until the configured ingress timeout elapses
+        clock.advance(Duration::from_millis(350));
+        endpoint.sync(clock.now());
+        assert_eq!(endpoint.live.len(), 1);
+
+        clock.advance(Duration::from_millis(50));
+        endpoint.sync(clock.now());
+        assert_eq!(endpoint.live.len(), 0);
+        assert_eq!(endpoint.disconnect_metrics().get("ingress_timeout"), Some(&1));
+
+        match endpoint.changes().next() {
+            Some(ConnectionChange::Suspended(id)) => assert_eq!(id, channel_id),
+            change => panic!("Unexpected change {:?}", change),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Keepalive interval must be shorter than the ingress timeout")]
+    fn test_invalid_timeouts() {
+        let timeouts = Timeouts {
+            keepalive: Duration::from_secs(30),
+            ..Timeouts::default()
+        };
+
+        make_endpoint_with_timeouts(&ManualClock::new(), timeouts);
+    }
+
+    #[test]
+    fn test_set_timeouts() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let (_client, channel_id) = connect_client(&mut endpoint, &clock);
+
+        endpoint.set_timeouts(Timeouts {
+            handshake: Duration::from_millis(100),
+            ingress: Duration::from_millis(400),
+            keepalive: Duration::from_millis(100),
+            housekeeping: Duration::from_millis(50),
+        });
+
+        // The running endpoint applies the shortened ingress timeout
+        clock.advance(Duration::from_millis(400));
+        endpoint.sync(clock.now());
+        assert!(!endpoint.live.contains(&channel_id));
+
+        match endpoint.changes().next() {
+            Some(ConnectionChange::Suspended(id)) => assert_eq!(id, channel_id),
+            change => panic!("Unexpected change {:?}", change),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Keepalive interval must be shorter than the ingress timeout")]
+    fn test_set_invalid_timeouts() {
+        let mut endpoint = make_endpoint(&ManualClock::new());
+
+        endpoint.set_timeouts(Timeouts {
+            keepalive: Duration::from_secs(30),
+            ..Timeouts::default()
+        });
+    }
+
+    #[test]
+    fn test_keepalive_and_ingress_timeout() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let (mut client, channel_id) = connect_client(&mut endpoint, &clock);
+
+        // The connection acceptance is sent on the next sync
+        let mut data = [0u8; 1024];
+        endpoint.sync(clock.now());
+        assert!(client.read(&mut data).unwrap() > 0);
+
+        // A keepalive is sent once the channel has been idle for the keepalive interval
+        clock.advance(Timeouts::default().keepalive);
+        endpoint.sync(clock.now());
+        assert!(client.read(&mut data).unwrap() > 0);
+        assert_eq!(endpoint.live.len(), 1);
+
+        // The client never sends anything, the channel is suspended once the ingress timeout elapses
+        clock.advance(Timeouts::default().ingress);
+        endpoint.sync(clock.now());
+        assert_eq!(endpoint.live.len(), 0);
+
+        match endpoint.changes().next() {
+            Some(ConnectionChange::Suspended(id)) => assert_eq!(id, channel_id),
+            change => panic!("Unexpected change {:?}", change),
+        }
+    }
+
+    #[test]
+    fn test_idle_compaction() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let (mut client, channel_id) = connect_client(&mut endpoint, &clock);
+
+        let mut data = [0u8; 1024];
+        endpoint.sync(clock.now());
+        assert!(client.read(&mut data).unwrap() > 0);
+
+        let capacity = endpoint.channels[channel_id].buffer_capacity();
+
+        // Quiet channels are left alone until the idle threshold elapses
+        clock.advance(Timeouts::default().housekeeping);
+        endpoint.sync(clock.now());
+        assert!(client.read(&mut data).unwrap() > 0);
+        assert_eq!(endpoint.channels[channel_id].buffer_capacity(), capacity);
+
+        clock.advance(Endpoint::IDLE_COMPACTION);
+        endpoint.sync(clock.now());
+
+        // The keepalive is still delivered from the shrunk write buffer
+        assert!(client.read(&mut data).unwrap() > 0);
+        let compacted = endpoint.channels[channel_id].buffer_capacity();
+        assert!(compacted < capacity);
+        assert_eq!(endpoint.live.len(), 1);
+
+        // Compacted channels stay at their minimum capacity on subsequent passes
+        clock.advance(Timeouts::default().housekeeping);
+        endpoint.sync(clock.now());
+        assert!(client.read(&mut data).unwrap() > 0);
+        assert_eq!(endpoint.channels[channel_id].buffer_capacity(), compacted);
+    }
+
+    #[test]
+    fn test_resume_session() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let (_client, channel_id) = connect_client(&mut endpoint, &clock);
+        let resume_token = endpoint.channels[channel_id].resume_token();
+
+        // Suspend the session through the ingress timeout
+        clock.advance(Timeouts::default().ingress);
+        endpoint.sync(clock.now());
+        match endpoint.changes().next() {
+            Some(ConnectionChange::Suspended(id)) => assert_eq!(id, channel_id),
+            change => panic!("Unexpected change {:?}", change),
+        }
+
+        let (_client, token) = send_handshake(&endpoint, &resume_token);
+
+        let mut changes = Vec::new();
+        sync_until(&mut endpoint, &clock, |endpoint| {
+            changes.extend(endpoint.changes());
+            !changes.is_empty()
+        });
+
+        match changes[0] {
+            ConnectionChange::Resumed(user_id, id) => {
+                assert_eq!(user_id, token.data.user_id);
+                assert_eq!(id, channel_id);
+            }
+            change => panic!("Unexpected change {:?}", change),
+        }
+        assert_eq!(endpoint.live.iter().cloned().collect::<Vec<_>>(), vec![channel_id]);
+
+        // Housekeeping only visits the resumed channel, the vacated handshake slot is free
+        clock.advance(Timeouts::default().housekeeping);
+        endpoint.sync(clock.now());
+        assert_eq!(endpoint.live.len(), 1);
+        assert!(endpoint.free.iter().all(|&id| id != channel_id));
+    }
+
+    #[test]
+    fn test_resume_pending_egress() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let (mut client, channel_id) = connect_client(&mut endpoint, &clock);
+        let resume_token = endpoint.channels[channel_id].resume_token();
+
+        let mut data = [0u8; 1024];
+        endpoint.sync(clock.now());
+        assert!(client.read(&mut data).unwrap() > 0);
+
+        // The client drops before the payload is sent, housekeeping runs ahead of the sends
+        let mut batch = PayloadBatch::new();
+        batch.push(Counter(7));
+        endpoint.push(channel_id, &mut batch, Priority::High).unwrap();
+        drop(client);
+
+        clock.advance(Timeouts::default().ingress);
+        endpoint.sync(clock.now());
+
+        match endpoint.changes().next() {
+            Some(ConnectionChange::Suspended(id)) => assert_eq!(id, channel_id),
+            change => panic!("Unexpected change {:?}", change),
+        }
+
+        let mut client = MockClient::resume(&endpoint, 8008, &resume_token);
+
+        // The payload retained by the suspended channel is delivered on the resumed connection
+        drive_until(|| {
+            client.sync();
+            endpoint.sync(clock.now());
+            client.received == vec![7]
+        });
+
+        match endpoint.changes().next() {
+            Some(ConnectionChange::Resumed(user_id, id)) => {
+                assert_eq!(user_id, client.user_id);
+                assert_eq!(id, channel_id);
+            }
+            change => panic!("Unexpected change {:?}", change),
+        }
+
+        clock.advance(Timeouts::default().housekeeping);
+        endpoint.sync(clock.now());
+        client.sync();
+
+        assert_eq!(endpoint.live.iter().cloned().collect::<Vec<_>>(), vec![channel_id]);
+        assert_eq!(endpoint.channels[channel_id].get_state(), ChannelState::Connected(client.user_id));
+    }
+
+    #[test]
+    fn test_rotate_keys() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let (_client, channel_id) = connect_client(&mut endpoint, &clock);
+
+        endpoint.rotate_keys(channel_id).unwrap();
+
+        // Only a single rotation can be in flight, the channel stays open
+        assert_eq!(endpoint.rotate_keys(channel_id).unwrap_err(), NetworkError::Wait);
+        assert_eq!(endpoint.live.len(), 1);
+    }
+
+    #[test]
+    fn test_accept_pending_connections() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        // All the connections are pending by the time the listener is polled
+        let _clients: Vec<_> = (0..3)
+            .map(|_| TcpStream::connect(endpoint.local_addr().unwrap()).unwrap())
+            .collect();
+
+        sync_until(&mut endpoint, &clock, |endpoint| endpoint.live.len() == 3);
+    }
+
+    #[test]
+    fn test_receive_after_client_close() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let (mut client, channel_id) = connect_client(&mut endpoint, &clock);
+
+        // Drain the connection acceptance so that the connection isn't reset on close
+        let mut data = [0u8; 1024];
+        endpoint.sync(clock.now());
+        assert!(client.read(&mut data).unwrap() > 0);
+
+        clock.advance(Duration::from_secs(1));
+
+        client.write_all(&[0u8; 8]).unwrap();
+        drop(client);
+
+        // Syncing the closed connection doesn't get stuck receiving
+        sync_until(&mut endpoint, &clock, |endpoint| {
+            endpoint.channels[channel_id].last_ingress_elapsed(clock.now()) == Duration::from_secs(0)
+        });
+
+        for _ in 0..10 {
+            endpoint.sync(clock.now());
+        }
+
+        // The channel is kept until the ingress timeout elapses
+        assert!(endpoint.live.contains(&channel_id));
+
+        clock.advance(Timeouts::default().ingress);
+        endpoint.sync(clock.now());
+        assert!(!endpoint.live.contains(&channel_id));
+
+        match endpoint.changes().next() {
+            Some(ConnectionChange::Suspended(id)) => assert_eq!(id, channel_id),
+            change => panic!("Unexpected change {:?}", change),
+        }
+    }
+
+    #[test]
+    fn test_send_after_flush() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+
+        let (mut client, channel_id) = connect_client(&mut endpoint, &clock);
+
+        // Flush the connection acceptance
+        let mut data = [0u8; 1024];
+        endpoint.sync(clock.now());
+        assert!(client.read(&mut data).unwrap() > 0);
+
+        clock.advance(Duration::from_secs(1));
+
+        // Incoming data also reports the channel writable, with nothing left to send
+        client.write_all(&[0u8; 8]).unwrap();
+
+        sync_until(&mut endpoint, &clock, |endpoint| {
+            endpoint.channels[channel_id].last_ingress_elapsed(clock.now()) == Duration::from_secs(0)
+        });
+
+        assert!(endpoint.live.contains(&channel_id));
+    }
+
+    /// Payload carrying a single value, exchanged with the mock clients.
+    #[derive(Clone)]
+    struct Counter(u64);
+
+    impl Serialize for Counter {
+        fn serialize<W: SizedWrite>(&self, stream: &mut W) -> NetworkResult<()> {
+            match stream.free_capacity() >= 8 {
+                true => stream.write_u64::<BigEndian>(self.0).map_err(Into::into),
+                _ => Err(NetworkError::Wait),
+            }
+        }
+    }
+
+    impl Deserialize for Counter {
+        fn deserialize<R: SizedRead>(stream: &mut R) -> NetworkResult<Self> {
+            match stream.remaining_data() >= 8 {
+                true => Ok(Counter(stream.read_u64::<BigEndian>()?)),
+                _ => Err(NetworkError::Wait),
+            }
+        }
+    }
+
+    /// Client connecting to the endpoint over loopback, driving a channel of its own.
+    struct MockClient {
+        user_id: flux::UserId,
+        channel: Channel,
+        received: Vec<u64>,
+    }
+
+    impl MockClient {
+        /// Connect to the endpoint and queue the handshake.
+        fn connect(endpoint: &Endpoint, user_id: flux::UserId) -> MockClient {
+            Self::resume(endpoint, user_id, &[0u8; RESUME_TOKEN_SIZE])
+        }
+
+        /// Connect to the endpoint and queue the handshake resuming the session of the resume token.
+        fn resume(endpoint: &Endpoint, user_id: flux::UserId, resume_token: &ResumeToken) -> MockClient {
+            let mut token = make_connection_token();
+            token.version = flux::VERSION_ID;
+            token.protocol = flux::PROTOCOL_ID;
+            token.data.user_id = user_id;
+
+            let stream = TcpStream::connect(endpoint.local_addr().unwrap()).unwrap();
+            let stream = mio::net::TcpStream::from_stream(stream).unwrap();
+
+            MockClient {
+                user_id,
+                channel: open_resuming_client_channel(stream, &token, &KEY, resume_token),
+                received: Vec::new(),
+            }
+        }
+
+        /// Queue a payload frame carrying the supplied value.
+        fn send(&mut self, value: u64) {
+            let mut batch = PayloadBatch::new();
+            batch.push(Counter(value));
+            self.channel.write_payload(&mut batch, Priority::High).unwrap();
+        }
+
+        /// Transmit the queued data and read in the payload frames sent by the endpoint.
+        fn sync(&mut self) {
+            let now = Instant::now();
+            assert!(!self.channel.send(now).has_failed());
+            assert!(!self.channel.receive(now).has_failed());
+
+            loop {
+                match self.channel.read() {
+                    Ok(Frame::Payload(pinfo)) => {
+                        let mut batch = PayloadBatch::<Counter>::new();
+                        self.channel.read_payload(&mut batch, pinfo).unwrap();
+                        self.received.extend(batch.drain().map(|counter| counter.0));
+                    }
+                    Ok(Frame::Control(_)) => (),
+                    Err(NetworkError::Wait) => break,
+                    Err(err) => panic!("Client read failed {:?}", err),
+                }
+            }
+        }
+    }
+
+    /// Mirror of the endpoint channels, reconstructed from the stream of connection changes. Each change
+    /// is checked against the state the channel is in.
+    #[derive(Default)]
+    struct ChangeTracker {
+        connected: HashMap<ChannelId, flux::UserId>,
+        suspended: HashSet<ChannelId>,
+    }
+
+    impl ChangeTracker {
+        fn apply(&mut self, endpoint: &mut Endpoint) {
+            for change in endpoint.changes() {
+                match change {
+                    ConnectionChange::Connected(user_id, _, channel_id) => {
+                        assert!(!self.suspended.contains(&channel_id));
+                        assert_eq!(self.connected.insert(channel_id, user_id), None);
+                    }
+                    ConnectionChange::Suspended(channel_id) => {
+                        assert!(self.connected.remove(&channel_id).is_some());
+                        self.suspended.insert(channel_id);
+                    }
+                    ConnectionChange::Resumed(..) => panic!("Unexpected change {:?}", change),
+                    ConnectionChange::Disconnected(channel_id) => {
+                        let connected = self.connected.remove(&channel_id).is_some();
+                        assert!(connected || self.suspended.remove(&channel_id));
+                    }
+                }
+            }
+
+            assert!(self.connected.keys().all(|channel_id| endpoint.live.contains(channel_id)));
+            assert!(self.suspended.iter().all(|channel_id| !endpoint.live.contains(channel_id)));
+        }
+    }
+
+    /// Sync the endpoint and echo the payloads received on the connected channels back to the clients.
+    /// Returns the number of payloads received.
+    fn serve(endpoint: &mut Endpoint, clock: &ManualClock, tracker: &mut ChangeTracker) -> usize {
+        endpoint.sync(clock.now());
+        tracker.apply(endpoint);
+
+        let mut received = 0;
+        let connected: Vec<ChannelId> = tracker.connected.keys().cloned().collect();
+
+        for channel_id in connected {
+            let mut batch = PayloadBatch::<Counter>::new();
+
+            for _ in 0..8 {
+                if !endpoint.live.contains(&channel_id) {
+                    break;
+                }
+
+                endpoint.pull(channel_id, &mut batch);
+            }
+
+            received += batch.len();
+
+            if batch.len() > 0 && endpoint.live.contains(&channel_id) {
+                endpoint.push(channel_id, &mut batch, Priority::High).unwrap();
+            }
+        }
+
+        tracker.apply(endpoint);
+        received
+    }
+
+    /// Run the step until it reports completion. Only waits for the loopback network operations.
+    fn drive_until<F: FnMut() -> bool>(mut step: F) {
+        for _ in 0..10000 {
+            if step() {
+                return;
+            }
+
+            thread::yield_now();
+        }
+
+        panic!("Load failed to reach the expected state")
+    }
+
+    /// Shape of the load generated by the mock clients.
+    struct LoadProfile {
+        clients: usize,
+        rounds: usize,
+        /// Payload frames sent by each client per round.
+        messages: u64,
+        /// Time elapsing on the endpoint clock between two frames of a client.
+        interval: Duration,
+        /// Worker threads syncing the endpoint channels.
+        threads: usize,
+    }
+
+    /// Run rounds of clients connecting, exchanging payload frames with the endpoint and disconnecting.
+    /// Half of the clients close the connection gracefully, the other half simply drop it.
+    fn run_load(profile: &LoadProfile) {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+        let mut tracker = ChangeTracker::default();
+        endpoint.set_threads(profile.threads);
+
+        for round in 0..profile.rounds {
+            let mut clients: Vec<_> = (0..profile.clients)
+                .map(|idx| MockClient::connect(&endpoint, (round * profile.clients + idx) as flux::UserId))
+                .collect();
+
+            drive_until(|| {
+                clients.iter_mut().for_each(MockClient::sync);
+                serve(&mut endpoint, &clock, &mut tracker);
+                tracker.connected.len() == profile.clients
+            });
+
+            assert_eq!(endpoint.live.len(), profile.clients);
+            assert!(clients
+                .iter()
+                .all(|client| tracker.connected.values().any(|&user_id| user_id == client.user_id)));
+
+            let mut received = 0;
+
+            for value in 0..profile.messages {
+                for client in clients.iter_mut() {
+                    client.send(value);
+                    client.sync();
+                }
+
+                clock.advance(profile.interval);
+                received += serve(&mut endpoint, &clock, &mut tracker);
+            }
+
+            drive_until(|| {
+                clients.iter_mut().for_each(MockClient::sync);
+                received += serve(&mut endpoint, &clock, &mut tracker);
+                clients
+                    .iter()
+                    .all(|client| client.received.len() == profile.messages as usize)
+            });
+
+            assert_eq!(received, profile.clients * profile.messages as usize);
+            assert!(clients
+                .iter()
+                .all(|client| client.received.iter().cloned().eq(0..profile.messages)));
+
+            let (graceful, dropped): (Vec<_>, Vec<_>) =
+                clients.into_iter().partition(|client| client.user_id % 2 == 0);
+
+            // Drain the connection before closing so that it isn't reset
+            for mut client in graceful {
+                client.sync();
+                client.channel.close(true);
+            }
+
+            let dropped_count = dropped.len();
+            drop(dropped);
+
+            drive_until(|| {
+                serve(&mut endpoint, &clock, &mut tracker);
+                tracker.connected.len() + tracker.suspended.len() == dropped_count
+            });
+
+            // Dropped connections are suspended once the ingress timeout elapses at the latest
+            clock.advance(Timeouts::default().ingress);
+            serve(&mut endpoint, &clock, &mut tracker);
+
+            assert!(tracker.connected.is_empty());
+            assert_eq!(tracker.suspended.len(), dropped_count);
+
+            clock.advance(Endpoint::RESUME_GRACE);
+            serve(&mut endpoint, &clock, &mut tracker);
+
+            assert!(tracker.suspended.is_empty());
+            assert_eq!(endpoint.live.len(), 0);
+            assert_eq!(endpoint.resumable.len(), 0);
+            assert_eq!(endpoint.free.len(), endpoint.channels.len());
+        }
+
+        // The channels are recycled across the rounds
+        assert_eq!(endpoint.channels.len(), profile.clients);
+    }
+
+    #[test]
+    fn test_connection_churn() {
+        run_load(&LoadProfile {
+            clients: 16,
+            rounds: 3,
+            messages: 20,
+            interval: Duration::from_millis(50),
+            threads: 1,
+        });
+    }
+
+    #[test]
+    fn test_connection_churn_parallel() {
+        run_load(&LoadProfile {
+            clients: 16,
+            rounds: 3,
+            messages: 20,
+            interval: Duration::from_millis(50),
+            threads: 4,
+        });
+    }
+
+    /// Connect the clients to an endpoint syncing on the given number of threads and have every client
+    /// exchange a sequence of payloads with the endpoint. Returns the values echoed back to each user.
+    fn exchange(threads: usize, clients: u64) -> Vec<(flux::UserId, Vec<u64>)> {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+        let mut tracker = ChangeTracker::default();
+        endpoint.set_threads(threads);
+
+        let mut clients: Vec<_> = (0..clients)
+            .map(|user_id| MockClient::connect(&endpoint, user_id))
+            .collect();
+
+        drive_until(|| {
+            clients.iter_mut().for_each(MockClient::sync);
+            serve(&mut endpoint, &clock, &mut tracker);
+            tracker.connected.len() == clients.len()
+        });
+
+        for client in clients.iter_mut() {
+            for value in 0..8 {
+                client.send(client.user_id * 100 + value);
+            }
+        }
+
+        drive_until(|| {
+            clients.iter_mut().for_each(MockClient::sync);
+            serve(&mut endpoint, &clock, &mut tracker);
+            clients.iter().all(|client| client.received.len() == 8)
+        });
+
+        clients
+            .into_iter()
+            .map(|client| (client.user_id, client.received))
+            .collect()
+    }
+
+    #[test]
+    fn test_parallel_sync() {
+        let serial = exchange(1, 48);
+        let parallel = exchange(4, 48);
+
+        assert_eq!(serial, parallel);
+        for (user_id, received) in serial {
+            let expected: Vec<_> = (0..8).map(|value| user_id * 100 + value).collect();
+            assert_eq!(received, expected);
+        }
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let clock = ManualClock::new();
+        let mut endpoint = make_endpoint(&clock);
+        let mut tracker = ChangeTracker::default();
+
+        let mut clients: Vec<_> = (0..4).map(|user_id| MockClient::connect(&endpoint, user_id)).collect();
+
+        drive_until(|| {
+            clients.iter_mut().for_each(MockClient::sync);
+            serve(&mut endpoint, &clock, &mut tracker);
+            tracker.connected.len() == clients.len()
+        });
+
+        // Fill up the write buffer of the first client's channel
+        let congested = tracker.connected.iter().find(|&(_, &user_id)| user_id == 0).map(|(&id, _)| id);
+        let congested = congested.unwrap();
+
+        let mut filler = PayloadBatch::new();
+        for value in 0..4096 {
+            filler.push(Counter(value));
+        }
+
+        while endpoint.push(congested, &mut filler.clone(), Priority::High).is_ok() {}
+
+        // Channels still in the handshake are left out of the broadcast
+        let _pending = TcpStream::connect(endpoint.local_addr().unwrap()).unwrap();
+        drive_until(|| {
+            endpoint.sync(clock.now());
+            endpoint.live.len() == clients.len() + 1
+        });
+
+        // Empty batches are not written at all
+        assert!(endpoint.broadcast(&mut PayloadBatch::<Counter>::new(), Priority::High).is_empty());
+
+        let mut batch = PayloadBatch::new();
+        for value in 0..5 {
+            batch.push(Counter(1000 + value));
+        }
+
+        // The congested channel is reported, the others take the entire batch
+        assert_eq!(endpoint.broadcast(&mut batch, Priority::High), vec![congested]);
+        assert_eq!(batch.len(), 0);
+        assert!(endpoint.live.contains(&congested));
+
+        let expected: Vec<_> = (1000..1005).collect();
+
+        drive_until(|| {
+            endpoint.sync(clock.now());
+            clients.iter_mut().for_each(MockClient::sync);
+            clients.iter().skip(1).all(|client| client.received.len() == expected.len())
+        });
+
+        for client in clients.iter().skip(1) {
+            assert_eq!(client.received, expected);
+        }
+    }
+}
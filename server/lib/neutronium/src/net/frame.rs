@@ -1,13 +1,24 @@
 use crate::net::support::{ErrorType, NetworkError, SizedWrite};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use flux::crypto;
 use flux::UserId;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
 
-#[derive(Debug, Eq, PartialEq)]
+pub const RESUME_TOKEN_SIZE: usize = 16;
+
+/// Token issued on connection acceptance, allowing a dropped client to resume its session.
+pub type ResumeToken = [u8; RESUME_TOKEN_SIZE];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Category {
     Payload = 0,
     Keepalive = 1,
     ConnectionAccepted = 2,
     ConnectionClosed = 3,
+    KeyRotate = 4,
+    KeyRotateAck = 5,
+    Ack = 6,
 }
 
 impl From<Category> for u8 {
@@ -17,23 +28,121 @@ impl From<Category> for u8 {
     }
 }
 
+impl TryFrom<u8> for Category {
+    type Error = NetworkError;
+
+    #[inline]
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Category::Payload,
+            1 => Category::Keepalive,
+            2 => Category::ConnectionAccepted,
+            3 => Category::ConnectionClosed,
+            4 => Category::KeyRotate,
+            5 => Category::KeyRotateAck,
+            6 => Category::Ack,
+            _ => return Err(NetworkError::Fatal(ErrorType::IncorrectCategory)),
+        })
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 #[repr(transparent)]
 pub struct PayloadInfo(usize);
 
 impl PayloadInfo {
-    /// Selects the correct slice of the payload buffer
+    /// Selects the correct slice of the payload buffer. Fails if the payload info refers to data
+    /// beyond the end of the buffer.
+    #[inline]
+    pub(crate) fn select(self, payload: &[u8]) -> Result<&[u8], NetworkError> {
+        match payload.get(..self.0) {
+            Some(slice) => Ok(slice),
+            _ => Err(NetworkError::Fatal(ErrorType::PayloadTooLarge)),
+        }
+    }
+}
+
+/// Header preceding the encrypted frame payload. The layout is fixed, all integers are big endian:
+///
+/// | Offset | Size | Field                                       |
+/// |--------|------|---------------------------------------------|
+/// | 0      | 1    | Category                                    |
+/// | 1      | 8    | Sequence                                    |
+/// | 9      | 2    | Payload size, including the mac             |
+/// | 11     | 4    | CRC32 checksum of the above and the payload |
+///
+/// The checksum only serves to cheaply reject corrupt frames, all other fields are authenticated by the
+/// AEAD: the category is part of the additional data, the sequence is the nonce and the payload size
+/// is implied by the ciphertext length. The checksum itself can't be authenticated as it covers the MAC.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Header {
+    pub category: u8,
+    pub sequence: u64,
+    pub payload_size: u16,
+    pub checksum: u32,
+}
+
+impl Header {
+    pub const SIZE: usize = 15;
+
     #[inline]
-    pub(crate) fn select(self, payload: &[u8]) -> &[u8] {
-        &payload[..self.0]
+    pub fn read(mut buffer: &[u8]) -> Result<Header, NetworkError> {
+        let category = buffer.read_u8()?;
+        let sequence = buffer.read_u64::<BigEndian>()?;
+        let payload_size = buffer.read_u16::<BigEndian>()?;
+        let checksum = buffer.read_u32::<BigEndian>()?;
+
+        Ok(Header {
+            category,
+            sequence,
+            payload_size,
+            checksum,
+        })
+    }
+
+    /// Computes the checksum of the header fields preceding it and the supplied encrypted payload.
+    #[inline]
+    pub fn compute_checksum(&self, payload: &[u8]) -> u32 {
+        let mut fields = [0u8; Header::SIZE - 4];
+
+        {
+            let mut stream = &mut fields[..];
+            stream.write_u8(self.category).expect("Error writing category");
+            stream
+                .write_u64::<BigEndian>(self.sequence)
+                .expect("Error writing sequence");
+            stream
+                .write_u16::<BigEndian>(self.payload_size)
+                .expect("Error writing payload size");
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&fields);
+        hasher.update(payload);
+        hasher.finalize()
+    }
+
+    #[inline]
+    pub fn write<W: Write>(&self, stream: &mut W) -> Result<(), NetworkError> {
+        stream.write_u8(self.category)?;
+        stream.write_u64::<BigEndian>(self.sequence)?;
+        stream.write_u16::<BigEndian>(self.payload_size)?;
+        stream.write_u32::<BigEndian>(self.checksum)?;
+        Ok(())
     }
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum ControlFrame {
     Keepalive(UserId),
-    ConnectionAccepted(UserId),
+    ConnectionAccepted(UserId, ResumeToken),
     ConnectionClosed(UserId),
+    KeyRotate {
+        new_server_key: [u8; crypto::KEY_SIZE],
+        new_client_key: [u8; crypto::KEY_SIZE],
+    },
+    KeyRotateAck,
+    Ack(u64),
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -45,16 +154,32 @@ pub enum Frame {
 impl Frame {
     #[inline]
     pub fn read(mut buffer: &[u8], category: u8) -> Result<Frame, NetworkError> {
-        if category > Category::ConnectionClosed.into() {
-            return Err(NetworkError::Fatal(ErrorType::IncorrectCategory));
-        }
+        Ok(match Category::try_from(category)? {
+            Category::Payload => Frame::Payload(PayloadInfo(buffer.len())),
+            Category::Keepalive => Frame::Control(ControlFrame::Keepalive(buffer.read_u64::<BigEndian>()?)),
+            Category::ConnectionAccepted => {
+                let user_id = buffer.read_u64::<BigEndian>()?;
+                let mut resume_token = [0u8; RESUME_TOKEN_SIZE];
+                buffer.read_exact(&mut resume_token)?;
 
-        Ok(match category {
-            0 => Frame::Payload(PayloadInfo(buffer.len())),
-            1 => Frame::Control(ControlFrame::Keepalive(buffer.read_u64::<BigEndian>()?)),
-            2 => Frame::Control(ControlFrame::ConnectionAccepted(buffer.read_u64::<BigEndian>()?)),
-            3 => Frame::Control(ControlFrame::ConnectionClosed(buffer.read_u64::<BigEndian>()?)),
-            _ => unreachable!(),
+                Frame::Control(ControlFrame::ConnectionAccepted(user_id, resume_token))
+            }
+            Category::ConnectionClosed => {
+                Frame::Control(ControlFrame::ConnectionClosed(buffer.read_u64::<BigEndian>()?))
+            }
+            Category::KeyRotate => {
+                let mut new_server_key = [0u8; crypto::KEY_SIZE];
+                let mut new_client_key = [0u8; crypto::KEY_SIZE];
+                buffer.read_exact(&mut new_server_key)?;
+                buffer.read_exact(&mut new_client_key)?;
+
+                Frame::Control(ControlFrame::KeyRotate {
+                    new_server_key,
+                    new_client_key,
+                })
+            }
+            Category::KeyRotateAck => Frame::Control(ControlFrame::KeyRotateAck),
+            Category::Ack => Frame::Control(ControlFrame::Ack(buffer.read_u64::<BigEndian>()?)),
         })
     }
 }
@@ -64,8 +189,11 @@ impl ControlFrame {
     pub fn category(&self) -> Category {
         match self {
             ControlFrame::Keepalive(_) => Category::Keepalive,
-            ControlFrame::ConnectionAccepted(_) => Category::ConnectionAccepted,
+            ControlFrame::ConnectionAccepted(..) => Category::ConnectionAccepted,
             ControlFrame::ConnectionClosed(_) => Category::ConnectionClosed,
+            ControlFrame::KeyRotate { .. } => Category::KeyRotate,
+            ControlFrame::KeyRotateAck => Category::KeyRotateAck,
+            ControlFrame::Ack(_) => Category::Ack,
         }
     }
 
@@ -73,9 +201,156 @@ impl ControlFrame {
     pub fn write<W: SizedWrite>(self, stream: &mut W) -> Result<(), NetworkError> {
         match self {
             ControlFrame::Keepalive(user_id) => stream.write_u64::<BigEndian>(user_id)?,
-            ControlFrame::ConnectionAccepted(user_id) => stream.write_u64::<BigEndian>(user_id)?,
+            ControlFrame::ConnectionAccepted(user_id, resume_token) => {
+                stream.write_u64::<BigEndian>(user_id)?;
+                stream.write_all(&resume_token)?;
+            }
             ControlFrame::ConnectionClosed(user_id) => stream.write_u64::<BigEndian>(user_id)?,
+            ControlFrame::KeyRotate {
+                new_server_key,
+                new_client_key,
+            } => {
+                stream.write_all(&new_server_key)?;
+                stream.write_all(&new_client_key)?;
+            }
+            ControlFrame::KeyRotateAck => (),
+            ControlFrame::Ack(sequence) => stream.write_u64::<BigEndian>(sequence)?,
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_info_select() {
+        let payload = [1u8, 2, 3, 4, 5];
+
+        assert_eq!(PayloadInfo(3).select(&payload).unwrap(), &[1, 2, 3]);
+        assert_eq!(PayloadInfo(5).select(&payload).unwrap(), &payload[..]);
+    }
+
+    #[test]
+    fn test_payload_info_select_empty() {
+        let payload = [1u8, 2, 3, 4, 5];
+
+        assert!(PayloadInfo(0).select(&payload).unwrap().is_empty());
+        assert!(PayloadInfo(0).select(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_payload_info_select_out_of_bounds() {
+        let payload = [1u8, 2, 3, 4, 5];
+
+        assert_eq!(
+            PayloadInfo(6).select(&payload).unwrap_err(),
+            NetworkError::Fatal(ErrorType::PayloadTooLarge)
+        );
+        assert_eq!(
+            PayloadInfo(usize::max_value()).select(&payload).unwrap_err(),
+            NetworkError::Fatal(ErrorType::PayloadTooLarge)
+        );
+    }
+
+    #[test]
+    fn test_category_try_from() {
+        let categories = [
+            Category::Payload,
+            Category::Keepalive,
+            Category::ConnectionAccepted,
+            Category::ConnectionClosed,
+            Category::KeyRotate,
+            Category::KeyRotateAck,
+            Category::Ack,
+        ];
+
+        for (value, &category) in categories.iter().enumerate() {
+            assert_eq!(Category::try_from(value as u8).unwrap(), category);
+            assert_eq!(u8::from(category), value as u8);
+        }
+    }
+
+    #[test]
+    fn test_category_try_from_invalid() {
+        for value in 7..=u8::max_value() {
+            assert_eq!(
+                Category::try_from(value).unwrap_err(),
+                NetworkError::Fatal(ErrorType::IncorrectCategory)
+            );
+        }
+    }
+
+    #[test]
+    fn test_frame_read_invalid_category() {
+        assert_eq!(
+            Frame::read(&[0u8; 8], 7).unwrap_err(),
+            NetworkError::Fatal(ErrorType::IncorrectCategory)
+        );
+    }
+
+    #[test]
+    fn test_header_write() {
+        let header = Header {
+            category: Category::Ack.into(),
+            sequence: 0x0102_0304_0506_0708,
+            payload_size: 0x090a,
+            checksum: 0x0b0c_0d0e,
+        };
+
+        let mut data = Vec::new();
+        header.write(&mut data).unwrap();
+
+        assert_eq!(data.len(), Header::SIZE);
+        assert_eq!(&data[..], &[6, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14]);
+    }
+
+    #[test]
+    fn test_header_read() {
+        let data = [2u8, 0, 0, 0, 0, 0, 0, 1, 0, 0, 32, 0xde, 0xad, 0xbe, 0xef, 99];
+
+        assert_eq!(
+            Header::read(&data).unwrap(),
+            Header {
+                category: Category::ConnectionAccepted.into(),
+                sequence: 256,
+                payload_size: 32,
+                checksum: 0xdead_beef,
+            }
+        );
+    }
+
+    #[test]
+    fn test_header_checksum() {
+        let payload = [7u8; 32];
+        let header = Header {
+            category: Category::Payload.into(),
+            sequence: 5,
+            payload_size: payload.len() as u16,
+            checksum: 0,
+        };
+
+        let checksum = header.compute_checksum(&payload);
+
+        // Every header field preceding the checksum is covered
+        assert_ne!(checksum, crc32fast::hash(&payload));
+        assert_ne!(checksum, Header { category: 1, ..header }.compute_checksum(&payload));
+        assert_ne!(checksum, Header { sequence: 6, ..header }.compute_checksum(&payload));
+        assert_ne!(checksum, Header { payload_size: 31, ..header }.compute_checksum(&payload));
+        assert_ne!(checksum, header.compute_checksum(&payload[1..]));
+
+        // The checksum field itself is not
+        assert_eq!(checksum, Header { checksum: 1, ..header }.compute_checksum(&payload));
+    }
+
+    #[test]
+    fn test_header_read_truncated() {
+        let data = [0u8; Header::SIZE - 1];
+
+        match Header::read(&data) {
+            Err(NetworkError::Fatal(_)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
@@ -1,6 +1,9 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::error;
 use std::fmt;
 use std::io;
+use std::marker::PhantomData;
+use std::mem;
 use std::net;
 
 pub type NetworkResult<T> = Result<T, NetworkError>;
@@ -13,11 +16,32 @@ pub enum NetworkError {
 
 impl fmt::Display for NetworkError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{:?}", self)
+        match self {
+            NetworkError::Wait => write!(f, "Operation would block"),
+            NetworkError::Fatal(error_type) => write!(f, "Fatal network error: {}", error_type),
+        }
     }
 }
 
-impl error::Error for NetworkError {}
+impl NetworkError {
+    /// Stable label of the error, suitable for aggregating errors in logs and metrics.
+    #[inline]
+    pub fn descriptor(&self) -> &'static str {
+        match self {
+            NetworkError::Wait => "wait",
+            NetworkError::Fatal(error_type) => error_type.descriptor(),
+        }
+    }
+}
+
+impl error::Error for NetworkError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match self {
+            NetworkError::Wait => None,
+            NetworkError::Fatal(error_type) => Some(error_type),
+        }
+    }
+}
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum ErrorType {
@@ -31,19 +55,72 @@ pub enum ErrorType {
     VersionMismatch,
     SequenceMismatch,
     Serialization,
+    Checksum,
     Crypto,
+    CipherSuiteMismatch,
+    KeyRotation,
     AddrParse,
     Io(io::ErrorKind),
 }
 
+impl ErrorType {
+    /// Stable label of the error type, suitable for aggregating errors in logs and metrics. The labels
+    /// must not change once published.
+    #[inline]
+    pub fn descriptor(&self) -> &'static str {
+        match self {
+            ErrorType::Expired => "expired",
+            ErrorType::Duplicate => "duplicate",
+            ErrorType::AlreadyConnected => "already_connected",
+            ErrorType::PayloadTooLarge => "payload_too_large",
+            ErrorType::EmptyPayload => "empty_payload",
+            ErrorType::IncorrectCategory => "incorrect_category",
+            ErrorType::ProtocolMismatch => "protocol_mismatch",
+            ErrorType::VersionMismatch => "version_mismatch",
+            ErrorType::SequenceMismatch => "sequence_mismatch",
+            ErrorType::Serialization => "serialization",
+            ErrorType::Checksum => "checksum",
+            ErrorType::Crypto => "crypto",
+            ErrorType::CipherSuiteMismatch => "cipher_suite_mismatch",
+            ErrorType::KeyRotation => "key_rotation",
+            ErrorType::AddrParse => "addr_parse",
+            ErrorType::Io(_) => "io",
+        }
+    }
+}
+
 impl fmt::Display for ErrorType {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{:?}", self)
+        match self {
+            ErrorType::Expired => write!(f, "Connection token expired"),
+            ErrorType::Duplicate => write!(f, "Duplicate connection token"),
+            ErrorType::AlreadyConnected => write!(f, "User already connected"),
+            ErrorType::PayloadTooLarge => write!(f, "Payload too large"),
+            ErrorType::EmptyPayload => write!(f, "Empty payload"),
+            ErrorType::IncorrectCategory => write!(f, "Incorrect frame category"),
+            ErrorType::ProtocolMismatch => write!(f, "Protocol mismatch"),
+            ErrorType::VersionMismatch => write!(f, "Version mismatch"),
+            ErrorType::SequenceMismatch => write!(f, "Sequence mismatch"),
+            ErrorType::Serialization => write!(f, "Serialization failed"),
+            ErrorType::Checksum => write!(f, "Checksum mismatch"),
+            ErrorType::Crypto => write!(f, "Encryption or authentication failed"),
+            ErrorType::CipherSuiteMismatch => write!(f, "Cipher suite mismatch"),
+            ErrorType::KeyRotation => write!(f, "Key rotation failed"),
+            ErrorType::AddrParse => write!(f, "Invalid network address"),
+            ErrorType::Io(kind) => write!(f, "IO error: {:?}", kind),
+        }
     }
 }
 
 impl error::Error for ErrorType {}
 
+impl From<ErrorType> for NetworkError {
+    #[inline]
+    fn from(error_type: ErrorType) -> Self {
+        NetworkError::Fatal(error_type)
+    }
+}
+
 impl From<io::Error> for NetworkError {
     #[inline]
     fn from(io_error: io::Error) -> Self {
@@ -107,31 +184,207 @@ impl SizedRead for io::Cursor<&[u8]> {
 ///
 /// Should return `Error::Wait` in case there is not enough capacity in the stream.
 pub trait Serialize {
+    /// Upper bound of the serialized size of any instance, zero if the size is unbounded. Batches skip
+    /// writing bounded messages upfront when the stream can't hold this many bytes.
+    const MAX_SIZE: usize = 0;
+
     fn serialize<W: SizedWrite>(&self, stream: &mut W) -> NetworkResult<()>;
 }
 
+/// Larger of the two sizes, usable in constant expressions.
+#[inline]
+pub const fn max_size(a: usize, b: usize) -> usize {
+    a * ((a >= b) as usize) + b * ((a < b) as usize)
+}
+
 /// Trait for manually deserialized objects.
 pub trait Deserialize: Sized {
     fn deserialize<R: SizedRead>(stream: &mut R) -> NetworkResult<Self>;
 }
 
+/// Number of bytes an object occupies once serialized. Composite objects use it to validate the free
+/// capacity of the stream before writing any of their parts.
+pub trait SerializedSize {
+    fn serialized_size(&self) -> usize;
+}
+
+macro_rules! primitive_codec {
+    ($type: ty, $write: ident, $read: ident) => {
+        primitive_codec!($type, |stream: &mut W, value| stream.$write::<BigEndian>(value), |stream: &mut R| {
+            stream.$read::<BigEndian>()
+        });
+    };
+    ($type: ty, $write: expr, $read: expr) => {
+        impl SerializedSize for $type {
+            #[inline]
+            fn serialized_size(&self) -> usize {
+                mem::size_of::<$type>()
+            }
+        }
+
+        impl Serialize for $type {
+            const MAX_SIZE: usize = mem::size_of::<$type>();
+
+            #[inline]
+            fn serialize<W: SizedWrite>(&self, stream: &mut W) -> NetworkResult<()> {
+                match stream.free_capacity() >= mem::size_of::<$type>() {
+                    true => ($write)(stream, *self).map_err(Into::into),
+                    _ => Err(NetworkError::Wait),
+                }
+            }
+        }
+
+        impl Deserialize for $type {
+            #[inline]
+            fn deserialize<R: SizedRead>(stream: &mut R) -> NetworkResult<Self> {
+                match stream.remaining_data() >= mem::size_of::<$type>() {
+                    true => ($read)(stream).map_err(Into::into),
+                    _ => Err(NetworkError::Wait),
+                }
+            }
+        }
+    };
+}
+
+primitive_codec!(u8, |stream: &mut W, value| stream.write_u8(value), |stream: &mut R| stream.read_u8());
+primitive_codec!(i8, |stream: &mut W, value| stream.write_i8(value), |stream: &mut R| stream.read_i8());
+primitive_codec!(u16, write_u16, read_u16);
+primitive_codec!(i16, write_i16, read_i16);
+primitive_codec!(u32, write_u32, read_u32);
+primitive_codec!(i32, write_i32, read_i32);
+primitive_codec!(u64, write_u64, read_u64);
+primitive_codec!(i64, write_i64, read_i64);
+primitive_codec!(f32, write_f32, read_f32);
+primitive_codec!(f64, write_f64, read_f64);
+primitive_codec!(
+    bool,
+    |stream: &mut W, value| stream.write_u8(value as u8),
+    |stream: &mut R| stream.read_u8().map(|value| value != 0)
+);
+
+/// Size of the length prefix preceding serde encoded values.
+pub const SERDE_PREFIX_SIZE: usize = 2;
+
+/// Write a serde serializable value into the stream in a compact binary encoding, prefixed with its
+/// encoded length. Nothing is written in case the stream lacks the capacity to hold the entire value.
+pub fn serialize_serde<T, W>(value: &T, stream: &mut W) -> NetworkResult<()>
+where
+    T: serde::Serialize,
+    W: SizedWrite,
+{
+    let size = bincode::serialized_size(value).map_err(|_| ErrorType::Serialization)? as usize;
+
+    if size > u16::max_value() as usize {
+        return Err(NetworkError::Fatal(ErrorType::PayloadTooLarge));
+    }
+
+    if stream.free_capacity() < SERDE_PREFIX_SIZE + size {
+        return Err(NetworkError::Wait);
+    }
+
+    stream.write_u16::<BigEndian>(size as u16)?;
+    bincode::serialize_into(&mut *stream, value).map_err(|_| ErrorType::Serialization)?;
+
+    Ok(())
+}
+
+/// Read a value written by `serialize_serde` from the stream. The encoded value must span exactly
+/// the prefixed length.
+pub fn deserialize_serde<T, R>(stream: &mut R) -> NetworkResult<T>
+where
+    T: serde::de::DeserializeOwned,
+    R: SizedRead,
+{
+    if stream.remaining_data() < SERDE_PREFIX_SIZE {
+        return Err(NetworkError::Wait);
+    }
+
+    let size = stream.read_u16::<BigEndian>()? as usize;
+
+    if stream.remaining_data() < size {
+        return Err(NetworkError::Wait);
+    }
+
+    let mut block = io::Read::take(&mut *stream, size as u64);
+    let value = bincode::deserialize_from(&mut block).map_err(|_| ErrorType::Serialization)?;
+
+    match block.limit() {
+        0 => Ok(value),
+        _ => Err(NetworkError::Fatal(ErrorType::Serialization)),
+    }
+}
+
+/// Implements the network `Serialize` and `Deserialize` traits for a type implementing their serde
+/// counterparts, using the encoding of `serialize_serde`.
+#[macro_export]
+macro_rules! serde_codec {
+    ($name: ty) => {
+        impl $crate::net::support::Serialize for $name {
+            #[inline]
+            fn serialize<W>(&self, stream: &mut W) -> $crate::net::support::NetworkResult<()>
+            where
+                W: $crate::net::support::SizedWrite,
+            {
+                $crate::net::support::serialize_serde(self, stream)
+            }
+        }
+
+        impl $crate::net::support::Deserialize for $name {
+            #[inline]
+            fn deserialize<R>(stream: &mut R) -> $crate::net::support::NetworkResult<Self>
+            where
+                R: $crate::net::support::SizedRead,
+            {
+                $crate::net::support::deserialize_serde(stream)
+            }
+        }
+    };
+}
+
 /// Batched payload messages for efficient serialization/deserialization.
+///
+/// Serialized batches are prefixed with the number of messages they contain, allowing them to be
+/// followed by other data in the same stream.
+#[derive(Clone)]
 pub struct PayloadBatch<P> {
     data: Vec<P>,
 }
 
 impl<P> PayloadBatch<P> {
+    /// Size of the message count prefix.
+    pub const COUNT_SIZE: usize = 2;
+
     /// Creates a new `PayloadBatch` instance.
     #[inline]
     pub fn new() -> PayloadBatch<P> {
         PayloadBatch { data: Vec::new() }
     }
 
+    /// Creates a new `PayloadBatch` instance with room for `capacity` messages without reallocating.
+    #[inline]
+    pub fn with_capacity(capacity: usize) -> PayloadBatch<P> {
+        PayloadBatch {
+            data: Vec::with_capacity(capacity),
+        }
+    }
+
     /// Returns the number of payload messages in the batch.
     #[inline]
     pub fn len(&self) -> usize {
         self.data.len()
     }
+
+    /// Returns the number of payload messages the batch can hold without reallocating.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.data.capacity()
+    }
+
+    /// Reserve room for at least `additional` more payload messages.
+    #[inline]
+    pub fn reserve(&mut self, additional: usize) {
+        self.data.reserve(additional)
+    }
 }
 
 impl<P: Serialize> PayloadBatch<P> {
@@ -147,37 +400,309 @@ impl<P: Serialize> PayloadBatch<P> {
         self.data.drain(..)
     }
 
-    /// Write as many payload messages as possible to the destination stream.
+    /// Write as many payload messages as possible to the destination stream, prefixed with the
+    /// number of messages written.
     #[inline]
-    pub fn write<W: SizedWrite>(&mut self, stream: &mut W) -> NetworkResult<()> {
-        let mut remaining = self.data.len();
+    pub fn write<W: SizedWrite + io::Seek>(&mut self, stream: &mut W) -> NetworkResult<()> {
+        self.drain_to_capacity(stream).map(|_| ())
+    }
+
+    /// Write as many payload messages as the destination stream can hold and remove them from the
+    /// batch. Returns the number of messages written and the number left in the batch.
+    pub fn drain_to_capacity<W>(&mut self, stream: &mut W) -> NetworkResult<(usize, usize)>
+    where
+        W: SizedWrite + io::Seek,
+    {
+        if stream.free_capacity() < Self::COUNT_SIZE {
+            return Err(NetworkError::Wait);
+        }
+
+        // Reserve space for the message count, it is filled in once the messages are written
+        let count_pos = stream.seek(io::SeekFrom::Current(0))?;
+        stream.write_u16::<BigEndian>(0)?;
+
+        let mut count = 0usize;
+
+        for payload in self.data.iter().take(u16::max_value() as usize) {
+            if stream.free_capacity() < P::MAX_SIZE {
+                break;
+            }
 
-        for payload in self.data.iter_mut() {
             match payload.serialize(stream) {
-                Ok(_) => remaining -= 1,
+                Ok(_) => count += 1,
                 Err(NetworkError::Wait) => break,
                 Err(error) => return Err(error),
             }
         }
 
         // Bail out in case nothing could be written into the stream
-        if remaining == self.data.len() {
+        if count == 0 {
             return Err(NetworkError::Wait);
         }
 
-        self.data.truncate(remaining);
-        Ok(())
+        let end_pos = stream.seek(io::SeekFrom::Current(0))?;
+        stream.seek(io::SeekFrom::Start(count_pos))?;
+        stream.write_u16::<BigEndian>(count as u16)?;
+        stream.seek(io::SeekFrom::Start(end_pos))?;
+
+        self.data.drain(..count);
+        Ok((count, self.data.len()))
     }
 }
 
 impl<P: Deserialize> PayloadBatch<P> {
-    /// Read as many messages as possible form the source stream into the current batch.
+    /// Read a batch of messages from the source stream into the current batch. Exactly as many
+    /// messages are read as specified by the count prefix, any trailing data is left in the stream.
     #[inline]
     pub fn read<R: SizedRead>(&mut self, stream: &mut R) -> NetworkResult<()> {
-        while stream.remaining_data() > 0 {
-            self.data.push(P::deserialize(stream)?)
+        if stream.remaining_data() < Self::COUNT_SIZE {
+            return Err(NetworkError::Fatal(ErrorType::Serialization));
+        }
+
+        let count = stream.read_u16::<BigEndian>()? as usize;
+        self.data.reserve(count);
+
+        for _ in 0..count {
+            match P::deserialize(stream) {
+                Ok(payload) => self.data.push(payload),
+                // Truncated batches can never be completed
+                Err(NetworkError::Wait) => return Err(NetworkError::Fatal(ErrorType::Serialization)),
+                Err(error) => return Err(error),
+            }
         }
 
         Ok(())
     }
 }
+
+/// Lazily deserializes the messages of a serialized batch. Messages are only read as the iterator is
+/// advanced, allowing the consumer to stop early without materializing the entire batch.
+///
+/// Each item is a result, the iteration ends after the first error.
+pub struct PayloadReader<'a, P> {
+    cursor: io::Cursor<&'a [u8]>,
+    remaining: usize,
+    _p: PhantomData<P>,
+}
+
+impl<'a, P: Deserialize> PayloadReader<'a, P> {
+    /// Creates a new reader over a serialized batch, consuming the message count prefix.
+    #[inline]
+    pub fn new(data: &'a [u8]) -> NetworkResult<PayloadReader<'a, P>> {
+        let mut cursor = io::Cursor::new(data);
+
+        if cursor.remaining_data() < PayloadBatch::<P>::COUNT_SIZE {
+            return Err(NetworkError::Fatal(ErrorType::Serialization));
+        }
+
+        let remaining = cursor.read_u16::<BigEndian>()? as usize;
+
+        Ok(PayloadReader {
+            cursor,
+            remaining,
+            _p: PhantomData,
+        })
+    }
+
+    /// Number of messages that haven't been read yet.
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+}
+
+impl<'a, P: Deserialize> Iterator for PayloadReader<'a, P> {
+    type Item = NetworkResult<P>;
+
+    #[inline]
+    fn next(&mut self) -> Option<NetworkResult<P>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        self.remaining -= 1;
+
+        match P::deserialize(&mut self.cursor) {
+            Ok(payload) => Some(Ok(payload)),
+            Err(error) => {
+                self.remaining = 0;
+
+                // Truncated batches can never be completed
+                match error {
+                    NetworkError::Wait => Some(Err(NetworkError::Fatal(ErrorType::Serialization))),
+                    error => Some(Err(error)),
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_network_error_display() {
+        assert_eq!(NetworkError::Wait.to_string(), "Operation would block");
+        assert_eq!(
+            NetworkError::Fatal(ErrorType::Checksum).to_string(),
+            "Fatal network error: Checksum mismatch"
+        );
+    }
+
+    #[test]
+    fn test_network_error_source() {
+        assert!(NetworkError::Wait.source().is_none());
+
+        let error = NetworkError::Fatal(ErrorType::Crypto);
+        assert_eq!(error.source().unwrap().to_string(), ErrorType::Crypto.to_string());
+    }
+
+    #[test]
+    fn test_error_type_display() {
+        let expected = [
+            (ErrorType::Expired, "Connection token expired"),
+            (ErrorType::Duplicate, "Duplicate connection token"),
+            (ErrorType::AlreadyConnected, "User already connected"),
+            (ErrorType::PayloadTooLarge, "Payload too large"),
+            (ErrorType::EmptyPayload, "Empty payload"),
+            (ErrorType::IncorrectCategory, "Incorrect frame category"),
+            (ErrorType::ProtocolMismatch, "Protocol mismatch"),
+            (ErrorType::VersionMismatch, "Version mismatch"),
+            (ErrorType::SequenceMismatch, "Sequence mismatch"),
+            (ErrorType::Serialization, "Serialization failed"),
+            (ErrorType::Checksum, "Checksum mismatch"),
+            (ErrorType::Crypto, "Encryption or authentication failed"),
+            (ErrorType::CipherSuiteMismatch, "Cipher suite mismatch"),
+            (ErrorType::KeyRotation, "Key rotation failed"),
+            (ErrorType::AddrParse, "Invalid network address"),
+            (ErrorType::Io(io::ErrorKind::ConnectionReset), "IO error: ConnectionReset"),
+        ];
+
+        for (error_type, text) in expected.iter() {
+            assert_eq!(error_type.to_string(), *text);
+        }
+    }
+
+    #[test]
+    fn test_error_type_descriptor() {
+        let expected = [
+            (ErrorType::Expired, "expired"),
+            (ErrorType::Duplicate, "duplicate"),
+            (ErrorType::AlreadyConnected, "already_connected"),
+            (ErrorType::PayloadTooLarge, "payload_too_large"),
+            (ErrorType::EmptyPayload, "empty_payload"),
+            (ErrorType::IncorrectCategory, "incorrect_category"),
+            (ErrorType::ProtocolMismatch, "protocol_mismatch"),
+            (ErrorType::VersionMismatch, "version_mismatch"),
+            (ErrorType::SequenceMismatch, "sequence_mismatch"),
+            (ErrorType::Serialization, "serialization"),
+            (ErrorType::Checksum, "checksum"),
+            (ErrorType::Crypto, "crypto"),
+            (ErrorType::CipherSuiteMismatch, "cipher_suite_mismatch"),
+            (ErrorType::KeyRotation, "key_rotation"),
+            (ErrorType::AddrParse, "addr_parse"),
+            (ErrorType::Io(io::ErrorKind::ConnectionReset), "io"),
+        ];
+
+        for (error_type, descriptor) in expected.iter() {
+            assert_eq!(error_type.descriptor(), *descriptor);
+        }
+
+        // Labels are unique across variants
+        let mut descriptors: Vec<_> = expected.iter().map(|(_, descriptor)| *descriptor).collect();
+        descriptors.sort();
+        descriptors.dedup();
+        assert_eq!(descriptors.len(), expected.len());
+
+        // IO errors share a single label regardless of the kind
+        assert_eq!(ErrorType::Io(io::ErrorKind::UnexpectedEof).descriptor(), "io");
+    }
+
+    #[test]
+    fn test_network_error_descriptor() {
+        assert_eq!(NetworkError::Wait.descriptor(), "wait");
+        assert_eq!(NetworkError::Fatal(ErrorType::Crypto).descriptor(), "crypto");
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        assert_eq!(
+            NetworkError::from(io::Error::from(io::ErrorKind::WouldBlock)),
+            NetworkError::Wait
+        );
+        assert_eq!(
+            NetworkError::from(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            NetworkError::Fatal(ErrorType::Io(io::ErrorKind::UnexpectedEof))
+        );
+    }
+
+    #[test]
+    fn test_from_error_type() {
+        assert_eq!(
+            NetworkError::from(ErrorType::Expired),
+            NetworkError::Fatal(ErrorType::Expired)
+        );
+    }
+
+    #[test]
+    fn test_drain_to_capacity() {
+        let mut buffer = [0u8; PayloadBatch::<u32>::COUNT_SIZE + 10];
+
+        let mut batch = PayloadBatch::new();
+        for i in 0..5u32 {
+            batch.push(i);
+        }
+
+        // Only two messages fit in the stream, the rest are retained
+        let mut cursor = io::Cursor::new(&mut buffer[..]);
+        assert_eq!(batch.drain_to_capacity(&mut cursor), Ok((2, 3)));
+        assert_eq!(batch.drain().collect::<Vec<_>>(), vec![2, 3, 4]);
+
+        let mut received = PayloadBatch::<u32>::new();
+        received.read(&mut io::Cursor::new(&buffer[..])).unwrap();
+        assert_eq!(received.drain().collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_payload_reader_truncated() {
+        let mut buffer = [0u8; PayloadBatch::<u32>::COUNT_SIZE + 8];
+
+        let mut batch = PayloadBatch::new();
+        batch.push(1u32);
+        batch.push(2u32);
+        batch.write(&mut io::Cursor::new(&mut buffer[..])).unwrap();
+
+        // Cut off the second message
+        let mut reader = PayloadReader::<u32>::new(&buffer[..buffer.len() - 1]).unwrap();
+
+        assert_eq!(reader.next(), Some(Ok(1)));
+        assert_eq!(reader.next(), Some(Err(NetworkError::Fatal(ErrorType::Serialization))));
+        assert_eq!(reader.next(), None);
+    }
+
+    #[test]
+    fn test_batch_with_capacity() {
+        let mut batch = PayloadBatch::with_capacity(64);
+        let capacity = batch.capacity();
+        assert!(capacity >= 64);
+
+        for i in 0..64u32 {
+            batch.push(i);
+        }
+
+        // Filling up the reserved capacity doesn't reallocate
+        assert_eq!(batch.len(), 64);
+        assert_eq!(batch.capacity(), capacity);
+
+        batch.drain().count();
+        batch.reserve(128);
+        assert!(batch.capacity() >= 128);
+    }
+}
@@ -6,6 +6,7 @@ use anymap::AnyMap;
 use indexmap::IndexMap;
 
 use crate::sync::{ReadGuard, RwCell, RwGuard};
+use std::cmp::Ordering;
 use std::fmt::Display;
 use std::intrinsics::type_name;
 use std::marker::Unsize;
@@ -115,6 +116,14 @@ where
         bundle.insert(Arc::new(RwCell::new(trait_obj, guard)));
     }
 
+    /// Remove the object and all its traits registered under the given key. Returns false if the key
+    /// is unknown. The order of the remaining instances is retained.
+    pub fn unregister(&mut self, key: &K) -> bool {
+        let count = self.data.len();
+        self.data.retain(|item_key, _| item_key != key);
+        self.data.len() != count
+    }
+
     /// Iterate over all registered instances with the supplied trait
     pub fn iter<T>(&self) -> impl Iterator<Item = (&K, ReadGuard<WeakBox<T>>)>
     where
@@ -141,10 +150,24 @@ where
             })
     }
 
+    /// Iterate over the keys of all registered instances
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.data.keys()
+    }
+
     /// Get the number of registered instances
     pub fn len(&self) -> usize {
         self.data.len()
     }
+
+    /// Reorder the registered instances using the supplied key comparison. Iteration follows the
+    /// new order afterwards.
+    pub fn sort_by<F>(&mut self, mut compare: F)
+    where
+        F: FnMut(&K, &K) -> Ordering,
+    {
+        self.data.sort_by(|key1, _, key2, _| compare(key1, key2));
+    }
 }
 
 pub type TraitBox<T> = Arc<RwCell<WeakBox<T>>>;
@@ -171,8 +194,7 @@ impl Bundle {
 /// A wrapper around `Box<T>` that deliberately leaks the contents of the inner box.
 /// Used as a crutch to avoid double free-ing memory pointed to by trait objects, as these
 /// normally assume that they fully own both the data and the vtable. When used by the registry,
-/// the leak is acceptable since items in the registry are never meant to be deleted and live
-/// until the end of the program.
+/// the leak is acceptable since the memory is owned and freed by the root object of the bundle.
 pub struct WeakBox<T: ?Sized> {
     item: ManuallyDrop<Box<T>>,
 }
@@ -341,6 +363,29 @@ mod tests {
         let _foo2 = registry.try_get::<Foo>(&123).unwrap().write();
     }
 
+    #[test]
+    fn test_unregister() {
+        let mut registry = Registry::<i32>::new();
+
+        for &id in [1, 2, 3].iter() {
+            registry.register(id, Foo { x: id });
+            registry.register_trait::<Foo, FooTrait>(&id);
+        }
+
+        assert!(registry.unregister(&2));
+        assert!(!registry.unregister(&2));
+
+        assert_eq!(registry.len(), 2);
+        assert!(registry.try_get::<Foo>(&2).is_none());
+        assert!(registry.try_get_trait::<FooTrait>(&2).is_none());
+
+        let remaining: Vec<_> = registry
+            .iter::<FooTrait>()
+            .map(|(&id, inst)| (id, inst.get_x_times_two()))
+            .collect();
+        assert_eq!(remaining, vec![(1, 2), (3, 6)]);
+    }
+
     #[test]
     fn test_iter_contents() {
         let mut registry = Registry::<i32>::new();
@@ -1,3 +1,4 @@
+use std::intrinsics::type_name;
 use std::ops::Deref;
 use std::ops::DerefMut;
 
@@ -21,7 +22,10 @@ impl<T> Take<T> {
     /// Take the value out from the sentinel. The sentinel won't be usable until a value is put back in.
     #[inline]
     pub fn take(&mut self) -> T {
-        self.data.take().expect("Data already taken")
+        match self.data.take() {
+            Some(data) => data,
+            _ => Self::missing(),
+        }
     }
 
     /// Put a value in the sentinel. Any old value will be lost.
@@ -35,6 +39,14 @@ impl<T> Take<T> {
     pub fn is_taken(&self) -> bool {
         self.data.is_none()
     }
+
+    /// Panic naming the type of the missing value, e.g. the resources of a system accessed before the
+    /// system was initialized.
+    #[cold]
+    #[inline(never)]
+    fn missing() -> ! {
+        panic!("Data already taken, no {} value present", unsafe { type_name::<T>() })
+    }
 }
 
 impl<T> Deref for Take<T> {
@@ -42,14 +54,20 @@ impl<T> Deref for Take<T> {
 
     #[inline]
     fn deref(&self) -> &T {
-        self.data.as_ref().expect("Data already taken")
+        match self.data {
+            Some(ref data) => data,
+            _ => Self::missing(),
+        }
     }
 }
 
 impl<T> DerefMut for Take<T> {
     #[inline]
     fn deref_mut(&mut self) -> &mut T {
-        self.data.as_mut().expect("Data already taken")
+        match self.data {
+            Some(ref mut data) => data,
+            _ => Self::missing(),
+        }
     }
 }
 
@@ -110,4 +128,11 @@ mod tests {
         sentinel.take();
         sentinel.take();
     }
+
+    #[test]
+    #[should_panic(expected = "Data already taken, no i32 value present")]
+    fn test_panic_deref_empty_names_type() {
+        let sentinel = Take::<i32>::empty();
+        let _result = *sentinel;
+    }
 }
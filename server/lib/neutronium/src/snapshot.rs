@@ -0,0 +1,276 @@
+use byteorder::{BigEndian, ReadBytesExt};
+use std::error;
+use std::fmt;
+use std::io;
+use std::str;
+
+/// Snapshot files start with the magic bytes followed by the format version.
+pub const MAGIC: [u8; 4] = *b"NSNP";
+pub const MAGIC_DELTA: [u8; 4] = *b"NSND";
+pub const VERSION: u16 = 1;
+
+pub type SnapshotResult<T> = Result<T, SnapshotError>;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Serialization(serde_json::Error),
+    InvalidHeader,
+    VersionMismatch(u16),
+    Checksum,
+    UnknownComponent(String),
+    BaseMismatch,
+    Corrupted(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            SnapshotError::Io(err) => write!(f, "Snapshot IO error: {}", err),
+            SnapshotError::Serialization(err) => write!(f, "Snapshot serialization error: {}", err),
+            SnapshotError::InvalidHeader => write!(f, "Not a snapshot file"),
+            SnapshotError::VersionMismatch(version) => {
+                write!(f, "Unsupported snapshot version {}, expected {}", version, VERSION)
+            }
+            SnapshotError::Checksum => write!(f, "Snapshot checksum mismatch"),
+            SnapshotError::UnknownComponent(name) => {
+                write!(f, "Snapshot component {} is not registered in the world", name)
+            }
+            SnapshotError::BaseMismatch => write!(f, "Delta snapshot was taken on a different base"),
+            SnapshotError::Corrupted(reason) => write!(f, "Corrupted snapshot: {}", reason),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    #[inline]
+    fn from(io_error: io::Error) -> Self {
+        SnapshotError::Io(io_error)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    #[inline]
+    fn from(json_error: serde_json::Error) -> Self {
+        SnapshotError::Serialization(json_error)
+    }
+}
+
+/// Builds the snapshot contents in memory. The header is written up front and the checksum of the
+/// contents is appended when finishing.
+pub(crate) struct SnapshotWriter {
+    buffer: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub(crate) fn new() -> SnapshotWriter {
+        Self::with_magic(MAGIC)
+    }
+
+    /// Delta snapshots reference their base by its checksum.
+    pub(crate) fn delta(base_checksum: u32) -> SnapshotWriter {
+        let mut writer = Self::with_magic(MAGIC_DELTA);
+        writer.write_u32(base_checksum);
+        writer
+    }
+
+    fn with_magic(magic: [u8; 4]) -> SnapshotWriter {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&magic);
+        buffer.extend_from_slice(&VERSION.to_be_bytes());
+
+        SnapshotWriter { buffer }
+    }
+
+    #[inline]
+    pub(crate) fn write_u16(&mut self, value: u16) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    #[inline]
+    pub(crate) fn write_u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    #[inline]
+    pub(crate) fn write_u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Write a length prefixed string.
+    #[inline]
+    pub(crate) fn write_str(&mut self, value: &str) {
+        self.write_u16(value.len() as u16);
+        self.buffer.extend_from_slice(value.as_bytes());
+    }
+
+    /// Write a length prefixed block of bytes.
+    #[inline]
+    pub(crate) fn write_block(&mut self, value: &[u8]) {
+        self.write_u64(value.len() as u64);
+        self.buffer.extend_from_slice(value);
+    }
+
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        let checksum = crc32fast::hash(&self.buffer);
+        self.write_u32(checksum);
+        self.buffer
+    }
+}
+
+/// Reads the contents of a snapshot. The header and checksum are verified when opening.
+pub(crate) struct SnapshotReader<'a> {
+    stream: &'a [u8],
+    checksum: u32,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub(crate) fn open(data: &'a [u8]) -> SnapshotResult<SnapshotReader<'a>> {
+        Self::open_with_magic(data, MAGIC)
+    }
+
+    /// Open a delta snapshot, the reader is positioned after the checksum of the base.
+    pub(crate) fn open_delta(data: &'a [u8]) -> SnapshotResult<SnapshotReader<'a>> {
+        Self::open_with_magic(data, MAGIC_DELTA)
+    }
+
+    /// Checksum of a full or delta snapshot, identifying it as the base of delta snapshots. Deltas are
+    /// chained onto either kind.
+    pub(crate) fn base_checksum(data: &[u8]) -> SnapshotResult<u32> {
+        let reader = match SnapshotReader::open(data) {
+            Err(SnapshotError::InvalidHeader) => SnapshotReader::open_delta(data)?,
+            result => result?,
+        };
+
+        Ok(reader.checksum)
+    }
+
+    fn open_with_magic(data: &'a [u8], magic: [u8; 4]) -> SnapshotResult<SnapshotReader<'a>> {
+        if data.len() < magic.len() + 6 || data[..magic.len()] != magic {
+            return Err(SnapshotError::InvalidHeader);
+        }
+
+        let (contents, mut checksum) = data.split_at(data.len() - 4);
+        let checksum = checksum.read_u32::<BigEndian>()?;
+
+        if crc32fast::hash(contents) != checksum {
+            return Err(SnapshotError::Checksum);
+        }
+
+        let mut stream = &contents[magic.len()..];
+
+        match stream.read_u16::<BigEndian>()? {
+            VERSION => Ok(SnapshotReader { stream, checksum }),
+            version => Err(SnapshotError::VersionMismatch(version)),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn read_u16(&mut self) -> SnapshotResult<u16> {
+        Ok(self.stream.read_u16::<BigEndian>()?)
+    }
+
+    #[inline]
+    pub(crate) fn read_u32(&mut self) -> SnapshotResult<u32> {
+        Ok(self.stream.read_u32::<BigEndian>()?)
+    }
+
+    #[inline]
+    pub(crate) fn read_u64(&mut self) -> SnapshotResult<u64> {
+        Ok(self.stream.read_u64::<BigEndian>()?)
+    }
+
+    #[inline]
+    pub(crate) fn read_str(&mut self) -> SnapshotResult<&'a str> {
+        let len = self.read_u16()? as usize;
+        let bytes = self.take(len)?;
+        str::from_utf8(bytes).map_err(|_| SnapshotError::Corrupted("invalid component name"))
+    }
+
+    #[inline]
+    pub(crate) fn read_block(&mut self) -> SnapshotResult<&'a [u8]> {
+        let len = self.read_u64()? as usize;
+        self.take(len)
+    }
+
+    /// Check whether all the contents have been read.
+    #[inline]
+    pub(crate) fn is_finished(&self) -> bool {
+        self.stream.is_empty()
+    }
+
+    #[inline]
+    fn take(&mut self, len: usize) -> SnapshotResult<&'a [u8]> {
+        if self.stream.len() < len {
+            return Err(SnapshotError::Corrupted("truncated block"));
+        }
+
+        let (head, tail) = self.stream.split_at(len);
+        self.stream = tail;
+        Ok(head)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    fn make_snapshot() -> Vec<u8> {
+        let mut writer = SnapshotWriter::new();
+        writer.write_u16(1);
+        writer.write_u32(2);
+        writer.write_u64(3);
+        writer.write_str("CompA");
+        writer.write_block(b"[1,2,3]");
+        writer.finish()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = make_snapshot();
+        let mut reader = SnapshotReader::open(&data).unwrap();
+
+        assert_eq!(reader.read_u16().unwrap(), 1);
+        assert_eq!(reader.read_u32().unwrap(), 2);
+        assert_eq!(reader.read_u64().unwrap(), 3);
+        assert_eq!(reader.read_str().unwrap(), "CompA");
+        assert_eq!(reader.read_block().unwrap(), b"[1,2,3]");
+        assert!(reader.is_finished());
+
+        match reader.read_u16() {
+            Err(SnapshotError::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => (),
+            _ => panic!("Read past the end of the snapshot"),
+        }
+    }
+
+    #[test]
+    fn test_open_err() {
+        match SnapshotReader::open(b"garbage") {
+            Err(SnapshotError::InvalidHeader) => (),
+            _ => panic!("Invalid header accepted"),
+        }
+
+        let mut data = make_snapshot();
+        data[10] ^= 0xff;
+
+        match SnapshotReader::open(&data) {
+            Err(SnapshotError::Checksum) => (),
+            _ => panic!("Corrupted contents accepted"),
+        }
+
+        // Bump the version and patch up the checksum
+        let mut data = make_snapshot();
+        data.truncate(data.len() - 4);
+        data[4..6].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        let checksum = crc32fast::hash(&data);
+        data.write_u32::<BigEndian>(checksum).unwrap();
+
+        match SnapshotReader::open(&data) {
+            Err(SnapshotError::VersionMismatch(version)) => assert_eq!(version, VERSION + 1),
+            _ => panic!("Unsupported version accepted"),
+        }
+    }
+}
@@ -1,12 +1,14 @@
 use crate::component::Component;
 use crate::component::{ComponentCoords, Shard};
 use crate::entity::{EntityId, TransactionContext};
-use crate::identity::ShardKey;
+use crate::identity::{ComponentClass, ShardKey, SystemId, Topic};
 use crate::messagebus::{Batcher, Bus, Message};
 use crate::sentinel::Take;
 use anymap::AnyMap;
 use hashbrown::HashMap;
 use indexmap::IndexMap;
+use std::any::TypeId;
+use std::intrinsics::type_name;
 use std::marker::PhantomData;
 use std::time;
 
@@ -20,6 +22,8 @@ pub trait RunSystem {
 
     fn run(&mut self, ctx: Context<Self::Data>, tx: &mut TransactionContext, msg: Router);
     fn init(&mut self) {}
+    /// Called once the game loop has been stopped, e.g. to release external resources.
+    fn shutdown(&mut self) {}
 }
 
 pub trait DataDef {
@@ -171,12 +175,15 @@ pub trait System {
         &mut self,
         entities: &HashMap<EntityId, ComponentCoords>,
         transactions: &mut TransactionContext,
-        incoming: &Bus,
+        incoming: &mut Bus,
         delta: f32,
         timestamp: time::Instant,
     );
     fn init(&mut self, resources: &AnyMap);
-    fn transfer_messages(&mut self, central_bus: &mut Bus);
+    fn shutdown(&mut self);
+    fn resource_access(&self, type_id: TypeId) -> Access;
+    fn access_set(&self) -> AccessSet;
+    fn transfer_messages(&mut self, id: SystemId, central_bus: &mut Bus);
     fn add_shard(&mut self, shard: &Shard);
     fn remove_shard(&mut self, key: ShardKey);
     fn check_shard(&self, shard_key: ShardKey) -> bool;
@@ -191,7 +198,7 @@ where
         &mut self,
         entities: &HashMap<EntityId, ComponentCoords>,
         transactions: &mut TransactionContext,
-        incoming: &Bus,
+        incoming: &mut Bus,
         delta: f32,
         timestamp: time::Instant,
     ) {
@@ -216,8 +223,25 @@ where
         self.runstate.init();
     }
 
-    fn transfer_messages(&mut self, central_bus: &mut Bus) {
-        central_bus.transfer(&mut self.messages);
+    #[inline]
+    fn shutdown(&mut self) {
+        self.runstate.shutdown();
+    }
+
+    #[inline]
+    fn resource_access(&self, type_id: TypeId) -> Access {
+        <<T::Data as DataDef>::Resources as ResourceQueryTup>::access(type_id)
+    }
+
+    fn access_set(&self) -> AccessSet {
+        AccessSet {
+            components: <<T::Data as DataDef>::Components as ComponentQueryTup>::accesses(),
+            resources: <<T::Data as DataDef>::Resources as ResourceQueryTup>::accesses(),
+        }
+    }
+
+    fn transfer_messages(&mut self, id: SystemId, central_bus: &mut Bus) {
+        central_bus.transfer_from(&mut self.messages, id);
     }
 
     #[inline]
@@ -242,7 +266,7 @@ where
 
 /// Routes messages to the correct bus.
 pub struct Router<'a> {
-    incoming: &'a Bus,
+    incoming: &'a mut Bus,
     outgoing: &'a mut Bus,
 }
 
@@ -256,6 +280,50 @@ impl<'a> Router<'_> {
         self.incoming.read::<T>()
     }
 
+    /// Take ownership of the incoming messages of a particular topic. Systems running later in the
+    /// frame won't receive the drained messages.
+    #[inline]
+    pub fn drain<T>(&mut self) -> Vec<T>
+    where
+        T: 'static + Message,
+    {
+        self.incoming.drain::<T>()
+    }
+
+    /// Move the incoming messages of a particular topic to the end of the supplied vector. Systems
+    /// running later in the frame won't receive the drained messages.
+    #[inline]
+    pub fn drain_into<T>(&mut self, target: &mut Vec<T>)
+    where
+        T: 'static + Message,
+    {
+        self.incoming.drain_into::<T>(target)
+    }
+
+    /// Read the messages for a particular topic that were published by the given system.
+    #[inline]
+    pub fn read_from<T>(&self, source: SystemId) -> impl Iterator<Item = &T>
+    where
+        T: 'static + Message,
+    {
+        self.incoming.read_from::<T>(source)
+    }
+
+    /// Read the retained messages of previous frames for a particular topic, oldest first.
+    #[inline]
+    pub fn read_history<T>(&self) -> impl Iterator<Item = &T>
+    where
+        T: 'static + Message,
+    {
+        self.incoming.read_history::<T>()
+    }
+
+    /// Iterate over the topics with incoming messages, highest priority first.
+    #[inline]
+    pub fn topics<'b>(&'b self) -> impl Iterator<Item = Topic> + 'b {
+        self.incoming.topics()
+    }
+
     /// Publish the supplied message on the bus.
     #[inline]
     pub fn publish<T>(&mut self, message: T)
@@ -287,6 +355,8 @@ pub trait IndexablePtrTup {
     type ItemTup;
 
     fn index(&self, idx: usize) -> Self::ItemTup;
+    /// Pointers advanced by the given number of elements. The result must stay within the data.
+    unsafe fn offset(&self, count: usize) -> Self;
 }
 
 pub trait ComponentDataTup {
@@ -304,19 +374,22 @@ pub trait ComponentQueryTup {
 
     fn reify_shard(shard: &Shard) -> Self::DataTup;
     fn get_shard_key() -> ShardKey;
+    fn accesses() -> Vec<(ComponentClass, Access)>;
 }
 
 pub mod store {
     use super::{
-        Component, ComponentDataTup, ComponentQueryTup, IndexablePtrTup, PhantomData, Read, Shard, ShardKey,
-        Write,
+        Access, Component, ComponentClass, ComponentDataTup, ComponentQueryTup, IndexablePtrTup, PhantomData,
+        Read, Shard, ShardKey, Write,
     };
     use std::ptr;
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     pub trait Indexable {
         type Item;
 
         fn index(&self, idx: usize) -> Self::Item;
+        unsafe fn offset(&self, count: usize) -> Self;
     }
 
     pub trait Data {
@@ -334,6 +407,7 @@ pub mod store {
         type DataType;
 
         fn execute(shard: &Shard) -> Self::QueryItem;
+        fn access() -> Access;
     }
 
     #[repr(transparent)]
@@ -363,6 +437,11 @@ pub mod store {
         fn index(&self, idx: usize) -> &'a T {
             unsafe { &*self.0.add(idx) }
         }
+
+        #[inline]
+        unsafe fn offset(&self, count: usize) -> ReadPtr<'a, T> {
+            ReadPtr::new(self.0.add(count))
+        }
     }
 
     impl<'a, T: 'a> Indexable for RwPtr<'a, T> {
@@ -372,6 +451,11 @@ pub mod store {
         fn index(&self, idx: usize) -> &'a mut T {
             unsafe { &mut *self.0.add(idx) }
         }
+
+        #[inline]
+        unsafe fn offset(&self, count: usize) -> RwPtr<'a, T> {
+            RwPtr::new(self.0.add(count))
+        }
     }
 
     #[repr(transparent)]
@@ -380,9 +464,9 @@ pub mod store {
         _x: PhantomData<&'a T>,
     }
 
-    #[repr(transparent)]
     pub struct WriteData<'a, T> {
         store: *mut Vec<T>,
+        dirty: *const AtomicBool,
         _x: PhantomData<&'a T>,
     }
 
@@ -409,9 +493,10 @@ pub mod store {
 
     impl<'a, T> WriteData<'a, T> {
         #[inline]
-        fn new(store: *mut Vec<T>) -> WriteData<'a, T> {
+        fn new(store: *mut Vec<T>, dirty: *const AtomicBool) -> WriteData<'a, T> {
             WriteData {
                 store,
+                dirty,
                 _x: PhantomData,
             }
         }
@@ -421,9 +506,13 @@ pub mod store {
             unsafe { &*self.store }
         }
 
+        /// Mutable access to the store, marking the shard dirty.
         #[inline]
         fn store_mut_ref(&mut self) -> &'a mut Vec<T> {
-            unsafe { &mut *self.store }
+            unsafe {
+                (*self.dirty).store(true, Ordering::Relaxed);
+                &mut *self.store
+            }
         }
     }
 
@@ -488,6 +577,11 @@ pub mod store {
         fn execute(shard: &Shard) -> ReadData<'a, T> {
             ReadData::new(shard.data_ptr::<T>())
         }
+
+        #[inline]
+        fn access() -> Access {
+            Access::Read
+        }
     }
 
     impl<'a, T> Query for Write<'a, T>
@@ -499,7 +593,12 @@ pub mod store {
 
         #[inline]
         fn execute(shard: &Shard) -> WriteData<'a, T> {
-            WriteData::new(shard.data_mut_ptr::<T>())
+            WriteData::new(shard.data_mut_ptr::<T>(), shard.dirty_ptr())
+        }
+
+        #[inline]
+        fn access() -> Access {
+            Access::Write
         }
     }
 
@@ -515,6 +614,11 @@ pub mod store {
                 fn index(&self, idx: usize) -> ($($field_type::Item),*) {
                     ($(self.$field_seq.index(idx)),*)
                 }
+
+                #[inline]
+                unsafe fn offset(&self, count: usize) -> Self {
+                    ($(self.$field_seq.offset(count),)*)
+                }
             }
         };
     }
@@ -534,6 +638,8 @@ pub mod store {
         fn index(&self, _idx: usize) -> Self::ItemTup {
             unimplemented!()
         }
+
+        unsafe fn offset(&self, _count: usize) -> Self {}
     }
 
     impl<T> IndexablePtrTup for T
@@ -544,7 +650,12 @@ pub mod store {
 
         #[inline]
         fn index(&self, idx: usize) -> Self::ItemTup {
-            self.index(idx)
+            Indexable::index(self, idx)
+        }
+
+        #[inline]
+        unsafe fn offset(&self, count: usize) -> Self {
+            Indexable::offset(self, count)
         }
     }
 
@@ -642,6 +753,10 @@ pub mod store {
                 fn get_shard_key() -> ShardKey {
                     ($($field_type::DataType::get_class())|*).into()
                 }
+
+                fn accesses() -> Vec<(ComponentClass, Access)> {
+                    vec![$(($field_type::DataType::get_class(), $field_type::access())),*]
+                }
             }
         };
     }
@@ -666,6 +781,10 @@ pub mod store {
         fn get_shard_key() -> ShardKey {
             ShardKey::empty()
         }
+
+        fn accesses() -> Vec<(ComponentClass, Access)> {
+            Vec::new()
+        }
     }
 
     impl<T> ComponentQueryTup for T
@@ -684,6 +803,10 @@ pub mod store {
         fn get_shard_key() -> ShardKey {
             T::DataType::get_class().into()
         }
+
+        fn accesses() -> Vec<(ComponentClass, Access)> {
+            vec![(T::DataType::get_class(), T::access())]
+        }
     }
 }
 
@@ -697,10 +820,59 @@ pub trait ResourceQueryTup {
     type DataTup: ResourceDataTup;
 
     fn reify(resources: &AnyMap) -> Self::DataTup;
+    fn access(type_id: TypeId) -> Access;
+    fn accesses() -> Vec<(TypeId, &'static str, Access)>;
+}
+
+/// Kind of access a query has to a resource, ordered from the least to the most exclusive.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Access {
+    None,
+    Read,
+    Write,
+}
+
+/// Components and resources accessed by a system, along with the kind of access.
+#[derive(Clone, Debug, Default)]
+pub struct AccessSet {
+    pub components: Vec<(ComponentClass, Access)>,
+    pub resources: Vec<(TypeId, &'static str, Access)>,
+}
+
+impl AccessSet {
+    /// Names of the components and resources accessed by both sets, with at least one of them writing.
+    /// Systems with conflicting access sets can't safely run in parallel.
+    pub fn conflicts(&self, other: &AccessSet) -> Vec<&'static str> {
+        let components = self.components.iter().filter(|&&(cls, access)| {
+            other
+                .components
+                .iter()
+                .any(|&(other_cls, other_access)| other_cls == cls && Self::writes(access, other_access))
+        });
+
+        let resources = self.resources.iter().filter(|&&(type_id, _, access)| {
+            other
+                .resources
+                .iter()
+                .any(|&(other_id, _, other_access)| other_id == type_id && Self::writes(access, other_access))
+        });
+
+        components
+            .map(|(cls, _)| cls.name())
+            .chain(resources.map(|&(_, name, _)| name))
+            .collect()
+    }
+
+    #[inline]
+    fn writes(access: Access, other: Access) -> bool {
+        access.max(other) == Access::Write
+    }
 }
 
 pub mod resource {
-    use super::{AnyMap, PhantomData, Read, ResourceDataTup, ResourceQueryTup, Write};
+    use super::{
+        type_name, Access, AnyMap, PhantomData, Read, ResourceDataTup, ResourceQueryTup, TypeId, Write,
+    };
     use std::ptr::NonNull;
 
     pub trait Data {
@@ -745,6 +917,8 @@ pub mod resource {
         type Data: Data;
 
         fn acquire(resources: &AnyMap) -> Self::Data;
+        fn access(type_id: TypeId) -> Access;
+        fn describe() -> (TypeId, &'static str, Access);
     }
 
     impl<'a, T> Query for Read<'a, T>
@@ -759,6 +933,18 @@ pub mod resource {
                 _x: PhantomData,
             }
         }
+
+        #[inline]
+        fn access(type_id: TypeId) -> Access {
+            match TypeId::of::<T>() == type_id {
+                true => Access::Read,
+                _ => Access::None,
+            }
+        }
+
+        fn describe() -> (TypeId, &'static str, Access) {
+            (TypeId::of::<T>(), unsafe { type_name::<T>() }, Access::Read)
+        }
     }
 
     impl<'a, T> Query for Write<'a, T>
@@ -773,6 +959,18 @@ pub mod resource {
                 _x: PhantomData,
             }
         }
+
+        #[inline]
+        fn access(type_id: TypeId) -> Access {
+            match TypeId::of::<T>() == type_id {
+                true => Access::Write,
+                _ => Access::None,
+            }
+        }
+
+        fn describe() -> (TypeId, &'static str, Access) {
+            (TypeId::of::<T>(), unsafe { type_name::<T>() }, Access::Write)
+        }
     }
 
     macro_rules! resource_tup {
@@ -832,6 +1030,15 @@ pub mod resource {
                 fn reify(resources: &AnyMap) -> Self::DataTup {
                     ($($field_type::acquire(resources),)*)
                 }
+
+                #[inline]
+                fn access(type_id: TypeId) -> Access {
+                    Access::None$(.max($field_type::access(type_id)))*
+                }
+
+                fn accesses() -> Vec<(TypeId, &'static str, Access)> {
+                    vec![$($field_type::describe()),*]
+                }
             }
         };
     }
@@ -849,6 +1056,14 @@ pub mod resource {
         type DataTup = ();
 
         fn reify(_: &AnyMap) -> Self::DataTup {}
+
+        fn access(_: TypeId) -> Access {
+            Access::None
+        }
+
+        fn accesses() -> Vec<(TypeId, &'static str, Access)> {
+            Vec::new()
+        }
     }
 
     impl<T> ResourceQueryTup for T
@@ -861,12 +1076,32 @@ pub mod resource {
         fn reify(resources: &AnyMap) -> Self::DataTup {
             T::acquire(resources)
         }
+
+        #[inline]
+        fn access(type_id: TypeId) -> Access {
+            T::access(type_id)
+        }
+
+        fn accesses() -> Vec<(TypeId, &'static str, Access)> {
+            vec![T::describe()]
+        }
     }
 }
 
 pub mod context {
     use super::{ComponentCoords, ComponentDataTup, EntityId, HashMap, IndexMap, IndexablePtrTup, ShardKey};
     use indexmap::map::ValuesMut;
+    use rayon::prelude::*;
+
+    /// Largest run of entities processed as a single task by `par_for_each`.
+    const PAR_RUN_SIZE: usize = 4096;
+
+    /// Contiguous run of entities within a shard.
+    struct Run<P>(usize, P);
+
+    // Runs cover disjoint ranges of the component vectors, the item bounds of `par_for_each` ensure that
+    // the data itself may be accessed from another thread.
+    unsafe impl<P> Send for Run<P> {}
 
     pub struct ComponentContext<'a, T>
     where
@@ -904,6 +1139,32 @@ pub mod context {
                 .for_each(f);
         }
 
+        /// Run the closure on every matched entity, splitting the shards into runs processed on the rayon
+        /// worker threads. Distinct shards own distinct vectors and the runs within a shard don't overlap,
+        /// so no two threads ever touch the same component. The closure is invoked concurrently, hence it
+        /// must be `Send` and `Sync`, and so must the component references it is passed.
+        pub fn par_for_each<F>(&mut self, f: F)
+        where
+            F: Fn(<T::PtrTup as IndexablePtrTup>::ItemTup) + Send + Sync,
+            <T::PtrTup as IndexablePtrTup>::ItemTup: Send,
+        {
+            let runs: Vec<_> = self
+                .iter()
+                .chunks()
+                .flat_map(|(len, ptrs)| {
+                    (0..len)
+                        .step_by(PAR_RUN_SIZE)
+                        .map(move |start| Run(PAR_RUN_SIZE.min(len - start), unsafe { ptrs.offset(start) }))
+                })
+                .collect();
+
+            runs.into_par_iter().for_each(|Run(len, ptrs)| {
+                for idx in 0..len {
+                    f(ptrs.index(idx));
+                }
+            });
+        }
+
         #[inline]
         pub fn iter(&mut self) -> ComponentIterator<T> {
             Self::iter_core(&mut self.shards)
@@ -975,6 +1236,53 @@ pub mod context {
             }
         }
     }
+
+    impl<'a, T> ComponentIterator<'a, T>
+    where
+        T: ComponentDataTup,
+    {
+        /// Turn the iterator into one over the contiguous runs of entities in each shard, yielding the
+        /// number of entities along with the pointers to their data. This lets the caller process a shard
+        /// in a tight loop without checking for shard boundaries on every entity. Entities already
+        /// visited by `next` are skipped, empty shards are not yielded.
+        #[inline]
+        pub fn chunks(self) -> ComponentChunks<'a, T> {
+            ComponentChunks { inner: self }
+        }
+    }
+
+    pub struct ComponentChunks<'a, T>
+    where
+        T: ComponentDataTup,
+    {
+        inner: ComponentIterator<'a, T>,
+    }
+
+    impl<'a, T> Iterator for ComponentChunks<'a, T>
+    where
+        T: ComponentDataTup,
+    {
+        type Item = (usize, T::PtrTup);
+
+        #[inline]
+        fn next(&mut self) -> Option<(usize, T::PtrTup)> {
+            let inner = &mut self.inner;
+
+            loop {
+                if inner.counter < inner.size {
+                    let chunk = (inner.size - inner.counter, unsafe { inner.shard.offset(inner.counter) });
+                    inner.counter = inner.size;
+                    return Some(chunk);
+                }
+
+                let item = inner.stream.next()?;
+                let (size, shard) = item.get_ptr_tup();
+                inner.shard = shard;
+                inner.size = size;
+                inner.counter = 0;
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -982,11 +1290,11 @@ mod tests {
     use super::*;
     use crate::component::ComponentVec;
     use crate::component_init;
+    use crate::entity::IdPool;
     use crate::identity::{ComponentClass, Topic};
     use crate::topic_init;
     use serde_derive::{Deserialize, Serialize};
     use std::marker::PhantomData;
-    use std::sync::atomic::ATOMIC_USIZE_INIT;
     use std::sync::Arc;
 
     #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -1191,7 +1499,7 @@ mod tests {
         entities.insert(1.into(), (shard_1.key, 1));
         entities.insert(2.into(), (shard_1.key, 2));
 
-        let mut transactions = TransactionContext::new(Arc::new(ATOMIC_USIZE_INIT));
+        let mut transactions = TransactionContext::new(Arc::new(IdPool::new()));
 
         // Set up central bus with some messages
         let mut messages = Bus::new();
@@ -1222,4 +1530,106 @@ mod tests {
         assert_eq!(system.messages.read::<Msg>(), &[Msg(100), Msg(101), Msg(102)]);
         assert_eq!(system.runstate.collect_messages, vec![Msg(1), Msg(2)])
     }
+
+    #[test]
+    fn test_iter_chunks() {
+        type Columns<'a> = (Read<'a, EntityId>, Read<'a, CompA>, Write<'a, CompB>);
+
+        let shard_1 = make_shard_1();
+
+        let mut map: HashMap<_, Box<ComponentVec>> = HashMap::new();
+        map.insert(CompA::get_class(), Box::new(Vec::<CompA>::new()));
+        map.insert(CompB::get_class(), Box::new(Vec::<CompB>::new()));
+        let shard_empty = Shard::new(shard_1.key + CompC::get_class(), map);
+
+        let mut map: HashMap<_, Box<ComponentVec>> = HashMap::new();
+        map.insert(CompA::get_class(), Box::new(vec![CompA(10), CompA(11)]));
+        map.insert(CompB::get_class(), Box::new(vec![CompB(10), CompB(11)]));
+        let shard_2 = Shard::new_with_ents(shard_1.key + CompD::get_class(), vec![10.into(), 11.into()], map);
+
+        let mut shards = IndexMap::new();
+        for shard in [&shard_1, &shard_empty, &shard_2].iter() {
+            shards.insert(shard.key, Columns::reify_shard(shard));
+        }
+
+        let entities = HashMap::new();
+        let mut ctx = context::ComponentContext::new(&mut shards, &entities);
+
+        let flat: Vec<_> = ctx.iter().map(|(&id, a, b)| (id, a.clone(), b.clone())).collect();
+
+        let mut chunked = Vec::new();
+        let mut sizes = Vec::new();
+        for (len, ptrs) in ctx.iter().chunks() {
+            sizes.push(len);
+            for idx in 0..len {
+                let (&id, a, b) = ptrs.index(idx);
+                chunked.push((id, a.clone(), b.clone()));
+            }
+        }
+
+        assert_eq!(flat.len(), 5);
+        assert_eq!(chunked, flat);
+        assert_eq!(sizes, vec![3, 2]);
+
+        // Entities already visited by the flat iterator are skipped
+        let mut iter = ctx.iter();
+        iter.next().unwrap();
+
+        let chunks: Vec<_> = iter.chunks().map(|(len, ptrs)| (len, *ptrs.index(0).0)).collect();
+        assert_eq!(chunks, vec![(2, 1.into()), (2, 10.into())]);
+    }
+
+    #[test]
+    fn test_par_for_each() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        type Columns<'a> = (Read<'a, CompA>, Write<'a, CompB>);
+
+        let key = CompA::get_class() + CompB::get_class() + EntityId::get_class();
+        let keys = [
+            key,
+            key + CompC::get_class(),
+            key + CompD::get_class(),
+            key + CompC::get_class() + CompD::get_class(),
+        ];
+
+        // Shards of varying size, some spanning multiple runs
+        let shards_data: Vec<_> = [10_000u64, 0, 3, 5_000]
+            .iter()
+            .zip(keys.iter())
+            .map(|(&count, &key)| {
+                let data_a: Vec<_> = (0..count).map(|i| CompA(i as i32)).collect();
+                let data_b: Vec<_> = (0..count).map(CompB).collect();
+
+                let mut map: HashMap<_, Box<ComponentVec>> = HashMap::new();
+                map.insert(CompA::get_class(), Box::new(data_a));
+                map.insert(CompB::get_class(), Box::new(data_b));
+
+                let entities = (0..count as usize).map(EntityId::from).collect();
+                Shard::new_with_ents(key, entities, map)
+            })
+            .collect();
+
+        let mut shards = IndexMap::new();
+        for shard in shards_data.iter() {
+            shards.insert(shard.key, Columns::reify_shard(shard));
+        }
+
+        let entities = HashMap::new();
+        let mut ctx = context::ComponentContext::new(&mut shards, &entities);
+
+        let serial: u64 = ctx.iter().map(|(a, b)| a.0 as u64 + b.0).sum();
+
+        let parallel = AtomicU64::new(0);
+        ctx.par_for_each(|(a, b)| {
+            parallel.fetch_add(a.0 as u64 + b.0, Ordering::Relaxed);
+            b.0 += 1;
+        });
+
+        assert_eq!(parallel.load(Ordering::Relaxed), serial);
+
+        // Every entity was visited exactly once
+        let incremented: u64 = ctx.iter().map(|(a, b)| a.0 as u64 + b.0).sum();
+        assert_eq!(incremented, serial + 15_003);
+    }
 }
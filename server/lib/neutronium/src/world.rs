@@ -1,17 +1,25 @@
+use crate::component;
 use crate::component::Component;
 use crate::component::{ComponentClassAux, ComponentCoords, Shard};
-use crate::entity::{EntityId, ShardDef, TransactionContext};
-use crate::identity::{ShardKey, SystemId};
-use crate::messagebus::Bus;
+use crate::entity::{ComponentEdit, EntityId, IdPool, ShardDef, TransactionContext};
+use crate::identity::{ComponentClass, ShardKey, SystemId};
+use crate::messagebus::{Bus, Message};
 use crate::registry::Registry;
-use crate::system::{RunSystem, System, SystemRuntime};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotResult, SnapshotWriter};
+use crate::system::{Access, RunSystem, System, SystemRuntime};
 use anymap::AnyMap;
 use flux::logging;
-use hashbrown::HashMap;
+use flux::time::{Clock, SystemClock};
+use hashbrown::{HashMap, HashSet};
+use std::any::TypeId;
+use std::cell::{Cell, RefCell};
+use std::fs;
 use std::intrinsics::type_name;
-use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
+use std::mem;
+use std::ptr::NonNull;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread;
 use std::time;
 
 pub struct World {
@@ -19,10 +27,41 @@ pub struct World {
     frame_delta_time: time::Duration,
     delta: f32,
     timestamp: time::Instant,
+    deterministic: bool,
+    clock: Box<Clock>,
+
+    // Fixed Step Settings
+    fixed_delta: Option<f32>,
+    fixed_max_steps: u32,
+    fixed_accumulator: f32,
+    fixed_systems: HashSet<SystemId>,
+
+    // Frame Statistics
+    frame_stats: FrameStats,
+
+    // Profiling
+    profiling: bool,
+    system_timings: RefCell<HashMap<SystemId, time::Duration>>,
+
+    // Memory Reclamation
+    reclaim_interval: u64,
+    frames_since_reclaim: u64,
+
+    // Pause Settings
+    paused: bool,
+    persistent_systems: HashSet<SystemId>,
+
+    // Disabled Systems
+    disabled_systems: HashSet<SystemId>,
+
+    // Stopping
+    should_stop: StopHandle,
+    shut_down: bool,
 
     // Game State
-    entity_counter: Arc<AtomicUsize>,
+    id_pool: Arc<IdPool>,
     state: GameState,
+    snapshot_checksum: Cell<Option<u32>>,
 
     // Transactions
     system_transactions: Vec<TransactionContext>,
@@ -30,12 +69,99 @@ pub struct World {
     finalized: bool,
 
     // Messaging
-    messages: Bus,
+    messages: RefCell<Bus>,
+
+    // Scheduling
+    system_names: HashMap<SystemId, &'static str>,
+    system_deps: HashMap<SystemId, Vec<SystemId>>,
+    strict_access: bool,
 
     // Logging
     log: logging::Logger,
 }
 
+/// Seeded random number generator resource of deterministic worlds (SplitMix64). Identically seeded
+/// generators produce identical sequences.
+pub struct WorldRng {
+    state: u64,
+}
+
+impl WorldRng {
+    #[inline]
+    pub fn new(seed: u64) -> WorldRng {
+        WorldRng { state: seed }
+    }
+
+    /// Draw the next random `u64`.
+    #[inline]
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Draw the next random `f32` in the range [0, 1).
+    #[inline]
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// Handle stopping the game loop of a world from the outside, e.g. from signal handlers or admin
+/// commands. Handles are cheap to clone and may be sent to other threads.
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    /// Stop the game loop after the current frame.
+    #[inline]
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Frame execution time statistics.
+#[derive(Debug, Clone, Default)]
+pub struct FrameStats {
+    /// Number of frames recorded.
+    pub frames: u64,
+    /// Number of frames that took longer than the frame time budget.
+    pub overruns: u64,
+    pub min: Option<time::Duration>,
+    pub max: Option<time::Duration>,
+    pub total: time::Duration,
+}
+
+impl FrameStats {
+    #[inline]
+    fn record(&mut self, elapsed: time::Duration, overrun: bool) {
+        self.frames += 1;
+        self.total += elapsed;
+        self.min = Some(self.min.map_or(elapsed, |min| min.min(elapsed)));
+        self.max = Some(self.max.map_or(elapsed, |max| max.max(elapsed)));
+
+        if overrun {
+            self.overruns += 1;
+        }
+    }
+
+    /// Average frame time, `None` if no frames have been recorded.
+    #[inline]
+    pub fn avg(&self) -> Option<time::Duration> {
+        match self.frames {
+            0 => None,
+            frames => Some(self.total / frames as u32),
+        }
+    }
+}
+
 impl Default for World {
     fn default() -> Self {
         World::new(20, None)
@@ -43,6 +169,10 @@ impl Default for World {
 }
 
 impl World {
+    /// Shards holding less than this fraction of their capacity are shrunk when reclaiming memory.
+    pub const RECLAIM_RATIO: usize = 4;
+    const DEFAULT_RECLAIM_INTERVAL: u64 = 600;
+
     /// Creates a `World` instance initialized with default parameters:
     /// FPS: 20
     #[inline]
@@ -52,31 +182,103 @@ impl World {
             _ => logging::Logger::root(logging::Discard, logging::o!()),
         };
 
-        let counter = Arc::new(ATOMIC_USIZE_INIT);
-        let frame_delta_time = time::Duration::from_millis(1000 / fps);
+        let id_pool = Arc::new(IdPool::new());
+        let frame_delta_time = Self::fps_to_delta_time(fps);
+        let clock: Box<Clock> = Box::new(SystemClock);
 
         let world = World {
             frame_delta_time,
             delta: Self::duration_to_delta(frame_delta_time),
-            timestamp: time::Instant::now(),
-            entity_counter: counter.clone(),
+            timestamp: clock.now(),
+            deterministic: false,
+            clock,
+            fixed_delta: None,
+            fixed_max_steps: 0,
+            fixed_accumulator: 0f32,
+            fixed_systems: HashSet::new(),
+            frame_stats: FrameStats::default(),
+            profiling: false,
+            system_timings: RefCell::new(HashMap::new()),
+            reclaim_interval: Self::DEFAULT_RECLAIM_INTERVAL,
+            frames_since_reclaim: 0,
+            paused: false,
+            persistent_systems: HashSet::new(),
+            disabled_systems: HashSet::new(),
+            should_stop: StopHandle::default(),
+            shut_down: false,
+            id_pool: id_pool.clone(),
             state: GameState::new(&world_log),
+            snapshot_checksum: Cell::new(None),
             system_transactions: Vec::new(),
-            transactions: TransactionContext::new(counter),
+            transactions: TransactionContext::new(id_pool),
             finalized: false,
-            messages: Bus::new(),
+            messages: RefCell::new(Bus::new()),
+            system_names: HashMap::new(),
+            system_deps: HashMap::new(),
+            strict_access: false,
             log: world_log,
         };
 
         world
     }
 
+    /// Creates a deterministic `World` instance for replays and testing. Every frame advances the
+    /// simulation by the supplied fixed delta regardless of the wall clock time, the game loop does not
+    /// sleep between frames. A `WorldRng` resource seeded with the given seed is registered for systems
+    /// requiring random numbers.
+    pub fn new_deterministic(fixed_delta: f32, seed: u64) -> Self {
+        let mut world = World::new(20, None);
+
+        world.frame_delta_time = time::Duration::from_nanos((fixed_delta as f64 * 1e9) as u64);
+        world.delta = fixed_delta;
+        world.deterministic = true;
+        world.register_resource(WorldRng::new(seed));
+
+        world
+    }
+
+    /// Change the target frame rate of the game loop. Takes effect from the next frame. Deterministic
+    /// worlds switch their fixed delta to the new frame time. Panics if the frame rate is 0.
+    #[inline]
+    pub fn set_fps(&mut self, fps: u64) {
+        self.frame_delta_time = Self::fps_to_delta_time(fps);
+
+        // Deterministic worlds advance the clock by the frame time, the delta has to stay in step
+        if self.deterministic {
+            self.delta = Self::duration_to_delta(self.frame_delta_time);
+        }
+
+        logging::info!(self.log, "frame rate changed";
+                       "context" => "set_fps",
+                       "fps" => fps,
+                       "frame_delta_time" => ?self.frame_delta_time);
+    }
+
+    #[inline]
+    fn fps_to_delta_time(fps: u64) -> time::Duration {
+        if fps == 0 {
+            panic!("Frame rate must be greater than 0")
+        }
+
+        time::Duration::from_millis(1000 / fps)
+    }
+
+    /// Replace the source of time driving the game loop, e.g. with a manually advanced clock in tests.
+    #[inline]
+    pub fn set_clock<C: 'static + Clock>(&mut self, clock: C) {
+        self.timestamp = clock.now();
+        self.clock = Box::new(clock);
+    }
+
     /// Builds and finalizes this world. After finalization, new components, resources and
     /// systems can no longer be added.
     pub fn build(&mut self) {
         self.finalized = true;
         logging::info!(self.log, "initializing world"; "context" => "build");
 
+        self.sort_systems();
+        self.check_access();
+
         for (id, mut system) in self.state.systems.iter_mut::<System>() {
             logging::info!(self.log, "initializing system";
                             "context" => "build",
@@ -86,7 +288,7 @@ impl World {
 
             // Create a copy of the main transaction context for each system so they can be run in parallel
             self.system_transactions
-                .push(TransactionContext::new(self.entity_counter.clone()));
+                .push(TransactionContext::new(self.id_pool.clone()));
         }
 
         logging::info!(self.log, "world initialization finished"; "context" => "build");
@@ -109,62 +311,325 @@ impl World {
     #[inline]
     pub fn process_messages(&mut self) {
         logging::trace!(self.log, "processing messages"; "context" => "process_messages");
-        self.messages.clear();
+        self.messages.get_mut().clear();
 
         for (id, mut system) in self.state.systems.iter_mut::<System>() {
             logging::trace!(self.log, "processing system messages";
                             "context" => "process_messages",
                             "system" => %id);
-            system.transfer_messages(&mut self.messages);
+            system.transfer_messages(*id, self.messages.get_mut());
+        }
+
+        for letter in self.messages.get_mut().dead_letters() {
+            logging::warn!(self.log, "dead letter, message topic is not registered";
+                           "context" => "process_messages",
+                           "message" => letter.message);
         }
         logging::debug!(self.log, "message processing finished"; "context" => "process_messages");
     }
 
-    /// Runs one game iteration
+    /// Runs one game iteration. While paused, only persistent systems are run and transactions are
+    /// left queued up until the world is resumed. Stopped worlds don't run any more frames.
     #[inline]
     pub fn run_once(&mut self) -> bool {
-        self.process_transactions();
-        self.process_systems();
+        if self.is_stopped() {
+            return false;
+        }
+
+        if self.paused {
+            logging::trace!(self.log, "executing persistent systems"; "context" => "run_once");
+            self.run_systems(self.delta, |id| self.persistent_systems.contains(id));
+        } else {
+            self.process_transactions();
+            self.process_systems();
+        }
         self.process_messages();
 
-        // Eventually, process stopping conditions from various triggers (local or via network).
-        true
+        !self.is_stopped()
     }
 
     /// Runs the main game loop with frame rate limiting.
     #[inline]
     pub fn run(&mut self) {
+        self.run_loop(|_| true);
+    }
+
+    /// Runs the game loop for the given number of frames, using the same frame rate limiting
+    /// as `run`.
+    #[inline]
+    pub fn run_for(&mut self, frames: u64) {
+        let mut remaining = frames;
+
+        self.run_loop(move |_| match remaining {
+            0 => false,
+            _ => {
+                remaining -= 1;
+                true
+            }
+        });
+    }
+
+    /// Runs the game loop until the supplied predicate returns true. The predicate is evaluated
+    /// before each frame.
+    #[inline]
+    pub fn run_until<F>(&mut self, mut pred: F)
+    where
+        F: FnMut(&World) -> bool,
+    {
+        self.run_loop(move |world| !pred(world));
+    }
+
+    /// Main game loop. Frames are executed for as long as the supplied condition holds.
+    fn run_loop<F>(&mut self, mut proceed: F)
+    where
+        F: FnMut(&World) -> bool,
+    {
         if !self.finalized {
             panic!("World must be built before starting the simulation");
         }
 
-        let mut proceed = true;
+        // Worlds stopped before running still need their systems shut down
+        if self.is_stopped() {
+            self.shutdown();
+            return;
+        }
+
+        let mut prev_timestamp = self.clock.now() - self.frame_delta_time;
 
-        let mut prev_timestamp = time::Instant::now() - self.frame_delta_time;
+        while proceed(self) {
+            // Frame times are measured on the wall clock, deterministic timestamps run ahead of it
+            let frame_start = self.clock.now();
 
-        while proceed {
-            self.timestamp = time::Instant::now();
-            self.delta = Self::duration_to_delta(self.timestamp - prev_timestamp);
+            // Deterministic worlds advance the clock by the fixed delta, leaving the delta untouched
+            if self.deterministic {
+                self.timestamp += self.frame_delta_time;
+            } else {
+                self.timestamp = frame_start;
+                self.delta = Self::duration_to_delta(self.timestamp - prev_timestamp);
+            }
 
             logging::trace!(self.log, "frame started";
                             "context" => "run",
                             "timestamp" => ?self.timestamp,
                             "delta" => ?self.delta);
 
-            proceed = self.run_once();
+            let running = self.run_once();
 
-            let elapsed = time::Instant::now().duration_since(self.timestamp);
+            let elapsed = self.clock.now().duration_since(frame_start);
 
             logging::trace!(self.log, "frame finished"; "context" => "run","elapsed" => ?elapsed);
 
-            if elapsed < self.frame_delta_time {
+            self.record_frame_time(elapsed);
+
+            if !running {
+                break;
+            }
+
+            // Reclaim memory only with time to spare in the frame
+            self.frames_since_reclaim += 1;
+
+            if self.reclaim_interval > 0
+                && self.frames_since_reclaim >= self.reclaim_interval
+                && elapsed < self.frame_delta_time
+            {
+                self.reclaim_memory();
+            }
+
+            if !self.deterministic && elapsed < self.frame_delta_time {
                 let timeout = self.frame_delta_time - elapsed;
                 logging::trace!(self.log, "frame timeout triggered"; "context" => "run", "timeout" => ?timeout);
-                thread::sleep(timeout);
+                self.clock.sleep(timeout);
             }
 
             prev_timestamp = self.timestamp;
         }
+
+        // The flag may also have been set after the last frame finished
+        if self.is_stopped() {
+            self.shutdown();
+        }
+    }
+
+    /// Record the execution time of a frame, warning about frames that significantly overran the
+    /// frame time budget.
+    #[inline]
+    fn record_frame_time(&mut self, elapsed: time::Duration) {
+        let overrun = elapsed > self.frame_delta_time;
+        self.frame_stats.record(elapsed, overrun);
+
+        // Only warn about frames exceeding the budget by more than half
+        if elapsed > self.frame_delta_time * 3 / 2 {
+            logging::warn!(self.log, "frame overran the time budget";
+                           "context" => "run",
+                           "elapsed" => ?elapsed,
+                           "frame_delta_time" => ?self.frame_delta_time,
+                           "overrun_count" => self.frame_stats.overruns);
+        }
+    }
+
+    /// Shrink the shards holding far less entities than their capacity, returning the number of shards
+    /// shrunk. The game loop calls this periodically on frames finishing within the time budget.
+    pub fn reclaim_memory(&mut self) -> usize {
+        self.frames_since_reclaim = 0;
+
+        let mut shrunk = 0;
+
+        for shard in self.state.shards.values_mut() {
+            if shard.len() < shard.capacity() / Self::RECLAIM_RATIO {
+                shard.shrink();
+                shrunk += 1;
+            }
+        }
+
+        logging::debug!(self.log, "memory reclaimed";
+                        "context" => "reclaim_memory",
+                        "shard_count" => shrunk);
+
+        shrunk
+    }
+
+    /// Set the number of frames between the memory reclamation passes of the game loop, zero disables
+    /// them.
+    #[inline]
+    pub fn set_reclaim_interval(&mut self, frames: u64) {
+        self.reclaim_interval = frames;
+    }
+
+    /// Get the frame time statistics accumulated since the start or the last reset.
+    #[inline]
+    pub fn frame_stats(&self) -> &FrameStats {
+        &self.frame_stats
+    }
+
+    /// Get a handle for stopping the game loop. The game loop stops and shuts the systems down after
+    /// finishing the frame during which the handle was tripped.
+    #[inline]
+    pub fn stop_handle(&self) -> StopHandle {
+        self.should_stop.clone()
+    }
+
+    /// Stop the game loop after the current frame.
+    #[inline]
+    pub fn stop(&self) {
+        self.should_stop.stop();
+    }
+
+    #[inline]
+    pub fn is_stopped(&self) -> bool {
+        self.should_stop.is_stopped()
+    }
+
+    /// Shut down all the systems. Called by the game loop once stopped, systems are only ever
+    /// shut down once.
+    pub fn shutdown(&mut self) {
+        if self.shut_down {
+            return;
+        }
+
+        self.shut_down = true;
+
+        logging::info!(self.log, "shutting down systems"; "context" => "shutdown");
+
+        for (id, mut system) in self.state.systems.iter_mut::<System>() {
+            logging::info!(self.log, "shutting down system";
+                           "context" => "shutdown",
+                           "system" => %id);
+
+            system.shutdown();
+        }
+    }
+
+    /// Reset the frame time statistics.
+    #[inline]
+    pub fn reset_frame_stats(&mut self) {
+        self.frame_stats = FrameStats::default();
+    }
+
+    /// Toggle measuring the execution time of the systems. Off by default, systems are run without any
+    /// extra overhead while disabled.
+    #[inline]
+    pub fn set_profiling(&mut self, enabled: bool) {
+        logging::info!(self.log, "toggling profiling"; "context" => "set_profiling", "enabled" => enabled);
+        self.profiling = enabled;
+    }
+
+    /// Get the total execution time of each system accumulated while profiling was enabled.
+    #[inline]
+    pub fn system_timings(&self) -> HashMap<SystemId, time::Duration> {
+        self.system_timings.borrow().clone()
+    }
+
+    /// Reset the accumulated system execution times.
+    #[inline]
+    pub fn reset_system_timings(&mut self) {
+        self.system_timings.borrow_mut().clear();
+    }
+
+    /// Reject systems with conflicting component or resource access when building the world, instead of
+    /// only logging the conflicts. Off by default.
+    #[inline]
+    pub fn set_strict_access(&mut self, strict: bool) {
+        self.strict_access = strict;
+    }
+
+    /// Get the pairs of systems that access the same component or resource with at least one of them
+    /// writing, along with the names of the contested data. Such systems can't safely run in parallel.
+    pub fn access_conflicts(&self) -> Vec<(SystemId, SystemId, Vec<&'static str>)> {
+        let access_sets: Vec<_> = self
+            .state
+            .systems
+            .iter::<System>()
+            .map(|(&id, system)| (id, system.access_set()))
+            .collect();
+
+        let mut conflicts = Vec::new();
+
+        for (idx, (id1, set1)) in access_sets.iter().enumerate() {
+            for (id2, set2) in access_sets[idx + 1..].iter() {
+                let contested = set1.conflicts(set2);
+
+                if !contested.is_empty() {
+                    conflicts.push((*id1, *id2, contested));
+                }
+            }
+        }
+
+        conflicts
+    }
+
+    /// Log the systems with conflicting access, panics in strict mode.
+    fn check_access(&self) {
+        let conflicts = self.access_conflicts();
+
+        for (id1, id2, contested) in conflicts.iter() {
+            logging::warn!(self.log, "conflicting system access";
+                           "context" => "check_access",
+                           "system1" => self.system_names[id1],
+                           "system2" => self.system_names[id2],
+                           "contested" => ?contested);
+        }
+
+        if self.strict_access && !conflicts.is_empty() {
+            let pairs: Vec<_> = conflicts
+                .iter()
+                .map(|(id1, id2, contested)| {
+                    format!(
+                        "{} and {} ({})",
+                        self.system_names[id1],
+                        self.system_names[id2],
+                        contested.join(", ")
+                    )
+                })
+                .collect();
+
+            panic!("Conflicting system access detected between systems: {}", pairs.join("; "))
+        }
+    }
+
+    /// Get the ids of the registered systems in the order they are run, the order is settled once the
+    /// world is built.
+    pub fn system_ids(&self) -> Vec<SystemId> {
+        self.state.systems.iter::<System>().map(|(&id, _)| id).collect()
     }
 
     #[inline]
@@ -176,6 +641,191 @@ impl World {
         &mut self.transactions
     }
 
+    /// Save all live entities and their components to a snapshot file. Staged transactions are not
+    /// included.
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> SnapshotResult<()> {
+        logging::info!(self.log, "saving snapshot";
+                       "context" => "save_snapshot",
+                       "path" => %path.as_ref().display(),
+                       "entity_count" => self.state.entities.len());
+
+        let data = self.state.write_snapshot(self.id_pool.counter() as u64)?;
+        let checksum = SnapshotReader::base_checksum(&data)?;
+
+        fs::write(path, data)?;
+        self.state.clear_dirty();
+        self.snapshot_checksum.set(Some(checksum));
+        Ok(())
+    }
+
+    /// Save the shards that changed since the last snapshot was saved, loaded or applied. Deltas form a
+    /// chain: the base must be that last snapshot, either the full snapshot or the previous delta, and the
+    /// delta can only be applied on top of the state restored up to that base.
+    pub fn save_delta_snapshot<B, P>(&self, base: B, path: P) -> SnapshotResult<()>
+    where
+        B: AsRef<Path>,
+        P: AsRef<Path>,
+    {
+        let base_checksum = SnapshotReader::base_checksum(&fs::read(base)?)?;
+
+        // The dirty flags only cover the changes since the last snapshot, any other base would lose the
+        // changes recorded in between
+        if self.snapshot_checksum.get() != Some(base_checksum) {
+            return Err(SnapshotError::BaseMismatch);
+        }
+
+        let dirty_count = self.state.shards.values().filter(|shard| shard.is_dirty()).count();
+
+        logging::info!(self.log, "saving delta snapshot";
+                       "context" => "save_delta_snapshot",
+                       "path" => %path.as_ref().display(),
+                       "shard_count" => dirty_count);
+
+        let data = self
+            .state
+            .write_delta_snapshot(base_checksum, self.id_pool.counter() as u64)?;
+        let checksum = SnapshotReader::base_checksum(&data)?;
+
+        fs::write(path, data)?;
+        self.state.clear_dirty();
+        self.snapshot_checksum.set(Some(checksum));
+        Ok(())
+    }
+
+    /// Replace all entities with the contents of the snapshot file. Every component in the snapshot
+    /// must be registered. The world is left untouched if the snapshot can't be loaded.
+    pub fn load_snapshot<P: AsRef<Path>>(&mut self, path: P) -> SnapshotResult<()> {
+        if !self.finalized {
+            panic!("World must be finalized before loading a snapshot")
+        }
+
+        logging::info!(self.log, "loading snapshot";
+                       "context" => "load_snapshot",
+                       "path" => %path.as_ref().display());
+
+        let data = fs::read(path)?;
+        let entity_counter = self.state.read_snapshot(&data)?;
+        self.restore_entity_counter(entity_counter);
+        self.state.clear_dirty();
+        self.snapshot_checksum.set(Some(SnapshotReader::base_checksum(&data)?));
+
+        logging::info!(self.log, "snapshot loaded";
+                       "context" => "load_snapshot",
+                       "entity_count" => self.state.entities.len());
+
+        Ok(())
+    }
+
+    /// Apply a delta snapshot on top of the state restored from its base snapshot. The shards in the
+    /// delta replace the current contents of the same shards. A chain of deltas is applied in order, each
+    /// with the previous delta as its base.
+    pub fn apply_delta_snapshot<B, P>(&mut self, base: B, path: P) -> SnapshotResult<()>
+    where
+        B: AsRef<Path>,
+        P: AsRef<Path>,
+    {
+        if !self.finalized {
+            panic!("World must be finalized before loading a snapshot")
+        }
+
+        logging::info!(self.log, "applying delta snapshot";
+                       "context" => "apply_delta_snapshot",
+                       "path" => %path.as_ref().display());
+
+        let base_checksum = SnapshotReader::base_checksum(&fs::read(base)?)?;
+
+        let data = fs::read(path)?;
+        let entity_counter = self.state.read_delta_snapshot(&data, base_checksum)?;
+        self.restore_entity_counter(entity_counter);
+        self.state.clear_dirty();
+        self.snapshot_checksum.set(Some(SnapshotReader::base_checksum(&data)?));
+
+        logging::info!(self.log, "delta snapshot applied";
+                       "context" => "apply_delta_snapshot",
+                       "entity_count" => self.state.entities.len());
+
+        Ok(())
+    }
+
+    #[inline]
+    fn restore_entity_counter(&mut self, entity_counter: u64) {
+        // Ids handed out to staged entities must not be reused either
+        self.id_pool.restore(entity_counter as usize);
+    }
+
+    /// Check whether the entity exists. Staged additions and removals only take effect once the
+    /// transactions are processed.
+    #[inline]
+    pub fn contains_entity(&self, id: EntityId) -> bool {
+        self.state.entities.contains_key(&id)
+    }
+
+    /// Get a component of the entity, `None` if the entity doesn't exist or lacks the component.
+    pub fn get_component<T>(&self, id: EntityId) -> Option<&T>
+    where
+        T: 'static + Component,
+    {
+        let (shard_key, loc) = *self.state.entities.get(&id)?;
+
+        if !shard_key.contains_id(T::get_class()) {
+            return None;
+        }
+
+        let shard = self.state.shards.get(&shard_key)?;
+        unsafe { (*shard.data_ptr::<T>()).get(loc) }
+    }
+
+    /// Number of live entities in the world.
+    #[inline]
+    pub fn entity_count(&self) -> usize {
+        self.state.entities.len()
+    }
+
+    /// Number of shards in the world, including the ones that have been emptied out.
+    #[inline]
+    pub fn shard_count(&self) -> usize {
+        self.state.shards.len()
+    }
+
+    /// Number of entities stored in each shard.
+    pub fn shard_histogram(&self) -> HashMap<ShardKey, usize> {
+        self.state
+            .shards
+            .iter()
+            .map(|(&key, shard)| (key, shard.len()))
+            .collect()
+    }
+
+    /// Human readable dump of the non-empty shards for debugging. Each shard is listed in key order with
+    /// the names of its components, followed by its entities and their serialized component values.
+    pub fn dump_state(&self) -> String {
+        use std::fmt::Write;
+
+        let mut shards: Vec<_> = self.state.shards.values().filter(|shard| shard.len() > 0).collect();
+        shards.sort_by_key(|shard| shard.key);
+
+        let mut out = String::new();
+
+        for shard in shards {
+            let mut components: Vec<_> = shard.iter_components().collect();
+            components.sort_by_key(|&(cls, _)| cls);
+
+            let names: Vec<_> = components.iter().map(|&(cls, _)| cls.name()).collect();
+            writeln!(out, "shard [{}] ({} entities)", names.join(", "), shard.len()).unwrap();
+
+            for (id, loc) in shard.iter_entities() {
+                writeln!(out, "  {:?}", id).unwrap();
+
+                for &(cls, data) in components.iter() {
+                    let value = data.row_to_json(loc).unwrap_or_else(|err| format!("<{}>", err));
+                    writeln!(out, "    {}: {}", cls.name(), value).unwrap();
+                }
+            }
+        }
+
+        out
+    }
+
     #[inline]
     fn duration_to_delta(duration: time::Duration) -> f32 {
         duration.as_float_secs() as f32
@@ -202,8 +852,13 @@ impl World {
             panic!("Can't add systems to finalized world")
         }
 
+        // Reuse the indexers of unregistered systems, keeping them dense for the transaction contexts
+        let indexer = (0..self.state.systems.len())
+            .find(|&idx| !self.system_names.contains_key(&SystemId::from_indexer(idx)))
+            .unwrap_or_else(|| self.state.systems.len());
+
         let runtime = self.create_runtime(system);
-        let id = SystemId::new::<T>(self.state.systems.len());
+        let id = SystemId::new::<T>(indexer);
 
         logging::debug!(self.log, "registering system";
                         "context" => "register_system",
@@ -211,44 +866,316 @@ impl World {
 
         self.state.systems.register(id, runtime);
         self.state.systems.register_trait::<SystemRuntime<T>, System>(&id);
+        self.system_names.insert(id, unsafe { type_name::<T>() });
         id
     }
 
-    /// Process all currently registered systems.
-    #[inline]
-    pub fn process_systems(&mut self) {
-        logging::debug!(self.log, "executing systems"; "context" => "process_systems");
-
-        for (id, mut system) in self.state.systems.iter_mut::<System>() {
-            logging::debug!(self.log, "system running";
-                            "context" => "process_systems",
-                            "system" => %id);
+    /// Remove the system from the world, dropping it along with its scheduling constraints. Systems can
+    /// only be removed before the world is built. The id of the removed system may be handed out again
+    /// to systems registered afterwards, so it should not be used anymore.
+    pub fn unregister_system(&mut self, id: SystemId) {
+        if self.finalized {
+            panic!("Can't remove systems from finalized world")
+        }
 
-            unsafe {
-                system.run(
-                    &self.state.entities,
-                    self.get_system_transactions(id.indexer()),
-                    &self.messages,
-                    self.delta,
-                    self.timestamp,
-                );
-            }
+        if !self.state.systems.unregister(&id) {
+            panic!("Unknown system {}", id)
         }
 
-        logging::debug!(self.log, "system execution finished"; "context" => "process_systems");
-    }
+        logging::debug!(self.log, "unregistering system";
+                        "context" => "unregister_system",
+                        "id" => ?id);
 
-    // TODO: Check the performance impact of drain/rebuild and switch if negligible
-    /// Horribly unsafe function to get mutable references to multiple elements of the system
-    /// transactions without having to drain and rebuild the vector all the time.
-    #[inline]
-    unsafe fn get_system_transactions<'a>(&self, idx: usize) -> &'a mut TransactionContext {
-        let ptr = self.system_transactions.as_ptr() as *mut TransactionContext;
-        &mut *ptr.add(idx)
+        self.system_names.remove(&id);
+        self.system_deps.remove(&id);
+        for deps in self.system_deps.values_mut() {
+            deps.retain(|dep| *dep != id);
+        }
+
+        self.fixed_systems.remove(&id);
+        self.persistent_systems.remove(&id);
+        self.disabled_systems.remove(&id);
+        self.system_timings.borrow_mut().remove(&id);
     }
-}
+
+    /// Register the supplied system with the world as a fixed step system. Once fixed stepping is
+    /// enabled via `set_fixed_step`, these systems run zero or more times per frame with a constant
+    /// delta, while all other systems run once per frame with the variable frame delta.
+    pub fn register_fixed_system<T>(&mut self, system: T) -> SystemId
+    where
+        T: 'static + RunSystem,
+    {
+        let id = self.register_system(system);
+        self.fixed_systems.insert(id);
+        id
+    }
+
+    /// Register the supplied system with the world as a persistent system. Persistent systems keep
+    /// running while the world is paused, e.g. to keep network connections alive.
+    pub fn register_persistent_system<T>(&mut self, system: T) -> SystemId
+    where
+        T: 'static + RunSystem,
+    {
+        let id = self.register_system(system);
+        self.persistent_systems.insert(id);
+        id
+    }
+
+    /// Pause the simulation. Only persistent systems are run until the world is resumed.
+    ///
+    /// Messages are only delivered for a single frame, the messages published while paused thus only
+    /// reach the persistent systems. Systems that need to catch up with them after resuming should read
+    /// the topic history, see `retain_messages`.
+    #[inline]
+    pub fn pause(&mut self) {
+        logging::info!(self.log, "pausing world"; "context" => "pause");
+        self.paused = true;
+    }
+
+    /// Resume a paused simulation.
+    #[inline]
+    pub fn resume(&mut self) {
+        logging::info!(self.log, "resuming world"; "context" => "resume");
+        self.paused = false;
+    }
+
+    /// Returns true if the simulation is paused.
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Enable or disable the system. Disabled systems are skipped by the game loop, but keep receiving
+    /// the shards matching their queries so they pick up where they left off once enabled again.
+    pub fn set_system_enabled(&mut self, id: SystemId, enabled: bool) {
+        logging::info!(self.log, "toggling system";
+                       "context" => "set_system_enabled",
+                       "system" => %id,
+                       "enabled" => enabled);
+
+        match enabled {
+            true => self.disabled_systems.remove(&id),
+            _ => self.disabled_systems.insert(id),
+        };
+    }
+
+    /// Returns true unless the system has been disabled.
+    #[inline]
+    pub fn is_system_enabled(&self, id: SystemId) -> bool {
+        !self.disabled_systems.contains(&id)
+    }
+
+    /// Enable fixed stepping with the supplied step duration. Elapsed frame time is accumulated and
+    /// fixed step systems are run once for each full step. At most `max_steps` steps are executed
+    /// per frame, any excess time is discarded to avoid falling further and further behind after
+    /// long stalls.
+    pub fn set_fixed_step(&mut self, step: time::Duration, max_steps: u32) {
+        if max_steps == 0 {
+            panic!("Fixed stepping requires at least one step per frame")
+        }
+
+        self.fixed_delta = Some(Self::duration_to_delta(step));
+        self.fixed_max_steps = max_steps;
+        self.fixed_accumulator = 0f32;
+    }
+
+    /// Retain the messages of the given topic for the last `frames` frames. Systems can access
+    /// the retained messages via `Router::read_history`.
+    pub fn retain_messages<T>(&mut self, frames: usize)
+    where
+        T: 'static + Message,
+    {
+        self.messages.get_mut().retain::<T>(frames);
+    }
+
+    /// Get a mutable reference to the system registered under the given id. Returns `None` if the
+    /// id is unknown or the system is not of type `T`.
+    pub fn get_system_mut<T>(&mut self, id: SystemId) -> Option<&mut T>
+    where
+        T: 'static + RunSystem,
+    {
+        self.state.systems.try_get::<SystemRuntime<T>>(&id).map(|runtime| unsafe {
+            // The registry keeps the runtime alive for the lifetime of the world and the mutable
+            // borrow of the world guarantees exclusive access.
+            (*runtime.get_ptr_raw()).get_system_mut()
+        })
+    }
+
+    /// Register the supplied system with the world. The system will be scheduled to run after all
+    /// the systems in `after`.
+    pub fn register_system_after<T>(&mut self, system: T, after: &[SystemId]) -> SystemId
+    where
+        T: 'static + RunSystem,
+    {
+        let id = self.register_system(system);
+        self.add_system_dependency(id, after);
+        id
+    }
+
+    /// Schedule an already registered system to run after all the systems in `after`. The final
+    /// execution order is resolved when the world is built.
+    pub fn add_system_dependency(&mut self, system: SystemId, after: &[SystemId]) {
+        if self.finalized {
+            panic!("Can't add system dependencies to finalized world")
+        }
+
+        for id in Some(&system).into_iter().chain(after.iter()) {
+            if !self.system_names.contains_key(id) {
+                panic!("Unknown system {}", id)
+            }
+        }
+
+        logging::debug!(self.log, "adding system dependency";
+                        "context" => "add_system_dependency",
+                        "id" => ?system,
+                        "after" => ?after);
+
+        self.system_deps
+            .entry(system)
+            .or_insert_with(Vec::new)
+            .extend_from_slice(after);
+    }
+
+    /// Sort the system registry so that each system runs after its dependencies. Systems without
+    /// constraints retain their registration order.
+    fn sort_systems(&mut self) {
+        if self.system_deps.is_empty() {
+            return;
+        }
+
+        let mut pending: Vec<SystemId> = self.state.systems.keys().cloned().collect();
+        let mut sorted: Vec<SystemId> = Vec::with_capacity(pending.len());
+
+        while !pending.is_empty() {
+            let next = pending.iter().position(|id| match self.system_deps.get(id) {
+                Some(deps) => deps.iter().all(|dep| sorted.contains(dep)),
+                None => true,
+            });
+
+            match next {
+                Some(idx) => sorted.push(pending.remove(idx)),
+                None => {
+                    let names: Vec<_> = pending.iter().map(|id| self.system_names[id]).collect();
+                    panic!(
+                        "Cyclic system ordering constraints detected between systems: {}",
+                        names.join(", ")
+                    )
+                }
+            }
+        }
+
+        logging::debug!(self.log, "sorted systems";
+                        "context" => "sort_systems",
+                        "order" => ?sorted);
+
+        let positions: HashMap<_, _> = sorted.iter().enumerate().map(|(pos, id)| (*id, pos)).collect();
+        self.state
+            .systems
+            .sort_by(|id1, id2| positions[id1].cmp(&positions[id2]));
+    }
+
+    /// Process all currently registered systems.
+    #[inline]
+    pub fn process_systems(&mut self) {
+        logging::debug!(self.log, "executing systems"; "context" => "process_systems");
+
+        match self.fixed_delta {
+            Some(fixed_delta) => {
+                let steps = self.advance_fixed_step(fixed_delta);
+
+                logging::debug!(self.log, "executing fixed step systems";
+                                "context" => "process_systems",
+                                "steps" => steps);
+
+                for _ in 0..steps {
+                    self.run_systems(fixed_delta, |id| self.fixed_systems.contains(id));
+                }
+
+                self.run_systems(self.delta, |id| !self.fixed_systems.contains(id));
+            }
+            _ => self.run_systems(self.delta, |_| true),
+        }
+
+        logging::debug!(self.log, "system execution finished"; "context" => "process_systems");
+    }
+
+    /// Accumulate the current frame delta and return the number of fixed steps to execute.
+    #[inline]
+    fn advance_fixed_step(&mut self, fixed_delta: f32) -> u32 {
+        let max_accumulated = fixed_delta * self.fixed_max_steps as f32;
+        self.fixed_accumulator = (self.fixed_accumulator + self.delta).min(max_accumulated);
+
+        let steps = (self.fixed_accumulator / fixed_delta) as u32;
+        self.fixed_accumulator -= fixed_delta * steps as f32;
+        steps
+    }
+
+    /// Run all the systems matching the selector with the given delta.
+    #[inline]
+    fn run_systems<F>(&self, delta: f32, select: F)
+    where
+        F: Fn(&SystemId) -> bool,
+    {
+        // Systems are run one at a time, each getting exclusive access to the central bus while it runs
+        let mut messages = self.messages.borrow_mut();
+
+        for (id, mut system) in self.state.systems.iter_mut::<System>() {
+            if !select(id) || self.disabled_systems.contains(id) {
+                continue;
+            }
+
+            logging::debug!(self.log, "system running";
+                            "context" => "process_systems",
+                            "system" => %id);
+
+            let started = match self.profiling {
+                true => Some(self.clock.now()),
+                _ => None,
+            };
+
+            unsafe {
+                system.run(
+                    &self.state.entities,
+                    self.get_system_transactions(id.indexer()),
+                    &mut messages,
+                    delta,
+                    self.timestamp,
+                );
+            }
+
+            if let Some(started) = started {
+                let elapsed = self.clock.now().duration_since(started);
+                *self.system_timings.borrow_mut().entry(*id).or_default() += elapsed;
+            }
+        }
+    }
+
+    // TODO: Check the performance impact of drain/rebuild and switch if negligible
+    /// Horribly unsafe function to get mutable references to multiple elements of the system
+    /// transactions without having to drain and rebuild the vector all the time.
+    #[inline]
+    unsafe fn get_system_transactions<'a>(&self, idx: usize) -> &'a mut TransactionContext {
+        let ptr = self.system_transactions.as_ptr() as *mut TransactionContext;
+        &mut *ptr.add(idx)
+    }
+}
 
 impl World {
+    /// Register the component class up front. Optional, components register themselves when first used
+    /// in a transaction or system query.
+    pub fn register_component<T>(&mut self) -> ComponentClass
+    where
+        T: 'static + Component,
+    {
+        let class = T::get_class();
+
+        logging::debug!(self.log, "registering component";
+                        "context" => "register_component",
+                        "name" => class.name());
+
+        class
+    }
+
     /// Register the supplied resource instance.
     pub fn register_resource<T>(&mut self, resource: T)
     where
@@ -265,6 +1192,82 @@ impl World {
         let boxed = Box::new(resource);
         self.state.resources.insert(Box::into_raw_non_null(boxed));
     }
+
+    /// Get a reference to the registered resource instance.
+    #[inline]
+    pub fn resource<T>(&self) -> Option<&T>
+    where
+        T: 'static,
+    {
+        // Systems only access resources while running, which requires a mutable borrow of the world
+        self.state
+            .resources
+            .get::<NonNull<T>>()
+            .map(|ptr| unsafe { &*ptr.as_ptr() })
+    }
+
+    /// Get a mutable reference to the registered resource instance.
+    #[inline]
+    pub fn resource_mut<T>(&mut self) -> Option<&mut T>
+    where
+        T: 'static,
+    {
+        self.state
+            .resources
+            .get::<NonNull<T>>()
+            .map(|ptr| unsafe { &mut *ptr.as_ptr() })
+    }
+
+    /// Replace the registered resource instance and return the previous one. The new value is moved
+    /// into the place of the old one, so systems holding the resource observe it on their next run.
+    /// Resources can't be replaced in a finalized world if any system writes them.
+    pub fn replace_resource<T>(&mut self, resource: T) -> Box<T>
+    where
+        T: 'static,
+    {
+        if self.finalized && self.resource_access(TypeId::of::<T>()) == Access::Write {
+            panic!("Can't replace resource written by systems of finalized world")
+        }
+
+        logging::debug!(self.log, "replacing resource";
+                        "context" => "replace_resource",
+                        "type" => unsafe { type_name::<T>() });
+
+        match self.state.resources.get::<NonNull<T>>() {
+            Some(ptr) => Box::new(mem::replace(unsafe { &mut *ptr.as_ptr() }, resource)),
+            _ => panic!("No {} resource registered", unsafe { type_name::<T>() }),
+        }
+    }
+
+    /// Remove the registered resource instance. Resources can't be removed from a finalized world
+    /// while any system queries them.
+    pub fn remove_resource<T>(&mut self) -> Option<Box<T>>
+    where
+        T: 'static,
+    {
+        if self.finalized && self.resource_access(TypeId::of::<T>()) != Access::None {
+            panic!("Can't remove resource used by systems of finalized world")
+        }
+
+        logging::debug!(self.log, "removing resource";
+                        "context" => "remove_resource",
+                        "type" => unsafe { type_name::<T>() });
+
+        self.state
+            .resources
+            .remove::<NonNull<T>>()
+            .map(|ptr| unsafe { Box::from_raw(ptr.as_ptr()) })
+    }
+
+    /// Get the most exclusive access any of the systems has to the given resource type.
+    fn resource_access(&self, type_id: TypeId) -> Access {
+        self.state
+            .systems
+            .iter::<System>()
+            .map(|(_, system)| system.resource_access(type_id))
+            .max()
+            .unwrap_or(Access::None)
+    }
 }
 
 pub struct GameState {
@@ -289,17 +1292,197 @@ impl GameState {
 }
 
 impl GameState {
+    /// Serialize the non-empty shards.
+    fn write_snapshot(&self, entity_counter: u64) -> SnapshotResult<Vec<u8>> {
+        let mut writer = SnapshotWriter::new();
+        writer.write_u64(entity_counter);
+
+        self.write_shards(&mut writer, |shard| shard.len() > 0)?;
+
+        Ok(writer.finish())
+    }
+
+    /// Serialize the dirty shards. Emptied out shards are included as well so that removals carry over.
+    fn write_delta_snapshot(&self, base_checksum: u32, entity_counter: u64) -> SnapshotResult<Vec<u8>> {
+        let mut writer = SnapshotWriter::delta(base_checksum);
+        writer.write_u64(entity_counter);
+
+        self.write_shards(&mut writer, |shard| shard.is_dirty())?;
+
+        Ok(writer.finish())
+    }
+
+    /// Serialize the selected shards in key order. Each shard is stored as the names of its components,
+    /// followed by the entity ids and the component vectors in the same order.
+    fn write_shards<F>(&self, writer: &mut SnapshotWriter, select: F) -> SnapshotResult<()>
+    where
+        F: Fn(&Shard) -> bool,
+    {
+        let mut shards: Vec<_> = self.shards.values().filter(|shard| select(shard)).collect();
+        shards.sort_by_key(|shard| shard.key);
+
+        writer.write_u32(shards.len() as u32);
+
+        for shard in shards {
+            let mut components: Vec<_> = shard.iter_components().collect();
+            components.sort_by_key(|&(cls, _)| cls);
+
+            writer.write_u16(components.len() as u16);
+            for &(cls, _) in components.iter() {
+                writer.write_str(cls.name());
+            }
+
+            let ids: Vec<_> = shard.iter_entities().map(|(id, _)| id).collect();
+            writer.write_block(&serde_json::to_vec(&ids)?);
+
+            for (_, data) in components {
+                writer.write_block(&data.to_json()?);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn clear_dirty(&self) {
+        for shard in self.shards.values() {
+            shard.clear_dirty();
+        }
+    }
+
+    /// Replace the entities with the snapshot contents and return the stored entity counter. The
+    /// snapshot is fully parsed before the current entities are dropped.
+    fn read_snapshot(&mut self, data: &[u8]) -> SnapshotResult<u64> {
+        let mut reader = SnapshotReader::open(data)?;
+
+        let entity_counter = reader.read_u64()?;
+        let shard_defs = Self::read_shards(&mut reader)?;
+
+        let mut ids: Vec<_> = self.entities.keys().cloned().collect();
+        ids.sort();
+
+        for id in ids {
+            self.process_delete(id);
+        }
+
+        self.ingest_shards(shard_defs);
+
+        Ok(entity_counter)
+    }
+
+    /// Replace the contents of the shards in the delta snapshot and return the stored entity counter.
+    fn read_delta_snapshot(&mut self, data: &[u8], base_checksum: u32) -> SnapshotResult<u64> {
+        let mut reader = SnapshotReader::open_delta(data)?;
+
+        if reader.read_u32()? != base_checksum {
+            return Err(SnapshotError::BaseMismatch);
+        }
+
+        let entity_counter = reader.read_u64()?;
+        let shard_defs = Self::read_shards(&mut reader)?;
+
+        let entity_comp_cls = EntityId::get_class();
+
+        for &(shard_key, _) in shard_defs.iter() {
+            let mut ids: Vec<_> = match self.shards.get(&(shard_key + entity_comp_cls)) {
+                Some(shard) => shard.iter_entities().map(|(id, _)| id).collect(),
+                None => continue,
+            };
+            ids.sort();
+
+            for id in ids {
+                self.process_delete(id);
+            }
+        }
+
+        // Entities may have moved into the replaced shards from shards outside of the delta
+        for (_, shard_def) in shard_defs.iter() {
+            for &id in shard_def.entity_ids.iter() {
+                self.process_delete(id);
+            }
+        }
+
+        self.ingest_shards(shard_defs);
+
+        Ok(entity_counter)
+    }
+
+    /// Parse the shards of the snapshot into shard definitions. Every component must be registered.
+    fn read_shards(reader: &mut SnapshotReader) -> SnapshotResult<Vec<(ShardKey, ShardDef)>> {
+        let shard_count = reader.read_u32()?;
+
+        let mut shard_defs = Vec::with_capacity(shard_count as usize);
+
+        for _ in 0..shard_count {
+            let comp_count = reader.read_u16()?;
+
+            let mut comp_classes = Vec::with_capacity(comp_count as usize);
+            for _ in 0..comp_count {
+                let name = reader.read_str()?;
+
+                let cls = match component::find_class(name) {
+                    Some(cls) if cls != EntityId::get_class() => cls,
+                    _ => return Err(SnapshotError::UnknownComponent(name.to_owned())),
+                };
+
+                if comp_classes.contains(&cls) {
+                    return Err(SnapshotError::Corrupted("duplicate component in shard"));
+                }
+
+                comp_classes.push(cls);
+            }
+
+            let mut shard_def = ShardDef::new(&comp_classes);
+            shard_def.entity_ids = serde_json::from_slice(reader.read_block()?)?;
+
+            for cls in comp_classes.iter() {
+                let count = shard_def
+                    .components
+                    .get_mut(cls)
+                    .unwrap()
+                    .extend_json(reader.read_block()?)?;
+
+                if count != shard_def.entity_ids.len() {
+                    return Err(SnapshotError::Corrupted("component count mismatch"));
+                }
+            }
+
+            let shard_key: ShardKey = comp_classes.iter().collect();
+            shard_defs.push((shard_key, shard_def));
+        }
+
+        if !reader.is_finished() {
+            return Err(SnapshotError::Corrupted("trailing data"));
+        }
+
+        Ok(shard_defs)
+    }
+
+    fn ingest_shards(&mut self, shard_defs: Vec<(ShardKey, ShardDef)>) {
+        for (shard_key, mut shard_def) in shard_defs {
+            if !shard_def.entity_ids.is_empty() {
+                self.process_add_uniform(shard_key, &mut shard_def);
+            }
+        }
+    }
+
     fn process_context(&mut self, ctx: &mut TransactionContext) {
         logging::trace!(self.log, "deleting entities"; "context" => "process_context");
-        // Drain all deleted entities into the delete buffer
+        // Drain all deleted entities into the delete buffer, the ids of removed entities are recycled
         for id in ctx.deleted.drain(..) {
-            if let Some(coords) = self.entities.remove(&id) {
-                logging::trace!(self.log, "deleting entity";
-                                "context" => "process_context",
-                                "id" => ?id,
-                                "shard_key" => ?coords.0,
-                                "loc" => coords.1);
-                self.process_remove(coords);
+            if self.process_delete(id) {
+                ctx.id_pool.release(id);
+            }
+        }
+
+        for predicate in ctx.deleted_where.drain(..) {
+            // Sorted to keep the resulting shard layout deterministic
+            let mut ids: Vec<_> = self.entities.keys().cloned().filter(|&id| (predicate.0)(id)).collect();
+            ids.sort();
+
+            for id in ids {
+                if self.process_delete(id) {
+                    ctx.id_pool.release(id);
+                }
             }
         }
 
@@ -310,6 +1493,56 @@ impl GameState {
                 self.process_add_uniform(key, shard);
             }
         }
+
+        logging::trace!(self.log, "editing entities"; "context" => "process_context");
+        for edit in ctx.edited.drain(..) {
+            self.process_edit(edit);
+        }
+    }
+
+    fn process_edit(&mut self, edit: ComponentEdit) {
+        let (id, comp_cls) = match edit {
+            ComponentEdit::Add(id, comp_cls, _) => (id, comp_cls),
+            ComponentEdit::Remove(id, comp_cls) => (id, comp_cls),
+        };
+
+        // Skip entities that have been deleted in the meantime
+        let coords = match self.entities.get(&id) {
+            Some(coords) => *coords,
+            None => return,
+        };
+
+        let entity_comp_cls = EntityId::get_class();
+
+        let shard_key = match edit {
+            ComponentEdit::Add(..) => coords.0 + comp_cls,
+            ComponentEdit::Remove(..) if coords.0.contains_id(comp_cls) => coords.0 - comp_cls,
+            // Nothing to do if the component is not present
+            ComponentEdit::Remove(..) => return,
+        };
+
+        // The shard definition must not contain the entity component class
+        let shard_key = shard_key - entity_comp_cls;
+
+        logging::trace!(self.log, "moving entity";
+                        "context" => "process_edit",
+                        "id" => ?id,
+                        "from_shard_key" => ?coords.0,
+                        "to_shard_key" => ?(shard_key + entity_comp_cls),
+                        "loc" => coords.1);
+
+        let comp_classes: Vec<_> = shard_key.decompose().collect();
+        let mut shard_def = ShardDef::new(&comp_classes);
+
+        // Move the existing components over to the new shard definition
+        shard_def.entity_ids.push(id);
+        self.process_move(coords, Some(&mut shard_def));
+
+        if let ComponentEdit::Add(_, comp_cls, data) = edit {
+            shard_def.components.insert(comp_cls, data);
+        }
+
+        self.process_add_uniform(shard_key, &mut shard_def);
     }
 
     fn process_add_uniform(&mut self, shard_key: ShardKey, shard_def: &mut ShardDef) {
@@ -333,7 +1566,8 @@ impl GameState {
         let shard = self.shards.entry(shard_key).or_insert_with(|| {
             logging::trace!(log, "adding new shard";
                             "context" => "process_add_uniform",
-                            "shard_key" => ?shard_key);
+                            "shard_key" => ?shard_key,
+                            "components" => ?shard_key.names());
 
             let store: HashMap<_, _> = shard_def
                 .components
@@ -348,7 +1582,8 @@ impl GameState {
         if shard.len() == 0 {
             logging::trace!(log, "notifying systems of newly populated shard";
                             "context" => "process_add_uniform",
-                            "shard_key" => ?shard_key);
+                            "shard_key" => ?shard_key,
+                            "components" => ?shard_key.names());
             systems
                 .iter_mut::<System>()
                 .for_each(|(_, mut sys)| sys.add_shard(shard));
@@ -364,13 +1599,42 @@ impl GameState {
         }
     }
 
-    fn process_remove(&mut self, (shard_key, loc): ComponentCoords) {
+    /// Delete the entity with the supplied id. Unknown and already deleted ids are ignored. Returns true
+    /// if the entity was deleted.
+    fn process_delete(&mut self, id: EntityId) -> bool {
+        match self.entities.remove(&id) {
+            Some(coords) => {
+                logging::trace!(self.log, "deleting entity";
+                                "context" => "process_delete",
+                                "id" => ?id,
+                                "shard_key" => ?coords.0,
+                                "loc" => coords.1);
+                self.process_remove(coords);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn process_remove(&mut self, coords: ComponentCoords) {
+        self.process_move(coords, None);
+    }
+
+    /// Remove the entity at the supplied coordinates from its shard, optionally moving its components
+    /// into the target shard definition.
+    fn process_move(&mut self, (shard_key, loc): ComponentCoords, target: Option<&mut ShardDef>) {
         let shard = self.shards.get_mut(&shard_key).unwrap();
 
+        let swapped = match target {
+            Some(shard_def) => shard.transfer(loc, shard_def),
+            None => shard.remove(loc),
+        };
+
         // Update the location of the swapped-in entity
-        if let Some(swapped_id) = shard.remove(loc) {
+        if let Some(swapped_id) = swapped {
             logging::trace!(self.log, "swapping in entity";
-                                "context" => "process_remove",
+                                "context" => "process_move",
                                 "id" => ?swapped_id,
                                 "shard_key" => ?shard_key,
                                 "loc" => loc);
@@ -380,8 +1644,9 @@ impl GameState {
         // Remove the shard from the systems if it got emptied out
         if shard.len() == 0 {
             logging::trace!(self.log, "unregistering empty shard";
-                                "context" => "process_remove",
-                                "shard_key" => ?shard_key);
+                                "context" => "process_move",
+                                "shard_key" => ?shard_key,
+                                "components" => ?shard_key.names());
 
             self.systems
                 .iter_mut::<System>()
@@ -398,11 +1663,12 @@ mod tests {
     use crate::messagebus::Message;
     use crate::system::{Components, Context, Read, Resources, Router, Write};
     use crate::topic_init;
+    use flux::time::ManualClock;
     use serde_derive::{Deserialize, Serialize};
     use std::cell::RefCell;
     use std::marker::PhantomData;
-    use std::ptr::NonNull;
     use std::rc::Rc;
+    use std::thread;
 
     #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
     struct CompA(i32);
@@ -428,107 +1694,1680 @@ mod tests {
         }
     }
 
-    #[derive(Debug, Clone, Eq, PartialEq)]
-    struct Msg1(i32);
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    struct Msg1(i32);
+
+    topic_init!(Msg1);
+
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    struct Msg2(i32);
+
+    topic_init!(Msg2);
+
+    #[test]
+    fn test_add_entity() {
+        let mut world = World::default();
+        world.build();
+
+        {
+            let mut batcher = world.entities().batch::<(CompA, CompB)>();
+            batcher.add(CompA(1), CompB(1));
+            batcher.add(CompA(2), CompB(2));
+            batcher.commit();
+        }
+
+        world.entities().add((CompA(3), CompB(3), CompC::new(3, 3)));
+
+        world.process_transactions();
+
+        assert_eq!(world.state.entities.len(), 3);
+        assert_eq!(world.state.shards.len(), 2);
+        assert_eq!(
+            world.state.entities[&0.into()],
+            (EntityId::get_class() + CompA::get_class() + CompB::get_class(), 0)
+        );
+        assert_eq!(
+            world.state.entities[&1.into()],
+            (EntityId::get_class() + CompA::get_class() + CompB::get_class(), 1)
+        );
+        assert_eq!(
+            world.state.entities[&2.into()],
+            (
+                EntityId::get_class() + CompA::get_class() + CompB::get_class() + CompC::get_class(),
+                0
+            )
+        );
+    }
+
+    #[test]
+    fn test_batch_commit_on_drop() {
+        let mut world = World::default();
+        world.build();
+
+        {
+            let mut batcher = world.entities().batch::<(CompA, CompB)>();
+            batcher.add(CompA(1), CompB(1));
+            batcher.commit();
+            batcher.add(CompA(2), CompB(2));
+            batcher.add(CompA(3), CompB(3));
+        }
+
+        let comp_classes = [CompA::get_class(), CompC::get_class()];
+
+        {
+            let mut batcher = world.entities().batch_json(&comp_classes);
+            batcher.add(&["4".to_owned(), r#"{"x": 4, "y": 4}"#.to_owned()]);
+        }
+
+        world.process_transactions();
+
+        // The uncommitted entities are committed when the builders are dropped
+        assert_eq!(world.entity_count(), 4);
+        assert_eq!(world.get_component::<CompA>(1.into()), Some(&CompA(2)));
+        assert_eq!(world.get_component::<CompB>(2.into()), Some(&CompB(3)));
+        assert_eq!(world.get_component::<CompC>(3.into()), Some(&CompC::new(4, 4)));
+    }
+
+    #[test]
+    fn test_multi_batch() {
+        let mut world = World::default();
+        world.build();
+
+        let ids = {
+            let mut batcher = world.entities().multi_batch();
+            batcher.add((CompA(1), CompB(1)));
+            batcher.add((CompA(2), CompC::new(2, 2)));
+            batcher.add((CompA(3), CompB(3)));
+            batcher.commit().to_vec()
+        };
+
+        assert_eq!(ids, vec![EntityId::from(0), EntityId::from(1), EntityId::from(2)]);
+
+        world.process_transactions();
+
+        let key_ab = EntityId::get_class() + CompA::get_class() + CompB::get_class();
+        let key_ac = EntityId::get_class() + CompA::get_class() + CompC::get_class();
+
+        assert_eq!(world.entity_count(), 3);
+        assert_eq!(world.state.entities[&ids[0]], (key_ab, 0));
+        assert_eq!(world.state.entities[&ids[1]], (key_ac, 0));
+        assert_eq!(world.state.entities[&ids[2]], (key_ab, 1));
+
+        assert_eq!(world.get_component::<CompB>(ids[2]), Some(&CompB(3)));
+        assert_eq!(world.get_component::<CompC>(ids[1]), Some(&CompC::new(2, 2)));
+    }
+
+    #[test]
+    fn test_remove_entity() {
+        let mut world = World::default();
+        world.build();
+
+        {
+            let mut batcher = world.entities().batch::<(CompA, CompB)>();
+            batcher.add(CompA(1), CompB(1));
+            batcher.add(CompA(2), CompB(2));
+            batcher.add(CompA(3), CompB(3));
+            batcher.add(CompA(4), CompB(4));
+            batcher.commit();
+        }
+
+        world.process_transactions();
+        assert_eq!(world.state.entities.len(), 4);
+        assert_eq!(world.state.entities[&0.into()].1, 0);
+        assert_eq!(world.state.entities[&1.into()].1, 1);
+        assert_eq!(world.state.entities[&2.into()].1, 2);
+        assert_eq!(world.state.entities[&3.into()].1, 3);
+
+        world.entities().remove(0.into());
+
+        world.process_transactions();
+        assert_eq!(world.state.entities.len(), 3);
+        assert_eq!(world.state.entities[&1.into()].1, 1);
+        assert_eq!(world.state.entities[&2.into()].1, 2);
+        assert_eq!(world.state.entities[&3.into()].1, 0);
+
+        world.entities().remove(1.into());
+
+        world.process_transactions();
+        assert_eq!(world.state.entities.len(), 2);
+        assert_eq!(world.state.entities[&2.into()].1, 1);
+        assert_eq!(world.state.entities[&3.into()].1, 0);
+
+        world.entities().remove(3.into());
+
+        world.process_transactions();
+        assert_eq!(world.state.entities.len(), 1);
+        assert_eq!(world.state.entities[&2.into()].1, 0);
+
+        world.entities().remove(2.into());
+
+        world.process_transactions();
+        assert_eq!(world.state.entities.len(), 0);
+    }
+
+    #[test]
+    fn test_clear_transactions() {
+        let mut world = World::default();
+        world.build();
+
+        let id = world.entities().add((CompA(1), CompB(1)));
+        world.process_transactions();
+        assert!(world.entities().is_empty());
+
+        {
+            let mut batcher = world.entities().batch::<(CompA, CompB)>();
+            batcher.add(CompA(2), CompB(2));
+            batcher.commit();
+        }
+
+        world.entities().add((CompA(3), CompB(3), CompC::new(3, 3)));
+        world.entities().add_component(id, CompC::new(1, 1));
+        world.entities().remove(id);
+        assert!(!world.entities().is_empty());
+
+        world.entities().clear();
+        assert!(world.entities().is_empty());
+
+        // Nothing staged before the clear is applied
+        world.process_transactions();
+        assert_eq!(world.state.entities.len(), 1);
+        assert_eq!(
+            world.state.entities[&id],
+            (EntityId::get_class() + CompA::get_class() + CompB::get_class(), 0)
+        );
+        assert_eq!(world.state.shards.len(), 1);
+
+        // The context remains usable afterwards
+        world.entities().add((CompA(4), CompB(4)));
+        world.process_transactions();
+        assert_eq!(world.state.entities.len(), 2);
+    }
+
+    #[test]
+    fn test_remove_bulk() {
+        let mut world = World::default();
+        world.build();
+
+        {
+            let mut batcher = world.entities().batch::<(CompA, CompB)>();
+            for i in 0..10 {
+                batcher.add(CompA(i), CompB(i as u64));
+            }
+            batcher.commit();
+        }
+
+        world.process_transactions();
+        assert_eq!(world.state.entities.len(), 10);
+
+        // Duplicate and unknown ids are ignored
+        world.entities().remove_all(&[1.into(), 3.into(), 3.into(), 100.into()]);
+        world.entities().remove_where(|id| {
+            let id: usize = id.into();
+            id % 2 == 0
+        });
+        world.entities().remove(1.into());
+
+        world.process_transactions();
+
+        let mut survivors: Vec<_> = world.state.entities.keys().cloned().collect();
+        survivors.sort();
+
+        assert_eq!(survivors, vec![5.into(), 7.into(), 9.into()]);
+
+        let key = EntityId::get_class() + CompA::get_class() + CompB::get_class();
+        assert_eq!(world.state.shards[&key].len(), 3);
+
+        for id in survivors {
+            assert!(world.state.entities[&id].1 < 3);
+        }
+    }
+
+    fn snapshot_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("neutronium_{}_{}.snapshot", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let path = snapshot_path("roundtrip");
+
+        let mut world = World::default();
+        world.build();
+
+        {
+            let mut batcher = world.entities().batch::<(CompA, CompB)>();
+            batcher.add(CompA(1), CompB(1));
+            batcher.add(CompA(2), CompB(2));
+            batcher.add(CompA(3), CompB(3));
+            batcher.commit();
+        }
+
+        let id = world.entities().add((CompA(4), CompB(4), CompC::new(4, 4)));
+        world.entities().remove(1.into());
+        world.process_transactions();
+
+        world.save_snapshot(&path).unwrap();
+
+        let mut loaded = World::default();
+        loaded.build();
+
+        // Existing entities are replaced
+        loaded.entities().add((CompA(10), CompC::new(10, 10)));
+        loaded.process_transactions();
+
+        loaded.load_snapshot(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.state.entities, world.state.entities);
+
+        let key_ab = EntityId::get_class() + CompA::get_class() + CompB::get_class();
+        let key_abc = key_ab + CompC::get_class();
+        let key_ac = EntityId::get_class() + CompA::get_class() + CompC::get_class();
+
+        assert_eq!(loaded.state.shards[&key_ab].len(), 2);
+        assert_eq!(loaded.state.shards[&key_abc].len(), 1);
+        assert_eq!(loaded.state.shards[&key_ac].len(), 0);
+
+        unsafe {
+            let shard_ab = &loaded.state.shards[&key_ab];
+            assert_eq!(*shard_ab.data_ptr::<EntityId>(), vec![EntityId::from(0), EntityId::from(2)]);
+            assert_eq!(*shard_ab.data_ptr::<CompA>(), vec![CompA(1), CompA(3)]);
+            assert_eq!(*shard_ab.data_ptr::<CompB>(), vec![CompB(1), CompB(3)]);
+
+            let shard_abc = &loaded.state.shards[&key_abc];
+            assert_eq!(*shard_abc.data_ptr::<CompC>(), vec![CompC::new(4, 4)]);
+        }
+
+        // New entities don't reuse the restored ids
+        let new_id = loaded.entities().add((CompA(5), CompB(5)));
+        assert!(new_id > id);
+    }
+
+    #[test]
+    fn test_delta_snapshot() {
+        struct TestSystem<'a> {
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = Components<(Read<'a, EntityId>, Write<'a, CompB>)>;
+
+            fn run(&mut self, mut ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                for (_, b) in ctx.components() {
+                    b.0 += 10;
+                }
+            }
+        }
+
+        let base_path = snapshot_path("delta_base");
+        let delta_path = snapshot_path("delta");
+
+        let mut world = World::default();
+        world.register_system(TestSystem { _p: PhantomData });
+        world.build();
+
+        world.entities().add((CompA(1), CompB(1)));
+        world.entities().add((CompA(2), CompB(2)));
+        world.entities().add((CompA(3), CompC::new(3, 3)));
+        world.process_transactions();
+
+        world.save_snapshot(&base_path).unwrap();
+
+        let key_ab = EntityId::get_class() + CompA::get_class() + CompB::get_class();
+        let key_ac = EntityId::get_class() + CompA::get_class() + CompC::get_class();
+
+        assert!(!world.state.shards[&key_ab].is_dirty());
+        assert!(!world.state.shards[&key_ac].is_dirty());
+
+        // The system only writes the components of the first shard
+        world.run_once();
+
+        assert!(world.state.shards[&key_ab].is_dirty());
+        assert!(!world.state.shards[&key_ac].is_dirty());
+
+        world.save_delta_snapshot(&base_path, &delta_path).unwrap();
+
+        assert!(!world.state.shards[&key_ab].is_dirty());
+
+        let delta = fs::read(&delta_path).unwrap();
+        let mut reader = SnapshotReader::open_delta(&delta).unwrap();
+        reader.read_u32().unwrap();
+        reader.read_u64().unwrap();
+
+        let shard_defs = GameState::read_shards(&mut reader).unwrap();
+        let keys: Vec<_> = shard_defs.iter().map(|&(key, _)| key + EntityId::get_class()).collect();
+
+        assert_eq!(keys, vec![key_ab]);
+
+        // Applying the delta on top of the base restores the current state
+        let mut loaded = World::default();
+        loaded.build();
+        loaded.load_snapshot(&base_path).unwrap();
+        loaded.apply_delta_snapshot(&base_path, &delta_path).unwrap();
+
+        fs::remove_file(&base_path).unwrap();
+        fs::remove_file(&delta_path).unwrap();
+
+        assert_eq!(loaded.state.entities, world.state.entities);
+
+        unsafe {
+            assert_eq!(*loaded.state.shards[&key_ab].data_ptr::<CompB>(), vec![CompB(11), CompB(12)]);
+            assert_eq!(*loaded.state.shards[&key_ac].data_ptr::<CompC>(), vec![CompC::new(3, 3)]);
+        }
+    }
+
+    #[test]
+    fn test_delta_snapshot_chain() {
+        struct TestSystem<'a> {
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = Components<(Read<'a, EntityId>, Write<'a, CompB>)>;
+
+            fn run(&mut self, mut ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                for (_, b) in ctx.components() {
+                    b.0 += 10;
+                }
+            }
+        }
+
+        let base_path = snapshot_path("chain_base");
+        let delta1_path = snapshot_path("chain_delta1");
+        let delta2_path = snapshot_path("chain_delta2");
+
+        let mut world = World::default();
+        world.register_system(TestSystem { _p: PhantomData });
+        world.build();
+
+        world.entities().add((CompA(1), CompB(1)));
+        world.process_transactions();
+        world.save_snapshot(&base_path).unwrap();
+
+        world.run_once();
+        world.save_delta_snapshot(&base_path, &delta1_path).unwrap();
+
+        // The second delta only holds the changes since the first one and must be based on it
+        world.run_once();
+        match world.save_delta_snapshot(&base_path, &delta2_path) {
+            Err(SnapshotError::BaseMismatch) => (),
+            _ => panic!("Delta saved on a stale base"),
+        }
+        world.save_delta_snapshot(&delta1_path, &delta2_path).unwrap();
+
+        let mut loaded = World::default();
+        loaded.build();
+        loaded.load_snapshot(&base_path).unwrap();
+
+        // Skipping a delta of the chain is rejected
+        match loaded.apply_delta_snapshot(&base_path, &delta2_path) {
+            Err(SnapshotError::BaseMismatch) => (),
+            _ => panic!("Delta applied on the wrong base"),
+        }
+
+        loaded.apply_delta_snapshot(&base_path, &delta1_path).unwrap();
+        loaded.apply_delta_snapshot(&delta1_path, &delta2_path).unwrap();
+
+        fs::remove_file(&base_path).unwrap();
+        fs::remove_file(&delta1_path).unwrap();
+        fs::remove_file(&delta2_path).unwrap();
+
+        let key_ab = EntityId::get_class() + CompA::get_class() + CompB::get_class();
+
+        assert_eq!(loaded.state.entities, world.state.entities);
+
+        unsafe {
+            assert_eq!(*loaded.state.shards[&key_ab].data_ptr::<CompB>(), vec![CompB(21)]);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_unknown_component() {
+        let path = snapshot_path("unknown_component");
+
+        let mut writer = SnapshotWriter::new();
+        writer.write_u64(1);
+        writer.write_u32(1);
+        writer.write_u16(1);
+        writer.write_str("NotAComponent");
+        writer.write_block(b"[0]");
+        writer.write_block(b"[0]");
+        fs::write(&path, writer.finish()).unwrap();
+
+        let mut world = World::default();
+        world.build();
+
+        let id = world.entities().add((CompA(1), CompB(1)));
+        world.process_transactions();
+
+        let result = world.load_snapshot(&path);
+        fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(SnapshotError::UnknownComponent(name)) => assert_eq!(name, "NotAComponent"),
+            _ => panic!("Snapshot with an unknown component loaded"),
+        }
+
+        // The world is left untouched
+        assert_eq!(world.entity_count(), 1);
+        assert!(world.state.entities.contains_key(&id));
+    }
+
+    #[test]
+    fn test_reclaim_memory() {
+        let mut world = World::default();
+        world.build();
+
+        {
+            let mut batcher = world.entities().batch::<(CompA, CompB)>();
+            for i in 0..1000 {
+                batcher.add(CompA(i), CompB(i as u64));
+            }
+            batcher.commit();
+        }
+
+        world.process_transactions();
+
+        let key = EntityId::get_class() + CompA::get_class() + CompB::get_class();
+        let ids: Vec<EntityId> = (10..1000usize).map(Into::into).collect();
+        world.entities().remove_all(&ids);
+        world.process_transactions();
+
+        let capacity = world.state.shards[&key].capacity();
+        assert!(capacity >= 1000);
+
+        assert_eq!(world.reclaim_memory(), 1);
+
+        let shard = &world.state.shards[&key];
+        assert_eq!(shard.len(), 10);
+        assert!(shard.capacity() < capacity);
+        assert!(shard.iter_components().all(|(_, data)| data.capacity() < capacity));
+
+        // Shards close to their capacity are left alone
+        assert_eq!(world.reclaim_memory(), 0);
+
+        // The shard remains usable afterwards
+        world.entities().add((CompA(1000), CompB(1000)));
+        world.process_transactions();
+        assert_eq!(world.state.shards[&key].len(), 11);
+    }
+
+    #[test]
+    fn test_get_component() {
+        let mut world = World::default();
+        world.build();
+
+        let id1 = world.entities().add((CompA(1), CompB(1)));
+        let id2 = world.entities().add((CompA(2), CompC::new(2, 2)));
+
+        // Staged entities don't exist yet
+        assert!(!world.contains_entity(id1));
+        assert_eq!(world.get_component::<CompA>(id1), None);
+
+        world.process_transactions();
+
+        assert!(world.contains_entity(id1));
+        assert!(world.contains_entity(id2));
+        assert_eq!(world.get_component::<CompA>(id1), Some(&CompA(1)));
+        assert_eq!(world.get_component::<CompB>(id1), Some(&CompB(1)));
+        assert_eq!(world.get_component::<EntityId>(id1), Some(&id1));
+        assert_eq!(world.get_component::<CompC>(id2), Some(&CompC::new(2, 2)));
+
+        // Missing components
+        assert_eq!(world.get_component::<CompC>(id1), None);
+        assert_eq!(world.get_component::<CompB>(id2), None);
+
+        // Removed entities
+        world.entities().remove(id1);
+        world.process_transactions();
+
+        assert!(!world.contains_entity(id1));
+        assert_eq!(world.get_component::<CompA>(id1), None);
+
+        // Other entities are unaffected
+        assert_eq!(world.get_component::<CompA>(id2), Some(&CompA(2)));
+
+        // Entities that never existed
+        assert!(!world.contains_entity(1000.into()));
+        assert_eq!(world.get_component::<CompA>(1000.into()), None);
+    }
+
+    #[test]
+    fn test_resources() {
+        struct TestResource1 {
+            x: i32,
+        }
+
+        struct TestResource2 {
+            x: i32,
+        }
+
+        struct TestSystem<'a> {
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = Resources<(Read<'a, TestResource1>, Write<'a, TestResource2>)>;
+
+            fn run(&mut self, mut ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                let (r1, mut r2) = ctx.resources();
+                r2.x = r1.x;
+            }
+        }
+
+        let mut world = World::default();
+        world.register_resource(TestResource1 { x: 100 });
+        world.register_resource(TestResource2 { x: 0 });
+        world.register_system(TestSystem { _p: PhantomData });
+        world.build();
+
+        world.run_once();
+
+        assert_eq!(world.resource::<TestResource2>().unwrap().x, 100)
+    }
+
+    #[test]
+    fn test_resource_accessors() {
+        let mut world = World::default();
+        world.register_resource(TestResource { x: 1 });
+        world.build();
+
+        assert_eq!(world.resource::<TestResource>().unwrap().x, 1);
+        assert!(world.resource::<i32>().is_none());
+
+        world.resource_mut::<TestResource>().unwrap().x = 5;
+        assert_eq!(world.resource::<TestResource>().unwrap().x, 5);
+        assert!(world.resource_mut::<i32>().is_none());
+    }
+
+    struct TestResource {
+        x: i32,
+    }
+
+    struct ReadResourceSystem<'a> {
+        seen: Rc<RefCell<i32>>,
+        _p: PhantomData<&'a ()>,
+    }
+
+    impl<'a> RunSystem for ReadResourceSystem<'a> {
+        type Data = Resources<Read<'a, TestResource>>;
+
+        fn run(&mut self, mut ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+            *self.seen.borrow_mut() = ctx.resources().x;
+        }
+    }
+
+    struct WriteResourceSystem<'a> {
+        _p: PhantomData<&'a ()>,
+    }
+
+    impl<'a> RunSystem for WriteResourceSystem<'a> {
+        type Data = Resources<Write<'a, TestResource>>;
+
+        fn run(&mut self, mut ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+            ctx.resources().x += 1;
+        }
+    }
+
+    #[test]
+    fn test_replace_resource() {
+        let seen = Rc::new(RefCell::new(0));
+
+        let mut world = World::default();
+        world.register_resource(TestResource { x: 1 });
+        world.register_system(ReadResourceSystem {
+            seen: seen.clone(),
+            _p: PhantomData,
+        });
+        world.build();
+
+        world.run_once();
+        assert_eq!(*seen.borrow(), 1);
+
+        let old = world.replace_resource(TestResource { x: 2 });
+        assert_eq!(old.x, 1);
+
+        world.run_once();
+        assert_eq!(*seen.borrow(), 2);
+    }
+
+    #[test]
+    fn test_remove_resource() {
+        let mut world = World::default();
+        world.register_resource(TestResource { x: 1 });
+
+        assert_eq!(world.remove_resource::<TestResource>().unwrap().x, 1);
+        assert!(world.remove_resource::<TestResource>().is_none());
+
+        // Unused resources can be removed from a finalized world
+        world.register_resource(TestResource { x: 2 });
+        world.build();
+
+        assert_eq!(world.remove_resource::<TestResource>().unwrap().x, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't replace resource written by systems of finalized world")]
+    fn test_replace_written_resource() {
+        let mut world = World::default();
+        world.register_resource(TestResource { x: 1 });
+        world.register_system(WriteResourceSystem { _p: PhantomData });
+        world.build();
+
+        world.replace_resource(TestResource { x: 2 });
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't remove resource used by systems of finalized world")]
+    fn test_remove_used_resource() {
+        let seen = Rc::new(RefCell::new(0));
+
+        let mut world = World::default();
+        world.register_resource(TestResource { x: 1 });
+        world.register_system(ReadResourceSystem { seen, _p: PhantomData });
+        world.build();
+
+        world.remove_resource::<TestResource>();
+    }
+
+    #[test]
+    fn test_ingest_system_transactions() {
+        // Create a system that adds a new entity and removes an existing one
+        struct TestSystem<'a> {
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = Components<(Read<'a, EntityId>, Read<'a, CompA>, Write<'a, CompB>)>;
+
+            fn run(&mut self, _ctx: Context<Self::Data>, tx: &mut TransactionContext, _msg: Router) {
+                tx.add((CompA(3), CompB(3)));
+                tx.remove(0.into());
+            }
+        }
+
+        let mut world = World::default();
+        world.register_system(TestSystem { _p: PhantomData });
+        world.build();
+
+        {
+            let mut batcher = world.entities().batch::<(CompA, CompB)>();
+            batcher.add(CompA(0), CompB(0));
+            batcher.add(CompA(1), CompB(1));
+            batcher.add(CompA(2), CompB(2));
+            batcher.commit();
+        }
+
+        // Process the initial state
+        world.process_transactions();
+
+        assert_eq!(world.state.entities.len(), 3);
+        assert_eq!(world.state.entities[&0.into()].1, 0);
+        assert_eq!(world.state.entities[&1.into()].1, 1);
+        assert_eq!(world.state.entities[&2.into()].1, 2);
+
+        // Run the system, triggering the edit and addition
+        world.run_once();
+        world.process_transactions();
+
+        assert_eq!(world.state.entities.len(), 3);
+        assert_eq!(world.state.entities[&1.into()].1, 1);
+        assert_eq!(world.state.entities[&2.into()].1, 0);
+        assert_eq!(world.state.entities[&3.into()].1, 2);
+    }
+
+    #[test]
+    fn test_system_messaging() {
+        struct TestSystem1<'a> {
+            _p: PhantomData<&'a ()>,
+            messages: Rc<RefCell<Vec<Msg1>>>,
+        }
+
+        impl<'a> RunSystem for TestSystem1<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, mut msg: Router) {
+                for message in msg.read::<Msg1>() {
+                    self.messages.borrow_mut().push(message.clone());
+                }
+
+                msg.publish(Msg2(0));
+                msg.publish(Msg2(1));
+                msg.publish(Msg2(2));
+            }
+        }
+
+        struct TestSystem2<'a> {
+            _p: PhantomData<&'a ()>,
+            messages: Rc<RefCell<Vec<Msg2>>>,
+        }
+
+        impl<'a> RunSystem for TestSystem2<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, mut msg: Router) {
+                for message in msg.read::<Msg2>() {
+                    self.messages.borrow_mut().push(message.clone());
+                }
+
+                msg.publish(Msg1(0));
+                msg.publish(Msg1(1));
+            }
+        }
+
+        let system_messages1 = Rc::new(RefCell::new(Vec::new()));
+        let system_messages2 = Rc::new(RefCell::new(Vec::new()));
+
+        let mut world = World::default();
+
+        world.register_system(TestSystem1 {
+            _p: PhantomData,
+            messages: system_messages1.clone(),
+        });
+
+        world.register_system(TestSystem2 {
+            _p: PhantomData,
+            messages: system_messages2.clone(),
+        });
+        world.build();
+
+        // Run the world iteration once, propagating the messages
+        world.run_once();
+
+        assert_eq!(world.messages.borrow().read::<Msg1>(), &[Msg1(0), Msg1(1)]);
+        assert_eq!(world.messages.borrow().read::<Msg2>(), &[Msg2(0), Msg2(1), Msg2(2)]);
+
+        // Run the world iteration the second time, allowing the systems to ingest the messages
+        world.run_once();
+
+        assert_eq!(*system_messages1.borrow(), vec![Msg1(0), Msg1(1)]);
+        assert_eq!(*system_messages2.borrow(), vec![Msg2(0), Msg2(1), Msg2(2)]);
+    }
+
+    #[test]
+    fn test_system_drain_messages() {
+        struct Publisher<'a> {
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for Publisher<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, mut msg: Router) {
+                msg.publish(Msg1(0));
+                msg.publish(Msg1(1));
+            }
+        }
+
+        struct Drainer<'a> {
+            _p: PhantomData<&'a ()>,
+            messages: Rc<RefCell<Vec<Msg1>>>,
+        }
+
+        impl<'a> RunSystem for Drainer<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, mut msg: Router) {
+                self.messages.borrow_mut().append(&mut msg.drain::<Msg1>());
+            }
+        }
+
+        struct Reader<'a> {
+            _p: PhantomData<&'a ()>,
+            seen: Rc<RefCell<usize>>,
+        }
+
+        impl<'a> RunSystem for Reader<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, msg: Router) {
+                *self.seen.borrow_mut() += msg.read::<Msg1>().len();
+            }
+        }
+
+        let drained = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::new(RefCell::new(0));
+
+        let mut world = World::default();
+        world.register_system(Publisher { _p: PhantomData });
+        world.register_system(Drainer {
+            _p: PhantomData,
+            messages: drained.clone(),
+        });
+        world.register_system(Reader {
+            _p: PhantomData,
+            seen: seen.clone(),
+        });
+        world.build();
+
+        world.run_once();
+        world.run_once();
+
+        // Systems running after the drain don't see the messages
+        assert_eq!(*drained.borrow(), vec![Msg1(0), Msg1(1)]);
+        assert_eq!(*seen.borrow(), 0);
+    }
+
+    #[test]
+    fn test_system_init() {
+        struct TestSystem1<'a> {
+            initialized: bool,
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem1<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, mut _msg: Router) {}
+
+            fn init(&mut self) {
+                self.initialized = true;
+            }
+        }
+
+        let mut world = World::default();
+
+        let id = world.register_system(TestSystem1 {
+            initialized: false,
+            _p: PhantomData,
+        });
+
+        world.build();
+
+        let mut system_runtime = world.state.systems.get::<SystemRuntime<TestSystem1>>(&id).write();
+        let system = system_runtime.get_system_mut();
+
+        assert_eq!(system.initialized, true);
+    }
+
+    #[test]
+    fn test_system_ordering() {
+        struct TestSystem<'a> {
+            tag: i32,
+            order: Rc<RefCell<Vec<i32>>>,
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                self.order.borrow_mut().push(self.tag);
+            }
+        }
+
+        let order = Rc::new(RefCell::new(Vec::new()));
+
+        let mut world = World::default();
+
+        let id1 = world.register_system(TestSystem {
+            tag: 1,
+            order: order.clone(),
+            _p: PhantomData,
+        });
+        let id2 = world.register_system(TestSystem {
+            tag: 2,
+            order: order.clone(),
+            _p: PhantomData,
+        });
+        world.register_system_after(
+            TestSystem {
+                tag: 3,
+                order: order.clone(),
+                _p: PhantomData,
+            },
+            &[id2],
+        );
+
+        // System 1 must run after system 2
+        world.add_system_dependency(id1, &[id2]);
+        world.build();
+
+        world.run_once();
+
+        assert_eq!(*order.borrow(), vec![2, 1, 3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cyclic system ordering constraints detected between systems")]
+    fn test_system_ordering_cycle() {
+        struct TestSystem<'a> {
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {}
+        }
+
+        let mut world = World::default();
+
+        let id1 = world.register_system(TestSystem { _p: PhantomData });
+        let id2 = world.register_system(TestSystem { _p: PhantomData });
+
+        world.add_system_dependency(id1, &[id2]);
+        world.add_system_dependency(id2, &[id1]);
+
+        world.build();
+    }
+
+    #[test]
+    fn test_add_remove_component() {
+        struct TestSystem<'a> {
+            seen: Rc<RefCell<Vec<(EntityId, CompC)>>>,
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = Components<(Read<'a, EntityId>, Read<'a, CompC>)>;
+
+            fn run(&mut self, mut ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                for (&id, c) in ctx.components() {
+                    self.seen.borrow_mut().push((id, c.clone()));
+                }
+            }
+        }
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+
+        let mut world = World::default();
+        world.register_system(TestSystem {
+            seen: seen.clone(),
+            _p: PhantomData,
+        });
+        world.build();
+
+        let id1 = world.entities().add((CompA(1), CompB(1)));
+        let id2 = world.entities().add((CompA(2), CompB(2)));
+
+        world.entities().add_component(id1, CompC::new(1, 1));
+        world.entities().remove_component(id2, CompB::get_class());
+
+        world.run_once();
+
+        let key_ab = EntityId::get_class() + CompA::get_class() + CompB::get_class();
+
+        assert_eq!(world.state.entities.len(), 2);
+        assert_eq!(world.state.entities[&id1], (key_ab + CompC::get_class(), 0));
+        assert_eq!(world.state.entities[&id2], (EntityId::get_class() + CompA::get_class(), 0));
+        assert_eq!(world.state.shards[&key_ab].len(), 0);
+        assert_eq!(*seen.borrow(), vec![(id1, CompC::new(1, 1))]);
+
+        // Removing the component again moves the entity out of the system's view
+        seen.borrow_mut().clear();
+        world.entities().remove_component(id1, CompC::get_class());
+
+        world.run_once();
+
+        assert_eq!(world.state.entities[&id1], (key_ab, 0));
+        assert_eq!(world.state.shards[&(key_ab + CompC::get_class())].len(), 0);
+        assert!(seen.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_run_for() {
+        struct TestSystem<'a> {
+            count: Rc<RefCell<u64>>,
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                *self.count.borrow_mut() += 1;
+            }
+        }
+
+        let count = Rc::new(RefCell::new(0u64));
+
+        let mut world = World::new(1000, None);
+        world.register_system(TestSystem {
+            count: count.clone(),
+            _p: PhantomData,
+        });
+        world.build();
+
+        world.run_for(5);
+        assert_eq!(*count.borrow(), 5);
+
+        world.run_for(0);
+        assert_eq!(*count.borrow(), 5);
+
+        let pred_count = count.clone();
+        world.run_until(move |_| *pred_count.borrow() >= 12);
+        assert_eq!(*count.borrow(), 12);
+    }
+
+    #[test]
+    fn test_stop() {
+        struct TestSystem<'a> {
+            count: Rc<RefCell<u64>>,
+            shutdown: Rc<RefCell<bool>>,
+            stop: StopHandle,
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                *self.count.borrow_mut() += 1;
+
+                if *self.count.borrow() == 3 {
+                    self.stop.stop();
+                }
+            }
+
+            fn shutdown(&mut self) {
+                *self.shutdown.borrow_mut() = true;
+            }
+        }
+
+        let count = Rc::new(RefCell::new(0u64));
+        let shutdown = Rc::new(RefCell::new(false));
+
+        let mut world = World::new(1000, None);
+        let stop = world.stop_handle();
+        world.register_system(TestSystem {
+            count: count.clone(),
+            shutdown: shutdown.clone(),
+            stop,
+            _p: PhantomData,
+        });
+        world.build();
+
+        world.run_for(100);
+
+        // The loop finishes the frame in which the flag was set, then shuts the systems down
+        assert!(world.is_stopped());
+        assert_eq!(*count.borrow(), 3);
+        assert!(*shutdown.borrow());
+
+        // Stopped worlds don't run any more frames
+        world.run_for(10);
+        assert_eq!(*count.borrow(), 3);
+    }
+
+    #[test]
+    fn test_stop_handle_run() {
+        let mut world = World::new(1000, None);
+        world.build();
+
+        let handle = world.stop_handle();
+        let stopper = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(50));
+            handle.stop();
+        });
+
+        // Only returns once the handle has been tripped from the other thread
+        world.run();
+
+        stopper.join().unwrap();
+        assert!(world.is_stopped());
+        assert!(world.frame_stats().frames > 0);
+    }
+
+    #[test]
+    fn test_stop_before_run() {
+        struct TestSystem<'a> {
+            count: Rc<RefCell<u64>>,
+            shutdown: Rc<RefCell<u64>>,
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                *self.count.borrow_mut() += 1;
+            }
+
+            fn shutdown(&mut self) {
+                *self.shutdown.borrow_mut() += 1;
+            }
+        }
+
+        let count = Rc::new(RefCell::new(0u64));
+        let shutdown = Rc::new(RefCell::new(0u64));
+
+        let mut world = World::new(1000, None);
+        world.register_system(TestSystem {
+            count: count.clone(),
+            shutdown: shutdown.clone(),
+            _p: PhantomData,
+        });
+        world.build();
+
+        world.stop_handle().stop();
+        world.run_for(10);
+
+        // No frames are run, but the systems are still shut down
+        assert_eq!(*count.borrow(), 0);
+        assert_eq!(*shutdown.borrow(), 1);
+
+        // Stepping the world manually doesn't run any frames either
+        assert!(!world.run_once());
+        assert_eq!(*count.borrow(), 0);
+
+        // Systems are shut down only once
+        world.run_for(10);
+        world.shutdown();
+        assert_eq!(*shutdown.borrow(), 1);
+    }
+
+    #[test]
+    fn test_get_system_mut() {
+        struct TestSystem<'a> {
+            count: u64,
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                self.count += 1;
+            }
+        }
+
+        struct OtherSystem<'a> {
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for OtherSystem<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {}
+        }
+
+        let mut world = World::default();
+        let id = world.register_system(TestSystem {
+            count: 0,
+            _p: PhantomData,
+        });
+        let other_id = world.register_system(OtherSystem { _p: PhantomData });
+        world.build();
+
+        world.run_once();
+        world.run_once();
+        world.run_once();
+
+        assert_eq!(world.get_system_mut::<TestSystem>(id).unwrap().count, 3);
+        assert!(world.get_system_mut::<TestSystem>(other_id).is_none());
+
+        // Reconfigure the system and ensure the changes are picked up
+        world.get_system_mut::<TestSystem>(id).unwrap().count = 10;
+        world.run_once();
+
+        assert_eq!(world.get_system_mut::<TestSystem>(id).unwrap().count, 11);
+    }
+
+    #[test]
+    fn test_fixed_step() {
+        struct TestSystem<'a> {
+            deltas: Rc<RefCell<Vec<f32>>>,
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = ();
+
+            fn run(&mut self, ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                self.deltas.borrow_mut().push(ctx.delta);
+            }
+        }
+
+        let fixed_deltas = Rc::new(RefCell::new(Vec::new()));
+        let deltas = Rc::new(RefCell::new(Vec::new()));
+
+        let mut world = World::default();
+        world.register_fixed_system(TestSystem {
+            deltas: fixed_deltas.clone(),
+            _p: PhantomData,
+        });
+        world.register_system(TestSystem {
+            deltas: deltas.clone(),
+            _p: PhantomData,
+        });
+        world.set_fixed_step(time::Duration::from_millis(125), 4);
+        world.build();
+
+        // Long stall, the number of fixed steps is capped
+        world.delta = 10f32;
+        world.process_systems();
+
+        assert_eq!(*fixed_deltas.borrow(), vec![0.125f32; 4]);
+        assert_eq!(*deltas.borrow(), vec![10f32]);
+
+        // Leftover time is carried over into the next frame
+        fixed_deltas.borrow_mut().clear();
+        world.delta = 0.3f32;
+        world.process_systems();
+
+        assert_eq!(fixed_deltas.borrow().len(), 2);
+
+        fixed_deltas.borrow_mut().clear();
+        world.delta = 0.1f32;
+        world.process_systems();
+
+        assert_eq!(fixed_deltas.borrow().len(), 1);
+        assert_eq!(deltas.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_world_metrics() {
+        let mut world = World::default();
+        world.build();
+
+        {
+            let mut batcher = world.entities().batch::<(CompA, CompB)>();
+            batcher.add(CompA(0), CompB(0));
+            batcher.add(CompA(1), CompB(1));
+            batcher.commit();
+        }
+        world.entities().add((CompA(2), CompB(2), CompC::new(2, 2)));
+
+        world.process_transactions();
+
+        let key_ab = EntityId::get_class() + CompA::get_class() + CompB::get_class();
+        let key_abc = key_ab + CompC::get_class();
+
+        assert_eq!(world.entity_count(), 3);
+        assert_eq!(world.shard_count(), 2);
+
+        let histogram = world.shard_histogram();
+
+        assert_eq!(histogram.len(), 2);
+        assert_eq!(histogram[&key_ab], 2);
+        assert_eq!(histogram[&key_abc], 1);
+    }
+
+    #[test]
+    fn test_system_messaging_source() {
+        struct Publisher<'a> {
+            _p: PhantomData<&'a ()>,
+            value: i32,
+        }
+
+        impl<'a> RunSystem for Publisher<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, mut msg: Router) {
+                msg.publish(Msg1(self.value));
+            }
+        }
+
+        struct Consumer<'a> {
+            _p: PhantomData<&'a ()>,
+            source: Option<SystemId>,
+            messages: Rc<RefCell<Vec<Msg1>>>,
+        }
+
+        impl<'a> RunSystem for Consumer<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, msg: Router) {
+                if let Some(source) = self.source {
+                    for message in msg.read_from::<Msg1>(source) {
+                        self.messages.borrow_mut().push(message.clone());
+                    }
+                }
+            }
+        }
+
+        let messages = Rc::new(RefCell::new(Vec::new()));
+
+        let mut world = World::default();
+
+        world.register_system(Publisher {
+            _p: PhantomData,
+            value: 1,
+        });
+        let pub_id = world.register_system(Publisher {
+            _p: PhantomData,
+            value: 2,
+        });
+        let consumer_id = world.register_system(Consumer {
+            _p: PhantomData,
+            source: None,
+            messages: messages.clone(),
+        });
+        world.build();
+
+        world.get_system_mut::<Consumer>(consumer_id).unwrap().source = Some(pub_id);
+
+        // Run the world iteration twice, allowing the consumer to ingest the messages
+        world.run_once();
+        world.run_once();
+
+        assert_eq!(world.messages.borrow().read::<Msg1>(), &[Msg1(1), Msg1(2)]);
+        assert_eq!(*messages.borrow(), vec![Msg1(2)]);
+    }
+
+    #[test]
+    fn test_set_fps() {
+        let mut world = World::new(20, None);
+        assert_eq!(world.frame_delta_time, time::Duration::from_millis(50));
+
+        world.set_fps(100);
+        assert_eq!(world.frame_delta_time, time::Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_set_fps_deterministic() {
+        let mut world = World::new_deterministic(0.05, 42);
+        world.build();
+
+        world.set_fps(10);
+        assert_eq!(world.frame_delta_time, time::Duration::from_millis(100));
+        assert!((world.delta - 0.1).abs() < 1e-5);
+
+        // The clock and the delta advance together
+        let timestamp = world.timestamp;
+        world.run_for(2);
+        assert_eq!(world.timestamp - timestamp, time::Duration::from_millis(200));
+        assert!((world.delta - 0.1).abs() < 1e-5);
+    }
+
+    #[test]
+    #[should_panic(expected = "Frame rate must be greater than 0")]
+    fn test_set_fps_zero() {
+        let mut world = World::new(20, None);
+        world.set_fps(0);
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        struct TestSystem<'a> {
+            count: Rc<RefCell<u64>>,
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                *self.count.borrow_mut() += 1;
+            }
+        }
+
+        let count = Rc::new(RefCell::new(0u64));
+        let persistent_count = Rc::new(RefCell::new(0u64));
+
+        let mut world = World::new(1000, None);
+        world.register_system(TestSystem {
+            count: count.clone(),
+            _p: PhantomData,
+        });
+        world.register_persistent_system(TestSystem {
+            count: persistent_count.clone(),
+            _p: PhantomData,
+        });
+        world.build();
+
+        world.run_once();
+        assert_eq!(*count.borrow(), 1);
+        assert_eq!(*persistent_count.borrow(), 1);
+
+        world.pause();
+        assert!(world.is_paused());
+
+        world.run_once();
+        world.run_once();
+        assert_eq!(*count.borrow(), 1);
+        assert_eq!(*persistent_count.borrow(), 3);
+
+        world.resume();
+        assert!(!world.is_paused());
+
+        world.run_once();
+        assert_eq!(*count.borrow(), 2);
+        assert_eq!(*persistent_count.borrow(), 4);
+    }
+
+    #[test]
+    fn test_pause_messages() {
+        struct Publisher<'a> {
+            _p: PhantomData<&'a ()>,
+            value: i32,
+        }
+
+        impl<'a> RunSystem for Publisher<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, mut msg: Router) {
+                self.value += 1;
+                msg.publish(Msg1(self.value));
+            }
+        }
+
+        struct Consumer<'a> {
+            _p: PhantomData<&'a ()>,
+            messages: Rc<RefCell<Vec<Msg1>>>,
+        }
+
+        impl<'a> RunSystem for Consumer<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, msg: Router) {
+                self.messages.borrow_mut().extend(msg.read::<Msg1>().iter().cloned());
+            }
+        }
+
+        let messages = Rc::new(RefCell::new(Vec::new()));
+
+        let mut world = World::default();
+        world.register_persistent_system(Publisher {
+            _p: PhantomData,
+            value: 0,
+        });
+        world.register_system(Consumer {
+            _p: PhantomData,
+            messages: messages.clone(),
+        });
+        world.build();
+
+        world.run_once();
+
+        world.pause();
+        world.run_once();
+        world.run_once();
+        world.resume();
+
+        // Only the messages of the last paused frame are still on the bus once resumed
+        world.run_once();
+        assert_eq!(*messages.borrow(), vec![Msg1(3)]);
+    }
+
+    #[test]
+    fn test_system_enabled() {
+        struct TestSystem<'a> {
+            frames: Rc<RefCell<u64>>,
+            seen: Rc<RefCell<usize>>,
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = Components<(Read<'a, CompA>,)>;
+
+            fn run(&mut self, mut ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                *self.frames.borrow_mut() += 1;
+                *self.seen.borrow_mut() = ctx.components().count();
+            }
+        }
+
+        let frames = Rc::new(RefCell::new(0u64));
+        let seen = Rc::new(RefCell::new(0usize));
+
+        let mut world = World::new(1000, None);
+        let id = world.register_system(TestSystem {
+            frames: frames.clone(),
+            seen: seen.clone(),
+            _p: PhantomData,
+        });
+        world.build();
+
+        world.entities().add((CompA(1),));
+        world.run_once();
+        assert_eq!(*frames.borrow(), 1);
+        assert_eq!(*seen.borrow(), 1);
+
+        world.set_system_enabled(id, false);
+        assert!(!world.is_system_enabled(id));
+
+        // Entities added while disabled are picked up once re-enabled
+        world.entities().add((CompA(2), CompB(2)));
+        world.run_once();
+        world.run_once();
+        assert_eq!(*frames.borrow(), 1);
+
+        world.set_system_enabled(id, true);
+        assert!(world.is_system_enabled(id));
+
+        world.run_once();
+        assert_eq!(*frames.borrow(), 2);
+        assert_eq!(*seen.borrow(), 2);
+    }
+
+    #[test]
+    fn test_system_timings() {
+        struct SlowSystem<'a> {
+            _p: PhantomData<&'a ()>,
+        }
+
+        impl<'a> RunSystem for SlowSystem<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                std::thread::sleep(time::Duration::from_millis(5));
+            }
+        }
 
-    topic_init!(Msg1);
+        struct FastSystem<'a> {
+            _p: PhantomData<&'a ()>,
+        }
 
-    #[derive(Debug, Clone, Eq, PartialEq)]
-    struct Msg2(i32);
+        impl<'a> RunSystem for FastSystem<'a> {
+            type Data = ();
 
-    topic_init!(Msg2);
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {}
+        }
 
-    #[test]
-    fn test_add_entity() {
-        let mut world = World::default();
+        let mut world = World::new(1000, None);
+        let slow = world.register_system(SlowSystem { _p: PhantomData });
+        let fast = world.register_system(FastSystem { _p: PhantomData });
         world.build();
 
-        {
-            let mut batcher = world.entities().batch::<(CompA, CompB)>();
-            batcher.add(CompA(1), CompB(1));
-            batcher.add(CompA(2), CompB(2));
-            batcher.commit();
-        }
+        // Nothing is recorded unless profiling is enabled
+        world.run_once();
+        assert!(world.system_timings().is_empty());
 
-        world.entities().add((CompA(3), CompB(3), CompC::new(3, 3)));
+        world.set_profiling(true);
+        world.run_once();
+        world.run_once();
 
-        world.process_transactions();
+        let timings = world.system_timings();
+        assert!(timings[&slow] >= time::Duration::from_millis(10));
+        assert!(timings[&fast] < timings[&slow]);
 
-        assert_eq!(world.state.entities.len(), 3);
-        assert_eq!(world.state.shards.len(), 2);
-        assert_eq!(
-            world.state.entities[&0.into()],
-            (EntityId::get_class() + CompA::get_class() + CompB::get_class(), 0)
-        );
-        assert_eq!(
-            world.state.entities[&1.into()],
-            (EntityId::get_class() + CompA::get_class() + CompB::get_class(), 1)
-        );
-        assert_eq!(
-            world.state.entities[&2.into()],
-            (
-                EntityId::get_class() + CompA::get_class() + CompB::get_class() + CompC::get_class(),
-                0
-            )
-        );
+        world.reset_system_timings();
+        assert!(world.system_timings().is_empty());
     }
 
     #[test]
-    fn test_remove_entity() {
-        let mut world = World::default();
-        world.build();
-
-        {
-            let mut batcher = world.entities().batch::<(CompA, CompB)>();
-            batcher.add(CompA(1), CompB(1));
-            batcher.add(CompA(2), CompB(2));
-            batcher.add(CompA(3), CompB(3));
-            batcher.add(CompA(4), CompB(4));
-            batcher.commit();
+    fn test_frame_stats_overrun() {
+        struct SlowSystem<'a> {
+            count: Rc<RefCell<u64>>,
+            clock: ManualClock,
+            _p: PhantomData<&'a ()>,
         }
 
-        world.process_transactions();
-        assert_eq!(world.state.entities.len(), 4);
-        assert_eq!(world.state.entities[&0.into()].1, 0);
-        assert_eq!(world.state.entities[&1.into()].1, 1);
-        assert_eq!(world.state.entities[&2.into()].1, 2);
-        assert_eq!(world.state.entities[&3.into()].1, 3);
+        impl<'a> RunSystem for SlowSystem<'a> {
+            type Data = ();
 
-        world.entities().remove(0.into());
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                // Only the second frame is slow
+                *self.count.borrow_mut() += 1;
+                if *self.count.borrow() == 2 {
+                    self.clock.advance(time::Duration::from_millis(30));
+                }
+            }
+        }
 
-        world.process_transactions();
-        assert_eq!(world.state.entities.len(), 3);
-        assert_eq!(world.state.entities[&1.into()].1, 1);
-        assert_eq!(world.state.entities[&2.into()].1, 2);
-        assert_eq!(world.state.entities[&3.into()].1, 0);
+        let clock = ManualClock::new();
 
-        world.entities().remove(1.into());
+        let mut world = World::new(100, None);
+        world.set_clock(clock.clone());
+        world.register_system(SlowSystem {
+            count: Rc::new(RefCell::new(0)),
+            clock: clock.clone(),
+            _p: PhantomData,
+        });
+        world.build();
 
-        world.process_transactions();
-        assert_eq!(world.state.entities.len(), 2);
-        assert_eq!(world.state.entities[&2.into()].1, 1);
-        assert_eq!(world.state.entities[&3.into()].1, 0);
+        assert!(world.frame_stats().avg().is_none());
 
-        world.entities().remove(3.into());
+        let start = clock.now();
+        world.run_for(3);
 
-        world.process_transactions();
-        assert_eq!(world.state.entities.len(), 1);
-        assert_eq!(world.state.entities[&2.into()].1, 0);
+        let stats = world.frame_stats().clone();
+        assert_eq!(stats.frames, 3);
+        assert_eq!(stats.overruns, 1);
+        assert_eq!(stats.max.unwrap(), time::Duration::from_millis(30));
+        assert_eq!(stats.min.unwrap(), time::Duration::from_millis(0));
+        assert_eq!(stats.avg().unwrap(), time::Duration::from_millis(10));
 
-        world.entities().remove(2.into());
+        // Frames within the budget sleep for the remainder, the slow frame doesn't
+        assert_eq!(clock.now() - start, time::Duration::from_millis(50));
 
-        world.process_transactions();
-        assert_eq!(world.state.entities.len(), 0);
+        world.reset_frame_stats();
+        assert_eq!(world.frame_stats().frames, 0);
     }
 
     #[test]
-    fn test_resources() {
-        struct TestResource1 {
-            x: i32,
+    fn test_deterministic_frame_stats() {
+        struct TestSystem<'a> {
+            clock: ManualClock,
+            _p: PhantomData<&'a ()>,
         }
 
-        struct TestResource2 {
-            x: i32,
+        impl<'a> RunSystem for TestSystem<'a> {
+            type Data = ();
+
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                self.clock.advance(time::Duration::from_millis(5));
+            }
+        }
+
+        let clock = ManualClock::new();
+
+        let mut world = World::new_deterministic(0.05, 42);
+        world.set_clock(clock.clone());
+        world.register_system(TestSystem {
+            clock: clock.clone(),
+            _p: PhantomData,
+        });
+        world.build();
+
+        let timestamp = world.timestamp;
+        world.run_for(3);
+
+        // The simulated time runs ahead of the wall clock, the frame times are still measured on the latter
+        assert_eq!(world.timestamp - timestamp, time::Duration::from_millis(150));
+        assert_eq!(clock.now() - timestamp, time::Duration::from_millis(15));
+
+        let stats = world.frame_stats();
+        assert_eq!(stats.frames, 3);
+        assert_eq!(stats.min.unwrap(), time::Duration::from_millis(5));
+        assert_eq!(stats.max.unwrap(), time::Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_deterministic_world() {
+        struct TestState {
+            values: Vec<u64>,
+            elapsed: f32,
         }
 
         struct TestSystem<'a> {
@@ -536,170 +3375,244 @@ mod tests {
         }
 
         impl<'a> RunSystem for TestSystem<'a> {
-            type Data = Resources<(Read<'a, TestResource1>, Write<'a, TestResource2>)>;
+            type Data = Resources<(Write<'a, WorldRng>, Write<'a, TestState>)>;
 
             fn run(&mut self, mut ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
-                let (r1, mut r2) = ctx.resources();
-                r2.x = r1.x;
+                let delta = ctx.delta;
+                let (mut rng, mut state) = ctx.resources();
+                let value = rng.next_u64();
+                state.values.push(value);
+                state.elapsed += delta;
             }
         }
 
-        let mut world = World::default();
-        world.register_resource(TestResource1 { x: 100 });
-        world.register_resource(TestResource2 { x: 0 });
-        world.register_system(TestSystem { _p: PhantomData });
-        world.build();
+        fn simulate(seed: u64) -> (Vec<u64>, f32) {
+            let mut world = World::new_deterministic(0.05, seed);
+            world.register_resource(TestState {
+                values: Vec::new(),
+                elapsed: 0.0,
+            });
+            world.register_system(TestSystem { _p: PhantomData });
+            world.build();
 
-        world.run_once();
+            world.run_for(10);
+
+            let state = world.resource::<TestState>().unwrap();
+            (state.values.clone(), state.elapsed)
+        }
 
-        let resource_val = world.state.resources.get::<NonNull<TestResource2>>().unwrap();
+        let (values1, elapsed1) = simulate(42);
+        let (values2, elapsed2) = simulate(42);
+        let (values3, _) = simulate(43);
 
-        assert_eq!(unsafe { resource_val.as_ref() }.x, 100)
+        assert_eq!(values1.len(), 10);
+        assert_eq!(values1, values2);
+        assert_ne!(values1, values3);
+        assert_eq!(elapsed1, elapsed2);
+        assert!((elapsed1 - 0.5).abs() < 1e-5);
     }
 
     #[test]
-    fn test_ingest_system_transactions() {
-        // Create a system that adds a new entity and removes an existing one
+    fn test_unregister_system() {
         struct TestSystem<'a> {
+            frames: Rc<RefCell<u64>>,
             _p: PhantomData<&'a ()>,
         }
 
         impl<'a> RunSystem for TestSystem<'a> {
-            type Data = Components<(Read<'a, EntityId>, Read<'a, CompA>, Write<'a, CompB>)>;
+            type Data = ();
 
-            fn run(&mut self, _ctx: Context<Self::Data>, tx: &mut TransactionContext, _msg: Router) {
-                tx.add((CompA(3), CompB(3)));
-                tx.remove(0.into());
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {
+                *self.frames.borrow_mut() += 1;
             }
         }
 
-        let mut world = World::default();
-        world.register_system(TestSystem { _p: PhantomData });
+        let frames: Vec<_> = (0..3).map(|_| Rc::new(RefCell::new(0u64))).collect();
+        let make_system = |idx: usize| TestSystem {
+            frames: frames[idx].clone(),
+            _p: PhantomData,
+        };
+
+        let mut world = World::new(1000, None);
+        let removed = world.register_system(make_system(0));
+        let kept = world.register_system_after(make_system(1), &[removed]);
+        world.unregister_system(removed);
+
+        // The freed indexer is handed out again
+        let reused = world.register_system(make_system(2));
+        assert_eq!(reused.indexer(), removed.indexer());
+        assert_ne!(reused, kept);
+
         world.build();
+        world.run_once();
 
-        {
-            let mut batcher = world.entities().batch::<(CompA, CompB)>();
-            batcher.add(CompA(0), CompB(0));
-            batcher.add(CompA(1), CompB(1));
-            batcher.add(CompA(2), CompB(2));
-            batcher.commit();
-        }
+        assert_eq!(*frames[0].borrow(), 0);
+        assert_eq!(*frames[1].borrow(), 1);
+        assert_eq!(*frames[2].borrow(), 1);
+    }
 
-        // Process the initial state
+    #[test]
+    fn test_stale_entity_handle() {
+        let mut world = World::default();
+        world.build();
+
+        let stale = world.entities().add((CompA(1), CompB(1)));
+        let other = world.entities().add((CompA(2), CompB(2)));
         world.process_transactions();
 
-        assert_eq!(world.state.entities.len(), 3);
-        assert_eq!(world.state.entities[&0.into()].1, 0);
-        assert_eq!(world.state.entities[&1.into()].1, 1);
-        assert_eq!(world.state.entities[&2.into()].1, 2);
+        assert_eq!((stale.index(), stale.generation()), (0, 0));
 
-        // Run the system, triggering the edit and addition
-        world.run_once();
+        world.entities().remove(stale);
         world.process_transactions();
 
-        assert_eq!(world.state.entities.len(), 3);
-        assert_eq!(world.state.entities[&1.into()].1, 1);
-        assert_eq!(world.state.entities[&2.into()].1, 0);
-        assert_eq!(world.state.entities[&3.into()].1, 2);
-    }
+        // The index of the removed entity is reused with the next generation
+        let reused = world.entities().add((CompA(3), CompB(3)));
+        world.process_transactions();
 
-    #[test]
-    fn test_system_messaging() {
-        struct TestSystem1<'a> {
-            _p: PhantomData<&'a ()>,
-            messages: Rc<RefCell<Vec<Msg1>>>,
-        }
+        assert_eq!(reused.index(), stale.index());
+        assert_eq!(reused.generation(), 1);
+        assert_ne!(reused, stale);
 
-        impl<'a> RunSystem for TestSystem1<'a> {
-            type Data = ();
+        // The saved handle no longer resolves
+        assert!(!world.contains_entity(stale));
+        assert_eq!(world.get_component::<CompA>(stale), None);
+        assert_eq!(world.get_component::<CompA>(reused), Some(&CompA(3)));
 
-            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, mut msg: Router) {
-                for message in msg.read::<Msg1>() {
-                    self.messages.borrow_mut().push(message.clone());
-                }
+        // Neither does removing the entity through the stale handle
+        world.entities().remove(stale);
+        world.process_transactions();
+        assert!(world.contains_entity(reused));
 
-                msg.publish(Msg2(0));
-                msg.publish(Msg2(1));
-                msg.publish(Msg2(2));
-            }
-        }
+        // Fresh indices are handed out once the recycled ones are used up
+        let fresh = world.entities().add((CompA(4), CompB(4)));
+        world.process_transactions();
 
-        struct TestSystem2<'a> {
+        assert_eq!((fresh.index(), fresh.generation()), (other.index() + 1, 0));
+        assert_eq!(world.entity_count(), 3);
+    }
+
+    #[test]
+    fn test_system_ids() {
+        struct TestSystem<'a> {
             _p: PhantomData<&'a ()>,
-            messages: Rc<RefCell<Vec<Msg2>>>,
         }
 
-        impl<'a> RunSystem for TestSystem2<'a> {
+        impl<'a> RunSystem for TestSystem<'a> {
             type Data = ();
 
-            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, mut msg: Router) {
-                for message in msg.read::<Msg2>() {
-                    self.messages.borrow_mut().push(message.clone());
-                }
-
-                msg.publish(Msg1(0));
-                msg.publish(Msg1(1));
-            }
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {}
         }
 
-        let system_messages1 = Rc::new(RefCell::new(Vec::new()));
-        let system_messages2 = Rc::new(RefCell::new(Vec::new()));
-
         let mut world = World::default();
 
-        world.register_system(TestSystem1 {
-            _p: PhantomData,
-            messages: system_messages1.clone(),
-        });
+        let id1 = world.register_system(TestSystem { _p: PhantomData });
+        let id2 = world.register_system(TestSystem { _p: PhantomData });
+        let id3 = world.register_system(TestSystem { _p: PhantomData });
 
-        world.register_system(TestSystem2 {
-            _p: PhantomData,
-            messages: system_messages2.clone(),
-        });
+        assert_eq!(world.system_ids(), vec![id1, id2, id3]);
+
+        world.add_system_dependency(id1, &[id3]);
         world.build();
 
-        // Run the world iteration once, propagating the messages
-        world.run_once();
+        assert_eq!(world.system_ids(), vec![id2, id3, id1]);
 
-        assert_eq!(world.messages.read::<Msg1>(), &[Msg1(0), Msg1(1)]);
-        assert_eq!(world.messages.read::<Msg2>(), &[Msg2(0), Msg2(1), Msg2(2)]);
+        // Read-only traversals can overlap
+        let systems = &world.state.systems;
+        let outer: Vec<_> = systems.iter::<System>().collect();
+        let inner: Vec<_> = systems.iter::<System>().map(|(&id, _)| id).collect();
 
-        // Run the world iteration the second time, allowing the systems to ingest the messages
-        world.run_once();
+        assert_eq!(outer.len(), 3);
+        assert_eq!(inner, world.system_ids());
+    }
 
-        assert_eq!(*system_messages1.borrow(), vec![Msg1(0), Msg1(1)]);
-        assert_eq!(*system_messages2.borrow(), vec![Msg2(0), Msg2(1), Msg2(2)]);
+    struct WriterSystem<'a> {
+        _p: PhantomData<&'a ()>,
+    }
+
+    impl<'a> RunSystem for WriterSystem<'a> {
+        type Data = Components<(Read<'a, CompA>, Write<'a, CompB>)>;
+
+        fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {}
     }
 
     #[test]
-    fn test_system_init() {
-        struct TestSystem1<'a> {
-            initialized: bool,
+    fn test_access_conflicts() {
+        struct ReaderSystem<'a> {
             _p: PhantomData<&'a ()>,
         }
 
-        impl<'a> RunSystem for TestSystem1<'a> {
-            type Data = ();
-
-            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, mut _msg: Router) {}
+        impl<'a> RunSystem for ReaderSystem<'a> {
+            type Data = Components<Read<'a, CompA>>;
 
-            fn init(&mut self) {
-                self.initialized = true;
-            }
+            fn run(&mut self, _ctx: Context<Self::Data>, _tx: &mut TransactionContext, _msg: Router) {}
         }
 
         let mut world = World::default();
+        world.register_resource(TestResource { x: 1 });
 
-        let id = world.register_system(TestSystem1 {
-            initialized: false,
+        let writer1 = world.register_system(WriterSystem { _p: PhantomData });
+        let writer2 = world.register_system(WriterSystem { _p: PhantomData });
+        world.register_system(ReaderSystem { _p: PhantomData });
+        let resource_reader = world.register_system(ReadResourceSystem {
+            seen: Rc::new(RefCell::new(0)),
             _p: PhantomData,
         });
+        let resource_writer = world.register_system(WriteResourceSystem { _p: PhantomData });
+
+        assert_eq!(
+            world.access_conflicts(),
+            vec![
+                (writer1, writer2, vec!["CompB"]),
+                (resource_reader, resource_writer, vec!["world::tests::TestResource"]),
+            ]
+        );
 
+        // Conflicts are only reported outside of strict mode
         world.build();
+        world.run_once();
+    }
 
-        let mut system_runtime = world.state.systems.get::<SystemRuntime<TestSystem1>>(&id).write();
-        let system = system_runtime.get_system_mut();
+    #[test]
+    #[should_panic(expected = "Conflicting system access detected between systems")]
+    fn test_access_conflicts_strict() {
+        let mut world = World::default();
+        world.set_strict_access(true);
 
-        assert_eq!(system.initialized, true);
+        world.register_system(WriterSystem { _p: PhantomData });
+        world.register_system(WriterSystem { _p: PhantomData });
+
+        world.build();
+    }
+
+    #[test]
+    fn test_dump_state() {
+        let mut world = World::default();
+        world.build();
+
+        let id = world.entities().add((CompA(7), CompB(42)));
+        world.entities().add((CompA(3),));
+        world.process_transactions();
+
+        let dump = world.dump_state();
+
+        assert!(dump
+            .lines()
+            .any(|line| line.starts_with("shard [") && line.contains("CompA") && line.contains("CompB")));
+        assert!(dump.contains(&format!("  {:?}\n    ", id)));
+        assert!(dump.contains("    CompA: 7\n"));
+        assert!(dump.contains("    CompB: 42\n"));
+        assert!(dump.contains("    CompA: 3\n"));
+        assert_eq!(dump.matches("shard [").count(), 2);
+    }
+
+    #[test]
+    fn test_shard_key_names() {
+        let _world = World::default();
+
+        let mut names = (CompA::get_class() + CompB::get_class()).names();
+        names.sort();
+
+        assert_eq!(names, vec!["CompA", "CompB"]);
+        assert!(ShardKey::empty().names().is_empty());
     }
 }
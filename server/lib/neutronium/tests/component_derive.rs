@@ -0,0 +1,85 @@
+use neutronium::component::Component;
+use neutronium::world::World;
+
+mod physics {
+    use neutronium_proc::Component;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Component, Serialize, Deserialize, Debug, Clone)]
+    #[component(name = "physics::Position")]
+    pub struct Position {
+        pub x: f32,
+        pub y: f32,
+    }
+}
+
+mod render {
+    use neutronium_proc::Component;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Component, Serialize, Deserialize, Debug, Clone)]
+    #[component(name = "render::Position")]
+    pub struct Position {
+        pub x: i32,
+        pub y: i32,
+    }
+
+    #[derive(Component, Serialize, Deserialize, Debug, Clone)]
+    pub struct Velocity {
+        pub x: i32,
+        pub y: i32,
+    }
+}
+
+mod tags {
+    use neutronium_proc::Component;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Component, Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Tag<T> {
+        pub value: T,
+    }
+}
+
+#[test]
+fn test_same_named_components() {
+    assert_ne!(physics::Position::get_class(), render::Position::get_class());
+
+    assert_eq!(physics::Position::get_type_name(), "physics::Position");
+    assert_eq!(render::Position::get_type_name(), "render::Position");
+}
+
+#[test]
+fn test_default_component_name() {
+    assert_eq!(render::Velocity::get_type_name(), "Velocity");
+}
+
+#[test]
+fn test_register_on_first_use() {
+    let mut world = World::default();
+    world.build();
+
+    // The generic component is never registered before being added
+    let id = world.entities().add((
+        render::Velocity { x: 1, y: 2 },
+        tags::Tag::<u16> { value: 7 },
+    ));
+    world.process_transactions();
+
+    assert_eq!(world.get_component::<tags::Tag<u16>>(id), Some(&tags::Tag { value: 7 }));
+    assert_eq!(world.get_component::<render::Velocity>(id).map(|v| (v.x, v.y)), Some((1, 2)));
+
+    // Explicit registration resolves to the same class
+    assert_eq!(world.register_component::<tags::Tag<u16>>(), tags::Tag::<u16>::get_class());
+    assert_eq!(world.register_component::<render::Velocity>(), render::Velocity::get_class());
+}
+
+#[test]
+fn test_generic_class_across_threads() {
+    let class = tags::Tag::<u64>::get_class();
+
+    // Threads cache the classes separately, but resolve to the same registration
+    let other = std::thread::spawn(tags::Tag::<u64>::get_class).join().unwrap();
+    assert_eq!(other, class);
+    assert_eq!(tags::Tag::<u64>::get_class(), class);
+}
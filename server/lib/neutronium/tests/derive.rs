@@ -0,0 +1,7 @@
+#[test]
+fn test_derive() {
+    let cases = trybuild::TestCases::new();
+    cases.pass("tests/ui/generic_component.rs");
+    cases.pass("tests/ui/tuple_component.rs");
+    cases.pass("tests/ui/net_message.rs");
+}
@@ -0,0 +1,29 @@
+use neutronium::messagebus::{Bus, Message};
+use neutronium_proc::Message;
+
+#[derive(Message, Debug, Clone)]
+#[message(group = "movement")]
+struct Moved(u32);
+
+#[derive(Message, Debug, Clone)]
+#[message(group = "movement")]
+struct Stopped(u32);
+
+#[derive(Message, Debug, Clone)]
+struct Spawned(u32);
+
+#[test]
+fn test_grouped_messages() {
+    assert!(Moved::get_group().is_some());
+    assert_eq!(Moved::get_group(), Stopped::get_group());
+    assert_eq!(Spawned::get_group(), None);
+    assert_eq!(Moved::get_group().unwrap().name(), "movement");
+
+    let mut bus = Bus::new();
+    bus.publish(Moved(0));
+    bus.publish(Stopped(1));
+    bus.publish(Spawned(2));
+
+    let topics: Vec<_> = bus.group_topics(Moved::get_group().unwrap()).collect();
+    assert_eq!(topics, vec![Moved::get_topic(), Stopped::get_topic()]);
+}
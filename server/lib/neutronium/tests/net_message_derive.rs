@@ -0,0 +1,150 @@
+use neutronium::net::support::{Deserialize, ErrorType, NetworkError, PayloadBatch, Serialize, SerializedSize};
+use neutronium_proc::NetMessage;
+use std::io;
+
+#[derive(NetMessage, Debug, Clone, PartialEq)]
+struct Spawn {
+    id: u32,
+    kind: u8,
+    health: i64,
+    alive: bool,
+}
+
+#[derive(NetMessage, Debug, Clone, PartialEq)]
+struct Position {
+    x: f32,
+    y: f32,
+}
+
+#[derive(NetMessage, Debug, Clone, PartialEq)]
+enum Command {
+    Idle,
+    Move(Position),
+    Attack { target: u64, critical: bool },
+    Emote(i16),
+}
+
+fn roundtrip<P: Serialize + Deserialize>(messages: Vec<P>) -> Vec<P> {
+    let mut buffer = vec![0u8; 256];
+
+    let mut outgoing = PayloadBatch::new();
+    messages.into_iter().for_each(|msg| outgoing.push(msg));
+    outgoing.write(&mut io::Cursor::new(&mut buffer[..])).unwrap();
+    assert_eq!(outgoing.len(), 0);
+
+    let mut incoming = PayloadBatch::<P>::new();
+    incoming.read(&mut io::Cursor::new(&buffer[..])).unwrap();
+    incoming.drain().collect()
+}
+
+#[test]
+fn test_struct_roundtrip() {
+    let messages = vec![
+        Spawn {
+            id: 1,
+            kind: 2,
+            health: -100,
+            alive: true,
+        },
+        Spawn {
+            id: u32::max_value(),
+            kind: 0,
+            health: i64::max_value(),
+            alive: false,
+        },
+    ];
+
+    assert_eq!(roundtrip(messages.clone()), messages);
+}
+
+#[test]
+fn test_enum_roundtrip() {
+    let messages = vec![
+        Command::Idle,
+        Command::Move(Position { x: 1.0, y: 2.0 }),
+        Command::Attack {
+            target: 42,
+            critical: true,
+        },
+        Command::Emote(-7),
+        Command::Idle,
+    ];
+
+    assert_eq!(roundtrip(messages.clone()), messages);
+}
+
+#[test]
+fn test_insufficient_capacity() {
+    let mut buffer = [0u8; 10];
+    let mut cursor = io::Cursor::new(&mut buffer[..]);
+
+    let attack = Command::Attack {
+        target: 42,
+        critical: true,
+    };
+
+    // The full message is required to fit, nothing is written otherwise
+    cursor.set_position(1);
+    assert_eq!(attack.serialize(&mut cursor), Err(NetworkError::Wait));
+    assert_eq!(cursor.position(), 1);
+
+    cursor.set_position(0);
+    assert_eq!(attack.serialize(&mut cursor), Ok(()));
+    assert_eq!(cursor.position(), 10);
+}
+
+#[test]
+fn test_truncated_and_invalid() {
+    let buffer = [1u8, 0, 0];
+    assert_eq!(
+        Command::deserialize(&mut io::Cursor::new(&buffer[..])),
+        Err(NetworkError::Wait)
+    );
+
+    let buffer = [9u8];
+    assert_eq!(
+        Command::deserialize(&mut io::Cursor::new(&buffer[..])),
+        Err(NetworkError::Fatal(ErrorType::Serialization))
+    );
+}
+
+#[test]
+fn test_max_size() {
+    let spawn = Spawn {
+        id: 1,
+        kind: 2,
+        health: 3,
+        alive: true,
+    };
+
+    let mut buffer = [0u8; 64];
+    let mut cursor = io::Cursor::new(&mut buffer[..]);
+    spawn.serialize(&mut cursor).unwrap();
+    assert_eq!(cursor.position() as usize, Spawn::MAX_SIZE);
+
+    // Enums are bounded by their largest variant
+    let attack = Command::Attack {
+        target: 42,
+        critical: true,
+    };
+
+    let mut cursor = io::Cursor::new(&mut buffer[..]);
+    attack.serialize(&mut cursor).unwrap();
+    assert_eq!(cursor.position() as usize, Command::MAX_SIZE);
+    assert!(Command::Idle.serialized_size() < Command::MAX_SIZE);
+}
+
+#[test]
+fn test_batch_respects_max_size() {
+    let mut buffer = [0u8; PayloadBatch::<Command>::COUNT_SIZE + 5];
+
+    // The message itself would fit, but not the worst case size of the type
+    let mut outgoing = PayloadBatch::new();
+    outgoing.push(Command::Idle);
+
+    assert_eq!(
+        outgoing.write(&mut io::Cursor::new(&mut buffer[..])),
+        Err(NetworkError::Wait)
+    );
+    assert_eq!(outgoing.len(), 1);
+}
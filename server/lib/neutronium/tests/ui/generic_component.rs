@@ -0,0 +1,31 @@
+use neutronium::component::Component;
+use neutronium_proc::Component;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+#[derive(Component, Serialize, Deserialize, Debug, Clone)]
+struct Tagged<T> {
+    tag: u32,
+    value: T,
+}
+
+#[derive(Component, Serialize, Deserialize, Debug, Clone)]
+struct Bounded<T>
+where
+    T: Debug + Default,
+{
+    value: T,
+}
+
+fn main() {
+    // Each instantiation is a distinct class
+    assert_ne!(Tagged::<u32>::get_class(), Tagged::<f32>::get_class());
+    assert_ne!(Tagged::<u32>::get_class(), Bounded::<u32>::get_class());
+
+    // The class is only registered once
+    assert_eq!(Tagged::<u32>::get_class(), Tagged::<u32>::get_class());
+    assert_eq!(Bounded::<u32>::get_class(), Bounded::<u32>::get_class());
+
+    assert!(Tagged::<u32>::get_type_name().contains("Tagged<u32>"));
+    assert!(Bounded::<i64>::get_type_name().contains("Bounded<i64>"));
+}
@@ -0,0 +1,24 @@
+use neutronium::net::support::SerializedSize;
+use neutronium_proc::NetMessage;
+
+#[derive(NetMessage)]
+struct Ping;
+
+#[derive(NetMessage)]
+struct Position(f32, f32);
+
+#[derive(NetMessage)]
+enum Command {
+    Idle,
+    Move(Position),
+    Attack { target: u64, critical: bool },
+}
+
+fn main() {
+    assert_eq!(Ping.serialized_size(), 0);
+    assert_eq!(Position(1.0, 2.0).serialized_size(), 8);
+
+    assert_eq!(Command::Idle.serialized_size(), 1);
+    assert_eq!(Command::Move(Position(1.0, 2.0)).serialized_size(), 9);
+    assert_eq!(Command::Attack { target: 5, critical: true }.serialized_size(), 10);
+}
@@ -0,0 +1,17 @@
+use neutronium::component::Component;
+use neutronium_proc::Component;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Component, Serialize, Deserialize, Debug, Clone)]
+struct Health(u32);
+
+#[derive(Component, Serialize, Deserialize, Debug, Clone)]
+#[component(name = "Mana")]
+struct Energy(u32, u32);
+
+fn main() {
+    assert_ne!(Health::get_class(), Energy::get_class());
+
+    assert_eq!(Health::get_type_name(), "Health");
+    assert_eq!(Energy::get_type_name(), "Mana");
+}
@@ -1,67 +1,408 @@
 extern crate proc_macro;
 
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
 use syn;
 
-#[proc_macro_derive(Message)]
+/// Derives the `Message` trait and registers the topic at startup. Related topics can be placed in a
+/// common group using `#[message(group = "...")]`, allowing the bus to drain them together.
+///
+/// Topics are registered before the message bus is constructed, generic messages are thus not supported.
+#[proc_macro_derive(Message, attributes(message))]
 pub fn derive_message(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let ast: syn::DeriveInput = syn::parse(item).unwrap();
-    derive_core(
-        &ast.ident.to_string(),
-        "Message",
-        "Topic",
-        "acquire_topic_id",
-        "get_topic",
-    )
+
+    let tokens = attribute_value(&ast.attrs, "message", "group")
+        .and_then(|group| derive_core(&ast, "Message", Kind::Topic(group)));
+
+    match tokens {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Derives the `Component` trait and registers the component class at startup. The name of the
+/// component defaults to the type name and can be overridden using `#[component(name = "...")]`,
+/// allowing distinct types with the same identifier to coexist.
+///
+/// Generic components are registered on first use, one class per instantiation, and are named after
+/// their full type name.
+#[proc_macro_derive(Component, attributes(component))]
+pub fn derive_component(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let ast: syn::DeriveInput = syn::parse(item).unwrap();
+
+    let tokens = attribute_value(&ast.attrs, "component", "name")
+        .and_then(|name| derive_core(&ast, "Component", Kind::Class(name)));
+
+    match tokens {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Derives the network `Serialize`, `Deserialize` and `SerializedSize` traits, encoding the fields in
+/// declaration order. Enums are prefixed with a discriminant byte, limiting them to 256 variants.
+///
+/// The `MAX_SIZE` bound is the sum of the field bounds, taking the largest variant for enums. It is zero,
+/// i.e. unbounded, if any of the fields are unbounded.
+///
+/// All fields must implement the network traits themselves. Serialization validates the free capacity
+/// for the entire message upfront and returns `Wait` if it doesn't fit.
+#[proc_macro_derive(NetMessage)]
+pub fn derive_net_message(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let ast: syn::DeriveInput = syn::parse(item).unwrap();
+
+    match derive_core(&ast, "NetMessage", Kind::Codec) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+enum Kind {
+    Class(Option<String>),
+    Topic(Option<String>),
+    Codec,
+}
+
+/// Generates the trait implementation along with the registration. The generated items are wrapped in
+/// a dummy `const` block to avoid leaking identifiers into the scope of the derived type.
+fn derive_core(ast: &syn::DeriveInput, main_trait: &str, kind: Kind) -> syn::Result<TokenStream> {
+    let ident = &ast.ident;
+    let is_generic = !ast.generics.params.is_empty();
+
+    let dummy_const = syn::Ident::new(
+        &format!("__IMPL_{}_FOR_{}", main_trait.to_uppercase(), ident),
+        Span::call_site(),
+    );
+
+    let body = match kind {
+        Kind::Class(name) => {
+            if is_generic {
+                if name.is_some() {
+                    return Err(syn::Error::new_spanned(
+                        &ast.generics,
+                        "Generic components are named after their type, the name attribute is not supported",
+                    ));
+                }
+                generic_class(ast)
+            } else {
+                let name = name.unwrap_or_else(|| ident.to_string());
+                class(ident, &name)
+            }
+        }
+        Kind::Topic(group) => {
+            if is_generic {
+                return Err(syn::Error::new_spanned(
+                    &ast.generics,
+                    "Topics are registered before the message bus is constructed, generic messages are not supported",
+                ));
+            }
+            topic(ident, group)
+        }
+        Kind::Codec => {
+            if is_generic {
+                return Err(syn::Error::new_spanned(
+                    &ast.generics,
+                    "Generic network messages are not supported",
+                ));
+            }
+            codec(ast)?
+        }
+    };
+
+    Ok(quote! {
+        #[allow(non_upper_case_globals)]
+        const #dummy_const: () = {
+            extern crate neutronium as _neutronium;
+            #body
+        };
+    })
 }
 
-fn derive_core(
-    struct_name: &str,
-    main_trait: &str,
-    id_type: &str,
-    acquire_name: &str,
-    getter_name: &str,
-) -> proc_macro::TokenStream {
-    let static_mod = format!("__{}Module", struct_name.to_uppercase());
-    let static_id = format!("__{}_ID", struct_name.to_uppercase());
+fn class(ident: &syn::Ident, name: &str) -> TokenStream {
+    // Components are registered at load time, but also on first use in case the constructor hasn't run
+    // yet (e.g. when used from another constructor).
+    quote! {
+        static INDEXER: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
+        static REGISTER: ::std::sync::Once = ::std::sync::Once::new();
 
-    let tokens = format!(
-        r###"
+        #[inline]
+        fn register() {
+            REGISTER.call_once(|| {
+                let class = _neutronium::component::register_component::<#ident>(#name);
+                INDEXER.store(class.indexer(), ::std::sync::atomic::Ordering::Relaxed);
+            });
+        }
+
+        impl _neutronium::component::Component for #ident {
+            #[inline]
+            fn get_class() -> _neutronium::identity::ComponentClass {
+                register();
+                _neutronium::identity::ComponentClass::from_indexer(
+                    INDEXER.load(::std::sync::atomic::Ordering::Relaxed)
+                )
+            }
+        }
+
+        #[_neutronium::identity::ctor::ctor]
+        fn init() {
+            register();
+        }
+    }
+}
 
-        mod {static_mod} {{
-            use super::{id_type};
+fn generic_class(ast: &syn::DeriveInput) -> TokenStream {
+    let ident = &ast.ident;
 
-            pub(crate) static mut {static_id}: {id_type} = {id_type}{{id: 0}};
-        }}
+    // The class is looked up by type id, which requires the instantiations to be 'static. The supertrait
+    // bounds are spelled out as the generic parameters of the serde derives carry their own bounds.
+    let mut generics = ast.generics.clone();
+    {
+        let (_, ty_generics, _) = ast.generics.split_for_impl();
+        generics.make_where_clause().predicates.push(syn::parse_quote! {
+            #ident #ty_generics: 'static
+                + _neutronium::identity::serde::Serialize
+                + _neutronium::identity::serde::de::DeserializeOwned
+                + ::std::fmt::Debug
+        });
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
-        impl {main_trait} for {struct_name} {{
+    quote! {
+        impl #impl_generics _neutronium::component::Component for #ident #ty_generics #where_clause {
             #[inline]
-            fn {acquire_name}() -> {id_type} {{
-                unsafe {{
-                    let counter = {id_type}::get_name_vec().len();
-                    {static_mod}::{static_id} = {id_type}::new::<{struct_name}>(counter);
+            fn get_class() -> _neutronium::identity::ComponentClass {
+                _neutronium::component::generic_class::<Self>()
+            }
+        }
+    }
+}
 
-                    {id_type}::get_name_vec().push("{struct_name}");
-                    {id_type}::get_id_vec().push({static_mod}::{static_id});
+fn topic(ident: &syn::Ident, group: Option<String>) -> TokenStream {
+    let name = ident.to_string();
+    let group = match group {
+        Some(group) => quote!(Some(#group)),
+        None => quote!(None),
+    };
 
-                    {static_mod}::{static_id}
-                }}
-            }}
+    quote! {
+        static INDEXER: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
 
+        impl _neutronium::messagebus::Message for #ident {
             #[inline]
-            fn {getter_name}() -> {id_type} {{
-                unsafe {{
-                    {static_mod}::{static_id}
-                }}
-            }}
-        }}"###,
-        static_mod = static_mod,
-        static_id = static_id,
-        id_type = id_type,
-        main_trait = main_trait,
-        struct_name = struct_name,
-        acquire_name = acquire_name,
-        getter_name = getter_name
-    );
+            fn get_topic() -> _neutronium::identity::Topic {
+                _neutronium::identity::Topic::from_indexer(
+                    INDEXER.load(::std::sync::atomic::Ordering::Relaxed)
+                )
+            }
+        }
+
+        #[_neutronium::identity::ctor::ctor]
+        fn register() {
+            let topic = _neutronium::messagebus::register_topic::<#ident>(#name, 0, #group);
+            INDEXER.store(topic.indexer(), ::std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+fn codec(ast: &syn::DeriveInput) -> syn::Result<TokenStream> {
+    let ident = &ast.ident;
+    let support = quote!(_neutronium::net::support);
+
+    let (max_size, size, write, read) = match ast.data {
+        syn::Data::Struct(ref data) => {
+            let max_size = bounded(&support, data.fields.iter(), field_max_sizes(&support, &data.fields));
+            let (pattern, bindings) = destructure(quote!(#ident), &data.fields);
+            let size = field_sizes(&support, &bindings);
+            let read = construct(&support, quote!(#ident), &data.fields);
+            let writes = bindings
+                .iter()
+                .map(|binding| quote!(#support::Serialize::serialize(#binding, stream)?;));
+
+            (
+                max_size,
+                quote!(match *self { #pattern => #size }),
+                quote!(match *self { #pattern => { #(#writes)* } }),
+                quote!(Ok(#read)),
+            )
+        }
+        syn::Data::Enum(ref data) => {
+            if data.variants.len() > 256 {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "Network message enums are limited to 256 variants",
+                ));
+            }
+
+            let mut max_size = quote!(0);
+            let mut sizes = Vec::new();
+            let mut writes = Vec::new();
+            let mut reads = Vec::new();
+
+            for (index, variant) in data.variants.iter().enumerate() {
+                let var_ident = &variant.ident;
+                let discriminant = index as u8;
+
+                let (pattern, bindings) = destructure(quote!(#ident::#var_ident), &variant.fields);
+                let size = field_sizes(&support, &bindings);
+                let read = construct(&support, quote!(#ident::#var_ident), &variant.fields);
+
+                let variant_max_size = field_max_sizes(&support, &variant.fields);
+                max_size = quote!(#support::max_size(#max_size, #variant_max_size));
+
+                sizes.push(quote!(#pattern => 1 + #size));
+                writes.push(quote!(#pattern => {
+                    #support::Serialize::serialize(&#discriminant, stream)?;
+                    #(#support::Serialize::serialize(#bindings, stream)?;)*
+                }));
+                reads.push(quote!(#discriminant => Ok(#read)));
+            }
+
+            let fields = data.variants.iter().flat_map(|variant| variant.fields.iter());
+            let max_size = bounded(&support, fields, quote!(1 + #max_size));
+
+            (
+                max_size,
+                quote!(match *self { #(#sizes,)* }),
+                quote!(match *self { #(#writes)* }),
+                quote! {
+                    match <u8 as #support::Deserialize>::deserialize(stream)? {
+                        #(#reads,)*
+                        _ => Err(#support::NetworkError::Fatal(#support::ErrorType::Serialization)),
+                    }
+                },
+            )
+        }
+        syn::Data::Union(_) => {
+            return Err(syn::Error::new_spanned(ident, "Unions can't be derived as network messages"));
+        }
+    };
+
+    Ok(quote! {
+        impl #support::SerializedSize for #ident {
+            #[inline]
+            #[allow(unused_variables)]
+            fn serialized_size(&self) -> usize {
+                #size
+            }
+        }
+
+        impl #support::Serialize for #ident {
+            const MAX_SIZE: usize = #max_size;
+
+            #[allow(unused_variables)]
+            fn serialize<W: #support::SizedWrite>(&self, stream: &mut W) -> #support::NetworkResult<()> {
+                if stream.free_capacity() < #support::SerializedSize::serialized_size(self) {
+                    return Err(#support::NetworkError::Wait);
+                }
+
+                #write
+
+                Ok(())
+            }
+        }
+
+        impl #support::Deserialize for #ident {
+            #[allow(unused_variables)]
+            fn deserialize<R: #support::SizedRead>(stream: &mut R) -> #support::NetworkResult<Self> {
+                #read
+            }
+        }
+    })
+}
+
+/// Pattern binding the fields by reference, along with the names of the bindings in declaration order.
+fn destructure(path: TokenStream, fields: &syn::Fields) -> (TokenStream, Vec<syn::Ident>) {
+    let bindings: Vec<_> = (0..fields.iter().count())
+        .map(|index| syn::Ident::new(&format!("__field{}", index), Span::call_site()))
+        .collect();
+
+    let pattern = match fields {
+        syn::Fields::Named(ref named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            let binds = bindings.iter();
+            quote!(#path { #(#names: ref #binds),* })
+        }
+        syn::Fields::Unnamed(_) => {
+            let binds = bindings.iter();
+            quote!(#path(#(ref #binds),*))
+        }
+        syn::Fields::Unit => quote!(#path),
+    };
+
+    (pattern, bindings)
+}
+
+fn field_sizes(support: &TokenStream, bindings: &[syn::Ident]) -> TokenStream {
+    quote!(0 #(+ #support::SerializedSize::serialized_size(#bindings))*)
+}
+
+fn field_max_sizes(support: &TokenStream, fields: &syn::Fields) -> TokenStream {
+    let types = fields.iter().map(|field| &field.ty);
+    quote!(0 #(+ <#types as #support::Serialize>::MAX_SIZE)*)
+}
+
+/// Zeroes out the size bound in case any of the fields are unbounded.
+fn bounded<'a, I>(support: &TokenStream, fields: I, max_size: TokenStream) -> TokenStream
+where
+    I: Iterator<Item = &'a syn::Field>,
+{
+    let types = fields.map(|field| &field.ty);
+    quote!((#max_size) #(* ((<#types as #support::Serialize>::MAX_SIZE != 0) as usize))*)
+}
+
+/// Expression building the value from fields read in declaration order.
+fn construct(support: &TokenStream, path: TokenStream, fields: &syn::Fields) -> TokenStream {
+    match fields {
+        syn::Fields::Named(ref named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote!(#path { #(#names: #support::Deserialize::deserialize(stream)?),* })
+        }
+        syn::Fields::Unnamed(ref unnamed) => {
+            let reads = unnamed
+                .unnamed
+                .iter()
+                .map(|_| quote!(#support::Deserialize::deserialize(stream)?));
+            quote!(#path(#(#reads),*))
+        }
+        syn::Fields::Unit => quote!(#path),
+    }
+}
+
+/// Extract the value of a `#[attr(key = "...")]` attribute, if present.
+fn attribute_value(attrs: &[syn::Attribute], attr_name: &str, key: &str) -> syn::Result<Option<String>> {
+    for attr in attrs {
+        let meta = match attr.parse_meta() {
+            Ok(syn::Meta::List(meta)) => meta,
+            _ => continue,
+        };
+
+        if meta.ident != attr_name {
+            continue;
+        }
+
+        for nested in meta.nested.iter() {
+            match nested {
+                syn::NestedMeta::Meta(syn::Meta::NameValue(ref name_value)) if name_value.ident == key => {
+                    match name_value.lit {
+                        syn::Lit::Str(ref lit) => return Ok(Some(lit.value())),
+                        ref lit => {
+                            return Err(syn::Error::new_spanned(
+                                lit,
+                                format!("The {} must be a string literal", key),
+                            ))
+                        }
+                    }
+                }
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        nested,
+                        format!("Unsupported {0} attribute, expected #[{0}({1} = \"...\")]", attr_name, key),
+                    ))
+                }
+            }
+        }
+    }
 
-    tokens.parse().unwrap()
+    Ok(None)
 }
@@ -1,4 +1,4 @@
-use authenticator::core::Config;
+use authenticator::core::{Config, DEFAULT_AUTH_RATE_LIMIT};
 use clap::{App, Arg};
 use flux::crypto;
 use flux::session::server::SessionKey;
@@ -24,6 +24,9 @@ fn main() {
 
     let config = Config {
         session_key: SessionKey::new(key),
+        tls: None,
+        auth_rate_limit: DEFAULT_AUTH_RATE_LIMIT,
+        admin_token: None,
     };
 
     serdeconv::to_toml_file(&config, config_file_path).expect("Config serialization failed");
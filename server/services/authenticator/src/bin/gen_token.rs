@@ -0,0 +1,106 @@
+use authenticator::core::Config;
+use authenticator::token;
+use clap::{App, Arg, ArgGroup};
+use flux::encoding::{base64, hex};
+use flux::session::server::SessionKey;
+use flux::time::timestamp_secs;
+use serdeconv;
+
+fn main() {
+    let matches = App::new("Token Generator")
+        .version("1.0")
+        .author("Bush Hammer Industries")
+        .about("Generates connection tokens for connecting to the game server directly.")
+        .arg(
+            Arg::with_name("USER_ID")
+                .help("Id of the user the token is issued to")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("CONFIG_FILE")
+                .long("config")
+                .takes_value(true)
+                .help("Path to the authenticator config file holding the session key"),
+        )
+        .arg(
+            Arg::with_name("SESSION_KEY")
+                .long("session-key")
+                .takes_value(true)
+                .help("Hex encoded session key"),
+        )
+        .group(
+            ArgGroup::with_name("KEY")
+                .args(&["CONFIG_FILE", "SESSION_KEY"])
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("ROLES")
+                .long("roles")
+                .takes_value(true)
+                .default_value("0")
+                .help("Roles bitmask of the user"),
+        )
+        .arg(
+            Arg::with_name("TTL")
+                .long("ttl")
+                .takes_value(true)
+                .default_value("3600")
+                .help("Seconds until the token expires"),
+        )
+        .arg(
+            Arg::with_name("SEQUENCE")
+                .long("sequence")
+                .takes_value(true)
+                .help("Encryption nonce of the token, random by default"),
+        )
+        .arg(
+            Arg::with_name("FORMAT")
+                .long("format")
+                .possible_values(&["hex", "base64"])
+                .default_value("hex")
+                .help("Encoding of the token and the keys"),
+        )
+        .get_matches();
+
+    let session_key = match matches.value_of("CONFIG_FILE") {
+        Some(config_file_path) => {
+            let config: Config =
+                serdeconv::from_toml_file(config_file_path).expect("Error parsing config file");
+            config.session_key
+        }
+        None => SessionKey::from_hex(matches.value_of("SESSION_KEY").unwrap())
+            .unwrap_or_else(|err| panic!("{}", err)),
+    };
+
+    let user_id = matches
+        .value_of("USER_ID")
+        .unwrap()
+        .parse()
+        .expect("User id must be a valid integer");
+    let roles = matches
+        .value_of("ROLES")
+        .unwrap()
+        .parse()
+        .expect("Roles must be a valid integer");
+    let ttl: u64 = matches
+        .value_of("TTL")
+        .unwrap()
+        .parse()
+        .expect("TTL must be a valid integer");
+    let sequence = matches
+        .value_of("SEQUENCE")
+        .map_or_else(rand::random, |sequence| {
+            sequence.parse().expect("Sequence must be a valid integer")
+        });
+
+    let minted = token::mint(&session_key, user_id, roles, timestamp_secs() + ttl, sequence);
+
+    let encode = |bytes: &[u8]| match matches.value_of("FORMAT").unwrap() {
+        "base64" => base64::encode(bytes),
+        _ => hex::encode(bytes),
+    };
+
+    println!("token: {}", encode(&minted.token));
+    println!("client_key: {}", encode(&minted.client_key));
+    println!("server_key: {}", encode(&minted.server_key));
+}
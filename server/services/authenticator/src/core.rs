@@ -1,16 +1,20 @@
 use chrono;
 use flux::choose;
 use flux::crypto;
+use flux::crypto::CipherSuite;
 use flux::encoding::base64;
 use flux::logging;
 use flux::session::server::SessionKey;
 use flux::session::user::PrivateData;
 use flux::time::timestamp_secs;
+use flux::Roles;
 use hashbrown::HashMap;
+use serde::{de, Deserialize, Deserializer};
 use serde_derive::{Deserialize, Serialize};
 use std::sync::atomic::{AtomicU64, Ordering, ATOMIC_U64_INIT};
 
 pub const KEY_LEN: usize = 24;
+pub const DEFAULT_AUTH_RATE_LIMIT: u32 = 30;
 
 /// Simple authenticator that constructs connection tokens based on client supplied serial keys.
 pub struct Authenticator {
@@ -94,7 +98,9 @@ impl Authenticator {
 
         // Temporary container for storing the private data
         let mut data = PrivateData {
+            version: PrivateData::VERSION,
             user_id: user.id,
+            roles: user.roles,
             client_key: [0u8; 32],
             server_key: [0u8; 32],
         };
@@ -115,6 +121,7 @@ impl Authenticator {
         let mut token = ConnectionToken {
             version: flux::VERSION_ID,
             protocol: flux::PROTOCOL_ID,
+            suite: CipherSuite::default().id(),
             expires: timestamp_secs() + flux::CONNECTION_TOKEN_EXPIRY_SECS,
             sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
             server_key: data.server_key,
@@ -126,14 +133,20 @@ impl Authenticator {
                         "context" => "create_token",
                         "user_id" => user.id);
         // Construct the additional data for the encryption.
-        let aed =
-            PrivateData::additional_data(&flux::VERSION_ID[..], flux::PROTOCOL_ID, token.expires).unwrap();
+        let aed = PrivateData::additional_data(
+            &flux::VERSION_ID[..],
+            flux::PROTOCOL_ID,
+            CipherSuite::default(),
+            token.expires,
+        )
+        .unwrap();
 
         logging::debug!(self.log, "encrypting private data";
                         "context" => "create_token",
                         "user_id" => user.id);
         // Encrypt the private data into the relevant field in the token.
         crypto::encrypt(
+            CipherSuite::default(),
             &mut token.data[..],
             &private_data[..],
             &aed[..],
@@ -157,9 +170,68 @@ impl Authenticator {
 unsafe impl Send for Authenticator {}
 unsafe impl Sync for Authenticator {}
 
-#[derive(Serialize, Deserialize)]
+/// Authenticator configuration. The session key is either given base64 encoded as `session_key` or
+/// hex encoded as `session_key_hex`. The `auth_rate_limit` is the number of authentication requests
+/// per minute accepted from a single address. The admin endpoints are only enabled if an `admin_token`
+/// is configured.
+#[derive(Serialize)]
 pub struct Config {
     pub session_key: SessionKey,
+    pub tls: Option<TlsConfig>,
+    pub auth_rate_limit: u32,
+    pub admin_token: Option<String>,
+}
+
+/// Paths of the PEM encoded certificate chain and private key used for serving over TLS.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TlsConfig {
+    pub certs: String,
+    pub key: String,
+}
+
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D>(deserializer: D) -> Result<Config, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct RawConfig {
+            session_key: Option<SessionKey>,
+            session_key_hex: Option<String>,
+            tls: Option<TlsConfig>,
+            auth_rate_limit: Option<u32>,
+            admin_token: Option<String>,
+        }
+
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        let session_key = match (raw.session_key, raw.session_key_hex) {
+            (Some(session_key), None) => session_key,
+            (None, Some(session_key_hex)) => {
+                SessionKey::from_hex(&session_key_hex).map_err(de::Error::custom)?
+            }
+            (Some(_), Some(_)) => {
+                return Err(de::Error::custom("Only one of session_key and session_key_hex may be set"))
+            }
+            (None, None) => return Err(de::Error::missing_field("session_key")),
+        };
+
+        let auth_rate_limit = raw.auth_rate_limit.unwrap_or(DEFAULT_AUTH_RATE_LIMIT);
+        if auth_rate_limit == 0 {
+            return Err(de::Error::custom("auth_rate_limit must be greater than 0"));
+        }
+
+        if raw.admin_token.as_ref().map_or(false, |token| token.is_empty()) {
+            return Err(de::Error::custom("admin_token must not be empty"));
+        }
+
+        Ok(Config {
+            session_key,
+            tls: raw.tls,
+            auth_rate_limit,
+            admin_token: raw.admin_token,
+        })
+    }
 }
 
 /// Connection token for delivery to the client. The token should be transmitted on secure protocols
@@ -169,6 +241,7 @@ pub struct ConnectionToken {
     #[serde(with = "base64")]
     pub version: [u8; 16],
     pub protocol: u16,
+    pub suite: u8,
     pub expires: u64,
     pub sequence: u64,
     #[serde(with = "base64")]
@@ -195,6 +268,9 @@ pub struct Ban {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct UserInfo {
     pub id: u64,
+    /// Roles baked into the connection tokens of the user, see `flux::session::user::roles`.
+    #[serde(default)]
+    pub roles: Roles,
     pub created: chrono::DateTime<chrono::Utc>,
     pub notes: Vec<Note>,
     pub ban: Option<Ban>,
@@ -204,6 +280,7 @@ impl UserInfo {
     pub fn new(id: u64) -> UserInfo {
         UserInfo {
             id,
+            roles: 0,
             created: chrono::Utc::now(),
             notes: Vec::new(),
             ban: None,
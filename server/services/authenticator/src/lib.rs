@@ -1,3 +1,6 @@
-#![feature(integer_atomics)]
+#![feature(integer_atomics, proc_macro_hygiene, decl_macro, duration_float)]
 
-pub mod core;
\ No newline at end of file
+pub mod core;
+pub mod limiter;
+pub mod server;
+pub mod token;
\ No newline at end of file
@@ -0,0 +1,129 @@
+use hashbrown::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Upper bound on the number of tracked addresses
+const MAX_TRACKED: usize = 65536;
+// Number of tracked addresses pruning settles at, new addresses can be tracked up to the bound until the
+// next prune
+const PRUNED_TRACKED: usize = MAX_TRACKED / 4 * 3;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter keyed by the client address. Each address may issue bursts of up to
+/// `per_minute` requests, the bucket refills continuously at the same rate per minute.
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    #[inline]
+    pub fn new(per_minute: u32) -> RateLimiter {
+        RateLimiter {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from the bucket of the given address. Returns false if the address has exceeded
+    /// the rate limit. At most `MAX_TRACKED` addresses are tracked, once exceeded the least recently
+    /// seen addresses are forgotten.
+    pub fn acquire(&self, addr: IpAddr, now: Instant) -> bool {
+        let capacity = f64::from(self.per_minute);
+        let refill = |bucket: &Bucket| {
+            // Requests handled concurrently may arrive slightly out of order
+            let elapsed = match now > bucket.updated {
+                true => now.duration_since(bucket.updated).as_float_secs(),
+                _ => 0f64,
+            };
+
+            (bucket.tokens + elapsed * capacity / 60f64).min(capacity)
+        };
+
+        let mut buckets = self.buckets.lock().expect("Failed to acquire rate limiter lock");
+
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(&addr) {
+            // Full buckets carry no state, they can be dropped and recreated on demand
+            buckets.retain(|_, bucket| refill(bucket) < capacity);
+
+            // Under a flood from many distinct addresses, evict the least recently seen ones
+            if buckets.len() > PRUNED_TRACKED {
+                let mut updated: Vec<(Instant, IpAddr)> =
+                    buckets.iter().map(|(&addr, bucket)| (bucket.updated, addr)).collect();
+                updated.sort_unstable();
+
+                for (_, addr) in updated.iter().take(buckets.len() - PRUNED_TRACKED) {
+                    buckets.remove(addr);
+                }
+            }
+        }
+
+        let bucket = buckets.entry(addr).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+
+        match bucket.tokens >= 1f64 {
+            true => {
+                bucket.tokens -= 1f64;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_acquire() {
+        let limiter = RateLimiter::new(3);
+        let addr1 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let addr2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let now = Instant::now();
+
+        assert!(limiter.acquire(addr1, now));
+        assert!(limiter.acquire(addr1, now));
+        assert!(limiter.acquire(addr1, now));
+        assert!(!limiter.acquire(addr1, now));
+
+        // Addresses are limited independently
+        assert!(limiter.acquire(addr2, now));
+
+        // One token is refilled every 20 seconds
+        assert!(!limiter.acquire(addr1, now + Duration::from_secs(19)));
+        assert!(limiter.acquire(addr1, now + Duration::from_secs(40)));
+        assert!(limiter.acquire(addr1, now + Duration::from_secs(40)));
+        assert!(!limiter.acquire(addr1, now + Duration::from_secs(40)));
+    }
+
+    #[test]
+    fn test_max_tracked() {
+        let limiter = RateLimiter::new(1);
+        let now = Instant::now();
+        let addr = |idx: usize| IpAddr::V4(Ipv4Addr::from(idx as u32));
+
+        for idx in 0..MAX_TRACKED * 2 {
+            assert!(limiter.acquire(addr(idx), now + Duration::from_millis(idx as u64)));
+            assert!(limiter.buckets.lock().unwrap().len() <= MAX_TRACKED);
+        }
+
+        // The least recently seen addresses were forgotten, the recent ones are still limited
+        assert!(!limiter.buckets.lock().unwrap().contains_key(&addr(0)));
+
+        let last = now + Duration::from_millis(MAX_TRACKED as u64 * 2);
+        assert!(!limiter.acquire(addr(MAX_TRACKED * 2 - 1), last));
+    }
+}
@@ -1,18 +1,11 @@
-#![feature(proc_macro_hygiene, decl_macro)]
-use authenticator::core::{AuthResult, Authenticator, Config, UserInfo};
+use authenticator::core::{Config, UserInfo};
+use authenticator::server;
 use clap::{App, Arg};
 use flux::logging;
 use hashbrown::HashMap;
 use rocket;
-use rocket::{post, routes, State};
-use rocket_contrib::json::Json;
 use serdeconv;
 
-#[post("/auth", data = "<auth_key>")]
-fn auth(auth: State<Authenticator>, auth_key: String) -> Json<AuthResult> {
-    Json(auth.authenticate(auth_key))
-}
-
 pub fn main() {
     let matches = App::new("Authenticator Service")
         .version("1.0")
@@ -28,10 +21,18 @@ pub fn main() {
                 .help("Path to the client file")
                 .required(true),
         )
+        .arg(
+            Arg::with_name("LOG_FORMAT")
+                .long("log-format")
+                .help("Format of the log output")
+                .env("LOG_FORMAT")
+                .possible_values(&["terminal", "json"])
+                .default_value("terminal"),
+        )
         .get_matches();
 
     // Initialize logging
-    let logger = logging::init();
+    let logger = logging::init_format(matches.value_of("LOG_FORMAT").unwrap().parse().unwrap());
 
     let config_file_path = matches.value_of("CONFIG_FILE").unwrap();
     logging::debug!(logger, "reading configuration file path";
@@ -47,10 +48,11 @@ pub fn main() {
     let user_info: HashMap<String, UserInfo> =
         serdeconv::from_toml_file(client_file_path).expect("Error parsing client data file");
 
+    let tls = config.tls.is_some();
+
     // Create rocket instnace
-    let rocket_instance = rocket::ignite()
-        .mount("/user", routes![auth])
-        .manage(Authenticator::new(config, user_info, &logger));
+    let rocket_instance = server::build(rocket::ignite(), config, user_info, &logger)
+        .unwrap_or_else(|err| panic!("Error enabling TLS: {}", err));
 
     let cfg = rocket_instance.config();
 
@@ -61,6 +63,7 @@ pub fn main() {
                    "port" => cfg.port,
                    "workers" => cfg.workers,
                    "keep_alive" => cfg.keep_alive,
+                   "tls" => tls,
                    "log_level" => cfg.log_level.to_string());
 
     rocket_instance.launch();
@@ -0,0 +1,180 @@
+use crate::core::{AuthResult, Authenticator, Config, UserInfo};
+use crate::limiter::RateLimiter;
+use flux::crypto;
+use flux::logging;
+use hashbrown::HashMap;
+use rocket;
+use rocket::config::ConfigError;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::{get, post, put, routes, Outcome, Request, Rocket, State};
+use rocket_contrib::json::Json;
+use serde_derive::Serialize;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Instant;
+
+/// Request guard admitting requests within the rate limit of the client address. Requests over the
+/// limit are rejected with 429 Too Many Requests. The limit is keyed on the peer address of the connection,
+/// client supplied headers like `X-Real-IP` are ignored as they would let clients pick their own bucket.
+struct RateLimited;
+
+impl<'a, 'r> FromRequest<'a, 'r> for RateLimited {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<RateLimited, ()> {
+        let limiter = request.guard::<State<RateLimiter>>()?;
+
+        // Requests without a known address share a single bucket
+        let addr = request
+            .remote()
+            .map(|remote| remote.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+        match limiter.acquire(addr, Instant::now()) {
+            true => Outcome::Success(RateLimited),
+            _ => {
+                let logger = request.guard::<State<logging::Logger>>()?;
+                logging::warn!(logger, "rate limit exceeded";
+                               "context" => "rate_limit",
+                               "address" => %addr);
+                Outcome::Failure((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
+
+/// Token required by the admin endpoints, which are disabled if no token is configured.
+struct AdminToken(Option<String>);
+
+/// Request guard admitting requests carrying the configured admin token in the `X-Admin-Token` header.
+/// Requests are rejected with 404 Not Found if the admin endpoints are disabled and with 403 Forbidden
+/// if the token is missing or doesn't match.
+struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+        let admin_token = request.guard::<State<AdminToken>>()?;
+
+        let expected = match &admin_token.0 {
+            Some(token) => token,
+            None => return Outcome::Failure((Status::NotFound, ())),
+        };
+
+        let supplied = request.headers().get_one("X-Admin-Token").unwrap_or("");
+
+        match crypto::constant_time_eq(supplied.as_bytes(), expected.as_bytes()) {
+            true => Outcome::Success(Admin),
+            _ => {
+                let logger = request.guard::<State<logging::Logger>>()?;
+                logging::warn!(logger, "admin request rejected";
+                               "context" => "admin",
+                               "uri" => %request.uri());
+                Outcome::Failure((Status::Forbidden, ()))
+            }
+        }
+    }
+}
+
+/// Liveness report of the service.
+#[derive(Serialize, Debug)]
+pub struct Health {
+    pub status: &'static str,
+    pub version: &'static str,
+    pub protocol: u16,
+}
+
+#[get("/health")]
+fn health() -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        version: env!("CARGO_PKG_VERSION"),
+        protocol: flux::PROTOCOL_ID,
+    })
+}
+
+#[post("/auth", data = "<auth_key>")]
+fn auth(_limit: RateLimited, auth: State<Authenticator>, auth_key: String) -> Json<AuthResult> {
+    Json(auth.authenticate(auth_key))
+}
+
+/// Adjusts the log level at runtime, e.g. `trace` or `warning`. Requires the admin token.
+#[put("/log_level", data = "<level>")]
+fn log_level(_admin: Admin, logger: State<logging::Logger>, level: String) -> Result<String, Status> {
+    let level: logging::Level = level.trim().parse().map_err(|_| Status::BadRequest)?;
+    logging::set_level(level);
+
+    logging::info!(logger, "log level changed";
+                   "context" => "log_level",
+                   "level" => level.as_str());
+
+    Ok(level.as_str().to_owned())
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Unreadable(String, io::Error),
+    Invalid(ConfigError),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            TlsError::Unreadable(path, err) => write!(f, "Unable to read TLS file {}: {}", path, err),
+            TlsError::Invalid(err) => write!(f, "Invalid TLS certificate chain or private key: {:?}", err),
+        }
+    }
+}
+
+impl error::Error for TlsError {}
+
+/// Mount the authenticator routes on the supplied rocket instance. TLS is enabled on top of the rocket
+/// configuration if the authenticator configuration specifies a certificate chain and private key.
+pub fn build(
+    rocket: Rocket,
+    config: Config,
+    user_info: HashMap<String, UserInfo>,
+    log: &logging::Logger,
+) -> Result<Rocket, TlsError> {
+    let rocket = match &config.tls {
+        Some(tls) => {
+            for path in [&tls.certs, &tls.key].iter() {
+                File::open(path).map_err(|err| TlsError::Unreadable(path.to_string(), err))?;
+            }
+
+            let mut rocket_config = rocket.config().clone();
+            rocket_config
+                .set_tls(&tls.certs, &tls.key)
+                .map_err(TlsError::Invalid)?;
+
+            logging::info!(log, "TLS enabled";
+                           "context" => "build",
+                           "certs" => &tls.certs,
+                           "key" => &tls.key);
+
+            rocket::custom(rocket_config)
+        }
+        None => {
+            logging::warn!(log, "TLS disabled, connection tokens are sent in cleartext";
+                           "context" => "build");
+            rocket
+        }
+    };
+
+    let limiter = RateLimiter::new(config.auth_rate_limit);
+    let admin_token = AdminToken(config.admin_token.clone());
+
+    Ok(rocket
+        .mount("/", routes![health])
+        .mount("/user", routes![auth])
+        .mount("/admin", routes![log_level])
+        .manage(limiter)
+        .manage(admin_token)
+        .manage(Authenticator::new(config, user_info, log))
+        .manage(log.clone()))
+}
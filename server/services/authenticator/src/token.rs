@@ -0,0 +1,51 @@
+use flux::crypto;
+use flux::session::server::SessionKey;
+use flux::session::user::{PrivateData, TokenBuilder};
+use flux::{Roles, UserId};
+
+/// Connection token minted outside of the authentication flow, along with the key pair the client
+/// needs for communicating on the channel.
+pub struct MintedToken {
+    pub token: Vec<u8>,
+    pub server_key: [u8; 32],
+    pub client_key: [u8; 32],
+}
+
+/// Mint an on-wire connection token for the given user with a fresh key pair. The `sequence` is the
+/// nonce of the private data encryption, it must not collide with the sequences of tokens issued by the
+/// authenticator under the same session key.
+///
+/// Meant for load tests and debugging the game endpoint by hand, clients proper obtain their tokens from
+/// the authenticator.
+pub fn mint(
+    session_key: &SessionKey,
+    user_id: UserId,
+    roles: Roles,
+    expires: u64,
+    sequence: u64,
+) -> MintedToken {
+    let mut data = PrivateData {
+        version: PrivateData::VERSION,
+        user_id,
+        roles,
+        server_key: [0u8; 32],
+        client_key: [0u8; 32],
+    };
+
+    crypto::random_bytes(&mut data.client_key);
+    crypto::random_bytes(&mut data.server_key);
+
+    let server_key = data.server_key;
+    let client_key = data.client_key;
+
+    let token = TokenBuilder::new(data)
+        .expires(expires)
+        .sequence(sequence)
+        .build(session_key);
+
+    MintedToken {
+        token,
+        server_key,
+        client_key,
+    }
+}
@@ -0,0 +1,52 @@
+use authenticator::core::Config;
+use flux::logging;
+use hashbrown::HashMap;
+use rocket::http::{Header, Status};
+use rocket::local::Client;
+
+mod common;
+
+fn make_client(admin_token: Option<&str>) -> Client {
+    let config = Config {
+        admin_token: admin_token.map(|token| token.to_owned()),
+        ..common::make_config()
+    };
+
+    common::make_client(config, HashMap::new())
+}
+
+#[test]
+fn test_log_level() {
+    let client = make_client(Some("secret"));
+
+    let set_level = |token: Option<&str>, level: &str| {
+        let mut request = client.put("/admin/log_level").body(level.to_owned());
+
+        if let Some(token) = token {
+            request = request.header(Header::new("X-Admin-Token", token.to_owned()));
+        }
+
+        request.dispatch().status()
+    };
+
+    assert_eq!(set_level(None, "debug"), Status::Forbidden);
+    assert_eq!(set_level(Some("guess"), "debug"), Status::Forbidden);
+    assert_eq!(logging::level(), logging::Level::Trace);
+
+    assert_eq!(set_level(Some("secret"), "nonsense"), Status::BadRequest);
+    assert_eq!(set_level(Some("secret"), "debug"), Status::Ok);
+    assert_eq!(logging::level(), logging::Level::Debug);
+}
+
+#[test]
+fn test_log_level_disabled() {
+    let client = make_client(None);
+
+    let response = client
+        .put("/admin/log_level")
+        .header(Header::new("X-Admin-Token", ""))
+        .body("debug")
+        .dispatch();
+
+    assert_eq!(response.status(), Status::NotFound);
+}
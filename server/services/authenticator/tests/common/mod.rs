@@ -0,0 +1,33 @@
+//! Fixtures shared by the integration tests.
+#![allow(dead_code)]
+
+use authenticator::core::{Config, UserInfo};
+use authenticator::server;
+use flux::logging;
+use flux::session::server::SessionKey;
+use hashbrown::HashMap;
+use rocket::local::Client;
+
+pub const SERIAL_KEY: &str = "ABCDEFGHIJKLMNOPQRSTUVWX";
+pub const SESSION_KEY: [u8; SessionKey::SIZE] = [7; SessionKey::SIZE];
+
+pub fn make_log() -> logging::Logger {
+    logging::Logger::root(logging::Discard, logging::o!())
+}
+
+/// Plain text configuration without an admin token.
+pub fn make_config() -> Config {
+    Config {
+        session_key: SessionKey::new(SESSION_KEY),
+        tls: None,
+        auth_rate_limit: 3,
+        admin_token: None,
+    }
+}
+
+/// Local client of a server built from the configuration.
+pub fn make_client(config: Config, user_info: HashMap<String, UserInfo>) -> Client {
+    let rocket = rocket::custom(rocket::Config::development());
+    let rocket = server::build(rocket, config, user_info, &make_log()).unwrap();
+    Client::new(rocket).unwrap()
+}
@@ -0,0 +1,39 @@
+use authenticator::core::Config;
+use common::SESSION_KEY;
+use flux::encoding::base64;
+
+mod common;
+
+fn parse(session_key: &str) -> Result<Config, serdeconv::Error> {
+    serdeconv::from_toml_str(&format!("{}\nauth_rate_limit = 3\n", session_key))
+}
+
+#[test]
+fn test_session_key_hex() {
+    let config = parse(&format!(r#"session_key_hex = "{}""#, "07".repeat(SESSION_KEY.len()))).unwrap();
+    assert_eq!(config.session_key[..], SESSION_KEY[..]);
+
+    // Either case is accepted
+    let config = parse(&format!(r#"session_key_hex = "{}""#, "aB".repeat(SESSION_KEY.len()))).unwrap();
+    assert_eq!(config.session_key[..], [0xab; SESSION_KEY.len()][..]);
+
+    // Both encodings load the same key
+    let config = parse(&format!(r#"session_key = "{}""#, base64::encode(&SESSION_KEY[..]))).unwrap();
+    assert_eq!(config.session_key[..], SESSION_KEY[..]);
+}
+
+#[test]
+fn test_session_key_hex_invalid() {
+    // Odd length, non-hex characters and keys of the wrong size are rejected
+    assert!(parse(&format!(r#"session_key_hex = "{}0""#, "07".repeat(SESSION_KEY.len()))).is_err());
+    assert!(parse(&format!(r#"session_key_hex = "{}""#, "zz".repeat(SESSION_KEY.len()))).is_err());
+    assert!(parse(r#"session_key_hex = "0707""#).is_err());
+
+    // The key may only be given once
+    let both = format!(
+        "session_key = \"{}\"\nsession_key_hex = \"{}\"",
+        base64::encode(&SESSION_KEY[..]),
+        "07".repeat(SESSION_KEY.len())
+    );
+    assert!(parse(&both).is_err());
+}
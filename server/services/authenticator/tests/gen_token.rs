@@ -0,0 +1,54 @@
+use authenticator::token;
+use common::SESSION_KEY;
+use flux::session::server::SessionKey;
+use flux::session::user::roles;
+use flux::time::timestamp_secs;
+use neutronium::net::endpoint::{ConnectionChange, Endpoint, Timeouts};
+use neutronium::net::frame::RESUME_TOKEN_SIZE;
+use std::io::Write;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Instant;
+
+mod common;
+
+#[test]
+fn test_minted_token_accepted() {
+    let log = common::make_log();
+
+    let mut endpoint =
+        Endpoint::new("127.0.0.1:0", SessionKey::new(SESSION_KEY), Timeouts::default(), &log).unwrap();
+    endpoint.init();
+
+    let minted = token::mint(
+        &SessionKey::new(SESSION_KEY),
+        8008,
+        roles::ADMIN,
+        timestamp_secs() + 60,
+        20,
+    );
+
+    // Fresh connections carry no resume token
+    let mut client = TcpStream::connect(endpoint.local_addr().unwrap()).unwrap();
+    client.write_all(&minted.token).unwrap();
+    client.write_all(&[0u8; RESUME_TOKEN_SIZE]).unwrap();
+
+    for _ in 0..10000 {
+        endpoint.sync(Instant::now());
+
+        if let Some(change) = endpoint.changes().next() {
+            match change {
+                ConnectionChange::Connected(user_id, user_roles, _) => {
+                    assert_eq!(user_id, 8008);
+                    assert_eq!(user_roles, roles::ADMIN);
+                    return;
+                }
+                change => panic!("Unexpected change {:?}", change),
+            }
+        }
+
+        thread::yield_now();
+    }
+
+    panic!("Minted token was not accepted")
+}
@@ -0,0 +1,22 @@
+use hashbrown::HashMap;
+use rocket::http::{ContentType, Status};
+
+mod common;
+
+#[test]
+fn test_health() {
+    let client = common::make_client(common::make_config(), HashMap::new());
+
+    let mut response = client.get("/health").dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    assert_eq!(
+        response.body_string().unwrap(),
+        format!(
+            r#"{{"status":"ok","version":"{}","protocol":{}}}"#,
+            env!("CARGO_PKG_VERSION"),
+            flux::PROTOCOL_ID
+        )
+    );
+}
@@ -0,0 +1,47 @@
+use authenticator::core::UserInfo;
+use common::SERIAL_KEY;
+use hashbrown::HashMap;
+use rocket::http::{Header, Status};
+use std::net::SocketAddr;
+
+mod common;
+
+#[test]
+fn test_auth_rate_limit() {
+    let mut user_info = HashMap::new();
+    user_info.insert(SERIAL_KEY.to_owned(), UserInfo::new(8008));
+
+    let client = common::make_client(common::make_config(), user_info);
+
+    let auth = |remote: &str| {
+        client
+            .post("/user/auth")
+            .remote(remote.parse::<SocketAddr>().unwrap())
+            .body(SERIAL_KEY)
+            .dispatch()
+            .status()
+    };
+
+    let auth_spoofed = |remote: &str, real_ip: &str| {
+        client
+            .post("/user/auth")
+            .remote(remote.parse::<SocketAddr>().unwrap())
+            .header(Header::new("X-Real-IP", real_ip.to_owned()))
+            .body(SERIAL_KEY)
+            .dispatch()
+            .status()
+    };
+
+    for _ in 0..3 {
+        assert_eq!(auth("10.0.0.1:5000"), Status::Ok);
+    }
+
+    assert_eq!(auth("10.0.0.1:5000"), Status::TooManyRequests);
+    assert_eq!(auth("10.0.0.1:5001"), Status::TooManyRequests);
+
+    // The client can't escape the limit by claiming another address
+    assert_eq!(auth_spoofed("10.0.0.1:5000", "10.0.0.3"), Status::TooManyRequests);
+
+    // Other addresses are unaffected
+    assert_eq!(auth("10.0.0.2:5000"), Status::Ok);
+}
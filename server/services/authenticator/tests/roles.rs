@@ -0,0 +1,48 @@
+use authenticator::core::{AuthResult, Authenticator, UserInfo};
+use common::{SERIAL_KEY, SESSION_KEY};
+use flux::crypto;
+use flux::crypto::CipherSuite;
+use flux::session::user::{roles, PrivateData};
+use hashbrown::HashMap;
+
+mod common;
+
+#[test]
+fn test_admin_token_roles() {
+    let mut admin = UserInfo::new(8008);
+    admin.roles = roles::ADMIN;
+
+    let mut user_info = HashMap::new();
+    user_info.insert(SERIAL_KEY.to_owned(), admin);
+
+    let authenticator = Authenticator::new(common::make_config(), user_info, &common::make_log());
+
+    let token = match authenticator.authenticate(SERIAL_KEY.to_owned()) {
+        AuthResult::Ok(token) => token,
+        _ => panic!("Authentication failed"),
+    };
+
+    let additional_data = PrivateData::additional_data(
+        &token.version,
+        token.protocol,
+        CipherSuite::from_id(token.suite).unwrap(),
+        token.expires,
+    )
+    .unwrap();
+    let mut plain = [0u8; PrivateData::SIZE];
+
+    assert!(crypto::decrypt(
+        CipherSuite::default(),
+        &mut plain,
+        &token.data,
+        &additional_data,
+        token.sequence,
+        &SESSION_KEY
+    ));
+
+    let data = PrivateData::read(&plain[..]).unwrap();
+    assert_eq!(data.user_id, 8008);
+    assert_eq!(data.roles, roles::ADMIN);
+    assert_eq!(data.client_key, token.client_key);
+    assert_eq!(data.server_key, token.server_key);
+}
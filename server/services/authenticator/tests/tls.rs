@@ -0,0 +1,117 @@
+use authenticator::core::{Config, TlsConfig, UserInfo};
+use authenticator::server::{self, TlsError};
+use common::SERIAL_KEY;
+use hashbrown::HashMap;
+use rocket::config::{Environment, LoggingLevel};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+fn data_path(name: &str) -> String {
+    format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn make_config(certs: &str, key: &str) -> Config {
+    Config {
+        tls: Some(TlsConfig {
+            certs: certs.to_owned(),
+            key: key.to_owned(),
+        }),
+        auth_rate_limit: 10,
+        ..common::make_config()
+    }
+}
+
+fn make_rocket(port: u16) -> rocket::Rocket {
+    let rocket_config = rocket::Config::build(Environment::Development)
+        .address("127.0.0.1")
+        .port(port)
+        .log_level(LoggingLevel::Off)
+        .finalize()
+        .unwrap();
+
+    rocket::custom(rocket_config)
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Send a request over TLS, trusting only the test certificate authority.
+fn https_request(port: u16, request: &str) -> String {
+    let mut tls_config = rustls::ClientConfig::new();
+    let mut ca = BufReader::new(File::open(data_path("ca.pem")).unwrap());
+    tls_config.root_store.add_pem_file(&mut ca).unwrap();
+
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut session = rustls::ClientSession::new(&Arc::new(tls_config), dns_name);
+
+    // Wait for the server to come up
+    let mut socket = (0..100)
+        .filter_map(|_| match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(socket) => Some(socket),
+            Err(_) => {
+                thread::sleep(Duration::from_millis(50));
+                None
+            }
+        })
+        .next()
+        .expect("Failed connecting to the authenticator");
+
+    let mut stream = rustls::Stream::new(&mut session, &mut socket);
+    stream.write_all(request.as_bytes()).unwrap();
+
+    // The server may close the connection without a TLS close notification
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+
+    String::from_utf8(response).unwrap()
+}
+
+#[test]
+fn test_auth_over_https() {
+    let port = free_port();
+
+    let mut user_info = HashMap::new();
+    user_info.insert(SERIAL_KEY.to_owned(), UserInfo::new(8008));
+
+    let rocket = server::build(
+        make_rocket(port),
+        make_config(&data_path("cert.pem"), &data_path("key.pem")),
+        user_info,
+        &common::make_log(),
+    )
+    .unwrap();
+
+    thread::spawn(move || rocket.launch());
+
+    let request = format!(
+        "POST /user/auth HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        SERIAL_KEY.len(),
+        SERIAL_KEY
+    );
+    let response = https_request(port, &request);
+
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response {}", response);
+    assert!(response.contains(r#""result":"Ok""#), "Unexpected response {}", response);
+}
+
+#[test]
+fn test_missing_certs() {
+    let missing = data_path("missing.pem");
+
+    match server::build(
+        make_rocket(free_port()),
+        make_config(&missing, &data_path("key.pem")),
+        HashMap::new(),
+        &common::make_log(),
+    ) {
+        Err(TlsError::Unreadable(path, _)) => assert_eq!(path, missing),
+        _ => panic!("Missing certificate chain accepted"),
+    }
+}